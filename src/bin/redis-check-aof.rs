@@ -0,0 +1,165 @@
+//! Checks an AOF offline, either a single file or every file listed by a
+//! multi-part AOF manifest, reporting where it stops being valid. With
+//! `--fix`, the last file is truncated to its last valid command.
+
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::Parser;
+use codecrafters_redis::internal::{
+    aof::{self, ScanError},
+    rdb,
+};
+
+#[derive(Parser, Debug)]
+#[command(version = "1.0", about = "Checks an append only file for corruption")]
+struct Args {
+    /// Truncate the last file to its last valid command.
+    #[arg(long)]
+    fix: bool,
+
+    /// AOF file, or the manifest of a multi-part AOF.
+    file: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    let is_manifest = args
+        .file
+        .extension()
+        .is_some_and(|extension| extension == "manifest");
+    let files = if is_manifest {
+        println!("Start checking Multi Part AOF");
+        let manifest = match std::fs::read_to_string(&args.file) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                println!("Cannot open the manifest {}: {}", args.file.display(), e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let dir = args.file.parent().unwrap_or(Path::new(""));
+        match aof::load_order(&manifest) {
+            Ok(names) => names.iter().map(|name| dir.join(name)).collect(),
+            Err(e) => {
+                println!("Invalid manifest {}: {}", args.file.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        println!("Start checking Old-Style AOF");
+        vec![args.file.clone()]
+    };
+    for (i, file) in files.iter().enumerate() {
+        let last = i + 1 == files.len();
+        if !check_file(file, last, args.fix).await {
+            return ExitCode::FAILURE;
+        }
+    }
+    if is_manifest {
+        println!("All AOF files and manifest are valid");
+    }
+    ExitCode::SUCCESS
+}
+
+/// Checks one file, fixing it when asked to and it is the `last` one.
+/// Returns whether the file is (now) valid.
+async fn check_file(path: &Path, last: bool, fix: bool) -> bool {
+    let name = path.display();
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Cannot open file {}: {}", name, e);
+            return false;
+        }
+    };
+    let mut start = 0;
+    if data.starts_with(b"REDIS") {
+        match rdb::load(&data).await {
+            Ok(len) => {
+                println!("[offset 0] RDB preamble of {} is OK", name);
+                start = len;
+            }
+            Err(e) => {
+                println!("RDB preamble of {} is not sane: {}", name, e);
+                return false;
+            }
+        }
+    }
+    let scan = aof::scan(&data, start);
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for command in &scan.commands {
+        *counts.entry(command.cmd.to_lowercase()).or_default() += 1;
+    }
+    for (cmd, count) in counts {
+        println!("[info] {} {} commands", count, cmd);
+    }
+    println!(
+        "AOF analyzed: filename={}, size={}, ok_up_to={}, diff={}",
+        name,
+        data.len(),
+        scan.valid,
+        data.len() - scan.valid
+    );
+    let Some(error) = scan.error else {
+        println!("AOF {} is valid", name);
+        return true;
+    };
+    match error {
+        ScanError::Truncated => println!(
+            "[offset {}] Unexpected end of file in a command or transaction",
+            scan.valid
+        ),
+        ScanError::BadFormat { offset } => println!("[offset {}] Bad file format", offset),
+        ScanError::UnknownCommand { name, offset } => {
+            println!("[offset {}] Unknown command '{}'", offset, name)
+        }
+    }
+    if !fix {
+        println!(
+            "AOF {} is not valid. Use the --fix option to try fixing it.",
+            name
+        );
+        return false;
+    }
+    if !last {
+        println!(
+            "AOF {} is not the last file of the AOF and can't be fixed",
+            name
+        );
+        return false;
+    }
+    print!(
+        "This will shrink the AOF {} from {} bytes, with {} bytes, to {} bytes\nContinue? [y/N]: ",
+        name,
+        data.len(),
+        data.len() - scan.valid,
+        scan.valid
+    );
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    let _ = io::stdin().lock().read_line(&mut answer);
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        println!("Aborting...");
+        return false;
+    }
+    let truncated = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(scan.valid as u64));
+    match truncated {
+        Ok(()) => {
+            println!("Successfully truncated AOF {}", name);
+            true
+        }
+        Err(e) => {
+            println!("Failed to truncate AOF {}: {}", name, e);
+            false
+        }
+    }
+}
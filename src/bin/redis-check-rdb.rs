@@ -0,0 +1,78 @@
+//! Checks an RDB file offline: loads it the way the server does, reporting
+//! where it is corrupt and what keys could be read.
+
+use std::{collections::BTreeMap, path::PathBuf, process::ExitCode, time::SystemTime};
+
+use clap::Parser;
+use codecrafters_redis::internal::{rdb, storage::STORAGE};
+
+#[derive(Parser, Debug)]
+#[command(version = "1.0", about = "Checks an RDB file for corruption")]
+struct Args {
+    /// RDB file to check.
+    file: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    println!("[offset 0] Checking RDB file {}", args.file.display());
+    let data = match std::fs::read(&args.file) {
+        Ok(data) => data,
+        Err(e) => {
+            println!(
+                "Fatal error: can't open the RDB file {} for reading: {}",
+                args.file.display(),
+                e
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = rdb::load(&data).await;
+    match &result {
+        Ok(len) => {
+            if *len < data.len() {
+                println!(
+                    "[offset {}] {} bytes follow the end of the RDB",
+                    len,
+                    data.len() - len
+                );
+            }
+            println!("[offset {}] \\o/ RDB looks OK! \\o/", len);
+        }
+        Err(e) => {
+            println!("--- RDB ERROR DETECTED ---");
+            println!("{}", e);
+        }
+    }
+    report_keys().await;
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(_) => ExitCode::FAILURE,
+    }
+}
+
+/// Statistics about the keys read, up to the corruption if any.
+async fn report_keys() {
+    let storage = STORAGE.lock().await;
+    let now = SystemTime::now();
+    let mut types: BTreeMap<&str, usize> = BTreeMap::new();
+    let (mut expires, mut expired) = (0, 0);
+    for entry in storage.values() {
+        if let Ok(value) = entry.value() {
+            *types.entry(value.type_name()).or_default() += 1;
+        }
+        if let Some(expiry) = entry.expiry() {
+            expires += 1;
+            if expiry <= now {
+                expired += 1;
+            }
+        }
+    }
+    println!("[info] {} keys read", storage.len());
+    println!("[info] {} expires", expires);
+    println!("[info] {} already expired", expired);
+    for (type_name, count) in types {
+        println!("[info] {} {} keys", count, type_name);
+    }
+}
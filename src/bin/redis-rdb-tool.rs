@@ -0,0 +1,695 @@
+//! Moves data in and out of RDB files. `export` loads an RDB the way the
+//! server does and prints every key either as a JSON line or as the commands
+//! rebuilding it, ready for `redis-cli --pipe`. `import` turns JSON lines back
+//! into an RDB file, so exporting what was imported doubles as a round-trip
+//! check of the RDB reader and writer.
+//!
+//! The commands target Redis 7.4 or later, which has `HPEXPIREAT` for hash
+//! field TTLs. This server only implements the string and stream ones (`SET`
+//! with `PXAT`, `XADD`, `XSETID`, `XGROUP`, `XCLAIM` and `PEXPIREAT`), so
+//! only those keys can be loaded back into it; lists, sets, sorted sets and
+//! hashes need a real Redis.
+//!
+//! Each JSON line holds `key`, `type`, `ttl` (milliseconds left, -1 when the
+//! key doesn't expire) and `value`:
+//!
+//! ```text
+//! string  "value"
+//! list    ["item", ...]                             head first
+//! set     ["member", ...]
+//! zset    [["member", score], ...]                  "inf"/"-inf" for infinite scores
+//! hash    {"field": "value", ...}                   plus "field_ttls": {"field": ms}
+//! stream  {"entries": [["id", ["field", "value", ...]], ...], "last_id", "entries_added",
+//!          "max_deleted_entry_id", "groups": [{"name", "last_id", "entries_read",
+//!          "consumers": [{"name", "seen_time", "active_time"}],
+//!          "pending": [["id", "consumer", delivery_time, delivery_count]]}]}
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use codecrafters_redis::internal::{
+    json::Json,
+    rdb,
+    storage::{DBEntry, STORAGE},
+    types::{
+        now_millis, Consumer, ConsumerGroup, DBValue, HashType, ListType, PendingEntry, SetType,
+        SortedSetType, StreamId, StreamType, StringType,
+    },
+};
+
+/// Largest number of elements sent in a single command when exporting as
+/// RESP, so huge collections don't turn into huge requests.
+const BATCH_SIZE: usize = 128;
+
+#[derive(Parser, Debug)]
+#[command(
+    version = "1.0",
+    about = "Exports RDB files as JSON or RESP, and imports JSON"
+)]
+struct Args {
+    #[command(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Prints the keys of an RDB file.
+    Export {
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+
+        /// RDB file to read.
+        file: PathBuf,
+    },
+    /// Writes an RDB file out of JSON lines, as printed by `export`.
+    Import {
+        /// JSON lines to read, `-` for the standard input.
+        file: PathBuf,
+
+        /// RDB file to write.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// One JSON object per key.
+    Json,
+    /// Commands rebuilding every key, for `redis-cli --pipe` into Redis 7.4+.
+    Resp,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let result = match Args::parse().mode {
+        Mode::Export { format, file } => export(format, file).await,
+        Mode::Import { file, output } => import(file, output),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn export(format: Format, file: PathBuf) -> Result<(), String> {
+    let data = std::fs::read(&file)
+        .map_err(|e| format!("Can't open the RDB file {}: {}", file.display(), e))?;
+    rdb::load(&data)
+        .await
+        .map_err(|e| format!("Can't load the RDB file {}: {}", file.display(), e))?;
+
+    let storage = STORAGE.lock().await;
+    let mut keys: Vec<&String> = storage.keys().collect();
+    keys.sort();
+    let now = SystemTime::now();
+    let mut out = BufWriter::new(io::stdout().lock());
+    for key in keys {
+        let entry = &storage[key];
+        // Keys that expired since they were saved are left out.
+        let Ok(value) = entry.value() else {
+            continue;
+        };
+        let written = match format {
+            Format::Json => writeln!(out, "{}", to_json(key, entry.expiry(), value, now)),
+            Format::Resp => out.write_all(&to_commands(key, entry.expiry(), value, now)),
+        };
+        written.map_err(|e| format!("Can't write the export: {}", e))?;
+    }
+    out.flush()
+        .map_err(|e| format!("Can't write the export: {}", e))
+}
+
+fn import(file: PathBuf, output: PathBuf) -> Result<(), String> {
+    let mut input = String::new();
+    let read = if file.as_os_str() == "-" {
+        io::stdin().read_to_string(&mut input)
+    } else {
+        std::fs::File::open(&file).and_then(|mut file| file.read_to_string(&mut input))
+    };
+    read.map_err(|e| format!("Can't read {}: {}", file.display(), e))?;
+
+    let now = SystemTime::now();
+    let mut keys = HashMap::new();
+    for (i, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (key, entry) = Json::parse(line)
+            .and_then(|json| from_json(&json, now))
+            .map_err(|e| format!("Line {}: {}", i + 1, e))?;
+        keys.insert(key, entry);
+    }
+    std::fs::write(&output, rdb::encode(keys.iter()))
+        .map_err(|e| format!("Can't write {}: {}", output.display(), e))?;
+    eprintln!("{} keys written to {}", keys.len(), output.display());
+    Ok(())
+}
+
+fn millis_since_epoch(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The hash without the fields that already expired, which the server would
+/// no longer return.
+fn live_fields(hash: &HashType, now: SystemTime) -> HashType {
+    let now = millis_since_epoch(now);
+    let mut hash = hash.clone();
+    let expired: Vec<String> = hash
+        .expires
+        .iter()
+        .filter(|(_, &at)| at <= now)
+        .map(|(field, _)| field.clone())
+        .collect();
+    for field in expired {
+        hash.fields.remove(&field);
+        hash.expires.remove(&field);
+    }
+    hash
+}
+
+fn score_to_string(score: f64) -> String {
+    match score {
+        f64::INFINITY => "inf".to_string(),
+        f64::NEG_INFINITY => "-inf".to_string(),
+        // The shortest form reading back as the same double, switching to
+        // an exponent for very large or small scores.
+        score => {
+            let score = format!("{:?}", score);
+            match score.strip_suffix(".0") {
+                Some(integer) => integer.to_string(),
+                None => score,
+            }
+        }
+    }
+}
+
+fn score_to_json(score: f64) -> Json {
+    if score.is_finite() {
+        Json::Number(score_to_string(score))
+    } else {
+        Json::String(score_to_string(score))
+    }
+}
+
+fn to_json(key: &str, expiry: Option<SystemTime>, value: &dyn DBValue, now: SystemTime) -> Json {
+    let ttl = expiry.map_or(-1, |at| {
+        at.duration_since(now).unwrap_or_default().as_millis() as i64
+    });
+    let mut members = vec![
+        ("key".to_string(), Json::str(key)),
+        ("type".to_string(), Json::str(value.type_name())),
+        ("ttl".to_string(), Json::int(ttl)),
+    ];
+
+    let any = value.as_any();
+    // JSON strings are text: binary string values come out lossily.
+    let value = if let Some(s) = any.downcast_ref::<StringType>() {
+        Json::str(&String::from_utf8_lossy(&s.bytes))
+    } else if let Some(list) = any.downcast_ref::<ListType>() {
+        Json::Array(list.items.iter().map(|item| Json::str(item)).collect())
+    } else if let Some(set) = any.downcast_ref::<SetType>() {
+        let members: BTreeSet<&String> = set.members.iter().collect();
+        Json::Array(
+            members
+                .into_iter()
+                .map(|member| Json::str(member))
+                .collect(),
+        )
+    } else if let Some(zset) = any.downcast_ref::<SortedSetType>() {
+        Json::Array(
+            zset.sorted()
+                .into_iter()
+                .map(|(member, score)| Json::Array(vec![Json::str(member), score_to_json(score)]))
+                .collect(),
+        )
+    } else if let Some(hash) = any.downcast_ref::<HashType>() {
+        let fields: BTreeMap<String, String> = live_fields(hash, now).fields.into_iter().collect();
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(field, value)| (field, Json::String(value)))
+                .collect(),
+        )
+    } else if let Some(stream) = any.downcast_ref::<StreamType>() {
+        stream_to_json(stream)
+    } else {
+        Json::Null
+    };
+    members.push(("value".to_string(), value));
+
+    if let Some(hash) = any.downcast_ref::<HashType>() {
+        let hash = live_fields(hash, now);
+        if !hash.expires.is_empty() {
+            let now = millis_since_epoch(now);
+            let expires: BTreeMap<String, u64> = hash.expires.into_iter().collect();
+            let ttls = expires
+                .into_iter()
+                .map(|(field, at)| (field, Json::int(at - now)))
+                .collect();
+            members.push(("field_ttls".to_string(), Json::Object(ttls)));
+        }
+    }
+    Json::Object(members)
+}
+
+fn stream_to_json(stream: &StreamType) -> Json {
+    let id = |id: &StreamId| Json::String(id.to_string());
+    let entries = stream
+        .range(StreamId::MIN, StreamId::MAX, None, false)
+        .into_iter()
+        .map(|(entry_id, fields)| {
+            let fields = fields
+                .iter()
+                .flat_map(|(field, value)| [Json::str(field), Json::str(value)])
+                .collect();
+            Json::Array(vec![id(&entry_id), Json::Array(fields)])
+        })
+        .collect();
+    let groups = stream
+        .groups()
+        .iter()
+        .map(|(name, group)| {
+            let consumers = group
+                .consumers
+                .iter()
+                .map(|(name, consumer)| {
+                    Json::Object(vec![
+                        ("name".to_string(), Json::str(name)),
+                        ("seen_time".to_string(), Json::int(consumer.seen_time)),
+                        (
+                            "active_time".to_string(),
+                            consumer.active_time.map_or(Json::Null, Json::int),
+                        ),
+                    ])
+                })
+                .collect();
+            let pending = group
+                .pel
+                .iter()
+                .map(|(entry_id, pending)| {
+                    Json::Array(vec![
+                        id(entry_id),
+                        Json::str(&pending.consumer),
+                        Json::int(pending.delivery_time),
+                        Json::int(pending.delivery_count),
+                    ])
+                })
+                .collect();
+            Json::Object(vec![
+                ("name".to_string(), Json::str(name)),
+                ("last_id".to_string(), id(&group.last_id)),
+                (
+                    "entries_read".to_string(),
+                    group.entries_read.map_or(Json::Null, Json::int),
+                ),
+                ("consumers".to_string(), Json::Array(consumers)),
+                ("pending".to_string(), Json::Array(pending)),
+            ])
+        })
+        .collect();
+    Json::Object(vec![
+        ("entries".to_string(), Json::Array(entries)),
+        ("last_id".to_string(), id(&stream.last_id())),
+        (
+            "entries_added".to_string(),
+            Json::int(stream.entries_added()),
+        ),
+        (
+            "max_deleted_entry_id".to_string(),
+            id(&stream.max_deleted_entry_id()),
+        ),
+        ("groups".to_string(), Json::Array(groups)),
+    ])
+}
+
+/// A command as a RESP array of bulk strings.
+fn command<A: AsRef<[u8]>>(args: &[A]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        let arg = arg.as_ref();
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Emits `name key` followed by the arguments, `BATCH_SIZE` elements of
+/// `per_element` arguments at a time.
+fn batched(out: &mut Vec<u8>, name: &str, key: &str, args: &[String], per_element: usize) {
+    for chunk in args.chunks(BATCH_SIZE * per_element) {
+        let mut cmd = vec![name, key];
+        cmd.extend(chunk.iter().map(String::as_str));
+        out.extend(command(&cmd));
+    }
+}
+
+fn to_commands(
+    key: &str,
+    expiry: Option<SystemTime>,
+    value: &dyn DBValue,
+    now: SystemTime,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    let any = value.as_any();
+    if let Some(s) = any.downcast_ref::<StringType>() {
+        // Folding the TTL into SET keeps strings within what this server
+        // accepts as well.
+        let mut cmd = vec![b"SET".to_vec(), key.as_bytes().to_vec(), s.bytes.clone()];
+        if let Some(at) = expiry {
+            cmd.push(b"PXAT".to_vec());
+            cmd.push(millis_since_epoch(at).to_string().into_bytes());
+        }
+        out.extend(command(&cmd));
+    } else if let Some(list) = any.downcast_ref::<ListType>() {
+        let items: Vec<String> = list.items.iter().cloned().collect();
+        batched(&mut out, "RPUSH", key, &items, 1);
+    } else if let Some(set) = any.downcast_ref::<SetType>() {
+        let members: Vec<String> = set.members.iter().cloned().collect();
+        batched(&mut out, "SADD", key, &members, 1);
+    } else if let Some(zset) = any.downcast_ref::<SortedSetType>() {
+        let args: Vec<String> = zset
+            .sorted()
+            .into_iter()
+            .flat_map(|(member, score)| [score_to_string(score), member.clone()])
+            .collect();
+        batched(&mut out, "ZADD", key, &args, 2);
+    } else if let Some(hash) = any.downcast_ref::<HashType>() {
+        let hash = live_fields(hash, now);
+        let args: Vec<String> = hash
+            .fields
+            .iter()
+            .flat_map(|(field, value)| [field.clone(), value.clone()])
+            .collect();
+        batched(&mut out, "HSET", key, &args, 2);
+        for (field, at) in &hash.expires {
+            out.extend(command(&[
+                "HPEXPIREAT",
+                key,
+                &at.to_string(),
+                "FIELDS",
+                "1",
+                field,
+            ]));
+        }
+    } else if let Some(stream) = any.downcast_ref::<StreamType>() {
+        stream_commands(&mut out, key, stream);
+    }
+    if let (Some(at), false) = (expiry, any.is::<StringType>()) {
+        out.extend(command(&[
+            "PEXPIREAT",
+            key,
+            &millis_since_epoch(at).to_string(),
+        ]));
+    }
+    out
+}
+
+fn stream_commands(out: &mut Vec<u8>, key: &str, stream: &StreamType) {
+    let entries = stream.range(StreamId::MIN, StreamId::MAX, None, false);
+    for (id, fields) in &entries {
+        let id = id.to_string();
+        let mut cmd = vec!["XADD", key, &id];
+        cmd.extend(
+            fields
+                .iter()
+                .flat_map(|(field, value)| [field.as_str(), value.as_str()]),
+        );
+        out.extend(command(&cmd));
+    }
+    if entries.is_empty() {
+        // Creates the key without leaving an entry behind; XSETID below puts
+        // the real last ID back.
+        let id = stream.last_id().max(StreamId { millis: 0, seq: 1 });
+        out.extend(command(&[
+            "XADD",
+            key,
+            "MAXLEN",
+            "0",
+            &id.to_string(),
+            "x",
+            "y",
+        ]));
+    }
+    out.extend(command(&[
+        "XSETID",
+        key,
+        &stream.last_id().to_string(),
+        "ENTRIESADDED",
+        &stream.entries_added().to_string(),
+        "MAXDELETEDID",
+        &stream.max_deleted_entry_id().to_string(),
+    ]));
+
+    for (name, group) in stream.groups() {
+        let last_id = group.last_id.to_string();
+        let mut create = vec!["XGROUP", "CREATE", key, name, &last_id];
+        let entries_read = group.entries_read.map(|read| read.to_string());
+        if let Some(read) = &entries_read {
+            create.extend(["ENTRIESREAD", read]);
+        }
+        out.extend(command(&create));
+        for consumer in group.consumers.keys() {
+            out.extend(command(&["XGROUP", "CREATECONSUMER", key, name, consumer]));
+        }
+        for (id, pending) in &group.pel {
+            out.extend(command(&[
+                "XCLAIM",
+                key,
+                name,
+                &pending.consumer,
+                "0",
+                &id.to_string(),
+                "TIME",
+                &pending.delivery_time.to_string(),
+                "RETRYCOUNT",
+                &pending.delivery_count.to_string(),
+                "FORCE",
+                "JUSTID",
+            ]));
+        }
+    }
+}
+
+fn from_json(json: &Json, now: SystemTime) -> Result<(String, DBEntry), String> {
+    let field = |name: &str| json.get(name).ok_or(format!("missing '{}'", name));
+    let key = field("key")?.as_str().ok_or("'key' must be a string")?;
+    let type_name = field("type")?.as_str().ok_or("'type' must be a string")?;
+    let ttl = field("ttl")?.as_i64().ok_or("'ttl' must be an integer")?;
+    let value = field("value")?;
+    let strings = |json: &Json| -> Result<Vec<String>, String> {
+        json.as_array()
+            .ok_or(format!("a {} value must be an array", type_name))?
+            .iter()
+            .map(|item| {
+                item.as_str()
+                    .map(str::to_string)
+                    .ok_or(format!("{} elements must be strings", type_name))
+            })
+            .collect()
+    };
+
+    let mut entry = match type_name {
+        "string" => DBEntry::from_string(
+            value
+                .as_str()
+                .ok_or("a string value must be a string")?
+                .as_bytes(),
+        ),
+        "list" => DBEntry::from_value(ListType {
+            items: strings(value)?.into_iter().collect::<VecDeque<_>>(),
+        }),
+        "set" => DBEntry::from_value(SetType {
+            members: strings(value)?.into_iter().collect::<HashSet<_>>(),
+        }),
+        "zset" => DBEntry::from_value(zset_from_json(value)?),
+        "hash" => {
+            let mut hash = hash_from_json(value)?;
+            if let Some(ttls) = json.get("field_ttls") {
+                let now = millis_since_epoch(now);
+                for (field, ttl) in ttls.as_object().ok_or("'field_ttls' must be an object")? {
+                    let ttl = ttl.as_u64().ok_or("field TTLs must be integers")?;
+                    hash.expires.insert(field.clone(), now + ttl);
+                }
+            }
+            DBEntry::from_value(hash)
+        }
+        "stream" => DBEntry::from_stream(stream_from_json(value)?),
+        other => return Err(format!("unknown type '{}'", other)),
+    };
+    if ttl >= 0 {
+        entry.set_expiry_at(now + Duration::from_millis(ttl as u64));
+    }
+    Ok((key.to_string(), entry))
+}
+
+fn zset_from_json(value: &Json) -> Result<SortedSetType, String> {
+    let mut zset = SortedSetType::default();
+    for pair in value.as_array().ok_or("a zset value must be an array")? {
+        let (member, score) = match pair.as_array() {
+            Some([Json::String(member), score]) => (member, score),
+            _ => return Err("zset elements must be [member, score] pairs".to_string()),
+        };
+        let score = match score {
+            Json::String(s) if matches!(s.as_str(), "inf" | "+inf") => f64::INFINITY,
+            Json::String(s) if s == "-inf" => f64::NEG_INFINITY,
+            score => score.as_f64().ok_or("zset scores must be numbers")?,
+        };
+        zset.scores.insert(member.clone(), score);
+    }
+    Ok(zset)
+}
+
+fn hash_from_json(value: &Json) -> Result<HashType, String> {
+    let mut hash = HashType::default();
+    for (field, value) in value.as_object().ok_or("a hash value must be an object")? {
+        let value = value.as_str().ok_or("hash values must be strings")?;
+        hash.fields.insert(field.clone(), value.to_string());
+    }
+    Ok(hash)
+}
+
+fn stream_id(json: Option<&Json>) -> Result<StreamId, String> {
+    let id = json.and_then(Json::as_str).ok_or("missing stream ID")?;
+    StreamId::parse(id, 0).map_err(|_| format!("invalid stream ID '{}'", id))
+}
+
+fn stream_from_json(value: &Json) -> Result<StreamType, String> {
+    let mut stream = StreamType::default();
+    let entries = value
+        .get("entries")
+        .and_then(Json::as_array)
+        .ok_or("a stream needs an 'entries' array")?;
+    for entry in entries {
+        let (id, fields) = match entry.as_array() {
+            Some([id, Json::Array(fields)]) => (stream_id(Some(id))?, fields),
+            _ => return Err("stream entries must be [id, [field, value, ...]]".to_string()),
+        };
+        let fields: Vec<&str> = fields
+            .iter()
+            .map(Json::as_str)
+            .collect::<Option<_>>()
+            .ok_or("stream fields must be strings")?;
+        if fields.is_empty() || !fields.len().is_multiple_of(2) {
+            return Err(format!("stream entry {} needs field-value pairs", id));
+        }
+        let fields = fields
+            .chunks(2)
+            .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+            .collect();
+        stream
+            .add(id, fields)
+            .map_err(|_| format!("stream entry {} is out of order", id))?;
+    }
+
+    let entries_added = value
+        .get("entries_added")
+        .and_then(Json::as_u64)
+        .ok_or("a stream needs 'entries_added'")?;
+    stream
+        .set_id(
+            stream_id(value.get("last_id"))?,
+            Some(entries_added),
+            Some(stream_id(value.get("max_deleted_entry_id"))?),
+        )
+        .map_err(|e| e.to_string())?;
+
+    let groups = value
+        .get("groups")
+        .and_then(Json::as_array)
+        .ok_or("a stream needs a 'groups' array")?;
+    for group in groups {
+        let name = group
+            .get("name")
+            .and_then(Json::as_str)
+            .ok_or("a group needs a 'name'")?;
+        let entries_read = match group.get("entries_read") {
+            Some(read) if !read.is_null() => {
+                Some(read.as_u64().ok_or("'entries_read' must be an integer")?)
+            }
+            _ => None,
+        };
+
+        let mut consumers = BTreeMap::new();
+        for consumer in group
+            .get("consumers")
+            .and_then(Json::as_array)
+            .ok_or("a group needs a 'consumers' array")?
+        {
+            let name = consumer
+                .get("name")
+                .and_then(Json::as_str)
+                .ok_or("a consumer needs a 'name'")?;
+            consumers.insert(
+                name.to_string(),
+                Consumer {
+                    seen_time: consumer
+                        .get("seen_time")
+                        .and_then(Json::as_u64)
+                        .unwrap_or_else(now_millis),
+                    active_time: consumer.get("active_time").and_then(Json::as_u64),
+                    pending: BTreeSet::new(),
+                },
+            );
+        }
+
+        let mut pel = BTreeMap::new();
+        for pending in group
+            .get("pending")
+            .and_then(Json::as_array)
+            .ok_or("a group needs a 'pending' array")?
+        {
+            let (id, consumer, delivery_time, delivery_count) = match pending.as_array() {
+                Some([id, Json::String(consumer), time, count]) => (
+                    stream_id(Some(id))?,
+                    consumer,
+                    time.as_u64().ok_or("delivery times must be integers")?,
+                    count.as_u64().ok_or("delivery counts must be integers")?,
+                ),
+                _ => {
+                    return Err(
+                        "pending entries must be [id, consumer, delivery_time, delivery_count]"
+                            .to_string(),
+                    )
+                }
+            };
+            consumers
+                .get_mut(consumer)
+                .ok_or(format!("pending entry {} has an unknown consumer", id))?
+                .pending
+                .insert(id);
+            pel.insert(
+                id,
+                PendingEntry {
+                    consumer: consumer.clone(),
+                    delivery_time,
+                    delivery_count,
+                },
+            );
+        }
+
+        stream.insert_group(
+            name.to_string(),
+            ConsumerGroup {
+                last_id: stream_id(group.get("last_id"))?,
+                entries_read,
+                pel,
+                consumers,
+            },
+        );
+    }
+    Ok(stream)
+}
@@ -0,0 +1,803 @@
+//! Access control lists: users, their passwords, and the commands, keys and
+//! channels each of them may use.
+//!
+//! Every user has a root selector and possibly more selectors added with
+//! `(...)` rules; a command is allowed when any of them allows it. Passwords
+//! are only kept as their SHA-256 digest.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use crate::internal::{
+    client,
+    command_table::{self, CommandSpec, COMMAND_TABLE},
+    commands::CommandError,
+    glob::glob_match,
+    parser::Command,
+    resp::Reply,
+    sha256::sha256,
+    types::now_millis,
+};
+
+pub const DEFAULT_USER: &str = "default";
+
+/// Categories known to `+@<category>` rules and `ACL CAT`.
+pub const CATEGORIES: [&str; 21] = [
+    "keyspace",
+    "read",
+    "write",
+    "set",
+    "sortedset",
+    "list",
+    "hash",
+    "string",
+    "bitmap",
+    "hyperloglog",
+    "geo",
+    "stream",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+];
+
+/// Entries kept in `ACL LOG`, Redis' default `acllog-max-len`.
+const LOG_MAX_LEN: usize = 128;
+/// Identical denials closer than this are folded into one `ACL LOG` entry.
+const LOG_GROUPING_MILLIS: u64 = 60_000;
+
+lazy_static! {
+    static ref USERS: Mutex<BTreeMap<String, User>> = Mutex::new(default_users());
+    static ref LOG: Mutex<AclLog> = Mutex::new(AclLog::default());
+    /// Plain `requirepass`, reported back by `CONFIG GET`.
+    static ref REQUIREPASS: Mutex<String> = Mutex::new(String::new());
+    static ref ACLFILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+fn default_users() -> BTreeMap<String, User> {
+    BTreeMap::from([(DEFAULT_USER.to_string(), User::default_user())])
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPattern {
+    fn describe(&self) -> String {
+        match (self.read, self.write) {
+            (true, false) => format!("%R~{}", self.pattern),
+            (false, true) => format!("%W~{}", self.pattern),
+            _ => format!("~{}", self.pattern),
+        }
+    }
+}
+
+/// Commands, keys and channels allowed together.
+#[derive(Debug, Clone, Default)]
+struct Selector {
+    /// Command rules as given, compacted on `+@all`/`-@all`, to describe the
+    /// selector back.
+    command_rules: Vec<String>,
+    /// Names of the allowed commands and subcommands, e.g. `config|get`.
+    commands: HashSet<&'static str>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<String>,
+}
+
+/// Why a command was refused, along with the offending object.
+enum Denial {
+    Command(&'static str),
+    Key(String),
+    Channel(String),
+}
+
+impl Denial {
+    fn reason(&self) -> &'static str {
+        match self {
+            Denial::Command(_) => "command",
+            Denial::Key(_) => "key",
+            Denial::Channel(_) => "channel",
+        }
+    }
+
+    fn object(&self) -> String {
+        match self {
+            Denial::Command(name) => name.to_string(),
+            Denial::Key(object) | Denial::Channel(object) => object.clone(),
+        }
+    }
+}
+
+/// Every command and subcommand of the table.
+fn all_commands() -> impl Iterator<Item = &'static CommandSpec> {
+    COMMAND_TABLE
+        .iter()
+        .flat_map(|spec| std::iter::once(spec).chain(spec.subcommands))
+}
+
+impl Selector {
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let lowered = rule.to_lowercase();
+        match lowered.as_str() {
+            "allkeys" => return self.apply("~*"),
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => return self.apply("&*"),
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => return self.apply("+@all"),
+            "nocommands" => return self.apply("-@all"),
+            _ if rule.starts_with('~') || rule.starts_with('%') => {
+                let (access, pattern) = rule.split_once('~').ok_or("Syntax error")?;
+                let access = access.strip_prefix('%').unwrap_or(access).to_uppercase();
+                let (read, write) = match access.as_str() {
+                    "" | "RW" | "WR" => (true, true),
+                    "R" => (true, false),
+                    "W" => (false, true),
+                    _ => return Err("Syntax error".to_string()),
+                };
+                let pattern = KeyPattern {
+                    pattern: pattern.to_string(),
+                    read,
+                    write,
+                };
+                if !self.keys.contains(&pattern) {
+                    self.keys.push(pattern);
+                }
+            }
+            _ if rule.starts_with('&') => {
+                let channel = rule[1..].to_string();
+                if !self.channels.contains(&channel) {
+                    self.channels.push(channel);
+                }
+            }
+            _ if rule.starts_with('+') || rule.starts_with('-') => {
+                self.apply_command_rule(&lowered)?
+            }
+            _ => return Err("Syntax error".to_string()),
+        }
+        Ok(())
+    }
+
+    fn apply_command_rule(&mut self, rule: &str) -> Result<(), String> {
+        let unknown = || "Unknown command or category name in ACL".to_string();
+        let (allow, name) = rule.split_at(1);
+        let allow = allow == "+";
+        let names: Vec<&'static str> = if let Some(category) = name.strip_prefix('@') {
+            if category == "all" {
+                all_commands().map(|spec| spec.name).collect()
+            } else if CATEGORIES.contains(&category) {
+                all_commands()
+                    .filter(|spec| spec.categories().contains(&category))
+                    .map(|spec| spec.name)
+                    .collect()
+            } else {
+                return Err(unknown());
+            }
+        } else if let Some((container, sub)) = name.split_once('|') {
+            let spec = command_table::lookup(container)
+                .and_then(|spec| spec.subcommand(sub))
+                .ok_or_else(unknown)?;
+            vec![spec.name]
+        } else {
+            let spec = command_table::lookup(name).ok_or_else(unknown)?;
+            std::iter::once(spec)
+                .chain(spec.subcommands)
+                .map(|spec| spec.name)
+                .collect()
+        };
+        for name in names {
+            if allow {
+                self.commands.insert(name);
+            } else {
+                self.commands.remove(name);
+            }
+        }
+        if name == "@all" {
+            self.command_rules.clear();
+        }
+        self.command_rules.push(rule.to_string());
+        Ok(())
+    }
+
+    fn describe_commands(&self) -> String {
+        if self.command_rules.is_empty() {
+            "-@all".to_string()
+        } else {
+            self.command_rules.join(" ")
+        }
+    }
+
+    fn describe_keys(&self) -> String {
+        self.keys
+            .iter()
+            .map(KeyPattern::describe)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn describe_channels(&self) -> String {
+        self.channels
+            .iter()
+            .map(|channel| format!("&{}", channel))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Rules recreating the selector, as listed by `ACL LIST`.
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.keys.is_empty() {
+            parts.push(self.describe_keys());
+        }
+        if self.channels.is_empty() {
+            parts.push("resetchannels".to_string());
+        } else {
+            parts.push(self.describe_channels());
+        }
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+
+    fn check(&self, spec: &'static CommandSpec, argv: &[String]) -> Result<(), Denial> {
+        if !self.commands.contains(spec.name) {
+            return Err(Denial::Command(spec.name));
+        }
+        if spec.has_flag(command_table::PUBSUB) {
+            return self.check_channels(spec, argv);
+        }
+        let read = spec.has_flag(command_table::READONLY);
+        let write = spec.has_flag(command_table::WRITE);
+        for key in spec.keys(argv) {
+            let allowed = self.keys.iter().any(|pattern| {
+                (!read || pattern.read)
+                    && (!write || pattern.write)
+                    && glob_match(&pattern.pattern, key)
+            });
+            if !allowed {
+                return Err(Denial::Key(key.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_channels(&self, spec: &CommandSpec, argv: &[String]) -> Result<(), Denial> {
+        let (channels, literal) = match spec.name {
+            "publish" | "spublish" => (&argv[1..2], false),
+            "subscribe" | "ssubscribe" => (&argv[1..], false),
+            // Patterns must be allowed as they are, since what they match
+            // cannot be told in advance.
+            "psubscribe" => (&argv[1..], true),
+            _ => return Ok(()),
+        };
+        for channel in channels {
+            let allowed = self.channels.iter().any(|pattern| {
+                pattern == "*" || pattern == channel || (!literal && glob_match(pattern, channel))
+            });
+            if !allowed {
+                return Err(Denial::Channel(channel.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct User {
+    name: String,
+    enabled: bool,
+    nopass: bool,
+    /// Hex encoded SHA-256 of each password.
+    passwords: BTreeSet<String>,
+    root: Selector,
+    selectors: Vec<Selector>,
+}
+
+fn hash_password(password: &str) -> String {
+    hex::encode(sha256(password.as_bytes()))
+}
+
+fn valid_hash(hash: &str) -> Result<(), String> {
+    let valid = hash.len() == 64
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+    if valid {
+        Ok(())
+    } else {
+        Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string())
+    }
+}
+
+/// Splits ACL rules on whitespace, keeping a `(...)` selector as one rule.
+fn split_rules(line: &str) -> Result<Vec<String>, String> {
+    let mut rules = Vec::new();
+    let mut words = line.split_whitespace();
+    while let Some(word) = words.next() {
+        let mut rule = word.to_string();
+        if rule.starts_with('(') {
+            while !rule.ends_with(')') {
+                let next = words
+                    .next()
+                    .ok_or("Unmatched parenthesis in acl selector")?;
+                rule.push(' ');
+                rule.push_str(next);
+            }
+        }
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+impl User {
+    /// A new user: disabled, without password and allowed nothing.
+    fn new(name: &str) -> Self {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            root: Selector::default(),
+            selectors: Vec::new(),
+        }
+    }
+
+    /// The `default` user, allowed everything without a password.
+    fn default_user() -> Self {
+        let mut user = User::new(DEFAULT_USER);
+        for rule in ["on", "nopass", "~*", "&*", "+@all"] {
+            user.apply(rule).expect("default user rules are valid");
+        }
+        user
+    }
+
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "reset" => *self = User::new(&self.name),
+            "clearselectors" => self.selectors.clear(),
+            _ if rule.starts_with('>') => {
+                self.passwords.insert(hash_password(&rule[1..]));
+                self.nopass = false;
+            }
+            _ if rule.starts_with('<') => {
+                if !self.passwords.remove(&hash_password(&rule[1..])) {
+                    return Err(
+                        "The password you are trying to remove from the user does not exist"
+                            .to_string(),
+                    );
+                }
+            }
+            _ if rule.starts_with('#') => {
+                valid_hash(&rule[1..])?;
+                self.passwords.insert(rule[1..].to_string());
+                self.nopass = false;
+            }
+            _ if rule.starts_with('!') => {
+                valid_hash(&rule[1..])?;
+                if !self.passwords.remove(&rule[1..]) {
+                    return Err(
+                        "The password you are trying to remove from the user does not exist"
+                            .to_string(),
+                    );
+                }
+            }
+            _ if rule.starts_with('(') && rule.ends_with(')') => {
+                let mut selector = Selector::default();
+                for rule in split_rules(&rule[1..rule.len() - 1])? {
+                    selector.apply(&rule)?;
+                }
+                self.selectors.push(selector);
+            }
+            _ => self.root.apply(rule)?,
+        }
+        Ok(())
+    }
+
+    fn authenticates(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash_password(password)))
+    }
+
+    fn check(&self, spec: &'static CommandSpec, argv: &[String]) -> Result<(), Denial> {
+        let denial = match self.root.check(spec, argv) {
+            Ok(()) => return Ok(()),
+            Err(denial) => denial,
+        };
+        if self
+            .selectors
+            .iter()
+            .any(|selector| selector.check(spec, argv).is_ok())
+        {
+            return Ok(());
+        }
+        Err(denial)
+    }
+
+    /// Rules recreating the user, as listed by `ACL LIST` and saved in the
+    /// ACL file.
+    fn describe(&self) -> String {
+        let mut parts = vec![
+            format!("user {}", self.name),
+            if self.enabled { "on" } else { "off" }.to_string(),
+        ];
+        if self.nopass {
+            parts.push("nopass".to_string());
+        }
+        parts.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        parts.push(self.root.describe());
+        parts.extend(
+            self.selectors
+                .iter()
+                .map(|selector| format!("({})", selector.describe())),
+        );
+        parts.join(" ")
+    }
+
+    /// `ACL GETUSER` reply.
+    fn info(&self) -> Reply {
+        let mut flags = vec![Reply::bulk(if self.enabled { "on" } else { "off" })];
+        if self.nopass {
+            flags.push(Reply::bulk("nopass"));
+        }
+        let selector_info = |selector: &Selector| {
+            vec![
+                ("commands", Reply::bulk(selector.describe_commands())),
+                ("keys", Reply::bulk(selector.describe_keys())),
+                ("channels", Reply::bulk(selector.describe_channels())),
+            ]
+        };
+        let mut fields = vec![
+            ("flags", Reply::Array(flags)),
+            (
+                "passwords",
+                Reply::bulks(self.passwords.iter().map(String::as_str)),
+            ),
+        ];
+        fields.extend(selector_info(&self.root));
+        fields.push((
+            "selectors",
+            Reply::Array(
+                self.selectors
+                    .iter()
+                    .map(|selector| Reply::fields(selector_info(selector)))
+                    .collect(),
+            ),
+        ));
+        Reply::fields(fields)
+    }
+}
+
+/// Whether new connections start authenticated as `default`.
+pub fn default_user_is_open() -> bool {
+    USERS
+        .lock()
+        .unwrap()
+        .get(DEFAULT_USER)
+        .is_some_and(|user| user.enabled && user.nopass)
+}
+
+/// Checks the credentials, logging a failed attempt.
+pub fn authenticate(username: &str, password: &str) -> bool {
+    let authenticated = USERS
+        .lock()
+        .unwrap()
+        .get(username)
+        .is_some_and(|user| user.authenticates(password));
+    if !authenticated {
+        log_denial("auth", "toplevel", "AUTH".to_string(), username);
+    }
+    authenticated
+}
+
+/// Checks the current connection may run `command`, before it runs or gets
+/// queued in a transaction. Unknown commands are left for the dispatch to
+/// reject.
+pub fn authorize(command: &Command, in_multi: bool) -> Result<(), CommandError> {
+    let Some(spec) = command_table::lookup(&command.cmd) else {
+        return Ok(());
+    };
+    let client = client::current();
+    if !client.authenticated && !spec.has_flag(command_table::NO_AUTH) {
+        return Err(CommandError::WithCode(
+            "NOAUTH",
+            "Authentication required.".to_string(),
+        ));
+    }
+    let spec = command
+        .args
+        .first()
+        .and_then(|sub| spec.subcommand(sub))
+        .unwrap_or(spec);
+    let argv: Vec<String> = std::iter::once(command.cmd.clone())
+        .chain(command.args.iter().cloned())
+        .collect();
+    let users = USERS.lock().unwrap();
+    let denial = match users.get(&client.user) {
+        Some(user) => match user.check(spec, &argv) {
+            Ok(()) => return Ok(()),
+            Err(denial) => denial,
+        },
+        // The user was deleted since the connection authenticated.
+        None => Denial::Command(spec.name),
+    };
+    drop(users);
+    let context = if in_multi { "multi" } else { "toplevel" };
+    log_denial(denial.reason(), context, denial.object(), &client.user);
+    let message = match denial {
+        Denial::Command(name) => format!(
+            "User {} has no permissions to run the '{}' command",
+            client.user, name
+        ),
+        Denial::Key(_) => "No permissions to access a key".to_string(),
+        Denial::Channel(_) => "No permissions to access a channel".to_string(),
+    };
+    Err(CommandError::WithCode("NOPERM", message))
+}
+
+/// `ACL SETUSER`: creates the user if needed, then applies the rules. Either
+/// every rule applies or none does.
+pub fn set_user(name: &str, rules: &[String]) -> Result<(), String> {
+    let mut users = USERS.lock().unwrap();
+    let mut user = users.get(name).cloned().unwrap_or_else(|| User::new(name));
+    for rule in rules {
+        user.apply(rule)
+            .map_err(|e| format!("Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+    }
+    users.insert(name.to_string(), user);
+    Ok(())
+}
+
+/// `ACL DELUSER`, returning how many users were deleted.
+pub fn delete_users(names: &[String]) -> Result<usize, String> {
+    if names.iter().any(|name| name == DEFAULT_USER) {
+        return Err("The 'default' user cannot be removed".to_string());
+    }
+    let mut users = USERS.lock().unwrap();
+    Ok(names
+        .iter()
+        .filter(|name| users.remove(name.as_str()).is_some())
+        .count())
+}
+
+pub fn get_user(name: &str) -> Reply {
+    USERS
+        .lock()
+        .unwrap()
+        .get(name)
+        .map_or(Reply::Null, User::info)
+}
+
+pub fn list() -> Vec<String> {
+    USERS.lock().unwrap().values().map(User::describe).collect()
+}
+
+pub fn usernames() -> Vec<String> {
+    USERS.lock().unwrap().keys().cloned().collect()
+}
+
+/// `ACL DRYRUN`: `None` when the user may run the command, otherwise why it
+/// may not.
+pub fn dry_run(username: &str, command: &Command) -> Result<Option<String>, String> {
+    let users = USERS.lock().unwrap();
+    let user = users
+        .get(username)
+        .ok_or_else(|| format!("User '{}' not found", username))?;
+    let spec = command_table::lookup(&command.cmd)
+        .ok_or_else(|| format!("Command '{}' not found", command.cmd))?;
+    spec.check_arity(command)
+        .map_err(|name| format!("wrong number of arguments for '{}' command", name))?;
+    let spec = command
+        .args
+        .first()
+        .and_then(|sub| spec.subcommand(sub))
+        .unwrap_or(spec);
+    let argv: Vec<String> = std::iter::once(command.cmd.clone())
+        .chain(command.args.iter().cloned())
+        .collect();
+    Ok(user.check(spec, &argv).err().map(|denial| match denial {
+        Denial::Command(name) => {
+            format!("This user has no permissions to run the '{}' command", name)
+        }
+        Denial::Key(key) => format!("This user has no permissions to access the '{}' key", key),
+        Denial::Channel(channel) => format!(
+            "This user has no permissions to access the '{}' channel",
+            channel
+        ),
+    }))
+}
+
+/// Commands in a category, `None` for an unknown category.
+pub fn category_commands(category: &str) -> Option<Vec<&'static str>> {
+    let category = category.to_lowercase();
+    CATEGORIES.contains(&category.as_str()).then(|| {
+        all_commands()
+            .filter(|spec| spec.categories().contains(&category.as_str()))
+            .map(|spec| spec.name)
+            .collect()
+    })
+}
+
+pub fn requirepass() -> String {
+    REQUIREPASS.lock().unwrap().clone()
+}
+
+/// Sets the `default` user's password, or lets it in without one when empty.
+pub fn set_requirepass(password: &str) {
+    let rules = if password.is_empty() {
+        vec!["nopass".to_string()]
+    } else {
+        vec!["resetpass".to_string(), format!(">{}", password)]
+    };
+    set_user(DEFAULT_USER, &rules).expect("password rules are valid");
+    *REQUIREPASS.lock().unwrap() = password.to_string();
+}
+
+pub fn aclfile() -> Option<PathBuf> {
+    ACLFILE.lock().unwrap().clone()
+}
+
+pub fn set_aclfile(path: Option<PathBuf>) {
+    *ACLFILE.lock().unwrap() = path;
+}
+
+fn no_aclfile() -> String {
+    "This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.".to_string()
+}
+
+/// Replaces every user with the ones of the ACL file. Nothing changes when
+/// any line is invalid.
+pub fn load_file() -> Result<(), String> {
+    let path = aclfile().ok_or_else(no_aclfile)?;
+    let content = fs::read_to_string(&path).map_err(|e| {
+        format!(
+            "Error loading ACLs, opening file '{}': {}",
+            path.display(),
+            e
+        )
+    })?;
+    let mut users = BTreeMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line_error = |e: String| format!("{}:{}: {}", path.display(), number + 1, e);
+        let rules = split_rules(line).map_err(line_error)?;
+        let Some((keyword, rules)) = rules.split_first() else {
+            continue;
+        };
+        let (name, rules) = match (keyword.as_str(), rules.split_first()) {
+            ("user", Some((name, rules))) => (name, rules),
+            _ => return Err(line_error("should start with user keyword".to_string())),
+        };
+        if users.contains_key(name) {
+            return Err(line_error(format!("Duplicate user '{}' found", name)));
+        }
+        let mut user = User::new(name);
+        for rule in rules {
+            user.apply(rule)
+                .map_err(|e| line_error(format!("Error in user declaration '{}': {}", rule, e)))?;
+        }
+        users.insert(name.clone(), user);
+    }
+    users
+        .entry(DEFAULT_USER.to_string())
+        .or_insert_with(User::default_user);
+    *USERS.lock().unwrap() = users;
+    Ok(())
+}
+
+/// Writes every user to the ACL file, through a temporary file so a crash
+/// never leaves it half written.
+pub fn save_file() -> Result<(), String> {
+    let path = aclfile().ok_or_else(no_aclfile)?;
+    let content: String = list().into_iter().map(|line| line + "\n").collect();
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)
+        .and_then(|()| fs::rename(&tmp, &path))
+        .map_err(|e| format!("There was an error trying to save the ACLs: {}", e))
+}
+
+struct LogEntry {
+    id: u64,
+    count: u64,
+    reason: &'static str,
+    context: &'static str,
+    object: String,
+    username: String,
+    client_info: String,
+    created: u64,
+    updated: u64,
+}
+
+#[derive(Default)]
+struct AclLog {
+    /// Newest first.
+    entries: VecDeque<LogEntry>,
+    next_id: u64,
+}
+
+fn log_denial(reason: &'static str, context: &'static str, object: String, username: &str) {
+    let client = client::current();
+    let now = now_millis();
+    let mut log = LOG.lock().unwrap();
+    let similar = log.entries.iter().position(|entry| {
+        entry.reason == reason
+            && entry.context == context
+            && entry.object == object
+            && entry.username == username
+            && now - entry.updated < LOG_GROUPING_MILLIS
+    });
+    if let Some(pos) = similar {
+        let mut entry = log.entries.remove(pos).expect("position is in range");
+        entry.count += 1;
+        entry.updated = now;
+        log.entries.push_front(entry);
+        return;
+    }
+    let id = log.next_id;
+    log.next_id += 1;
+    log.entries.push_front(LogEntry {
+        id,
+        count: 1,
+        reason,
+        context,
+        object,
+        username: username.to_string(),
+        client_info: client.describe(),
+        created: now,
+        updated: now,
+    });
+    log.entries.truncate(LOG_MAX_LEN);
+}
+
+/// `ACL LOG [count]`, newest entries first.
+pub fn log_reply(count: usize) -> Reply {
+    let now = now_millis();
+    let log = LOG.lock().unwrap();
+    Reply::Array(
+        log.entries
+            .iter()
+            .take(count)
+            .map(|entry| {
+                Reply::fields([
+                    ("count", Reply::Integer(entry.count as i64)),
+                    ("reason", Reply::bulk(entry.reason)),
+                    ("context", Reply::bulk(entry.context)),
+                    ("object", Reply::bulk(entry.object.clone())),
+                    ("username", Reply::bulk(entry.username.clone())),
+                    (
+                        "age-seconds",
+                        Reply::Double((now - entry.created) as f64 / 1000.0),
+                    ),
+                    ("client-info", Reply::bulk(entry.client_info.clone())),
+                    ("entry-id", Reply::Integer(entry.id as i64)),
+                    ("timestamp-created", Reply::Integer(entry.created as i64)),
+                    (
+                        "timestamp-last-updated",
+                        Reply::Integer(entry.updated as i64),
+                    ),
+                ])
+            })
+            .collect(),
+    )
+}
+
+pub fn reset_log() {
+    LOG.lock().unwrap().entries.clear();
+}
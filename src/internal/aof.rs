@@ -0,0 +1,1165 @@
+//! Append-only file, in the multi-part layout of Redis 7: a base file holding
+//! the dataset at the last rewrite, incremental files with the write commands
+//! since, in the RESP form they are propagated to replicas, and a manifest
+//! listing them. They all live in `appenddirname`.
+//!
+//! `BGREWRITEAOF` opens a fresh incremental file for new writes, then saves
+//! the dataset as a new RDB base in the background; once written, the
+//! manifest is switched over and the files it replaces are deleted.
+
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::RwLock;
+
+use crate::internal::{
+    commands::{self, CommandError, COMMANDS_REGISTRY},
+    parser::{self, Command},
+    persistence,
+    rdb::{self, RdbError},
+    resp::Reply,
+    server::ServerMetadata,
+    storage::{DBEntry, STORAGE},
+    transaction::EXEC_LOCK,
+    types::now_millis,
+};
+
+const FSYNC_PERIOD: Duration = Duration::from_secs(1);
+
+/// `appendfsync`: when appended commands are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fsync {
+    /// After every write command, before replying.
+    Always,
+    /// Once per second, in the background.
+    Everysec,
+    /// Left to the operating system.
+    No,
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::Everysec),
+            "no" => Ok(Fsync::No),
+            _ => Err("argument(s) must be one of the following: always, everysec, no".to_string()),
+        }
+    }
+}
+
+impl Display for Fsync {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Fsync::Always => "always",
+            Fsync::Everysec => "everysec",
+            Fsync::No => "no",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// `appendonly`.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// `appendfsync`, as the index of the policy in `FSYNC_POLICIES`.
+static FSYNC: AtomicU8 = AtomicU8::new(1);
+const FSYNC_POLICIES: [Fsync; 3] = [Fsync::Always, Fsync::Everysec, Fsync::No];
+/// `aof-load-truncated`: whether a file cut in the middle of a command is
+/// loaded up to the last complete one instead of refusing to start.
+static LOAD_TRUNCATED: AtomicBool = AtomicBool::new(true);
+/// `auto-aof-rewrite-percentage`: growth since the last rewrite, relative to
+/// the size it left, that triggers a new one. 0 disables automatic rewrites.
+static REWRITE_PERCENTAGE: AtomicU64 = AtomicU64::new(100);
+/// `auto-aof-rewrite-min-size`: no automatic rewrite below this size.
+static REWRITE_MIN_SIZE: AtomicU64 = AtomicU64::new(64 * 1024 * 1024);
+
+static LAST_WRITE_OK: AtomicBool = AtomicBool::new(true);
+static FSYNC_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// `appendfilename`, the prefix of the files' names.
+    static ref FILENAME: Mutex<String> = Mutex::new("appendonly.aof".to_string());
+    /// `appenddirname`, relative to `dir`.
+    static ref DIRNAME: Mutex<String> = Mutex::new("appendonlydir".to_string());
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+struct State {
+    manifest: Manifest,
+    /// Incremental file being appended to, `None` while the AOF is off.
+    incr: Option<File>,
+    /// Commands were written since the last fsync.
+    unsynced: bool,
+    /// Size of the base and incremental files.
+    size: u64,
+    /// `size` after the last rewrite or load, the reference for
+    /// `auto-aof-rewrite-percentage`.
+    base_size: u64,
+    rewrite: Option<Rewrite>,
+    /// `BGREWRITEAOF` was asked for while a `BGSAVE` was running.
+    rewrite_scheduled: bool,
+    last_rewrite_time: Option<Duration>,
+    last_rewrite_ok: bool,
+    rewrites: u64,
+    rewrites_consecutive_failures: u64,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            manifest: Manifest::default(),
+            incr: None,
+            unsynced: false,
+            size: 0,
+            base_size: 0,
+            rewrite: None,
+            rewrite_scheduled: false,
+            last_rewrite_time: None,
+            last_rewrite_ok: true,
+            rewrites: 0,
+            rewrites_consecutive_failures: 0,
+        }
+    }
+}
+
+struct Rewrite {
+    started: Instant,
+    /// Incremental files from this one on hold the writes made after the
+    /// snapshot, and outlive the rewrite.
+    first_incr: usize,
+}
+
+impl State {
+    fn append(&mut self, bytes: &[u8]) {
+        let Some(file) = self.incr.as_mut() else {
+            return;
+        };
+        if let Err(e) = file.write_all(bytes) {
+            eprintln!("Error writing to the AOF file: {}", e);
+            LAST_WRITE_OK.store(false, Ordering::Relaxed);
+            return;
+        }
+        LAST_WRITE_OK.store(true, Ordering::Relaxed);
+        self.size += bytes.len() as u64;
+        self.unsynced = true;
+        if fsync_policy() == Fsync::Always {
+            match file.sync_data() {
+                Ok(()) => self.unsynced = false,
+                Err(e) => eprintln!("Can't fsync the AOF file: {}", e),
+            }
+        }
+    }
+
+    /// Switches appends to a new incremental file, recorded in the manifest
+    /// before anything is written to it.
+    fn open_new_incr(&mut self, aof_dir: &Path) -> io::Result<()> {
+        let seq = self.manifest.incrs.last().map_or(1, |incr| incr.seq + 1);
+        let incr = AofFile {
+            name: format!("{}.{}.incr.aof", filename(), seq),
+            seq,
+            kind: AofKind::Incr,
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(aof_dir.join(&incr.name))?;
+        self.manifest.incrs.push(incr);
+        self.manifest.write(aof_dir)?;
+        if let Some(old) = self.incr.replace(file) {
+            if let Err(e) = old.sync_data() {
+                eprintln!("Can't fsync the AOF file: {}", e);
+            }
+        }
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Makes the base written at `temp` the new base, dropping the files it
+    /// supersedes: the previous base and incremental files before
+    /// `first_incr`.
+    fn install_base(&mut self, aof_dir: &Path, temp: &Path, first_incr: usize) -> io::Result<()> {
+        let seq = self.manifest.base.as_ref().map_or(1, |base| base.seq + 1);
+        let base = AofFile {
+            name: format!("{}.{}.base.rdb", filename(), seq),
+            seq,
+            kind: AofKind::Base,
+        };
+        fs::rename(temp, aof_dir.join(&base.name))?;
+        let mut history: Vec<AofFile> = self.manifest.incrs.drain(..first_incr).collect();
+        history.extend(self.manifest.base.replace(base));
+        for file in &mut history {
+            file.kind = AofKind::History;
+        }
+        self.manifest.history.extend(history);
+        self.manifest.write(aof_dir)?;
+        self.manifest.delete_history(aof_dir);
+        self.size = self.manifest.size(aof_dir);
+        self.base_size = self.size;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AofKind {
+    Base,
+    Incr,
+    /// Superseded by a rewrite, waiting to be deleted.
+    History,
+}
+
+impl AofKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            AofKind::Base => "b",
+            AofKind::Incr => "i",
+            AofKind::History => "h",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AofFile {
+    name: String,
+    seq: u64,
+    kind: AofKind,
+}
+
+/// The files making up the AOF, one per line of the manifest:
+///
+/// ```text
+/// file appendonly.aof.2.base.rdb seq 2 type b
+/// file appendonly.aof.5.incr.aof seq 5 type i
+/// ```
+#[derive(Debug, Default)]
+struct Manifest {
+    base: Option<AofFile>,
+    incrs: Vec<AofFile>,
+    history: Vec<AofFile>,
+}
+
+impl Manifest {
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut manifest = Manifest::default();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let field = |key: &str| {
+                parts
+                    .chunks_exact(2)
+                    .find(|pair| pair[0] == key)
+                    .map(|pair| pair[1])
+            };
+            let invalid = || format!("Invalid AOF manifest line: {}", line);
+            let name = field("file").ok_or_else(invalid)?.to_string();
+            let seq = field("seq")
+                .and_then(|seq| seq.parse().ok())
+                .ok_or_else(invalid)?;
+            match field("type").ok_or_else(invalid)? {
+                "b" => {
+                    if manifest.base.is_some() {
+                        return Err("Found duplicate base file information".to_string());
+                    }
+                    manifest.base = Some(AofFile {
+                        name,
+                        seq,
+                        kind: AofKind::Base,
+                    });
+                }
+                "i" => {
+                    if manifest.incrs.last().is_some_and(|incr| incr.seq >= seq) {
+                        return Err("Found a non-monotonic sequence number".to_string());
+                    }
+                    manifest.incrs.push(AofFile {
+                        name,
+                        seq,
+                        kind: AofKind::Incr,
+                    });
+                }
+                "h" => manifest.history.push(AofFile {
+                    name,
+                    seq,
+                    kind: AofKind::History,
+                }),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(manifest)
+    }
+
+    fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(&self.history).chain(&self.incrs)
+    }
+
+    /// Writes the manifest through a temporary file renamed over it.
+    fn write(&self, aof_dir: &Path) -> io::Result<()> {
+        let contents: String = self
+            .files()
+            .map(|file| {
+                format!(
+                    "file {} seq {} type {}\n",
+                    file.name,
+                    file.seq,
+                    file.kind.as_str()
+                )
+            })
+            .collect();
+        let temp = aof_dir.join(format!("temp-{}", manifest_name()));
+        let mut file = File::create(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, aof_dir.join(manifest_name()))
+    }
+
+    fn delete_history(&mut self, aof_dir: &Path) {
+        for file in self.history.drain(..) {
+            if let Err(e) = fs::remove_file(aof_dir.join(&file.name)) {
+                eprintln!("Can't remove the history AOF file {}: {}", file.name, e);
+            }
+        }
+        if let Err(e) = self.write(aof_dir) {
+            eprintln!("Can't update the AOF manifest: {}", e);
+        }
+    }
+
+    /// Size of the base and incremental files.
+    fn size(&self, aof_dir: &Path) -> u64 {
+        self.base
+            .iter()
+            .chain(&self.incrs)
+            .filter_map(|file| fs::metadata(aof_dir.join(&file.name)).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn fsync_policy() -> Fsync {
+    FSYNC_POLICIES[FSYNC.load(Ordering::Relaxed) as usize]
+}
+
+pub fn set_fsync_policy(policy: Fsync) {
+    let index = FSYNC_POLICIES.iter().position(|p| *p == policy).unwrap();
+    FSYNC.store(index as u8, Ordering::Relaxed);
+}
+
+pub fn load_truncated() -> bool {
+    LOAD_TRUNCATED.load(Ordering::Relaxed)
+}
+
+pub fn set_load_truncated(enabled: bool) {
+    LOAD_TRUNCATED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn rewrite_percentage() -> u64 {
+    REWRITE_PERCENTAGE.load(Ordering::Relaxed)
+}
+
+pub fn set_rewrite_percentage(percentage: u64) {
+    REWRITE_PERCENTAGE.store(percentage, Ordering::Relaxed);
+}
+
+pub fn rewrite_min_size() -> u64 {
+    REWRITE_MIN_SIZE.load(Ordering::Relaxed)
+}
+
+pub fn set_rewrite_min_size(size: u64) {
+    REWRITE_MIN_SIZE.store(size, Ordering::Relaxed);
+}
+
+pub fn filename() -> String {
+    FILENAME.lock().unwrap().clone()
+}
+
+pub fn set_filename(filename: String) {
+    *FILENAME.lock().unwrap() = filename;
+}
+
+pub fn dirname() -> String {
+    DIRNAME.lock().unwrap().clone()
+}
+
+pub fn set_dirname(dirname: String) {
+    *DIRNAME.lock().unwrap() = dirname;
+}
+
+fn manifest_name() -> String {
+    format!("{}.manifest", filename())
+}
+
+/// Whether there is an AOF to load from `dir`: a manifest, or a single file
+/// from before the multi-part layout.
+pub fn exists(dir: &Path) -> bool {
+    dir.join(dirname()).join(manifest_name()).exists() || dir.join(filename()).exists()
+}
+
+pub fn rewrite_in_progress() -> bool {
+    STATE.lock().unwrap().rewrite.is_some()
+}
+
+/// The command as appended to the file, given the reply it got. Commands
+/// whose effect depends on when they run are made deterministic, as Redis
+/// does: a relative `SET` TTL becomes an absolute `PXAT`, `XADD` gets the
+/// ID it generated, and `XCLAIM` records the delivery time as an absolute
+/// `TIME`, so replaying them later does the same thing.
+pub fn encode(command: &Command, reply: &Reply) -> Vec<u8> {
+    let mut args: Vec<Vec<u8>> = std::iter::once(command.cmd.as_bytes().to_vec())
+        .chain(command.args.iter().enumerate().map(|(i, arg)| {
+            command
+                .arg_bytes(i)
+                .unwrap_or_else(|| arg.as_bytes().to_vec())
+        }))
+        .collect();
+    match command.cmd.to_lowercase().as_str() {
+        "set" => {
+            let ttl = command
+                .args
+                .get(2)
+                .filter(|option| option.eq_ignore_ascii_case("px"))
+                .and(command.args.get(3))
+                .and_then(|ttl| ttl.parse::<u64>().ok());
+            // `set` rejected the TTLs this would overflow with.
+            if let Some(at) = ttl.and_then(|ttl| now_millis().checked_add(ttl)) {
+                args[3] = b"PXAT".to_vec();
+                args[4] = at.to_string().into_bytes();
+            }
+        }
+        "xadd" => {
+            if let (Some(index), Reply::Bulk(id)) = (commands::xadd_id_index(&command.args), reply)
+            {
+                args[index + 1] = id.as_bytes().to_vec();
+            }
+        }
+        "restore" => {
+            let absttl = command.args[3..]
+                .iter()
+                .any(|option| option.eq_ignore_ascii_case("absttl"));
+            let ttl = command.args.get(1).and_then(|ttl| ttl.parse::<u64>().ok());
+            if let (false, Some(ttl @ 1..)) = (absttl, ttl) {
+                args[2] = (now_millis() + ttl).to_string().into_bytes();
+                args.push(b"ABSTTL".to_vec());
+            }
+        }
+        // Replayed, a read must not wait for entries.
+        "xreadgroup" => {
+            let streams = args
+                .iter()
+                .position(|arg| arg.eq_ignore_ascii_case(b"streams"));
+            if let Some(index) =
+                (4..streams.unwrap_or(args.len())).find(|&i| args[i].eq_ignore_ascii_case(b"block"))
+            {
+                args.drain(index..index + 2);
+            }
+        }
+        "xclaim" => {
+            let now = now_millis();
+            let option = |name: &str| {
+                (5..args.len()).find(|&i| args[i].eq_ignore_ascii_case(name.as_bytes()))
+            };
+            if let Some(index) = option("idle") {
+                let idle = command
+                    .args
+                    .get(index)
+                    .and_then(|idle| idle.parse::<u64>().ok())
+                    .unwrap_or(0);
+                args[index] = b"TIME".to_vec();
+                if let Some(value) = args.get_mut(index + 1) {
+                    *value = now.saturating_sub(idle).to_string().into_bytes();
+                }
+            } else if option("time").is_none() {
+                args.push(b"TIME".to_vec());
+                args.push(now.to_string().into_bytes());
+            }
+        }
+        _ => {}
+    }
+    Reply::Array(args.into_iter().map(Reply::BulkBytes).collect()).encode(2)
+}
+
+/// Appends an encoded command, when the AOF is on.
+pub fn feed(bytes: &[u8]) {
+    STATE.lock().unwrap().append(bytes);
+}
+
+/// Writes `data` to a temporary file of `aof_dir`, to become a base.
+fn write_temp_base(aof_dir: &Path, data: &[u8]) -> io::Result<PathBuf> {
+    let temp = aof_dir.join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+    let result = File::create(&temp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(temp)
+}
+
+/// Starts appending to the AOF in `dir`, continuing its last incremental
+/// file. With `fresh` or when there is no AOF yet, a base holding the current
+/// dataset is written first.
+pub async fn start(dir: &Path, fresh: bool) -> io::Result<()> {
+    let aof_dir = dir.join(dirname());
+    fs::create_dir_all(&aof_dir)?;
+    let storage = STORAGE.lock().await;
+    let mut state = STATE.lock().unwrap();
+    if fresh || (state.manifest.base.is_none() && state.manifest.incrs.is_empty()) {
+        let temp = write_temp_base(&aof_dir, &rdb::encode(storage.iter()))?;
+        state.open_new_incr(&aof_dir)?;
+        let first_incr = state.manifest.incrs.len() - 1;
+        state.install_base(&aof_dir, &temp, first_incr)?;
+        return Ok(());
+    }
+    match state.manifest.incrs.last() {
+        Some(incr) => {
+            let file = OpenOptions::new()
+                .append(true)
+                .open(aof_dir.join(&incr.name))?;
+            state.incr = Some(file);
+        }
+        None => state.open_new_incr(&aof_dir)?,
+    }
+    Ok(())
+}
+
+/// Stops appending, flushing what was written.
+pub fn stop() {
+    if let Some(file) = STATE.lock().unwrap().incr.take() {
+        if let Err(e) = file.sync_data() {
+            eprintln!("Can't fsync the AOF file: {}", e);
+        }
+    }
+}
+
+/// `BGREWRITEAOF`, returning the status to reply with.
+pub async fn bgrewrite(dir: PathBuf) -> Result<&'static str, String> {
+    let bgsave = persistence::bgsave_in_progress();
+    {
+        let mut state = STATE.lock().unwrap();
+        if state.rewrite.is_some() {
+            return Err("Background append only file rewriting already in progress".to_string());
+        }
+        if bgsave {
+            state.rewrite_scheduled = true;
+            return Ok("Background append only file rewriting scheduled");
+        }
+    }
+    start_rewrite(dir).await?;
+    Ok("Background append only file rewriting started")
+}
+
+async fn start_rewrite(dir: PathBuf) -> Result<(), String> {
+    let aof_dir = dir.join(dirname());
+    fs::create_dir_all(&aof_dir).map_err(|e| format!("Can't create the AOF directory: {}", e))?;
+    let snapshot: Vec<(String, DBEntry)> = {
+        let storage = STORAGE.lock().await;
+        let mut state = STATE.lock().unwrap();
+        if state.rewrite.is_some() {
+            return Err("Background append only file rewriting already in progress".to_string());
+        }
+        // Writes from now on aren't part of the snapshot: they go to a new
+        // incremental file, kept once the rewrite completes.
+        if state.incr.is_some() {
+            state
+                .open_new_incr(&aof_dir)
+                .map_err(|e| format!("Can't open a new incremental AOF file: {}", e))?;
+        }
+        let first_incr = match state.incr {
+            Some(_) => state.manifest.incrs.len() - 1,
+            None => state.manifest.incrs.len(),
+        };
+        state.rewrite = Some(Rewrite {
+            started: Instant::now(),
+            first_incr,
+        });
+        state.rewrite_scheduled = false;
+        storage
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect()
+    };
+    tokio::spawn(async move {
+        let written = {
+            let aof_dir = aof_dir.clone();
+            tokio::task::spawn_blocking(move || {
+                let data = rdb::encode(snapshot.iter().map(|(key, entry)| (key, entry)));
+                write_temp_base(&aof_dir, &data)
+            })
+            .await
+        };
+        let mut state = STATE.lock().unwrap();
+        let Some(rewrite) = state.rewrite.take() else {
+            return;
+        };
+        state.last_rewrite_time = Some(rewrite.started.elapsed());
+        let result = match written {
+            Ok(Ok(temp)) => state
+                .install_base(&aof_dir, &temp, rewrite.first_incr)
+                .map_err(|e| e.to_string()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(()) => {
+                state.last_rewrite_ok = true;
+                state.rewrites += 1;
+                state.rewrites_consecutive_failures = 0;
+            }
+            Err(e) => {
+                eprintln!("Background AOF rewrite failed: {}", e);
+                state.last_rewrite_ok = false;
+                state.rewrites_consecutive_failures += 1;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Flushes the incremental file once per second under `appendfsync
+/// everysec`, and starts scheduled or automatic rewrites.
+pub async fn cron(server_metadata: Arc<RwLock<ServerMetadata>>) {
+    let mut interval = tokio::time::interval(FSYNC_PERIOD);
+    loop {
+        interval.tick().await;
+        fsync_in_background();
+        let bgsave = persistence::bgsave_in_progress();
+        let due = {
+            let state = STATE.lock().unwrap();
+            let percentage = rewrite_percentage();
+            let grown = state.incr.is_some()
+                && percentage > 0
+                && state.size >= rewrite_min_size()
+                && state.size * 100 >= state.base_size.max(1) * (100 + percentage);
+            !bgsave && state.rewrite.is_none() && (state.rewrite_scheduled || grown)
+        };
+        if due {
+            let dir = server_metadata.read().await.dir.clone();
+            // Like a command, so that the snapshot and the switch to a new
+            // incremental file fall between transactions and writes, not
+            // within them. `BGREWRITEAOF` holds it already.
+            let _exclusive = EXEC_LOCK.write().await;
+            if let Err(e) = start_rewrite(dir).await {
+                eprintln!("Can't rewrite the append only file: {}", e);
+            }
+        }
+    }
+}
+
+/// Runs the `everysec` fsync on a blocking thread, so a slow disk doesn't
+/// hold up clients.
+fn fsync_in_background() {
+    if fsync_policy() != Fsync::Everysec || FSYNC_IN_PROGRESS.load(Ordering::Relaxed) {
+        return;
+    }
+    let file = {
+        let mut state = STATE.lock().unwrap();
+        if !state.unsynced {
+            return;
+        }
+        state.unsynced = false;
+        match state.incr.as_ref().map(File::try_clone) {
+            Some(Ok(file)) => file,
+            Some(Err(e)) => {
+                eprintln!("Can't fsync the AOF file: {}", e);
+                return;
+            }
+            None => return,
+        }
+    };
+    FSYNC_IN_PROGRESS.store(true, Ordering::Relaxed);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = file.sync_data() {
+            eprintln!("Can't fsync the AOF file: {}", e);
+        }
+        FSYNC_IN_PROGRESS.store(false, Ordering::Relaxed);
+    });
+}
+
+/// Why the AOF couldn't be loaded, with the file and offset where the
+/// problem was found.
+#[derive(Debug)]
+pub enum AofError {
+    Io(io::Error),
+    Manifest(String),
+    Rdb {
+        file: String,
+        error: RdbError,
+    },
+    /// A file ends in the middle of a command or transaction, and either
+    /// isn't the last one or `aof-load-truncated` is off.
+    Truncated {
+        file: String,
+        offset: usize,
+    },
+    BadFormat {
+        file: String,
+        offset: usize,
+    },
+    UnknownCommand {
+        file: String,
+        name: String,
+        offset: usize,
+    },
+}
+
+impl Error for AofError {}
+
+impl Display for AofError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AofError::Io(e) => write!(f, "Can't read the append only file: {}", e),
+            AofError::Manifest(reason) => write!(f, "Can't load the AOF manifest: {}", reason),
+            AofError::Rdb { file, error } => {
+                write!(f, "Bad RDB contents in the AOF file {}: {}", file, error)
+            }
+            AofError::Truncated { file, offset } => write!(
+                f,
+                "Unexpected end of file reading the append only file {} after offset {}",
+                file, offset
+            ),
+            AofError::BadFormat { file, offset } => write!(
+                f,
+                "Bad file format reading the append only file {} at offset {}",
+                file, offset
+            ),
+            AofError::UnknownCommand { file, name, offset } => write!(
+                f,
+                "Unknown command '{}' reading the append only file {} at offset {}",
+                name, file, offset
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for AofError {
+    fn from(e: io::Error) -> Self {
+        AofError::Io(e)
+    }
+}
+
+/// Loads the AOF in `dir`: the base then every incremental file listed in the
+/// manifest. A single file from before the multi-part layout is first moved
+/// into `appenddirname` as the base.
+pub async fn load(
+    dir: &Path,
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+) -> Result<(), AofError> {
+    let aof_dir = dir.join(dirname());
+    let manifest_path = aof_dir.join(manifest_name());
+    if !manifest_path.exists() {
+        upgrade(dir)?;
+    }
+    let contents = fs::read_to_string(&manifest_path)?;
+    let mut manifest = Manifest::parse(&contents).map_err(AofError::Manifest)?;
+    manifest.delete_history(&aof_dir);
+
+    let files: Vec<&AofFile> = manifest.base.iter().chain(&manifest.incrs).collect();
+    for (i, file) in files.iter().enumerate() {
+        let last = i + 1 == files.len();
+        load_file(&aof_dir, &file.name, last, server_metadata).await?;
+    }
+    // Replayed commands were never part of the replication stream.
+    server_metadata
+        .read()
+        .await
+        .master_repl_offset
+        .store(0, Ordering::SeqCst);
+
+    let mut state = STATE.lock().unwrap();
+    state.size = manifest.size(&aof_dir);
+    state.base_size = state.size;
+    state.manifest = manifest;
+    Ok(())
+}
+
+/// Names of the files a manifest lists, in the order they are loaded: the
+/// base then the incremental files.
+pub fn load_order(manifest: &str) -> Result<Vec<String>, String> {
+    let manifest = Manifest::parse(manifest)?;
+    Ok(manifest
+        .base
+        .iter()
+        .chain(&manifest.incrs)
+        .map(|file| file.name.clone())
+        .collect())
+}
+
+/// Moves a single file AOF into `appenddirname`, becoming the base of a new
+/// manifest.
+fn upgrade(dir: &Path) -> Result<(), AofError> {
+    let aof_dir = dir.join(dirname());
+    fs::create_dir_all(&aof_dir)?;
+    let name = filename();
+    fs::rename(dir.join(&name), aof_dir.join(&name))?;
+    let manifest = Manifest {
+        base: Some(AofFile {
+            name,
+            seq: 1,
+            kind: AofKind::Base,
+        }),
+        ..Manifest::default()
+    };
+    manifest.write(&aof_dir)?;
+    Ok(())
+}
+
+/// Why reading the commands of an AOF file stopped before its end.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanError {
+    /// The file ends in the middle of a command or of a transaction.
+    Truncated,
+    BadFormat {
+        offset: usize,
+    },
+    UnknownCommand {
+        name: String,
+        offset: usize,
+    },
+}
+
+/// The commands of an AOF file, as far as they could be read.
+pub struct Scan {
+    /// Commands to apply in order, without the `MULTI` and `EXEC` around
+    /// transactions: a transaction is only included once its `EXEC` is read.
+    pub commands: Vec<Command>,
+    /// End of the last complete command outside of a transaction, where the
+    /// file can be cut to drop what couldn't be read.
+    pub valid: usize,
+    pub error: Option<ScanError>,
+}
+
+/// Reads the commands of an AOF file from `start`, past its RDB preamble.
+pub fn scan(data: &[u8], start: usize) -> Scan {
+    let mut scan = Scan {
+        commands: Vec::new(),
+        valid: start,
+        error: None,
+    };
+    let mut pos = start;
+    let mut transaction: Option<Vec<Command>> = None;
+    while pos < data.len() {
+        let offset = pos;
+        let parsed = match data[pos] {
+            b'*' => parser::parse_command(data, pos).ok(),
+            _ => None,
+        };
+        let (command, next) = match parsed {
+            Some(Some((Some(command), next))) => (command, next),
+            Some(None) => {
+                scan.error = Some(ScanError::Truncated);
+                return scan;
+            }
+            _ => {
+                scan.error = Some(ScanError::BadFormat { offset });
+                return scan;
+            }
+        };
+        pos = next;
+        match command.cmd.to_lowercase().as_str() {
+            "multi" => transaction = Some(Vec::new()),
+            "exec" => scan.commands.extend(transaction.take().unwrap_or_default()),
+            _ => {
+                if let Err(CommandError::CommandNotFound(_)) = COMMANDS_REGISTRY.check(&command) {
+                    scan.error = Some(ScanError::UnknownCommand {
+                        name: command.cmd,
+                        offset,
+                    });
+                    return scan;
+                }
+                match transaction.as_mut() {
+                    Some(queued) => queued.push(command),
+                    None => scan.commands.push(command),
+                }
+            }
+        }
+        if transaction.is_none() {
+            scan.valid = pos;
+        }
+    }
+    if transaction.is_some() {
+        scan.error = Some(ScanError::Truncated);
+    }
+    scan
+}
+
+/// Replays one file of the AOF. It may start with an RDB, the only contents
+/// of `.rdb` bases. A truncated command or transaction at the end is only
+/// tolerated in the `last` file, which is cut before it.
+async fn load_file(
+    aof_dir: &Path,
+    name: &str,
+    last: bool,
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+) -> Result<(), AofError> {
+    let path = aof_dir.join(name);
+    let data = fs::read(&path)?;
+    let file = || name.to_string();
+    let mut start = 0;
+    if data.starts_with(b"REDIS") {
+        start = rdb::load(&data).await.map_err(|error| AofError::Rdb {
+            file: file(),
+            error,
+        })?;
+    }
+    let scan = scan(&data, start);
+    match scan.error {
+        Some(ScanError::BadFormat { offset }) => {
+            return Err(AofError::BadFormat {
+                file: file(),
+                offset,
+            })
+        }
+        Some(ScanError::UnknownCommand { name, offset }) => {
+            return Err(AofError::UnknownCommand {
+                file: file(),
+                name,
+                offset,
+            })
+        }
+        Some(ScanError::Truncated) if !last || !load_truncated() => {
+            return Err(AofError::Truncated {
+                file: file(),
+                offset: scan.valid,
+            })
+        }
+        _ => {}
+    }
+    for command in scan.commands {
+        replay(command, server_metadata).await;
+    }
+    if scan.error.is_some() {
+        eprintln!(
+            "!!! Warning: short read while loading the AOF file {}!!!",
+            path.display()
+        );
+        eprintln!(
+            "AOF {} loaded anyway because aof-load-truncated is enabled, truncated to {} bytes",
+            path.display(),
+            scan.valid
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(scan.valid as u64)?;
+    }
+    Ok(())
+}
+
+async fn replay(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) {
+    let _ = commands::dispatch(
+        command,
+        server_metadata,
+        &COMMANDS_REGISTRY,
+        commands::Context::Internal,
+    )
+    .await;
+}
+
+/// The `aof_*` fields of `INFO persistence`.
+pub fn info() -> Vec<String> {
+    let state = STATE.lock().unwrap();
+    let seconds = |time: Option<Duration>| time.map_or(-1, |time| time.as_secs() as i64);
+    let status = |ok: bool| if ok { "ok" } else { "err" };
+    let mut info = vec![
+        format!("aof_enabled:{}", enabled() as u8),
+        format!("aof_rewrite_in_progress:{}", state.rewrite.is_some() as u8),
+        format!("aof_rewrite_scheduled:{}", state.rewrite_scheduled as u8),
+        format!(
+            "aof_last_rewrite_time_sec:{}",
+            seconds(state.last_rewrite_time)
+        ),
+        format!(
+            "aof_current_rewrite_time_sec:{}",
+            seconds(
+                state
+                    .rewrite
+                    .as_ref()
+                    .map(|rewrite| rewrite.started.elapsed())
+            )
+        ),
+        format!(
+            "aof_last_bgrewrite_status:{}",
+            status(state.last_rewrite_ok)
+        ),
+        format!("aof_rewrites:{}", state.rewrites),
+        format!(
+            "aof_rewrites_consecutive_failures:{}",
+            state.rewrites_consecutive_failures
+        ),
+        format!(
+            "aof_last_write_status:{}",
+            status(LAST_WRITE_OK.load(Ordering::Relaxed))
+        ),
+    ];
+    if state.incr.is_some() {
+        info.extend([
+            format!("aof_current_size:{}", state.size),
+            format!("aof_base_size:{}", state.base_size),
+            format!("aof_pending_rewrite:{}", state.rewrite_scheduled as u8),
+            // Commands are written right away rather than buffered.
+            "aof_buffer_length:0".to_string(),
+            format!(
+                "aof_pending_bio_fsync:{}",
+                FSYNC_IN_PROGRESS.load(Ordering::Relaxed) as u8
+            ),
+            "aof_delayed_fsync:0".to_string(),
+        ]);
+    }
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::client::{self, Client, Role};
+
+    fn command(args: &[&str]) -> Command {
+        let raw = Reply::bulks(args.iter().copied()).encode(2);
+        let (mut commands, _) = parser::parse_request(&raw).unwrap();
+        commands.remove(0)
+    }
+
+    fn encoded(args: &[&str], reply: &Reply) -> Vec<String> {
+        let raw = encode(&command(args), reply);
+        let (commands, _) = parser::parse_request(&raw).unwrap();
+        let command = &commands[0];
+        std::iter::once(command.cmd.clone())
+            .chain(command.args.iter().cloned())
+            .collect()
+    }
+
+    #[test]
+    fn restore_is_logged_with_an_absolute_ttl() {
+        let before = now_millis();
+        let logged = encoded(&["RESTORE", "k", "5000", "payload"], &Reply::ok());
+        assert_eq!(logged[..2], ["RESTORE", "k"]);
+        let at: u64 = logged[2].parse().unwrap();
+        assert!((before + 5000..=now_millis() + 5000).contains(&at));
+        assert_eq!(logged[3..], ["payload", "ABSTTL"]);
+
+        for args in [
+            &["RESTORE", "k", "0", "payload", "REPLACE"][..],
+            &["RESTORE", "k", "1700000000000", "payload", "absttl"],
+        ] {
+            assert_eq!(encoded(args, &Reply::ok()), args);
+        }
+    }
+
+    #[test]
+    fn set_is_logged_with_an_absolute_ttl() {
+        let before = now_millis();
+        let logged = encoded(&["SET", "k", "v", "px", "5000"], &Reply::ok());
+        assert_eq!(logged[..4], ["SET", "k", "v", "PXAT"]);
+        let at: u64 = logged[4].parse().unwrap();
+        assert!((before + 5000..=now_millis() + 5000).contains(&at));
+    }
+
+    #[tokio::test]
+    async fn approximate_trims_are_logged_exactly() {
+        let metadata = Arc::new(RwLock::new(ServerMetadata::new(
+            "127.0.0.1",
+            6379,
+            false,
+            PathBuf::new(),
+            "dump.rdb".to_string(),
+        )));
+        let run = |line: &str| {
+            let args: Vec<String> = line.split(' ').map(String::from).collect();
+            let command = Command::from_args(args[0].clone(), args[1..].to_vec());
+            let metadata = Arc::clone(&metadata);
+            async move {
+                commands::dispatch(
+                    command,
+                    &metadata,
+                    &COMMANDS_REGISTRY,
+                    commands::Context::Internal,
+                )
+                .await
+            }
+        };
+        let client = Client::new(String::new(), String::new(), Role::Normal);
+        let mut replica = client::scope(client, async {
+            for seq in 1..=250 {
+                run(&format!("XADD aof-test:trim 1-{} f v", seq)).await;
+            }
+            let replica = metadata.read().await.broadcast.subscribe();
+            // Whole nodes of 100 entries go, as long as 120 remain.
+            let reply = run("XTRIM aof-test:trim MAXLEN ~ 120").await;
+            assert_eq!(reply, Reply::Integer(100));
+            let reply = run("XADD aof-test:trim MINID ~ 1-150 LIMIT 1000 1-* f v").await;
+            assert_eq!(reply, Reply::bulk("1-251"));
+            replica
+        })
+        .await;
+
+        let mut propagated = Vec::new();
+        while let Ok(raw) = replica.try_recv() {
+            propagated.push(raw.to_vec());
+        }
+        let encoded = |line: &str| Reply::bulks(line.split(' ')).encode(2);
+        assert_eq!(
+            propagated,
+            [
+                encoded("XTRIM aof-test:trim MAXLEN = 150"),
+                // The node holding 1-150 stays, so entries from 1-101 do.
+                encoded("XADD aof-test:trim MINID = 1-101 1-251 f v"),
+            ]
+        );
+    }
+
+    #[test]
+    fn xreadgroup_is_logged_without_block() {
+        let reply = Reply::Array(Vec::new());
+        assert_eq!(
+            encoded(
+                &[
+                    "XREADGROUP",
+                    "GROUP",
+                    "g",
+                    "block",
+                    "COUNT",
+                    "1",
+                    "BLOCK",
+                    "0",
+                    "STREAMS",
+                    "s",
+                    ">"
+                ],
+                &reply
+            ),
+            [
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "block",
+                "COUNT",
+                "1",
+                "STREAMS",
+                "s",
+                ">"
+            ]
+        );
+    }
+}
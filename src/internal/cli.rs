@@ -2,6 +2,8 @@ use std::{num::ParseIntError, path::PathBuf};
 
 use clap::Parser;
 
+use crate::internal::aof::Fsync;
+
 #[derive(Parser, Debug, Clone)]
 #[command(
     version = "1.0",
@@ -20,6 +22,73 @@ pub struct CliArgs {
 
     #[arg(long = "dbfilename", required = false)]
     pub dbfilename: Option<String>,
+
+    #[arg(long = "requirepass", required = false)]
+    pub requirepass: Option<String>,
+
+    #[arg(long = "aclfile", required = false)]
+    pub aclfile: Option<PathBuf>,
+
+    #[arg(long = "rdbchecksum", required = false, value_parser = parse_yes_no)]
+    pub rdbchecksum: Option<bool>,
+
+    #[arg(long = "rdbcompression", required = false, value_parser = parse_yes_no)]
+    pub rdbcompression: Option<bool>,
+
+    #[arg(long = "save", required = false)]
+    pub save: Option<String>,
+
+    #[arg(long = "appendonly", required = false, value_parser = parse_yes_no)]
+    pub appendonly: Option<bool>,
+
+    #[arg(long = "appendfilename", required = false)]
+    pub appendfilename: Option<String>,
+
+    #[arg(long = "appenddirname", required = false)]
+    pub appenddirname: Option<String>,
+
+    #[arg(long = "appendfsync", required = false)]
+    pub appendfsync: Option<Fsync>,
+
+    #[arg(long = "aof-load-truncated", required = false, value_parser = parse_yes_no)]
+    pub aof_load_truncated: Option<bool>,
+
+    #[arg(long = "auto-aof-rewrite-percentage", required = false)]
+    pub auto_aof_rewrite_percentage: Option<u64>,
+
+    #[arg(long = "auto-aof-rewrite-min-size", required = false, value_parser = parse_memory)]
+    pub auto_aof_rewrite_min_size: Option<u64>,
+}
+
+/// Parses a boolean option given as `yes` or `no`, like in redis.conf.
+pub fn parse_yes_no(arg: &str) -> Result<bool, String> {
+    match arg.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+/// Parses a size in bytes, optionally followed by a unit as in redis.conf:
+/// `k`/`m`/`g` count in powers of 1000, `kb`/`mb`/`gb` in powers of 1024.
+pub fn parse_memory(arg: &str) -> Result<u64, String> {
+    let lower = arg.to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err("argument must be a memory value".to_string()),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| "argument must be a memory value".to_string())
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,544 @@
+//! Per-connection state, reachable from command handlers running on the
+//! connection's task without threading it through every handler, and from
+//! the client table other connections inspect with `CLIENT LIST` or
+//! `CLIENT KILL`.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+use crate::internal::{acl, command_table, commands, parser::Command, tracking, types::now_millis};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Size of the buffer connections read requests into, reported as
+/// `qbuf-free` headroom.
+pub const READ_BUFFER_LEN: usize = 255;
+
+tokio::task_local! {
+    static CONNECTION: Handle;
+}
+
+lazy_static! {
+    /// Every open connection by id.
+    static ref CLIENTS: Mutex<BTreeMap<u64, Handle>> = Mutex::new(BTreeMap::new());
+    /// Ongoing `CLIENT PAUSE`: when it ends and what it holds back.
+    static ref PAUSE: Mutex<Option<(u64, PauseMode)>> = Mutex::new(None);
+    static ref UNPAUSED: Notify = Notify::new();
+}
+
+/// What the connection is to this server, as filtered by `CLIENT KILL TYPE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Normal,
+    /// The link to our master, on a replica.
+    Master,
+    /// A replica streaming our writes.
+    Replica,
+}
+
+/// `CLIENT REPLY` state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyMode {
+    On,
+    Off,
+    /// `SKIP` was requested: the next command's reply is dropped.
+    SkipNext,
+    /// The reply of the running command is dropped.
+    Skip,
+}
+
+/// Commands held back by `CLIENT PAUSE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseMode {
+    Write,
+    All,
+}
+
+/// Client types accepted by `CLIENT LIST TYPE` and `CLIENT KILL TYPE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientType {
+    Normal,
+    Master,
+    Replica,
+    Pubsub,
+}
+
+impl ClientType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "normal" => Some(ClientType::Normal),
+            "master" => Some(ClientType::Master),
+            "replica" | "slave" => Some(ClientType::Replica),
+            "pubsub" => Some(ClientType::Pubsub),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub id: u64,
+    pub addr: String,
+    pub laddr: String,
+    pub name: Option<String>,
+    /// RESP version negotiated through `HELLO`, 2 until then.
+    pub protocol: u8,
+    /// ACL user the connection runs commands as.
+    pub user: String,
+    /// Whether the connection may run commands beyond `AUTH` and `HELLO`.
+    pub authenticated: bool,
+    pub role: Role,
+    /// Unix time in milliseconds the connection was accepted.
+    pub created: u64,
+    /// Unix time in milliseconds of the last command.
+    pub last_interaction: u64,
+    /// Full name of the last command, e.g. `client|list`.
+    pub last_cmd: Option<&'static str>,
+    /// Request bytes read but not parsed yet.
+    pub qbuf: usize,
+    /// Size of the arguments of the last command.
+    pub argv_mem: usize,
+    /// Commands queued since `MULTI` and their size, `None` outside a
+    /// transaction.
+    pub multi: Option<(usize, usize)>,
+    /// Channels, patterns and shard channels subscribed to.
+    pub subscriptions: (usize, usize, usize),
+    pub reply: ReplyMode,
+    pub no_evict: bool,
+    pub no_touch: bool,
+    /// Whether a blocking command (e.g. `WAIT`) is running.
+    pub blocked: bool,
+    /// Whether blocking commands must reply right away, as they do when run
+    /// by `EXEC` or sent by our master.
+    pub deny_blocking: bool,
+    /// Error replies sent to the connection.
+    pub errors: u64,
+    pub lib_name: Option<String>,
+    pub lib_ver: Option<String>,
+    /// `CLIENT TRACKING` options, `None` while tracking is off.
+    pub tracking: Option<tracking::Options>,
+    /// Arguments the running command is logged and propagated with instead
+    /// of its own, as set by its handler.
+    pub rewritten_args: Option<Vec<String>>,
+}
+
+impl Client {
+    /// A new connection, authenticated as `default` when that user needs no
+    /// password.
+    pub fn new(addr: String, laddr: String, role: Role) -> Self {
+        let now = now_millis();
+        Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            addr,
+            laddr,
+            name: None,
+            protocol: 2,
+            user: acl::DEFAULT_USER.to_string(),
+            authenticated: acl::default_user_is_open(),
+            role,
+            created: now,
+            last_interaction: now,
+            last_cmd: None,
+            qbuf: 0,
+            argv_mem: 0,
+            multi: None,
+            subscriptions: (0, 0, 0),
+            reply: ReplyMode::On,
+            no_evict: false,
+            no_touch: false,
+            blocked: false,
+            // The replication stream must never stall on a command.
+            deny_blocking: role == Role::Master,
+            errors: 0,
+            lib_name: None,
+            lib_ver: None,
+            tracking: None,
+            rewritten_args: None,
+        }
+    }
+
+    fn is_pubsub(&self) -> bool {
+        let (channels, patterns, shard_channels) = self.subscriptions;
+        channels + patterns + shard_channels > 0
+    }
+
+    pub fn is_type(&self, client_type: ClientType) -> bool {
+        match client_type {
+            ClientType::Normal => self.role == Role::Normal && !self.is_pubsub(),
+            ClientType::Master => self.role == Role::Master,
+            ClientType::Replica => self.role == Role::Replica,
+            ClientType::Pubsub => self.role == Role::Normal && self.is_pubsub(),
+        }
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = String::new();
+        match self.role {
+            Role::Replica => flags.push('S'),
+            Role::Master => flags.push('M'),
+            Role::Normal => {}
+        }
+        if self.is_pubsub() {
+            flags.push('P');
+        }
+        if self.multi.is_some() {
+            flags.push('x');
+        }
+        if self.blocked {
+            flags.push('b');
+        }
+        if self.no_evict {
+            flags.push('e');
+        }
+        if self.no_touch {
+            flags.push('T');
+        }
+        if let Some(tracking) = &self.tracking {
+            flags.push('t');
+            if tracking.redirect.is_some_and(|id| get(id).is_none()) {
+                flags.push('R');
+            }
+            if tracking.bcast {
+                flags.push('B');
+            }
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        flags
+    }
+
+    /// The connection's line in `CLIENT LIST` and `CLIENT INFO`.
+    pub fn describe(&self) -> String {
+        let now = now_millis();
+        let (sub, psub, ssub) = self.subscriptions;
+        let (multi, multi_mem) = match self.multi {
+            Some((count, size)) => (count as i64, size),
+            None => (-1, 0),
+        };
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db=0 sub={} psub={} ssub={} multi={} qbuf={} qbuf-free={} argv-mem={} multi-mem={} obl=0 oll=0 omem=0 tot-mem={} events=r cmd={} user={} redir=-1 resp={} lib-name={} lib-ver={}",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.name.as_deref().unwrap_or_default(),
+            now.saturating_sub(self.created) / 1000,
+            now.saturating_sub(self.last_interaction) / 1000,
+            self.flags(),
+            sub,
+            psub,
+            ssub,
+            multi,
+            self.qbuf,
+            READ_BUFFER_LEN.saturating_sub(self.qbuf),
+            self.argv_mem,
+            multi_mem,
+            READ_BUFFER_LEN + self.qbuf + self.argv_mem + multi_mem,
+            self.last_cmd.unwrap_or("NULL"),
+            self.user,
+            self.protocol,
+            self.lib_name.as_deref().unwrap_or_default(),
+            self.lib_ver.as_deref().unwrap_or_default(),
+        )
+    }
+}
+
+/// A registered connection: its state and the signals other connections
+/// send it.
+#[derive(Clone)]
+struct Handle {
+    client: Arc<Mutex<Client>>,
+    kill: Arc<Notify>,
+    unblock: Arc<Notify>,
+    /// Whether `CLIENT UNBLOCK` asked for the blocked command to fail.
+    unblock_error: Arc<AtomicBool>,
+    /// The peer closed the connection while a command was running.
+    closed: Arc<Notify>,
+}
+
+/// Leaves the client table when the connection's task ends, however it ends.
+struct Registration(u64);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        CLIENTS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Runs a connection's task with its own client state, listed in the client
+/// table for as long as it runs.
+pub async fn scope<F: Future>(client: Client, f: F) -> F::Output {
+    let id = client.id;
+    let handle = Handle {
+        client: Arc::new(Mutex::new(client)),
+        kill: Arc::new(Notify::new()),
+        unblock: Arc::new(Notify::new()),
+        unblock_error: Arc::new(AtomicBool::new(false)),
+        closed: Arc::new(Notify::new()),
+    };
+    CLIENTS.lock().unwrap().insert(id, handle.clone());
+    let _registration = Registration(id);
+    CONNECTION.scope(handle, f).await
+}
+
+fn with<R>(f: impl FnOnce(&mut Client) -> R) -> Option<R> {
+    CONNECTION
+        .try_with(|handle| f(&mut handle.client.lock().unwrap()))
+        .ok()
+}
+
+/// Snapshot of the current connection's state. Outside a connection task
+/// (e.g. background jobs) a fresh RESP2 client is reported.
+pub fn current() -> Client {
+    with(|client| client.clone()).unwrap_or_else(|| {
+        let mut client = Client::new(String::new(), String::new(), Role::Normal);
+        client.id = 0;
+        client.authenticated = true;
+        client
+    })
+}
+
+/// Has the running command logged and propagated with `args`, for a form
+/// that replays the same, like Redis' `rewriteClientCommandArgument`.
+pub fn rewrite_args(args: Vec<String>) {
+    with(|client| client.rewritten_args = Some(args));
+}
+
+/// The arguments given to `rewrite_args` by the command that just ran.
+pub fn take_rewritten_args() -> Option<Vec<String>> {
+    with(|client| client.rewritten_args.take()).flatten()
+}
+
+/// Whether the current connection is the link to our master, on a replica.
+pub fn is_master_link() -> bool {
+    with(|client| client.role == Role::Master).unwrap_or(false)
+}
+
+/// Applies `f` to the current connection's state.
+pub fn update(f: impl FnOnce(&mut Client)) {
+    with(f);
+}
+
+pub fn protocol() -> u8 {
+    with(|client| client.protocol).unwrap_or(2)
+}
+
+pub fn set_protocol(protocol: u8) {
+    with(|client| client.protocol = protocol);
+}
+
+/// Names the connection, an empty name removing it.
+pub fn set_name(name: String) {
+    with(|client| client.name = Some(name).filter(|name| !name.is_empty()));
+}
+
+/// Switches the connection to `user` once its credentials were checked.
+pub fn set_user(user: &str) {
+    with(|client| {
+        client.user = user.to_string();
+        client.authenticated = true;
+    });
+}
+
+/// Goes back to the `default` user, as done by `RESET`.
+pub fn reset_user() {
+    with(|client| {
+        client.user = acl::DEFAULT_USER.to_string();
+        client.authenticated = acl::default_user_is_open();
+    });
+}
+
+/// Accounts for a command about to run: the last command and interaction
+/// time, and the `CLIENT REPLY SKIP` window.
+pub fn begin_command(command: &Command, qbuf: usize) {
+    with(|client| {
+        client.last_interaction = now_millis();
+        // `CLIENT CACHING` only holds for the command that follows it.
+        if client.last_cmd != Some("client|caching") {
+            if let Some(tracking) = client.tracking.as_mut() {
+                tracking.caching = None;
+            }
+        }
+        client.last_cmd = command_table::lookup(&command.cmd).map(|spec| {
+            command
+                .args
+                .first()
+                .and_then(|sub| spec.subcommand(sub))
+                .unwrap_or(spec)
+                .name
+        });
+        client.qbuf = qbuf;
+        client.argv_mem = command.cmd.len() + command.args.iter().map(String::len).sum::<usize>();
+        client.reply = match client.reply {
+            ReplyMode::SkipNext => ReplyMode::Skip,
+            ReplyMode::Skip => ReplyMode::On,
+            mode => mode,
+        };
+    });
+}
+
+/// Whether replies should reach the connection, per `CLIENT REPLY`.
+pub fn replies_enabled() -> bool {
+    with(|client| matches!(client.reply, ReplyMode::On | ReplyMode::SkipNext)).unwrap_or(true)
+}
+
+/// Resolves once the connection was killed through `CLIENT KILL`.
+pub async fn killed() {
+    match CONNECTION.try_with(|handle| Arc::clone(&handle.kill)) {
+        Ok(kill) => kill.notified().await,
+        Err(_) => std::future::pending().await,
+    }
+}
+
+/// Marks the connection blocked, letting `CLIENT UNBLOCK` reach it.
+pub fn set_blocked(blocked: bool) {
+    with(|client| client.blocked = blocked);
+}
+
+/// Whether a command may block. Never outside a connection task, e.g. when
+/// replaying the AOF.
+pub fn blocking_allowed() -> bool {
+    with(|client| !client.deny_blocking).unwrap_or(false)
+}
+
+pub fn set_deny_blocking(deny: bool) {
+    with(|client| client.deny_blocking = deny);
+}
+
+/// Resolves once `CLIENT UNBLOCK` targets the connection, with whether the
+/// blocked command should fail rather than time out.
+pub async fn unblocked() -> bool {
+    let Ok(handle) = CONNECTION.try_with(Handle::clone) else {
+        return std::future::pending().await;
+    };
+    handle.unblock.notified().await;
+    handle.unblock_error.swap(false, Ordering::SeqCst)
+}
+
+/// Tells the running command that the peer closed the connection, so that
+/// it stops blocking and the connection can go.
+pub fn set_closed() {
+    if let Ok(handle) = CONNECTION.try_with(Handle::clone) {
+        handle.closed.notify_one();
+    }
+}
+
+/// Resolves once the peer closed the connection, as seen by `set_closed`.
+pub async fn closed() {
+    match CONNECTION.try_with(|handle| Arc::clone(&handle.closed)) {
+        Ok(closed) => closed.notified().await,
+        Err(_) => std::future::pending().await,
+    }
+}
+
+/// Snapshots of the connections in the client table, by id.
+pub fn list() -> Vec<Client> {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|handle| handle.client.lock().unwrap().clone())
+        .collect()
+}
+
+/// Snapshot of the connection with the given id, if still open.
+pub fn get(id: u64) -> Option<Client> {
+    let handle = CLIENTS.lock().unwrap().get(&id).cloned()?;
+    let client = handle.client.lock().unwrap().clone();
+    Some(client)
+}
+
+/// Closes the given connections, returning how many were still open.
+pub fn kill(ids: &[u64]) -> usize {
+    let mut clients = CLIENTS.lock().unwrap();
+    ids.iter()
+        .filter_map(|id| clients.remove(id))
+        .map(|handle| handle.kill.notify_one())
+        .count()
+}
+
+/// `CLIENT UNBLOCK`: whether the connection was blocked.
+pub fn unblock(id: u64, error: bool) -> bool {
+    let clients = CLIENTS.lock().unwrap();
+    let Some(handle) = clients.get(&id) else {
+        return false;
+    };
+    if !handle.client.lock().unwrap().blocked {
+        return false;
+    }
+    handle.unblock_error.store(error, Ordering::SeqCst);
+    handle.unblock.notify_one();
+    true
+}
+
+/// Connected clients, replicas aside, and how many of them are blocked.
+pub fn stats() -> (usize, usize) {
+    let clients = list();
+    let connected = clients
+        .iter()
+        .filter(|client| client.role != Role::Replica)
+        .count();
+    let blocked = clients.iter().filter(|client| client.blocked).count();
+    (connected, blocked)
+}
+
+/// `CLIENT PAUSE`: holds back commands until `millis` from now. A pause
+/// already in effect is only ever extended or made stricter.
+pub fn pause(millis: u64, mode: PauseMode) {
+    let until = now_millis() + millis;
+    let mut pause = PAUSE.lock().unwrap();
+    *pause = Some(match *pause {
+        Some((current_until, current_mode)) => (
+            until.max(current_until),
+            if current_mode == PauseMode::All {
+                PauseMode::All
+            } else {
+                mode
+            },
+        ),
+        None => (until, mode),
+    });
+}
+
+pub fn unpause() {
+    *PAUSE.lock().unwrap() = None;
+    UNPAUSED.notify_waiters();
+}
+
+/// Waits for an ongoing `CLIENT PAUSE` holding back the command to end.
+/// `CLIENT UNPAUSE` always goes through, or nothing could lift an `ALL`
+/// pause early.
+pub async fn wait_unpaused(command: &Command) {
+    let unpause = command.cmd.eq_ignore_ascii_case("client")
+        && command
+            .args
+            .first()
+            .is_some_and(|sub| sub.eq_ignore_ascii_case("unpause"));
+    if unpause {
+        return;
+    }
+    let is_write = commands::is_write_command(command);
+    loop {
+        let notified = UNPAUSED.notified();
+        let now = now_millis();
+        let remaining = match *PAUSE.lock().unwrap() {
+            Some((until, mode)) if until > now && (mode == PauseMode::All || is_write) => {
+                until - now
+            }
+            _ => return,
+        };
+        tokio::select! {
+            _ = notified => {}
+            _ = tokio::time::sleep(Duration::from_millis(remaining)) => {}
+        }
+    }
+}
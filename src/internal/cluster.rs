@@ -0,0 +1,57 @@
+//! Key to hash slot mapping, as used by Redis Cluster to route keys and shard
+//! channels to the node owning their slot.
+
+use crate::internal::commands::CommandError;
+
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+/// CRC16-CCITT (XMODEM), the checksum Redis Cluster hashes keys with.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Slot of a key. When the key contains a non-empty `{...}` hash tag, only
+/// the tag is hashed so related keys can be forced into the same slot.
+pub fn key_hash_slot(key: &str) -> u16 {
+    let bytes = key.as_bytes();
+    let tag = bytes.iter().position(|b| *b == b'{').and_then(|start| {
+        bytes[start + 1..]
+            .iter()
+            .position(|b| *b == b'}')
+            .filter(|len| *len > 0)
+            .map(|len| &bytes[start + 1..start + 1 + len])
+    });
+    crc16(tag.unwrap_or(bytes)) & (CLUSTER_SLOTS - 1)
+}
+
+/// Fails with `CROSSSLOT` when the keys spread over several slots. This node
+/// serves every slot, so a request never needs redirecting elsewhere.
+pub fn check_single_slot<'a>(
+    keys: impl IntoIterator<Item = &'a String>,
+) -> Result<(), CommandError> {
+    let mut slot = None;
+    for key in keys {
+        let key_slot = key_hash_slot(key);
+        match slot {
+            Some(slot) if slot != key_slot => {
+                return Err(CommandError::WithCode(
+                    "CROSSSLOT",
+                    "Keys in request don't hash to the same slot".to_string(),
+                ))
+            }
+            _ => slot = Some(key_slot),
+        }
+    }
+    Ok(())
+}
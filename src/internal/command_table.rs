@@ -0,0 +1,1327 @@
+//! Static description of every command: arity, flags, key positions and
+//! documentation. Arity is enforced from here before a handler runs, and the
+//! `COMMAND` family introspects it so clients can route keys and discover
+//! what the server supports.
+
+use crate::internal::{parser::Command, resp::Reply};
+
+/// The command may modify the dataset.
+pub const WRITE: u32 = 1 << 0;
+/// The command only reads the dataset.
+pub const READONLY: u32 = 1 << 1;
+/// The command may grow memory usage, so it is refused when out of memory.
+pub const DENYOOM: u32 = 1 << 2;
+/// Administrative command, e.g. `CONFIG` or `DEBUG`.
+pub const ADMIN: u32 = 1 << 3;
+/// Pub/Sub related command.
+pub const PUBSUB: u32 = 1 << 4;
+/// The command is not allowed inside scripts.
+pub const NOSCRIPT: u32 = 1 << 5;
+/// The command is allowed while the dataset is loading.
+pub const LOADING: u32 = 1 << 6;
+/// The command is allowed on a replica with stale data.
+pub const STALE: u32 = 1 << 7;
+/// The command runs in constant or logarithmic time.
+pub const FAST: u32 = 1 << 8;
+/// The command can run before the connection authenticated.
+pub const NO_AUTH: u32 = 1 << 9;
+/// The command may wait, e.g. for entries to read or replicas to catch up.
+pub const BLOCKING: u32 = 1 << 10;
+
+/// Flag names as reported by `COMMAND INFO`, in Redis' order.
+const FLAG_NAMES: [(u32, &str); 11] = [
+    (WRITE, "write"),
+    (READONLY, "readonly"),
+    (DENYOOM, "denyoom"),
+    (ADMIN, "admin"),
+    (PUBSUB, "pubsub"),
+    (NOSCRIPT, "noscript"),
+    (BLOCKING, "blocking"),
+    (LOADING, "loading"),
+    (STALE, "stale"),
+    (FAST, "fast"),
+    (NO_AUTH, "no_auth"),
+];
+
+/// Where the keys are found among the arguments, the command name being
+/// argument 0.
+#[derive(Debug, Clone, Copy)]
+pub enum Keys {
+    None,
+    /// Keys from `first` to `last` every `step` arguments. A negative `last`
+    /// counts from the end.
+    Range {
+        first: i64,
+        last: i64,
+        step: i64,
+    },
+    /// Keys start after `keyword` and take the first half of what follows,
+    /// as in `XREAD ... STREAMS key1 key2 id1 id2`.
+    Keyword(&'static str),
+}
+
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Number of arguments including the name, or their minimum when
+    /// negative.
+    pub arity: i64,
+    pub flags: u32,
+    pub keys: Keys,
+    pub group: &'static str,
+    pub since: &'static str,
+    pub summary: &'static str,
+    pub subcommands: &'static [CommandSpec],
+}
+
+impl CommandSpec {
+    const fn new(
+        name: &'static str,
+        arity: i64,
+        flags: u32,
+        keys: Keys,
+        group: &'static str,
+        since: &'static str,
+        summary: &'static str,
+    ) -> Self {
+        CommandSpec {
+            name,
+            arity,
+            flags,
+            keys,
+            group,
+            since,
+            summary,
+            subcommands: &[],
+        }
+    }
+
+    const fn with_subcommands(self, subcommands: &'static [CommandSpec]) -> Self {
+        CommandSpec {
+            subcommands,
+            ..self
+        }
+    }
+
+    fn arity_matches(&self, argc: usize) -> bool {
+        let argc = argc as i64;
+        if self.arity < 0 {
+            argc >= -self.arity
+        } else {
+            argc == self.arity
+        }
+    }
+
+    /// Checks the number of arguments, the subcommand's ones when it is a
+    /// known one. Returns the offending name, e.g. `config|get`, on mismatch.
+    pub fn check_arity(&self, command: &Command) -> Result<(), String> {
+        let argc = command.args.len() + 1;
+        if !self.arity_matches(argc) {
+            return Err(self.name.to_string());
+        }
+        let sub = command.args.first().and_then(|sub| self.subcommand(sub));
+        match sub {
+            Some(sub) if !sub.arity_matches(argc) => Err(sub.name.to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn subcommand(&self, name: &str) -> Option<&CommandSpec> {
+        self.subcommands
+            .iter()
+            .find(|sub| sub.short_name().eq_ignore_ascii_case(name))
+    }
+
+    /// Name without its container, `get` for `config|get`.
+    fn short_name(&self) -> &'static str {
+        self.name.rsplit('|').next().unwrap_or(self.name)
+    }
+
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// ACL categories: the command's group along with the ones implied by
+    /// its flags.
+    pub fn categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::new();
+        let implied = [
+            (WRITE, "write"),
+            (READONLY, "read"),
+            (ADMIN, "admin"),
+            (ADMIN, "dangerous"),
+            (PUBSUB, "pubsub"),
+            (BLOCKING, "blocking"),
+        ];
+        for (flag, category) in implied {
+            if self.has_flag(flag) {
+                categories.push(category);
+            }
+        }
+        let group = match self.group {
+            "generic" => Some("keyspace"),
+            "string" => Some("string"),
+            "stream" => Some("stream"),
+            "pubsub" => Some("pubsub"),
+            "connection" => Some("connection"),
+            "transactions" => Some("transaction"),
+            _ => None,
+        };
+        if let Some(group) = group.filter(|group| !categories.contains(group)) {
+            categories.push(group);
+        }
+        categories.push(if self.has_flag(FAST) { "fast" } else { "slow" });
+        categories
+    }
+
+    /// Legacy `(first, last, step)` key positions, zeroed when the keys
+    /// cannot be told from positions alone.
+    fn key_range(&self) -> (i64, i64, i64) {
+        match self.keys {
+            Keys::Range { first, last, step } => (first, last, step),
+            Keys::None | Keys::Keyword(_) => (0, 0, 0),
+        }
+    }
+
+    fn key_specs(&self) -> Vec<Reply> {
+        let access = if self.has_flag(WRITE) {
+            "RW"
+        } else if self.has_flag(READONLY) {
+            "RO"
+        } else {
+            "not_key"
+        };
+        let (begin_search, find_keys) = match self.keys {
+            Keys::None => return vec![],
+            Keys::Range { first, last, step } => (
+                Reply::fields([
+                    ("type", Reply::bulk("index")),
+                    ("spec", Reply::fields([("index", Reply::Integer(first))])),
+                ]),
+                Reply::fields([
+                    ("type", Reply::bulk("range")),
+                    (
+                        "spec",
+                        Reply::fields([
+                            (
+                                "lastkey",
+                                Reply::Integer(if last < 0 { last } else { last - first }),
+                            ),
+                            ("keystep", Reply::Integer(step)),
+                            ("limit", Reply::Integer(0)),
+                        ]),
+                    ),
+                ]),
+            ),
+            Keys::Keyword(keyword) => (
+                Reply::fields([
+                    ("type", Reply::bulk("keyword")),
+                    (
+                        "spec",
+                        Reply::fields([
+                            ("keyword", Reply::bulk(keyword)),
+                            ("startfrom", Reply::Integer(1)),
+                        ]),
+                    ),
+                ]),
+                Reply::fields([
+                    ("type", Reply::bulk("range")),
+                    (
+                        "spec",
+                        Reply::fields([
+                            ("lastkey", Reply::Integer(-1)),
+                            ("keystep", Reply::Integer(1)),
+                            ("limit", Reply::Integer(2)),
+                        ]),
+                    ),
+                ]),
+            ),
+        };
+        vec![Reply::fields([
+            ("flags", Reply::Set(vec![Reply::Simple(access.to_string())])),
+            ("begin_search", begin_search),
+            ("find_keys", find_keys),
+        ])]
+    }
+
+    /// Entry of `COMMAND INFO`.
+    pub fn info(&self) -> Reply {
+        let mut flags: Vec<Reply> = FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.has_flag(*flag))
+            .map(|(_, name)| Reply::Simple(name.to_string()))
+            .collect();
+        if matches!(self.keys, Keys::Keyword(_)) {
+            flags.push(Reply::Simple("movablekeys".to_string()));
+        }
+        let (first, last, step) = self.key_range();
+        Reply::Array(vec![
+            Reply::bulk(self.name),
+            Reply::Integer(self.arity),
+            Reply::Set(flags),
+            Reply::Integer(first),
+            Reply::Integer(last),
+            Reply::Integer(step),
+            Reply::Set(
+                self.categories()
+                    .into_iter()
+                    .map(|category| Reply::Simple(format!("@{}", category)))
+                    .collect(),
+            ),
+            Reply::Array(vec![]),
+            Reply::Array(self.key_specs()),
+            Reply::Array(self.subcommands.iter().map(CommandSpec::info).collect()),
+        ])
+    }
+
+    /// Entry of `COMMAND DOCS`, without the name it is keyed by.
+    pub fn docs(&self) -> Reply {
+        let mut fields = vec![
+            ("summary", Reply::bulk(self.summary)),
+            ("since", Reply::bulk(self.since)),
+            ("group", Reply::bulk(self.group)),
+        ];
+        if !self.subcommands.is_empty() {
+            fields.push((
+                "subcommands",
+                Reply::Map(
+                    self.subcommands
+                        .iter()
+                        .map(|sub| (Reply::bulk(sub.name), sub.docs()))
+                        .collect(),
+                ),
+            ));
+        }
+        Reply::fields(fields)
+    }
+
+    /// Keys among `argv`, the full command line including its name.
+    pub fn keys<'a>(&self, argv: &'a [String]) -> Vec<&'a String> {
+        match self.keys {
+            Keys::None => vec![],
+            Keys::Range { first, last, step } => {
+                let last = if last < 0 {
+                    argv.len() as i64 + last
+                } else {
+                    last
+                };
+                (first..=last.min(argv.len() as i64 - 1))
+                    .step_by(step.max(1) as usize)
+                    .map(|pos| &argv[pos as usize])
+                    .collect()
+            }
+            Keys::Keyword(keyword) => {
+                let Some(start) = argv
+                    .iter()
+                    .position(|arg| arg.eq_ignore_ascii_case(keyword))
+                else {
+                    return vec![];
+                };
+                let rest = &argv[start + 1..];
+                rest[..rest.len() / 2].iter().collect()
+            }
+        }
+    }
+}
+
+const NO_KEYS: Keys = Keys::None;
+const FIRST_KEY: Keys = Keys::Range {
+    first: 1,
+    last: 1,
+    step: 1,
+};
+const SECOND_KEY: Keys = Keys::Range {
+    first: 2,
+    last: 2,
+    step: 1,
+};
+
+/// Every command the server knows, including the ones served by the
+/// connection itself such as `MULTI` or `SUBSCRIBE`.
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec::new(
+        "acl",
+        -2,
+        0,
+        NO_KEYS,
+        "server",
+        "6.0.0",
+        "A container for Access List Control commands.",
+    )
+    .with_subcommands(&[
+        CommandSpec::new(
+            "acl|cat",
+            -2,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Lists the ACL categories, or the commands inside a category.",
+        ),
+        CommandSpec::new(
+            "acl|deluser",
+            -3,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Deletes ACL users, and terminates their connections.",
+        ),
+        CommandSpec::new(
+            "acl|dryrun",
+            -4,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "7.0.0",
+            "Simulates the execution of a command by a user, without executing the command.",
+        ),
+        CommandSpec::new(
+            "acl|getuser",
+            3,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Lists the ACL rules of a user.",
+        ),
+        CommandSpec::new(
+            "acl|list",
+            2,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Dumps the effective rules in ACL file format.",
+        ),
+        CommandSpec::new(
+            "acl|load",
+            2,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Reloads the rules from the configured ACL file.",
+        ),
+        CommandSpec::new(
+            "acl|log",
+            -2,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Lists recent security events generated due to ACL rules.",
+        ),
+        CommandSpec::new(
+            "acl|save",
+            2,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Saves the effective ACL rules in the configured ACL file.",
+        ),
+        CommandSpec::new(
+            "acl|setuser",
+            -3,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Creates and modifies an ACL user and its rules.",
+        ),
+        CommandSpec::new(
+            "acl|users",
+            2,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Lists all ACL users.",
+        ),
+        CommandSpec::new(
+            "acl|whoami",
+            2,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "6.0.0",
+            "Returns the authenticated username of the current connection.",
+        ),
+    ]),
+    CommandSpec::new(
+        "auth",
+        -2,
+        NOSCRIPT | LOADING | STALE | FAST | NO_AUTH,
+        NO_KEYS,
+        "connection",
+        "1.0.0",
+        "Authenticates the connection.",
+    ),
+    CommandSpec::new(
+        "bgrewriteaof",
+        1,
+        ADMIN | NOSCRIPT,
+        NO_KEYS,
+        "server",
+        "1.0.0",
+        "Asynchronously rewrites the append-only file to disk.",
+    ),
+    CommandSpec::new(
+        "bgsave",
+        -1,
+        ADMIN | NOSCRIPT,
+        NO_KEYS,
+        "server",
+        "1.0.0",
+        "Asynchronously saves the database(s) to disk.",
+    ),
+    CommandSpec::new(
+        "client",
+        -2,
+        0,
+        NO_KEYS,
+        "connection",
+        "2.4.0",
+        "A container for client connection commands.",
+    )
+    .with_subcommands(&[
+        CommandSpec::new(
+            "client|caching",
+            3,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "6.0.0",
+            "Instructs the server whether to track the keys in the next request.",
+        ),
+        CommandSpec::new(
+            "client|getname",
+            2,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "2.6.9",
+            "Returns the name of the connection.",
+        ),
+        CommandSpec::new(
+            "client|getredir",
+            2,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "6.0.0",
+            "Returns the client ID to which the connection's tracking notifications are redirected.",
+        ),
+        CommandSpec::new(
+            "client|id",
+            2,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "5.0.0",
+            "Returns the unique client ID of the connection.",
+        ),
+        CommandSpec::new(
+            "client|info",
+            2,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "6.2.0",
+            "Returns information about the connection.",
+        ),
+        CommandSpec::new(
+            "client|kill",
+            -3,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "2.4.0",
+            "Terminates open connections.",
+        ),
+        CommandSpec::new(
+            "client|list",
+            -2,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "2.4.0",
+            "Lists open connections.",
+        ),
+        CommandSpec::new(
+            "client|no-evict",
+            3,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "7.0.0",
+            "Sets the client eviction mode of the connection.",
+        ),
+        CommandSpec::new(
+            "client|no-touch",
+            3,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "7.2.0",
+            "Controls whether commands sent by the client affect the LRU/LFU of accessed keys.",
+        ),
+        CommandSpec::new(
+            "client|pause",
+            -3,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "3.0.0",
+            "Suspends commands processing.",
+        ),
+        CommandSpec::new(
+            "client|reply",
+            3,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "3.2.0",
+            "Instructs the server whether to reply to commands.",
+        ),
+        CommandSpec::new(
+            "client|setinfo",
+            4,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "7.2.0",
+            "Sets information specific to the client or connection.",
+        ),
+        CommandSpec::new(
+            "client|setname",
+            3,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "2.6.9",
+            "Sets the connection name.",
+        ),
+        CommandSpec::new(
+            "client|tracking",
+            -3,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "6.0.0",
+            "Controls server-assisted client-side caching for the connection.",
+        ),
+        CommandSpec::new(
+            "client|trackinginfo",
+            2,
+            NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "6.2.0",
+            "Returns information about server-assisted client-side caching for the connection.",
+        ),
+        CommandSpec::new(
+            "client|unblock",
+            -3,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "5.0.0",
+            "Unblocks a client blocked by a blocking command from a different connection.",
+        ),
+        CommandSpec::new(
+            "client|unpause",
+            2,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "connection",
+            "6.2.0",
+            "Resumes processing commands from paused clients.",
+        ),
+    ]),
+    CommandSpec::new(
+        "command",
+        -1,
+        LOADING | STALE,
+        NO_KEYS,
+        "server",
+        "2.8.13",
+        "Returns detailed information about all commands.",
+    )
+    .with_subcommands(&[
+        CommandSpec::new(
+            "command|count",
+            2,
+            LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "2.8.13",
+            "Returns a count of commands.",
+        ),
+        CommandSpec::new(
+            "command|docs",
+            -2,
+            LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "7.0.0",
+            "Returns documentary information about one, multiple or all commands.",
+        ),
+        CommandSpec::new(
+            "command|getkeys",
+            -3,
+            LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "2.8.13",
+            "Extracts the key names from an arbitrary command.",
+        ),
+        CommandSpec::new(
+            "command|info",
+            -2,
+            LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "2.8.13",
+            "Returns information about one, multiple or all commands.",
+        ),
+        CommandSpec::new(
+            "command|list",
+            -2,
+            LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "7.0.0",
+            "Returns a list of command names.",
+        ),
+    ]),
+    CommandSpec::new(
+        "config",
+        -2,
+        0,
+        NO_KEYS,
+        "server",
+        "2.0.0",
+        "A container for server configuration commands.",
+    )
+    .with_subcommands(&[
+        CommandSpec::new(
+            "config|get",
+            -3,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "2.0.0",
+            "Returns the effective values of configuration parameters.",
+        ),
+        CommandSpec::new(
+            "config|set",
+            -4,
+            ADMIN | NOSCRIPT | LOADING | STALE,
+            NO_KEYS,
+            "server",
+            "2.0.0",
+            "Sets configuration parameters in-flight.",
+        ),
+    ]),
+    CommandSpec::new(
+        "debug",
+        -2,
+        ADMIN | NOSCRIPT | LOADING | STALE,
+        NO_KEYS,
+        "server",
+        "1.0.0",
+        "A container for debugging commands.",
+    ),
+    CommandSpec::new(
+        "del",
+        -2,
+        WRITE,
+        Keys::Range {
+            first: 1,
+            last: -1,
+            step: 1,
+        },
+        "generic",
+        "1.0.0",
+        "Deletes one or more keys.",
+    ),
+    CommandSpec::new(
+        "discard",
+        1,
+        NOSCRIPT | LOADING | STALE | FAST,
+        NO_KEYS,
+        "transactions",
+        "2.0.0",
+        "Discards a transaction.",
+    ),
+    CommandSpec::new(
+        "dump",
+        2,
+        READONLY,
+        FIRST_KEY,
+        "generic",
+        "2.6.0",
+        "Returns a serialized representation of the value stored at a key.",
+    ),
+    CommandSpec::new(
+        "echo",
+        2,
+        LOADING | STALE | FAST,
+        NO_KEYS,
+        "connection",
+        "1.0.0",
+        "Returns the given string.",
+    ),
+    CommandSpec::new(
+        "exec",
+        1,
+        NOSCRIPT | LOADING | STALE,
+        NO_KEYS,
+        "transactions",
+        "1.2.0",
+        "Executes all commands in a transaction.",
+    ),
+    CommandSpec::new(
+        "get",
+        2,
+        READONLY | FAST,
+        FIRST_KEY,
+        "string",
+        "1.0.0",
+        "Returns the string value of a key.",
+    ),
+    CommandSpec::new(
+        "hello",
+        -1,
+        NOSCRIPT | LOADING | STALE | FAST | NO_AUTH,
+        NO_KEYS,
+        "connection",
+        "6.0.0",
+        "Handshakes with the Redis server.",
+    ),
+    CommandSpec::new(
+        "info",
+        -1,
+        LOADING | STALE,
+        NO_KEYS,
+        "server",
+        "1.0.0",
+        "Returns information and statistics about the server.",
+    ),
+    CommandSpec::new(
+        "keys",
+        2,
+        READONLY,
+        NO_KEYS,
+        "generic",
+        "1.0.0",
+        "Returns all key names that match a pattern.",
+    ),
+    CommandSpec::new(
+        "lastsave",
+        1,
+        LOADING | STALE | FAST,
+        NO_KEYS,
+        "server",
+        "1.0.0",
+        "Returns the Unix timestamp of the last successful save to disk.",
+    ),
+    CommandSpec::new(
+        "memory",
+        -2,
+        0,
+        NO_KEYS,
+        "server",
+        "4.0.0",
+        "A container for memory diagnostics commands.",
+    )
+    .with_subcommands(&[CommandSpec::new(
+        "memory|usage",
+        -3,
+        READONLY,
+        SECOND_KEY,
+        "server",
+        "4.0.0",
+        "Estimates the memory usage of a key.",
+    )]),
+    CommandSpec::new(
+        "migrate",
+        -6,
+        WRITE,
+        Keys::Range {
+            first: 3,
+            last: 3,
+            step: 1,
+        },
+        "generic",
+        "2.6.0",
+        "Atomically transfers a key from one Redis instance to another.",
+    ),
+    CommandSpec::new(
+        "multi",
+        1,
+        NOSCRIPT | LOADING | STALE | FAST,
+        NO_KEYS,
+        "transactions",
+        "1.2.0",
+        "Starts a transaction.",
+    ),
+    CommandSpec::new(
+        "pexpireat",
+        -3,
+        WRITE | FAST,
+        FIRST_KEY,
+        "generic",
+        "2.6.0",
+        "Sets the expiration time of a key to a Unix milliseconds timestamp.",
+    ),
+    CommandSpec::new(
+        "ping",
+        -1,
+        FAST,
+        NO_KEYS,
+        "connection",
+        "1.0.0",
+        "Returns the server's liveliness response.",
+    ),
+    CommandSpec::new(
+        "psubscribe",
+        -2,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        NO_KEYS,
+        "pubsub",
+        "2.0.0",
+        "Listens for messages published to channels that match one or more patterns.",
+    ),
+    CommandSpec::new(
+        "psync",
+        -3,
+        ADMIN | NOSCRIPT,
+        NO_KEYS,
+        "server",
+        "2.8.0",
+        "An internal command used in replication.",
+    ),
+    CommandSpec::new(
+        "publish",
+        3,
+        PUBSUB | LOADING | STALE | FAST,
+        NO_KEYS,
+        "pubsub",
+        "2.0.0",
+        "Posts a message to a channel.",
+    ),
+    CommandSpec::new(
+        "pubsub",
+        -2,
+        0,
+        NO_KEYS,
+        "pubsub",
+        "2.8.0",
+        "A container for Pub/Sub commands.",
+    )
+    .with_subcommands(&[
+        CommandSpec::new(
+            "pubsub|channels",
+            -2,
+            PUBSUB | LOADING | STALE,
+            NO_KEYS,
+            "pubsub",
+            "2.8.0",
+            "Returns the active channels.",
+        ),
+        CommandSpec::new(
+            "pubsub|numpat",
+            2,
+            PUBSUB | LOADING | STALE,
+            NO_KEYS,
+            "pubsub",
+            "2.8.0",
+            "Returns a count of unique pattern subscriptions.",
+        ),
+        CommandSpec::new(
+            "pubsub|numsub",
+            -2,
+            PUBSUB | LOADING | STALE,
+            NO_KEYS,
+            "pubsub",
+            "2.8.0",
+            "Returns a count of subscribers to channels.",
+        ),
+        CommandSpec::new(
+            "pubsub|shardchannels",
+            -2,
+            PUBSUB | LOADING | STALE,
+            NO_KEYS,
+            "pubsub",
+            "7.0.0",
+            "Returns the active shard channels.",
+        ),
+        CommandSpec::new(
+            "pubsub|shardnumsub",
+            -2,
+            PUBSUB | LOADING | STALE,
+            NO_KEYS,
+            "pubsub",
+            "7.0.0",
+            "Returns the count of subscribers of shard channels.",
+        ),
+    ]),
+    CommandSpec::new(
+        "punsubscribe",
+        -1,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        NO_KEYS,
+        "pubsub",
+        "2.0.0",
+        "Stops listening to messages published to channels that match one or more patterns.",
+    ),
+    CommandSpec::new(
+        "quit",
+        -1,
+        NOSCRIPT | LOADING | STALE | FAST | NO_AUTH,
+        NO_KEYS,
+        "connection",
+        "1.0.0",
+        "Closes the connection.",
+    ),
+    CommandSpec::new(
+        "replconf",
+        -1,
+        ADMIN | NOSCRIPT | LOADING | STALE,
+        NO_KEYS,
+        "server",
+        "3.0.0",
+        "An internal command for configuring the replication stream.",
+    ),
+    CommandSpec::new(
+        "reset",
+        1,
+        NOSCRIPT | LOADING | STALE | FAST | NO_AUTH,
+        NO_KEYS,
+        "connection",
+        "6.2.0",
+        "Resets the connection.",
+    ),
+    CommandSpec::new(
+        "restore",
+        -4,
+        WRITE | DENYOOM,
+        FIRST_KEY,
+        "generic",
+        "2.6.0",
+        "Creates a key from the serialized representation of a value.",
+    ),
+    CommandSpec::new(
+        "save",
+        1,
+        ADMIN | NOSCRIPT,
+        NO_KEYS,
+        "server",
+        "1.0.0",
+        "Synchronously saves the database(s) to disk.",
+    ),
+    CommandSpec::new(
+        "set",
+        -3,
+        WRITE | DENYOOM,
+        FIRST_KEY,
+        "string",
+        "1.0.0",
+        "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.",
+    ),
+    CommandSpec::new(
+        "spublish",
+        3,
+        PUBSUB | LOADING | STALE | FAST,
+        FIRST_KEY,
+        "pubsub",
+        "7.0.0",
+        "Post a message to a shard channel.",
+    ),
+    CommandSpec::new(
+        "ssubscribe",
+        -2,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        Keys::Range {
+            first: 1,
+            last: -1,
+            step: 1,
+        },
+        "pubsub",
+        "7.0.0",
+        "Listens for messages published to shard channels.",
+    ),
+    CommandSpec::new(
+        "subscribe",
+        -2,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        NO_KEYS,
+        "pubsub",
+        "2.0.0",
+        "Listens for messages published to channels.",
+    ),
+    CommandSpec::new(
+        "sunsubscribe",
+        -1,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        Keys::Range {
+            first: 1,
+            last: -1,
+            step: 1,
+        },
+        "pubsub",
+        "7.0.0",
+        "Stops listening to messages posted to shard channels.",
+    ),
+    CommandSpec::new(
+        "type",
+        2,
+        READONLY | FAST,
+        FIRST_KEY,
+        "generic",
+        "1.0.0",
+        "Determines the type of value stored at a key.",
+    ),
+    CommandSpec::new(
+        "unsubscribe",
+        -1,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        NO_KEYS,
+        "pubsub",
+        "2.0.0",
+        "Stops listening to messages posted to channels.",
+    ),
+    CommandSpec::new(
+        "unwatch",
+        1,
+        NOSCRIPT | LOADING | STALE | FAST,
+        NO_KEYS,
+        "transactions",
+        "2.2.0",
+        "Forgets about watched keys of a transaction.",
+    ),
+    CommandSpec::new(
+        "wait",
+        3,
+        BLOCKING,
+        NO_KEYS,
+        "generic",
+        "3.0.0",
+        "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed.",
+    ),
+    CommandSpec::new(
+        "watch",
+        -2,
+        NOSCRIPT | LOADING | STALE | FAST,
+        Keys::Range {
+            first: 1,
+            last: -1,
+            step: 1,
+        },
+        "transactions",
+        "2.2.0",
+        "Monitors changes to keys to determine the execution of a transaction.",
+    ),
+    CommandSpec::new(
+        "xack",
+        -4,
+        WRITE | FAST,
+        FIRST_KEY,
+        "stream",
+        "5.0.0",
+        "Returns the number of messages that were successfully acknowledged by the consumer group member of a stream.",
+    ),
+    CommandSpec::new(
+        "xadd",
+        -5,
+        WRITE | DENYOOM | FAST,
+        FIRST_KEY,
+        "stream",
+        "5.0.0",
+        "Appends a new message to a stream. Creates the key if it doesn't exist.",
+    ),
+    CommandSpec::new(
+        "xclaim",
+        -6,
+        WRITE | FAST,
+        FIRST_KEY,
+        "stream",
+        "5.0.0",
+        "Changes, or acquires, ownership of a message in a consumer group, as if the message was delivered a consumer group member.",
+    ),
+    CommandSpec::new(
+        "xdel",
+        -3,
+        WRITE | FAST,
+        FIRST_KEY,
+        "stream",
+        "5.0.0",
+        "Returns the number of messages after removing them from a stream.",
+    ),
+    CommandSpec::new(
+        "xgroup",
+        -2,
+        0,
+        NO_KEYS,
+        "stream",
+        "5.0.0",
+        "A container for consumer groups commands.",
+    )
+    .with_subcommands(&[
+        CommandSpec::new(
+            "xgroup|create",
+            -5,
+            WRITE | DENYOOM,
+            SECOND_KEY,
+            "stream",
+            "5.0.0",
+            "Creates a consumer group.",
+        ),
+        CommandSpec::new(
+            "xgroup|createconsumer",
+            5,
+            WRITE | DENYOOM,
+            SECOND_KEY,
+            "stream",
+            "6.2.0",
+            "Creates a consumer in a consumer group.",
+        ),
+        CommandSpec::new(
+            "xgroup|delconsumer",
+            5,
+            WRITE,
+            SECOND_KEY,
+            "stream",
+            "5.0.0",
+            "Deletes a consumer from a consumer group.",
+        ),
+        CommandSpec::new(
+            "xgroup|destroy",
+            4,
+            WRITE,
+            SECOND_KEY,
+            "stream",
+            "5.0.0",
+            "Destroys a consumer group.",
+        ),
+        CommandSpec::new(
+            "xgroup|setid",
+            -5,
+            WRITE,
+            SECOND_KEY,
+            "stream",
+            "5.0.0",
+            "Sets the last-delivered ID of a consumer group.",
+        ),
+    ]),
+    CommandSpec::new(
+        "xinfo",
+        -2,
+        0,
+        NO_KEYS,
+        "stream",
+        "5.0.0",
+        "A container for stream introspection commands.",
+    )
+    .with_subcommands(&[
+        CommandSpec::new(
+            "xinfo|consumers",
+            4,
+            READONLY,
+            SECOND_KEY,
+            "stream",
+            "5.0.0",
+            "Returns a list of the consumers in a consumer group.",
+        ),
+        CommandSpec::new(
+            "xinfo|groups",
+            3,
+            READONLY,
+            SECOND_KEY,
+            "stream",
+            "5.0.0",
+            "Returns a list of the consumer groups of a stream.",
+        ),
+        CommandSpec::new(
+            "xinfo|stream",
+            -3,
+            READONLY,
+            SECOND_KEY,
+            "stream",
+            "5.0.0",
+            "Returns information about a stream.",
+        ),
+    ]),
+    CommandSpec::new(
+        "xlen",
+        2,
+        READONLY | FAST,
+        FIRST_KEY,
+        "stream",
+        "5.0.0",
+        "Return the number of messages in a stream.",
+    ),
+    CommandSpec::new(
+        "xrange",
+        -4,
+        READONLY,
+        FIRST_KEY,
+        "stream",
+        "5.0.0",
+        "Returns the messages from a stream within a range of IDs.",
+    ),
+    CommandSpec::new(
+        "xread",
+        -4,
+        READONLY,
+        Keys::Keyword("STREAMS"),
+        "stream",
+        "5.0.0",
+        "Returns messages from multiple streams with IDs greater than the ones requested.",
+    ),
+    CommandSpec::new(
+        "xreadgroup",
+        -7,
+        WRITE | BLOCKING,
+        Keys::Keyword("STREAMS"),
+        "stream",
+        "5.0.0",
+        "Returns new or historical messages from a stream for a consumer in a group.",
+    ),
+    CommandSpec::new(
+        "xrevrange",
+        -4,
+        READONLY,
+        FIRST_KEY,
+        "stream",
+        "5.0.0",
+        "Returns the messages from a stream within a range of IDs in reverse order.",
+    ),
+    CommandSpec::new(
+        "xsetid",
+        -3,
+        WRITE | DENYOOM | FAST,
+        FIRST_KEY,
+        "stream",
+        "5.0.0",
+        "An internal command for replicating stream values.",
+    ),
+    CommandSpec::new(
+        "xtrim",
+        -4,
+        WRITE,
+        FIRST_KEY,
+        "stream",
+        "5.0.0",
+        "Deletes messages from the beginning of a stream.",
+    ),
+];
+
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
@@ -3,21 +3,42 @@ use std::{
     error::Error,
     fmt::{Display, Formatter},
     future::Future,
+    ops::Range,
+    path::Path,
     pin::Pin,
     sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use crate::internal::acl;
+use crate::internal::aof;
+use crate::internal::cli;
+use crate::internal::client;
+use crate::internal::command_table::{self, CommandSpec, COMMAND_TABLE};
+use crate::internal::glob::glob_match;
+use crate::internal::migrate::{self, MigrateError};
+use crate::internal::notify::{self, notify_keyspace_event};
+use crate::internal::persistence;
+use crate::internal::pubsub::PUBSUB;
+use crate::internal::rdb;
+use crate::internal::resp::Reply;
 use crate::internal::server::ServerMetadata;
 use crate::internal::server_info;
-use crate::internal::storage::{DBEntry, STORAGE};
+use crate::internal::storage::{self, expire_if_needed, DBEntry, STORAGE};
+use crate::internal::tracking;
+use crate::internal::transaction::{self, EXEC_LOCK};
 use crate::internal::{
     parser::Command,
-    types::{StreamId, StreamType},
+    types::{
+        entries_to_resp, entry_to_resp, now_millis, ClaimOptions, DBValue, StreamFields, StreamId,
+        StreamType, StringType, TrimOptions, TrimStrategy,
+    },
 };
 use tokio::{
     io::AsyncWriteExt,
     net::TcpStream,
-    sync::{broadcast, RwLock},
+    sync::{broadcast, Notify, RwLock},
+    time::Instant,
 };
 
 #[derive(Debug)]
@@ -25,6 +46,9 @@ pub enum CommandError {
     CommandNotFound(String),
     InvalidArgument(String),
     StorageError(String),
+    WrongType,
+    /// An error replied with its own code instead of `ERR`, e.g. `NOGROUP`.
+    WithCode(&'static str, String),
     _ErrorWhileExecution(String),
 }
 
@@ -36,6 +60,8 @@ impl Display for CommandError {
             CommandError::CommandNotFound(cmd) => write!(f, "Command not found: {}", cmd),
             CommandError::InvalidArgument(msg) => write!(f, "Invalid arguments: {}", msg),
             CommandError::StorageError(msg) => write!(f, "Storage error: {}", msg),
+            CommandError::WrongType => write!(f, "Wrong type: {}", self.message()),
+            CommandError::WithCode(code, msg) => write!(f, "{} {}", code, msg),
             CommandError::_ErrorWhileExecution(msg) => {
                 write!(f, "Error while executing the command: {}", msg)
             }
@@ -44,27 +70,50 @@ impl Display for CommandError {
 }
 
 impl CommandError {
-    fn as_resp(&self) -> String {
+    /// Code the error reply starts with, `ERR` unless a more specific one
+    /// applies.
+    pub fn code(&self) -> &'static str {
         match self {
-            CommandError::InvalidArgument(st) => format!("-ERR {}\r\n", st),
-            _ => "Error".to_string(),
+            CommandError::WrongType => "WRONGTYPE",
+            CommandError::WithCode(code, _) => code,
+            _ => "ERR",
+        }
+    }
+
+    /// Message of the error reply, following its code.
+    pub fn message(&self) -> String {
+        match self {
+            CommandError::CommandNotFound(cmd) => format!("unknown command {}", cmd),
+            CommandError::InvalidArgument(msg)
+            | CommandError::StorageError(msg)
+            | CommandError::WithCode(_, msg) => msg.clone(),
+            CommandError::WrongType => {
+                "Operation against a key holding the wrong kind of value".to_string()
+            }
+            CommandError::_ErrorWhileExecution(msg) => {
+                format!("Error while executing the command: {}", msg)
+            }
         }
     }
 }
 
 pub type CommandFn = Arc<
     dyn for<'a> Fn(
-            Arc<RwLock<TcpStream>>,
             Command,
             &'a Arc<RwLock<ServerMetadata>>,
-        ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>
+        ) -> Pin<Box<dyn Future<Output = Reply> + Send + 'a>>
         + Send
         + Sync,
 >;
 
+struct Registered {
+    spec: &'static CommandSpec,
+    handler: CommandFn,
+}
+
 #[derive(Default)]
 pub struct CommandsReg {
-    commands: HashMap<&'static str, CommandFn>,
+    commands: HashMap<&'static str, Registered>,
 }
 
 impl PartialEq for CommandsReg {
@@ -80,7 +129,13 @@ macro_rules! register_commands {
             $(
                 m.commands.insert(
                     stringify!($name),
-                    Arc::new(move |stream, command, metadata| Box::pin($func(stream, command, metadata)))
+                    Registered {
+                        spec: command_table::lookup(stringify!($name))
+                            .expect("registered commands must be described in the command table"),
+                        handler: Arc::new(move |command, metadata| {
+                            Box::pin($func(command, metadata))
+                        }),
+                    },
                 );
             )*
             m
@@ -91,36 +146,146 @@ macro_rules! register_commands {
 lazy_static! {
     pub static ref MASTER_REPLICA_COMMANDS: CommandsReg = register_commands! {
         config => config,
+        del => del,
         echo => echo,
         get => get,
         info => info,
         keys => keys,
+        pexpireat => pexpireat,
         ping => ping,
+        publish => publish_replicated,
         replconf => replconf,
+        restore => restore,
         set => set,
-        type_fn => type_fn,
+        spublish => spublish_replicated,
+        type => type_fn,
+        xack => xack,
         xadd => xadd,
+        xclaim => xclaim,
+        xdel => xdel,
+        xgroup => xgroup,
+        xinfo => xinfo,
+        xlen => xlen,
         xrange => xrange,
         xread => xread,
+        xreadgroup => xreadgroup,
+        xrevrange => xrevrange,
+        xsetid => xsetid,
+        xtrim => xtrim,
     };
 }
 
 lazy_static! {
     pub static ref COMMANDS_REGISTRY: CommandsReg = register_commands! {
+        acl => acl,
+        auth => auth,
+        bgrewriteaof => bgrewriteaof,
+        bgsave => bgsave,
+        client => client_fn,
+        command => command,
         config => config,
+        debug => debug,
+        del => del,
+        dump => dump,
         echo => echo,
         get => get,
+        hello => hello,
         info => info,
         keys=> keys,
+        lastsave => lastsave,
+        memory => memory,
+        migrate => migrate,
+        pexpireat => pexpireat,
         ping => ping,
+        publish => publish,
+        pubsub => pubsub,
         replconf => replconf,
+        restore => restore,
+        save => save,
         set => set,
-        type_fn => type_fn,
+        spublish => spublish,
+        type => type_fn,
         wait => wait,
+        xack => xack,
         xadd => xadd,
+        xclaim => xclaim,
+        xdel => xdel,
+        xgroup => xgroup,
+        xinfo => xinfo,
+        xlen => xlen,
         xrange => xrange,
         xread => xread,
+        xreadgroup => xreadgroup,
+        xrevrange => xrevrange,
+        xsetid => xsetid,
+        xtrim => xtrim,
+    };
+}
+
+/// Version reported to clients, e.g. by `HELLO`.
+pub const REDIS_VERSION: &str = "7.2.0";
+
+/// Whether the command may modify the dataset, looking into the subcommand
+/// for containers such as `XGROUP`.
+pub fn is_write_command(command: &Command) -> bool {
+    let Some(spec) = command_table::lookup(&command.cmd) else {
+        return false;
     };
+    let spec = command
+        .args
+        .first()
+        .and_then(|sub| spec.subcommand(sub))
+        .unwrap_or(spec);
+    spec.has_flag(command_table::WRITE)
+}
+
+impl CommandsReg {
+    fn lookup(&self, name: &str) -> Option<&Registered> {
+        self.commands.get(name.to_lowercase().as_str())
+    }
+
+    /// Checks the command exists and is given a valid number of arguments,
+    /// as done before running or queueing it.
+    pub fn check(&self, command: &Command) -> Result<(), CommandError> {
+        let registered = self
+            .lookup(&command.cmd)
+            .ok_or_else(|| unknown_command(command))?;
+        registered
+            .spec
+            .check_arity(command)
+            .map_err(|name| _wrong_args(&name))
+    }
+}
+
+pub fn unknown_command(command: &Command) -> CommandError {
+    // Like Redis, arguments are cut short and kept to a single line.
+    let args: String = command
+        .args
+        .iter()
+        .map(|arg| {
+            let mut end = arg.len().min(128);
+            while !arg.is_char_boundary(end) {
+                end -= 1;
+            }
+            format!("'{}' ", arg[..end].replace(['\r', '\n'], " "))
+        })
+        .collect();
+    CommandError::CommandNotFound(format!(
+        "'{}', with args beginning with: {}",
+        command.cmd, args
+    ))
+}
+
+/// What a command runs for, which decides the ACL check it goes through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Context {
+    /// Sent by a client.
+    TopLevel,
+    /// Queued by a client in a transaction, run by `EXEC`.
+    Multi,
+    /// Sent by our master or replayed from the AOF, which no user's
+    /// permissions apply to.
+    Internal,
 }
 
 pub async fn run_command(
@@ -128,110 +293,196 @@ pub async fn run_command(
     command: Command,
     server_metadata: &Arc<RwLock<ServerMetadata>>,
     command_reg: &CommandsReg,
+    replies: bool,
 ) {
-    let lowered = command.cmd.to_lowercase();
-    let mut cmd_key = lowered.as_str();
-    if cmd_key == "type" {
-        cmd_key = "type_fn";
+    let context = if replies {
+        Context::TopLevel
+    } else {
+        Context::Internal
+    };
+    // The master only ever expects an answer to `REPLCONF GETACK`.
+    let replies = replies || command.cmd.eq_ignore_ascii_case("replconf");
+    // Those that can't block, e.g. sent by our master, run like any other.
+    let blocking = command_table::lookup(&command.cmd)
+        .is_some_and(|spec| spec.has_flag(command_table::BLOCKING))
+        && client::blocking_allowed();
+    let reply = if blocking {
+        let dispatched = dispatch(command, server_metadata, command_reg, context);
+        tokio::pin!(dispatched);
+        // Nothing else reads the socket meanwhile, so a peer going away
+        // would leave the command blocked, and the client listed, forever.
+        tokio::select! {
+            reply = &mut dispatched => reply,
+            () = _peer_closed(&stream) => {
+                client::set_closed();
+                dispatched.await
+            }
+        }
+    } else {
+        let _shared = EXEC_LOCK.read().await;
+        dispatch(command, server_metadata, command_reg, context).await
+    };
+    if replies {
+        write_reply(&stream, &reply).await;
     }
+}
 
-    match command_reg
-        .commands
-        .get(cmd_key)
-        .ok_or_else(|| CommandError::CommandNotFound(command.cmd.clone()))
-    {
-        Ok(function) => function(stream, command, server_metadata).await,
-        Err(_) => eprintln!("Cannot find function with name \"{}\"", command.cmd),
+/// Resolves once the peer closed the connection. Pipelined requests waiting
+/// to be read hide that, and are left for after the command.
+async fn _peer_closed(stream: &Arc<RwLock<TcpStream>>) {
+    let stream = stream.read().await;
+    let mut buf = [0u8; 1];
+    match stream.peek(&mut buf).await {
+        Ok(0) | Err(_) => {}
+        Ok(_) => std::future::pending().await,
     }
 }
 
-async fn replconf(
-    stream: Arc<RwLock<TcpStream>>,
+/// Runs the command without taking `EXEC_LOCK`, which `EXEC` already holds.
+/// Client commands are checked against the user's permissions first, as
+/// they stand when the command runs.
+pub async fn dispatch(
     command: Command,
-    _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+    command_reg: &CommandsReg,
+    context: Context,
+) -> Reply {
+    if context != Context::Internal {
+        if let Err(e) = acl::authorize(&command, context == Context::Multi) {
+            return e.into();
+        }
+    }
+    let Some(registered) = command_reg.lookup(&command.cmd) else {
+        return unknown_command(&command).into();
+    };
+    if let Err(name) = registered.spec.check_arity(&command) {
+        return _wrong_args(&name).into();
+    }
+    if persistence::loading() && !registered.spec.has_flag(command_table::LOADING) {
+        return CommandError::WithCode(
+            "LOADING",
+            "Redis is loading the dataset in memory".to_string(),
+        )
+        .into();
+    }
+    let access = tracking::Access::of(&command);
+    let write = is_write_command(&command);
+    // `MIGRATE` logs the keys it deleted instead of itself.
+    let logs_itself = command.cmd.eq_ignore_ascii_case("migrate");
+    let logged = (write && !logs_itself).then(|| command.clone());
+    let received_size = command.raw_cmd.len() as u64;
+    let reply = (registered.handler)(command, server_metadata).await;
+    let logged = match (logged, client::take_rewritten_args()) {
+        (Some(logged), Some(args)) => Some(Command::from_args(logged.cmd, args)),
+        (logged, _) => logged,
+    };
+    // Including those `EXEC` runs, queued by the master's `MULTI`.
+    if write && client::is_master_link() {
+        _count_replicated_write(received_size, server_metadata).await;
+    }
+    if !matches!(reply, Reply::Error(_)) {
+        access.apply();
+        if write {
+            persistence::mark_dirty(1);
+        }
+        // An `XREADGROUP` that delivered nothing, e.g. timed out, changed
+        // nothing worth replaying.
+        if let Some(logged) = logged.filter(|_| reply != Reply::NullArray) {
+            // Replicas and the AOF get the same deterministic form, e.g.
+            // with the ID `XADD` generated.
+            let raw_cmd = aof::encode(&logged, &reply);
+            if aof::enabled() {
+                aof::feed(&raw_cmd);
+            }
+            _propagate(raw_cmd, server_metadata).await;
+        }
+    }
+    reply
+}
+
+/// Sends a command to the replicas, accounting for it in the replication
+/// offset. Only a master has any.
+async fn _propagate(raw_cmd: Vec<u8>, server_metadata: &Arc<RwLock<ServerMetadata>>) {
+    let metadata = server_metadata.read().await;
+    if metadata.role != 0 {
+        return;
+    }
+    let command_size = raw_cmd.len() as u64;
+    sync_replicas(raw_cmd, &metadata.broadcast).await;
+    metadata
+        .master_repl_offset
+        .fetch_add(command_size, Ordering::SeqCst);
+}
+
+/// On a replica, writes received from the master count towards the
+/// processed replication offset, whether they succeeded or not.
+async fn _count_replicated_write(size: u64, server_metadata: &Arc<RwLock<ServerMetadata>>) {
+    server_metadata
+        .read()
+        .await
+        .master_repl_offset
+        .fetch_add(size, Ordering::SeqCst);
+}
+
+async fn replconf(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
     match command.args.first() {
         Some(sub) => match sub.to_lowercase().as_str() {
-            "getack" => _replconf_getack(stream, command, _server_metadata).await,
-            "listening-port" => _replconf_listening_port(stream, command, _server_metadata).await,
-            "capa" => _replconf_capa(stream, command, _server_metadata).await,
-            _ => _replconf(stream, command, _server_metadata).await,
+            "getack" => _replconf_getack(command, _server_metadata).await,
+            "listening-port" => _replconf_listening_port(command, _server_metadata).await,
+            "capa" => _replconf_capa(command, _server_metadata).await,
+            _ => _replconf(command, _server_metadata).await,
         },
-        None => _replconf(stream, command, _server_metadata).await,
+        None => _replconf(command, _server_metadata).await,
     }
 }
 
-async fn _replconf(
-    stream: Arc<RwLock<TcpStream>>,
-    _command: Command,
-    _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
-    let res = "+OK\r\n".to_string();
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(res.as_bytes()).await;
-    let _ = stream.flush().await;
+async fn _replconf(_command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    Reply::ok()
 }
 
 async fn _replconf_capa(
-    stream: Arc<RwLock<TcpStream>>,
     _command: Command,
     _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+) -> Reply {
     // TODO: define capa functionality.
-    _replconf(stream, _command, _server_metadata).await
+    _replconf(_command, _server_metadata).await
 }
 
 async fn _replconf_listening_port(
-    stream: Arc<RwLock<TcpStream>>,
     _command: Command,
     _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+) -> Reply {
     // TODO: define listening port functionality.
-    _replconf(stream, _command, _server_metadata).await
+    _replconf(_command, _server_metadata).await
 }
 
 async fn _replconf_getack(
-    stream: Arc<RwLock<TcpStream>>,
     command: Command,
     server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+) -> Reply {
     let metadata = server_metadata.read().await;
     let repl_offset = metadata.master_repl_offset.load(Ordering::SeqCst);
-    let offset_str = repl_offset.to_string();
-    let res = format!(
-        "*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n${}\r\n{}\r\n",
-        offset_str.len(),
-        offset_str
-    );
-    _write_stream_and_flush(&stream, res.as_str()).await;
     let command_size = command.raw_cmd.len() as u64;
     metadata
         .master_repl_offset
         .fetch_add(command_size, Ordering::SeqCst);
+    Reply::bulks(["REPLCONF", "ACK", &repl_offset.to_string()])
 }
 
-async fn ping(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+async fn ping(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
     let metadata = server_metadata.read().await;
     if metadata.role == 0 {
-        let res = "+PONG\r\n";
-        _write_stream_and_flush(&stream, res).await;
-    } else if metadata.role == 1 {
+        Reply::Simple("PONG".to_string())
+    } else {
         let command_size = command.raw_cmd.len() as u64;
         metadata
             .master_repl_offset
             .fetch_add(command_size, Ordering::SeqCst);
+        Reply::Nothing
     }
 }
 
-async fn wait(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+async fn wait(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
     let metadata = server_metadata.read().await;
     let num_replicas: usize = command
         .args
@@ -244,107 +495,142 @@ async fn wait(
         .and_then(|s| s.parse().ok())
         .unwrap_or(0);
     let target = metadata.master_repl_offset.load(Ordering::SeqCst);
+    let acked = || {
+        metadata
+            .replica_offsets
+            .iter()
+            .filter(|o| o.load(Ordering::SeqCst) >= target)
+            .count()
+    };
 
     if target == 0 {
-        let res = format!(":{}\r\n", metadata.broadcast.receiver_count());
-        _write_stream_and_flush(&stream, res.as_str()).await;
+        Reply::Integer(metadata.broadcast.receiver_count() as i64)
+    } else if !client::blocking_allowed() {
+        // Run by `EXEC`: replies with the replicas that acknowledged so far.
+        Reply::Integer(acked() as i64)
     } else {
         // Broadcast REPLCONF GETACK * to all replicas
-        let getack_cmd = "*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n".to_string();
-        _sync_replicas(getack_cmd, &metadata.broadcast).await;
+        let getack_cmd = b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n".to_vec();
+        sync_replicas(getack_cmd, &metadata.broadcast).await;
 
         // Wait for responses with timeout
         let timeout = tokio::time::sleep(tokio::time::Duration::from_millis(ms_timeout));
         tokio::pin!(timeout);
 
-        let count = loop {
-            let c = metadata
-                .replica_offsets
-                .iter()
-                .filter(|o| o.load(Ordering::SeqCst) >= target)
-                .count();
+        client::set_blocked(true);
+        let reply = loop {
+            let c = acked();
             if c >= num_replicas {
-                break c;
+                break Reply::Integer(c as i64);
             }
 
             tokio::select! {
                 _ = metadata.ack_notify.notified() => continue,
-                _ = &mut timeout => break c,
+                _ = &mut timeout => break Reply::Integer(c as i64),
+                _ = client::closed() => break Reply::Integer(c as i64),
+                error = client::unblocked() => break match error {
+                    true => Reply::Error(
+                        "UNBLOCKED client unblocked via CLIENT UNBLOCK".to_string(),
+                    ),
+                    false => Reply::Integer(c as i64),
+                },
             }
         };
-        let res = format!(":{}\r\n", count);
-        _write_stream_and_flush(&stream, res.as_str()).await;
+        client::set_blocked(false);
+        reply
     }
 }
 
-async fn echo(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+async fn echo(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
     let args = command.args;
     let echo_arg = match args.first() {
         Some(val) => val,
         None => "",
     };
-    let res = format!("${}\r\n{}\r\n", echo_arg.len(), echo_arg);
-    _write_stream_and_flush(&stream, res.as_str()).await;
+    Reply::bulk(echo_arg)
 }
 
-async fn set(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+async fn set(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
     let metadata = server_metadata.read().await;
-    let args = command.args;
+    let args = &command.args;
     let key = args.first().unwrap();
     match args
         .get(1)
         .ok_or_else(|| CommandError::InvalidArgument("Missing arguments".to_string()))
     {
         Ok(value) => {
-            let mut db_entry = DBEntry::from_string(value);
-            if args.len() > 2 && args[2].to_lowercase() == "px" {
-                let _ = db_entry.set_ttl(args.get(3));
-            }
+            let value = command
+                .arg_bytes(1)
+                .unwrap_or_else(|| value.as_bytes().to_vec());
+            let mut db_entry = DBEntry::from_string(&value);
+            let expires = match args.get(2).map(|option| option.to_lowercase()).as_deref() {
+                // `PXAT` is the absolute form the AOF logs relative TTLs with.
+                Some(unit @ ("px" | "pxat")) => match _set_expire_at(unit, args.get(3)) {
+                    Ok(at) => {
+                        db_entry.set_expiry_at(UNIX_EPOCH + Duration::from_millis(at));
+                        true
+                    }
+                    Err(e) => return e.into(),
+                },
+                _ => false,
+            };
             let mut storage = STORAGE.lock().await;
-            storage.insert(key.to_string(), db_entry);
+            expire_if_needed(&mut storage, key).await;
+            if storage.insert(key.to_string(), db_entry).is_none() {
+                notify_keyspace_event(notify::NEW, "new", key).await;
+            }
+            notify_keyspace_event(notify::STRING, "set", key).await;
+            if expires {
+                storage::track_expiry(key);
+                notify_keyspace_event(notify::GENERIC, "expire", key).await;
+            }
             if metadata.role == 0 {
-                let res = "+OK\r\n";
-                _write_stream_and_flush(&stream, res).await;
+                Reply::ok()
+            } else {
+                Reply::Nothing
             }
-            let command_size = command.raw_cmd.len() as u64;
-            _sync_replicas(command.raw_cmd, &metadata.broadcast).await;
-
-            metadata
-                .master_repl_offset
-                .fetch_add(command_size, Ordering::SeqCst);
         }
-        Err(_) => eprintln!("Error setting a value"),
+        Err(e) => e.into(),
+    }
+}
+
+/// Expiry time given to `SET` by `PX` or `PXAT`, in unix milliseconds. Like
+/// Redis, it must be positive and fit in a signed 64-bit integer.
+fn _set_expire_at(unit: &str, value: Option<&String>) -> Result<u64, CommandError> {
+    let value: i64 = value
+        .ok_or_else(_syntax_error)?
+        .parse()
+        .map_err(|_| _not_integer())?;
+    let invalid =
+        || CommandError::InvalidArgument("invalid expire time in 'set' command".to_string());
+    if value <= 0 {
+        return Err(invalid());
+    }
+    let at = match unit {
+        "px" => now_millis().checked_add(value as u64).ok_or_else(invalid)?,
+        _ => value as u64,
+    };
+    if at > i64::MAX as u64 {
+        return Err(invalid());
     }
+    Ok(at)
 }
 
-async fn _sync_replicas(raw_command: String, sender: &broadcast::Sender<Arc<Vec<u8>>>) {
+pub async fn sync_replicas(raw_command: Vec<u8>, sender: &broadcast::Sender<Arc<Vec<u8>>>) {
     if sender.receiver_count() > 0 {
-        let v = Arc::new(raw_command.into_bytes());
+        let v = Arc::new(raw_command);
         let _ = sender.send(v);
     }
 }
 
-async fn xread(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
-    let res = match xread_inner(command).await {
-        Ok(stream_resp) => stream_resp,
-        Err(e) => e.as_resp(),
-    };
-    _write_stream_and_flush(&stream, res.as_str()).await;
+async fn xread(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xread_inner(command).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
 }
 
-async fn xread_inner(command: Command) -> Result<String, CommandError> {
+async fn xread_inner(command: Command) -> Result<Reply, CommandError> {
     let args = command.args;
 
     let position = args
@@ -358,226 +644,2564 @@ async fn xread_inner(command: Command) -> Result<String, CommandError> {
                 .to_string(),
         ));
     }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    let ids = ids
+        .iter()
+        .map(|id| match id.as_str() {
+            "$" => Ok(None),
+            id => StreamId::parse(id, 0).map(Some),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
     let storage = STORAGE.lock().await;
     let mut res = Vec::new();
-
-    let (keys, ids) = rest.split_at(rest.len() / 2);
     for (key, id) in keys.iter().zip(ids) {
-        let entry = storage.get(key).ok_or_else(|| _missing_entry("xrange"))?;
-
+        let Some(entry) = storage.get(key) else {
+            continue;
+        };
         let stream = entry
             .value()?
             .as_any()
             .downcast_ref::<StreamType>()
-            .to_owned()
             .ok_or_else(_wrong_type)?;
 
-        let body = stream.to_resp_range(
-            StreamId::from(id),
-            StreamId {
-                millis: u64::MAX,
-                seq: u64::MAX,
-            },
-        );
+        // Entries after the given ID, or after the last one for `$`.
+        let Some(start) = id.unwrap_or_else(|| stream.last_id()).incr() else {
+            continue;
+        };
+        let entries = stream.range(start, StreamId::MAX, None, false);
+        if entries.is_empty() {
+            continue;
+        }
+        res.push(Reply::Array(vec![
+            Reply::bulk(key),
+            entries_to_resp(&entries),
+        ]));
+    }
 
-        res.push(format!("*2\r\n${}\r\n{}\r\n{}", key.len(), key, body));
+    if res.is_empty() {
+        return Ok(Reply::NullArray);
     }
+    Ok(Reply::Array(res))
+}
 
-    Ok(format!("*{}\r\n{}", res.len(), res.join("")))
+async fn xrange(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xrange_inner(command, false).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
 }
 
-async fn xrange(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
-    let res = match xrange_inner(command).await {
-        Ok(stream_entity) => stream_entity,
-        Err(e) => e.as_resp(),
-    };
-    _write_stream_and_flush(&stream, res.as_str()).await;
+async fn xrevrange(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xrange_inner(command, true).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
 }
 
-async fn xrange_inner(command: Command) -> Result<String, CommandError> {
+async fn xrange_inner(command: Command, rev: bool) -> Result<Reply, CommandError> {
+    let cmd = if rev { "xrevrange" } else { "xrange" };
     let args = command.args;
+    if args.len() < 3 {
+        return Err(_wrong_args(cmd));
+    }
+
+    // XREVRANGE takes the interval the other way around: `end start`.
+    let key = &args[0];
+    let (start, end) = if rev {
+        (&args[2], &args[1])
+    } else {
+        (&args[1], &args[2])
+    };
+    let start = _parse_interval_id(start, 0, true)?;
+    let end = _parse_interval_id(end, u64::MAX, false)?;
+
+    let count = match &args[3..] {
+        [] => None,
+        [opt, count] if opt.eq_ignore_ascii_case("count") => {
+            let count: i64 = count.parse().map_err(|_| _not_integer())?;
+            Some(count.max(0) as usize)
+        }
+        _ => return Err(_syntax_error()),
+    };
 
-    // Create the stream
-    let key = args.first().ok_or_else(|| _wrong_args("xrange"))?;
-    let start = args.get(1).ok_or_else(|| _wrong_args("xrange"))?;
-    let end = args.get(2).ok_or_else(|| _wrong_args("xrange"))?;
     let storage = STORAGE.lock().await;
-    let entry = storage.get(key).ok_or_else(|| _missing_entry("xrange"))?;
+    let Some(entry) = storage.get(key) else {
+        notify_keyspace_event(notify::KEY_MISS, "keymiss", key).await;
+        return Ok(Reply::Array(vec![]));
+    };
 
     let stream = entry
         .value()?
         .as_any()
         .downcast_ref::<StreamType>()
-        .to_owned()
         .ok_or_else(_wrong_type)?;
-    let start_stream = if start == "-" {
-        StreamId { millis: 0, seq: 0 }
-    } else {
-        StreamId::from(start)
-    };
 
-    let end_stream = if end == "+" {
-        StreamId {
-            millis: u64::MAX,
-            seq: u64::MAX,
+    Ok(entries_to_resp(&stream.range(start, end, count, rev)))
+}
+
+/// Parses one side of an `XRANGE` interval: `-`, `+`, a full or incomplete
+/// ID, or an exclusive `(<id>` bound.
+fn _parse_interval_id(s: &str, missing_seq: u64, is_start: bool) -> Result<StreamId, CommandError> {
+    if let Some(id) = s.strip_prefix('(').filter(|id| !id.is_empty()) {
+        let id = StreamId::parse(id, missing_seq)?;
+        let (bound, side) = if is_start {
+            (id.incr(), "start")
+        } else {
+            (id.decr(), "end")
+        };
+        return bound.ok_or_else(|| {
+            CommandError::InvalidArgument(format!("invalid {} ID for the interval", side))
+        });
+    }
+
+    match s {
+        "-" => Ok(StreamId::MIN),
+        "+" => Ok(StreamId::MAX),
+        _ => StreamId::parse(s, missing_seq),
+    }
+}
+
+/// Parses `<MAXLEN | MINID> [= | ~] threshold [LIMIT count]` starting at
+/// `pos`, returning the options and the position right after them.
+fn _parse_trim_args(args: &[String], mut pos: usize) -> Result<(TrimOptions, usize), CommandError> {
+    let is_maxlen = args[pos].eq_ignore_ascii_case("maxlen");
+    pos += 1;
+
+    let mut approx = false;
+    match args.get(pos).map(String::as_str) {
+        Some("~") => {
+            approx = true;
+            pos += 1;
+        }
+        Some("=") => pos += 1,
+        _ => {}
+    }
+
+    let threshold = args.get(pos).ok_or_else(_syntax_error)?;
+    pos += 1;
+    let strategy = if is_maxlen {
+        let maxlen: i64 = threshold.parse().map_err(|_| _not_integer())?;
+        if maxlen < 0 {
+            return Err(CommandError::InvalidArgument(
+                "The MAXLEN argument must be >= 0.".to_string(),
+            ));
         }
+        TrimStrategy::MaxLen(maxlen as usize)
     } else {
-        StreamId::from(end)
+        TrimStrategy::MinId(StreamId::parse(threshold, 0)?)
     };
 
-    Ok(stream.to_resp_range(start_stream, end_stream))
+    let mut limit = None;
+    if args
+        .get(pos)
+        .is_some_and(|arg| arg.eq_ignore_ascii_case("limit"))
+    {
+        let count: i64 = args
+            .get(pos + 1)
+            .ok_or_else(_syntax_error)?
+            .parse()
+            .map_err(|_| _not_integer())?;
+        if count < 0 {
+            return Err(CommandError::InvalidArgument(
+                "The LIMIT argument must be >= 0.".to_string(),
+            ));
+        }
+        if !approx {
+            return Err(CommandError::InvalidArgument(
+                "syntax error, LIMIT cannot be used without the special ~ option".to_string(),
+            ));
+        }
+        limit = Some(count as usize);
+        pos += 2;
+    }
+
+    Ok((
+        TrimOptions {
+            strategy,
+            approx,
+            limit,
+        },
+        pos,
+    ))
 }
 
-async fn xadd(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    _server_metadata: &Arc<RwLock<ServerMetadata>>,
+/// Has an approximate trim, whose result depends on where stream nodes
+/// start, logged and propagated as the exact one it amounted to, like Redis
+/// does: `~` becomes `=`, the threshold the resulting length or first ID,
+/// and `LIMIT` goes.
+fn _rewrite_approx_trim(
+    args: &[String],
+    trim_args: Range<usize>,
+    opts: &TrimOptions,
+    stream: &StreamType,
 ) {
-    let res = match xadd_inner(command).await {
-        Ok(id) => {
-            let s = id.to_string();
-            format!("${}\r\n{}\r\n", s.len(), s)
-        }
-        Err(e) => e.as_resp(),
+    if !opts.approx {
+        return;
+    }
+    let threshold = match opts.strategy {
+        TrimStrategy::MaxLen(_) => stream.len().to_string(),
+        TrimStrategy::MinId(min_id) => stream
+            .first_entry()
+            .map_or(min_id, |(first, _)| first)
+            .to_string(),
     };
+    let mut rewritten = args[..trim_args.start].to_vec();
+    rewritten.extend([args[trim_args.start].clone(), "=".to_string(), threshold]);
+    rewritten.extend_from_slice(&args[trim_args.end..]);
+    client::rewrite_args(rewritten);
+}
 
-    _write_stream_and_flush(&stream, res.as_str()).await;
+async fn xtrim(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xtrim_inner(command).await {
+        Ok(removed) => Reply::Integer(removed as i64),
+        Err(e) => e.into(),
+    }
 }
 
-async fn xadd_inner(command: Command) -> Result<StreamId, CommandError> {
+async fn xtrim_inner(command: Command) -> Result<usize, CommandError> {
     let args = command.args;
-
-    // Create the stream
-    let key = args.first().ok_or_else(|| _wrong_args("xadd"))?;
-    let stream_id_str = args.get(1).ok_or_else(|| _wrong_args("xadd"))?;
-    let rest = args.get(2..).unwrap_or(&[]);
-    if rest.is_empty() {
-        return Err(_wrong_args("xadd"));
+    if args.len() < 3 {
+        return Err(_wrong_args("xtrim"));
+    }
+    let key = &args[0];
+    if !args[1].eq_ignore_ascii_case("maxlen") && !args[1].eq_ignore_ascii_case("minid") {
+        return Err(_syntax_error());
+    }
+    let (opts, pos) = _parse_trim_args(&args, 1)?;
+    if pos != args.len() {
+        return Err(_syntax_error());
     }
 
     let mut storage = STORAGE.lock().await;
-    let entry = storage
-        .entry(key.clone())
-        .or_insert_with(|| DBEntry::from_stream(StreamType::default()));
+    let Some(entry) = storage.get_mut(key) else {
+        return Ok(0);
+    };
     let stream = entry
         .value_mut()?
         .as_any_mut()
         .downcast_mut::<StreamType>()
         .ok_or_else(_wrong_type)?;
+    let removed = stream.trim(&opts);
+    _rewrite_approx_trim(&args, 1..pos, &opts, stream);
+    if removed > 0 {
+        entry.touch();
+        notify_keyspace_event(notify::STREAM, "xtrim", key).await;
+    }
+    Ok(removed)
+}
 
-    let stream_id = stream.parse_stream_id(stream_id_str)?;
-    let fields: Vec<(String, String)> = rest
-        .chunks_exact(2)
-        .map(|c| (c[0].clone(), c[1].clone()))
-        .collect();
-
-    stream.add(stream_id, fields)
+async fn xdel(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xdel_inner(command).await {
+        Ok(removed) => Reply::Integer(removed as i64),
+        Err(e) => e.into(),
+    }
 }
 
-async fn get(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+async fn xdel_inner(command: Command) -> Result<usize, CommandError> {
     let args = command.args;
-    let key = args.first().unwrap();
-    let storage = STORAGE.lock().await;
-    let res = match storage.get(key) {
-        Some(val) => format_result(val),
-        None => "$-1\r\n".to_string(),
+    if args.len() < 2 {
+        return Err(_wrong_args("xdel"));
+    }
+    let ids = args[1..]
+        .iter()
+        .map(|id| StreamId::parse(id, 0))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut storage = STORAGE.lock().await;
+    let Some(entry) = storage.get_mut(&args[0]) else {
+        return Ok(0);
     };
-    _write_stream_and_flush(&stream, res.as_str()).await;
+    let stream = entry
+        .value_mut()?
+        .as_any_mut()
+        .downcast_mut::<StreamType>()
+        .ok_or_else(_wrong_type)?;
+    let deleted = stream.delete(&ids);
+    if deleted > 0 {
+        entry.touch();
+        notify_keyspace_event(notify::STREAM, "xdel", &args[0]).await;
+    }
+    Ok(deleted)
 }
 
-async fn type_fn(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
-    let args = command.args;
-    let key = args.first().unwrap();
+async fn xlen(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xlen_inner(command).await {
+        Ok(len) => Reply::Integer(len as i64),
+        Err(e) => e.into(),
+    }
+}
+
+async fn xlen_inner(command: Command) -> Result<usize, CommandError> {
+    let key = command.args.first().ok_or_else(|| _wrong_args("xlen"))?;
     let storage = STORAGE.lock().await;
-    let res = match storage.get(key) {
-        // TODO: read the type dynamically.
-        Some(entry) => format!("+{}\r\n", entry.value().unwrap().type_name()),
-        None => "+none\r\n".to_string(),
+    let Some(entry) = storage.get(key) else {
+        notify_keyspace_event(notify::KEY_MISS, "keymiss", key).await;
+        return Ok(0);
     };
-    _write_stream_and_flush(&stream, res.as_str()).await;
+    let stream = entry
+        .value()?
+        .as_any()
+        .downcast_ref::<StreamType>()
+        .ok_or_else(_wrong_type)?;
+    Ok(stream.len())
 }
 
-async fn info(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
+async fn xsetid(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xsetid_inner(command).await {
+        Ok(()) => Reply::ok(),
+        Err(e) => e.into(),
+    }
+}
+
+async fn xsetid_inner(command: Command) -> Result<(), CommandError> {
     let args = command.args;
-    let info_section = args.first().unwrap();
-    let metadata = server_metadata.read().await;
-    if info_section == "replication" {
-        match server_info::get_server_info(&metadata) {
-            Ok(res) => {
-                _write_stream_and_flush(&stream, res.as_str()).await;
+    if args.len() < 2 {
+        return Err(_wrong_args("xsetid"));
+    }
+    let last_id = StreamId::parse(&args[1], 0)?;
+
+    let mut entries_added = None;
+    let mut max_deleted_entry_id = None;
+    let mut options = args[2..].chunks(2);
+    for option in options.by_ref() {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case("entriesadded") => {
+                let added: i64 = value.parse().map_err(|_| _not_integer())?;
+                if added < 0 {
+                    return Err(CommandError::InvalidArgument(
+                        "entries_added must be positive".to_string(),
+                    ));
+                }
+                entries_added = Some(added as u64);
             }
-            Err(_) => {
-                eprintln!("Cannot return replication info");
+            [name, value] if name.eq_ignore_ascii_case("maxdeletedid") => {
+                let id = StreamId::parse(value, 0)?;
+                if last_id < id {
+                    return Err(CommandError::InvalidArgument(
+                        "The ID specified in XSETID is smaller than the provided max_deleted_entry_id"
+                            .to_string(),
+                    ));
+                }
+                max_deleted_entry_id = Some(id);
             }
+            _ => return Err(_syntax_error()),
         }
     }
-}
 
-async fn keys(
-    stream: Arc<RwLock<TcpStream>>,
-    _command: Command,
-    _server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
-    let storage = STORAGE.lock().await;
-    let mut res = format!("*{}\r\n", storage.len());
-    for key in storage.keys() {
-        res.push_str(&format!("${}\r\n{}\r\n", key.len(), key));
-    }
-    _write_stream_and_flush(&stream, res.as_str()).await;
+    let mut storage = STORAGE.lock().await;
+    let entry = storage.get_mut(&args[0]).ok_or_else(_no_such_key)?;
+    let stream = entry
+        .value_mut()?
+        .as_any_mut()
+        .downcast_mut::<StreamType>()
+        .ok_or_else(_wrong_type)?;
+    stream.set_id(last_id, entries_added, max_deleted_entry_id)?;
+    entry.touch();
+    notify_keyspace_event(notify::STREAM, "xsetid", &args[0]).await;
+    Ok(())
 }
 
-async fn config(
-    stream: Arc<RwLock<TcpStream>>,
-    command: Command,
-    server_metadata: &Arc<RwLock<ServerMetadata>>,
-) {
-    let metadata = server_metadata.read().await;
-    let operation = command.args.first().unwrap();
-    if operation.to_lowercase() == "get" {
-        let config_name = command.args.get(1).unwrap();
-        let config_val = match config_name.to_lowercase().as_str() {
-            "dir" => metadata.dir.to_string_lossy().to_string(),
-            "dbfilename" => metadata.dbfilename.clone(),
-            _ => String::new(),
-        };
-        let res = format!(
-            "*2\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
-            config_name.len(),
-            config_name,
-            config_val.len(),
-            config_val
-        );
-        _write_stream_and_flush(&stream, res.as_str()).await;
+async fn xgroup(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xgroup_inner(command).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
     }
 }
 
-fn format_result(value: &DBEntry) -> String {
-    match value.value() {
-        Ok(v) => format!("${}\r\n{}\r\n", v.len(), v),
-        Err(_) => "$-1\r\n".to_string(),
+async fn xgroup_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    let sub = args.first().ok_or_else(|| _wrong_args("xgroup"))?;
+    let sub = sub.to_lowercase();
+    let (arity, name) = match sub.as_str() {
+        "create" => (4, "xgroup|create"),
+        "setid" => (4, "xgroup|setid"),
+        "destroy" => (3, "xgroup|destroy"),
+        "createconsumer" => (4, "xgroup|createconsumer"),
+        "delconsumer" => (4, "xgroup|delconsumer"),
+        _ => return Err(_unknown_subcommand(&args[0], "XGROUP")),
+    };
+    if args.len() < arity {
+        return Err(_wrong_args(name));
     }
-}
+    let key = &args[1];
+    let group = &args[2];
 
-async fn _write_stream_and_flush(stream: &Arc<RwLock<TcpStream>>, res: &str) {
+    // Trailing options of CREATE and SETID.
+    let mut mkstream = false;
+    let mut entries_read = None;
+    if matches!(sub.as_str(), "create" | "setid") {
+        let mut pos = 4;
+        while let Some(opt) = args.get(pos) {
+            if sub == "create" && opt.eq_ignore_ascii_case("mkstream") {
+                mkstream = true;
+                pos += 1;
+            } else if opt.eq_ignore_ascii_case("entriesread") {
+                let read: i64 = args
+                    .get(pos + 1)
+                    .ok_or_else(_syntax_error)?
+                    .parse()
+                    .map_err(|_| _not_integer())?;
+                if read < -1 {
+                    return Err(CommandError::InvalidArgument(
+                        "value for ENTRIESREAD must be positive or -1".to_string(),
+                    ));
+                }
+                entries_read = u64::try_from(read).ok();
+                pos += 2;
+            } else {
+                return Err(_syntax_error());
+            }
+        }
+    } else if args.len() > arity {
+        return Err(_wrong_args(name));
+    }
+
+    let mut storage = STORAGE.lock().await;
+    expire_if_needed(&mut storage, key).await;
+    if !storage.contains_key(key) {
+        if !mkstream {
+            return Err(CommandError::InvalidArgument(
+                "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."
+                    .to_string(),
+            ));
+        }
+        storage.insert(key.clone(), DBEntry::from_stream(StreamType::default()));
+        notify_keyspace_event(notify::NEW, "new", key).await;
+    }
+    let entry = storage.get_mut(key).ok_or_else(_no_such_key)?;
+    let stream = entry
+        .value_mut()?
+        .as_any_mut()
+        .downcast_mut::<StreamType>()
+        .ok_or_else(_wrong_type)?;
+    let no_group = || _no_group(key, group);
+
+    let (reply, modified) = match sub.as_str() {
+        "create" | "setid" => {
+            let id = match args[3].as_str() {
+                "$" => stream.last_id(),
+                id => StreamId::parse(id, 0)?,
+            };
+            if sub == "create" {
+                if !stream.create_group(group, id, entries_read) {
+                    return Err(CommandError::WithCode(
+                        "BUSYGROUP",
+                        "Consumer Group name already exists".to_string(),
+                    ));
+                }
+            } else if !stream.set_group_id(group, id, entries_read) {
+                return Err(no_group());
+            }
+            let event = if sub == "create" {
+                "xgroup-create"
+            } else {
+                "xgroup-setid"
+            };
+            notify_keyspace_event(notify::STREAM, event, key).await;
+            (Reply::ok(), true)
+        }
+        "destroy" => {
+            let destroyed = stream.destroy_group(group);
+            if destroyed {
+                notify_keyspace_event(notify::STREAM, "xgroup-destroy", key).await;
+            }
+            (Reply::Integer(i64::from(destroyed)), destroyed)
+        }
+        "createconsumer" => {
+            let created = stream
+                .create_consumer(group, &args[3])
+                .ok_or_else(no_group)?;
+            if created {
+                notify_keyspace_event(notify::STREAM, "xgroup-createconsumer", key).await;
+            }
+            (Reply::Integer(i64::from(created)), created)
+        }
+        _ => {
+            let existed = stream
+                .group(group)
+                .is_some_and(|g| g.consumers.contains_key(&args[3]));
+            let pending = stream
+                .delete_consumer(group, &args[3])
+                .ok_or_else(no_group)?;
+            notify_keyspace_event(notify::STREAM, "xgroup-delconsumer", key).await;
+            (Reply::Integer(pending as i64), existed)
+        }
+    };
+    if modified {
+        entry.touch();
+    }
+    Ok(reply)
+}
+
+lazy_static! {
+    /// Wakes the `XREADGROUP ... BLOCK` calls waiting for new entries when
+    /// `XADD` adds one.
+    static ref STREAM_ADDED: Notify = Notify::new();
+}
+
+async fn xreadgroup(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xreadgroup_inner(command).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+async fn xreadgroup_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    if args.len() < 6 || !args[0].eq_ignore_ascii_case("group") {
+        return Err(_wrong_args("xreadgroup"));
+    }
+    let group = &args[1];
+    let consumer = &args[2];
+
+    let mut count = None;
+    let mut noack = false;
+    let mut block = None;
+    let mut pos = 3;
+    loop {
+        let opt = args.get(pos).ok_or_else(_syntax_error)?.to_lowercase();
+        match opt.as_str() {
+            "count" => {
+                let n: i64 = args
+                    .get(pos + 1)
+                    .ok_or_else(_syntax_error)?
+                    .parse()
+                    .map_err(|_| _not_integer())?;
+                count = (n > 0).then_some(n as usize);
+                pos += 2;
+            }
+            "block" => {
+                let ms: i64 = args
+                    .get(pos + 1)
+                    .ok_or_else(_syntax_error)?
+                    .parse()
+                    .map_err(|_| {
+                        CommandError::InvalidArgument(
+                            "timeout is not an integer or out of range".to_string(),
+                        )
+                    })?;
+                if ms < 0 {
+                    return Err(CommandError::InvalidArgument(
+                        "timeout is negative".to_string(),
+                    ));
+                }
+                block = Some(ms as u64);
+                pos += 2;
+            }
+            "noack" => {
+                noack = true;
+                pos += 1;
+            }
+            "streams" => {
+                pos += 1;
+                break;
+            }
+            _ => return Err(_syntax_error()),
+        }
+    }
+    let rest = args.get(pos..).unwrap_or(&[]);
+    if rest.is_empty() || rest.len() % 2 == 1 {
+        return Err(CommandError::InvalidArgument(
+            "Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified."
+                .to_string(),
+        ));
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    let ids = ids
+        .iter()
+        .map(|id| match id.as_str() {
+            ">" => Ok(None),
+            id => StreamId::parse(id, 0).map(Some),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Only reads of new entries wait for some to be added, and never when
+    // run by `EXEC`.
+    let block = block.filter(|_| ids.iter().all(Option::is_none) && client::blocking_allowed());
+    let Some(ms_timeout) = block else {
+        let _shared = transaction::lock_for_blocking().await;
+        return read_group(group, consumer, keys, &ids, count, noack).await;
+    };
+    let deadline = (ms_timeout > 0).then(|| Instant::now() + Duration::from_millis(ms_timeout));
+    let timeout = async move {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            // `BLOCK 0` waits for as long as it takes.
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(timeout);
+
+    client::set_blocked(true);
+    let reply = loop {
+        // Registered before reading, so that entries added right after are
+        // not missed.
+        let added = STREAM_ADDED.notified();
+        let read = {
+            let _shared = transaction::lock_for_blocking().await;
+            read_group(group, consumer, keys, &ids, count, noack).await
+        };
+        if !matches!(read, Ok(Reply::NullArray)) {
+            break read;
+        }
+        tokio::select! {
+            _ = added => continue,
+            _ = &mut timeout => break Ok(Reply::NullArray),
+            _ = client::closed() => break Ok(Reply::NullArray),
+            error = client::unblocked() => break match error {
+                true => Err(CommandError::WithCode(
+                    "UNBLOCKED",
+                    "client unblocked via CLIENT UNBLOCK".to_string(),
+                )),
+                false => Ok(Reply::NullArray),
+            },
+        }
+    };
+    client::set_blocked(false);
+    reply
+}
+
+/// Reads the streams once for `XREADGROUP`, a null reply meaning there was
+/// nothing new for the consumer.
+async fn read_group(
+    group: &str,
+    consumer: &str,
+    keys: &[String],
+    ids: &[Option<StreamId>],
+    count: Option<usize>,
+    noack: bool,
+) -> Result<Reply, CommandError> {
+    let mut storage = STORAGE.lock().await;
+    let mut res = Vec::new();
+    let mut any_new = false;
+    for (key, id) in keys.iter().zip(ids.iter().copied()) {
+        let no_group = || {
+            CommandError::WithCode(
+                "NOGROUP",
+                format!(
+                    "No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                    key, group
+                ),
+            )
+        };
+        let entry = storage.get_mut(key).ok_or_else(no_group)?;
+        let stream = entry
+            .value_mut()?
+            .as_any_mut()
+            .downcast_mut::<StreamType>()
+            .ok_or_else(_wrong_type)?;
+        // Reading through a group implicitly creates the consumer.
+        let new_consumer = stream
+            .group(group)
+            .is_some_and(|g| !g.consumers.contains_key(consumer));
+        if new_consumer {
+            notify_keyspace_event(notify::STREAM, "xgroup-createconsumer", key).await;
+        }
+
+        let body = match id {
+            None => {
+                let entries = stream
+                    .read_group_new(group, consumer, count, noack)
+                    .ok_or_else(no_group)?;
+                if entries.is_empty() {
+                    if new_consumer {
+                        entry.touch();
+                    }
+                    continue;
+                }
+                any_new = true;
+                entry.touch();
+                entries_to_resp(&entries)
+            }
+            Some(start) => {
+                let entries = stream
+                    .read_group_pending(group, consumer, start, count)
+                    .ok_or_else(no_group)?;
+                if new_consumer {
+                    entry.touch();
+                }
+                Reply::Array(
+                    entries
+                        .iter()
+                        .map(|(id, fields)| match fields {
+                            Some(fields) => entry_to_resp(*id, fields),
+                            None => {
+                                Reply::Array(vec![Reply::bulk(id.to_string()), Reply::NullArray])
+                            }
+                        })
+                        .collect(),
+                )
+            }
+        };
+        res.push(Reply::Array(vec![Reply::bulk(key), body]));
+    }
+
+    if res.is_empty() && !any_new {
+        return Ok(Reply::NullArray);
+    }
+    Ok(Reply::Array(res))
+}
+
+async fn xack(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xack_inner(command).await {
+        Ok(acked) => Reply::Integer(acked as i64),
+        Err(e) => e.into(),
+    }
+}
+
+async fn xack_inner(command: Command) -> Result<usize, CommandError> {
+    let args = command.args;
+    if args.len() < 3 {
+        return Err(_wrong_args("xack"));
+    }
+    let ids = args[2..]
+        .iter()
+        .map(|id| StreamId::parse(id, 0))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut storage = STORAGE.lock().await;
+    let Some(entry) = storage.get_mut(&args[0]) else {
+        return Ok(0);
+    };
+    let stream = entry
+        .value_mut()?
+        .as_any_mut()
+        .downcast_mut::<StreamType>()
+        .ok_or_else(_wrong_type)?;
+    let acked = stream.ack(&args[1], &ids).unwrap_or(0);
+    if acked > 0 {
+        entry.touch();
+    }
+    Ok(acked)
+}
+
+async fn xclaim(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xclaim_inner(command).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+async fn xclaim_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    if args.len() < 5 {
+        return Err(_wrong_args("xclaim"));
+    }
+    let (key, group, consumer) = (&args[0], &args[1], &args[2]);
+    let invalid =
+        |what: &str| CommandError::InvalidArgument(format!("Invalid {} argument for XCLAIM", what));
+    let min_idle: i64 = args[3].parse().map_err(|_| invalid("min-idle-time"))?;
+    let mut opts = ClaimOptions {
+        min_idle: min_idle.max(0) as u64,
+        ..Default::default()
+    };
+    // IDs come first; the first argument that isn't one starts the options.
+    let mut pos = 4;
+    let mut ids = Vec::new();
+    while let Some(id) = args.get(pos).and_then(|arg| StreamId::parse(arg, 0).ok()) {
+        ids.push(id);
+        pos += 1;
+    }
+
+    let now = now_millis();
+    while pos < args.len() {
+        let option = args[pos].to_lowercase();
+        let value = args.get(pos + 1);
+        let millis = |what: &str| {
+            value
+                .and_then(|value| value.parse::<i64>().ok())
+                .map(|value| value.max(0) as u64)
+                .ok_or_else(|| invalid(what))
+        };
+        match option.as_str() {
+            "force" => opts.force = true,
+            "justid" => opts.justid = true,
+            "idle" => opts.delivery_time = Some(now.saturating_sub(millis("IDLE option")?)),
+            "time" => opts.delivery_time = Some(millis("TIME option")?),
+            "retrycount" => opts.retry_count = Some(millis("RETRYCOUNT option")?),
+            "lastid" => {
+                let id = value.ok_or_else(_syntax_error)?;
+                opts.last_id = Some(StreamId::parse(id, 0)?);
+            }
+            _ => return Err(_syntax_error()),
+        }
+        pos += if matches!(option.as_str(), "force" | "justid") {
+            1
+        } else {
+            2
+        };
+    }
+    // A delivery in the future would make the entry's idle time negative.
+    opts.delivery_time = opts.delivery_time.map(|at| at.min(now));
+
+    let no_group = || {
+        CommandError::WithCode(
+            "NOGROUP",
+            format!("No such key '{}' or consumer group '{}'", key, group),
+        )
+    };
+    let mut storage = STORAGE.lock().await;
+    let entry = storage.get_mut(key).ok_or_else(no_group)?;
+    let stream = entry
+        .value_mut()?
+        .as_any_mut()
+        .downcast_mut::<StreamType>()
+        .ok_or_else(_wrong_type)?;
+    let new_consumer = stream
+        .group(group)
+        .is_some_and(|g| !g.consumers.contains_key(consumer));
+    let claimed = stream
+        .claim(group, consumer, &ids, &opts)
+        .ok_or_else(no_group)?;
+    if new_consumer {
+        notify_keyspace_event(notify::STREAM, "xgroup-createconsumer", key).await;
+    }
+    if new_consumer || !claimed.is_empty() || opts.last_id.is_some() {
+        entry.touch();
+    }
+
+    if opts.justid {
+        return Ok(Reply::bulks(claimed.iter().map(|(id, _)| id.to_string())));
+    }
+    Ok(entries_to_resp(&claimed))
+}
+
+async fn xinfo(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xinfo_inner(command).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+async fn xinfo_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    let sub = args
+        .first()
+        .ok_or_else(|| _wrong_args("xinfo"))?
+        .to_lowercase();
+    let (arity, name) = match sub.as_str() {
+        "stream" => (2, "xinfo|stream"),
+        "groups" => (2, "xinfo|groups"),
+        "consumers" => (3, "xinfo|consumers"),
+        _ => return Err(_unknown_subcommand(&args[0], "XINFO")),
+    };
+    if args.len() < arity || (sub != "stream" && args.len() > arity) {
+        return Err(_wrong_args(name));
+    }
+    let key = &args[1];
+
+    let storage = STORAGE.lock().await;
+    let stream = storage
+        .get(key)
+        .ok_or_else(_no_such_key)?
+        .value()?
+        .as_any()
+        .downcast_ref::<StreamType>()
+        .ok_or_else(_wrong_type)?;
+    let now = now_millis();
+
+    match sub.as_str() {
+        "stream" => _xinfo_stream(stream, &args[2..]),
+        "groups" => {
+            let groups: Vec<Reply> = stream
+                .groups()
+                .iter()
+                .map(|(name, group)| {
+                    Reply::fields([
+                        ("name", Reply::bulk(name)),
+                        ("consumers", Reply::Integer(group.consumers.len() as i64)),
+                        ("pending", Reply::Integer(group.pel.len() as i64)),
+                        ("last-delivered-id", Reply::bulk(group.last_id.to_string())),
+                        ("entries-read", Reply::optional_int(group.entries_read)),
+                        ("lag", Reply::optional_int(stream.group_lag(group))),
+                    ])
+                })
+                .collect();
+            Ok(Reply::Array(groups))
+        }
+        _ => {
+            let group = stream
+                .group(&args[2])
+                .ok_or_else(|| _no_group(key, &args[2]))?;
+            let consumers: Vec<Reply> = group
+                .consumers
+                .iter()
+                .map(|(name, consumer)| {
+                    let inactive = consumer
+                        .active_time
+                        .map_or(-1, |active| now.saturating_sub(active) as i64);
+                    Reply::fields([
+                        ("name", Reply::bulk(name)),
+                        ("pending", Reply::Integer(consumer.pending.len() as i64)),
+                        (
+                            "idle",
+                            Reply::Integer(now.saturating_sub(consumer.seen_time) as i64),
+                        ),
+                        ("inactive", Reply::Integer(inactive)),
+                    ])
+                })
+                .collect();
+            Ok(Reply::Array(consumers))
+        }
+    }
+}
+
+/// `XINFO STREAM key [FULL [COUNT count]]`, `args` being the part after the key.
+fn _xinfo_stream(stream: &StreamType, args: &[String]) -> Result<Reply, CommandError> {
+    let full = match args.first() {
+        None => false,
+        Some(opt) if opt.eq_ignore_ascii_case("full") => true,
+        Some(_) => return Err(_syntax_error()),
+    };
+    let count = match args.get(1..).unwrap_or(&[]) {
+        [] => 10,
+        [opt, count] if full && opt.eq_ignore_ascii_case("count") => {
+            let count: i64 = count.parse().map_err(|_| _not_integer())?;
+            count.max(0) as usize
+        }
+        _ => return Err(_syntax_error()),
+    };
+    // A COUNT of 0 means everything.
+    let count = (count > 0).then_some(count);
+
+    let mut fields = vec![
+        ("length", Reply::Integer(stream.len() as i64)),
+        (
+            "radix-tree-keys",
+            Reply::Integer(stream.node_count() as i64),
+        ),
+        (
+            "radix-tree-nodes",
+            Reply::Integer(stream.node_count() as i64),
+        ),
+        (
+            "last-generated-id",
+            Reply::bulk(stream.last_id().to_string()),
+        ),
+        (
+            "max-deleted-entry-id",
+            Reply::bulk(stream.max_deleted_entry_id().to_string()),
+        ),
+        (
+            "entries-added",
+            Reply::Integer(stream.entries_added() as i64),
+        ),
+        (
+            "recorded-first-entry-id",
+            Reply::bulk(stream.recorded_first_id().to_string()),
+        ),
+    ];
+
+    if !full {
+        let entry_or_nil = |entry: Option<(StreamId, StreamFields)>| {
+            entry.map_or_else(|| Reply::Null, |(id, f)| entry_to_resp(id, &f))
+        };
+        fields.extend([
+            ("groups", Reply::Integer(stream.groups().len() as i64)),
+            ("first-entry", entry_or_nil(stream.first_entry())),
+            ("last-entry", entry_or_nil(stream.last_entry())),
+        ]);
+        return Ok(Reply::fields(fields));
+    }
+
+    let limit = count.unwrap_or(usize::MAX);
+    let groups: Vec<Reply> = stream
+        .groups()
+        .iter()
+        .map(|(name, group)| {
+            let pending: Vec<Reply> = group
+                .pel
+                .iter()
+                .take(limit)
+                .map(|(id, nack)| {
+                    Reply::Array(vec![
+                        Reply::bulk(id.to_string()),
+                        Reply::bulk(nack.consumer.clone()),
+                        Reply::Integer(nack.delivery_time as i64),
+                        Reply::Integer(nack.delivery_count as i64),
+                    ])
+                })
+                .collect();
+            let consumers: Vec<Reply> = group
+                .consumers
+                .iter()
+                .map(|(name, consumer)| {
+                    let pending: Vec<Reply> = consumer
+                        .pending
+                        .iter()
+                        .take(limit)
+                        .filter_map(|id| group.pel.get(id).map(|nack| (id, nack)))
+                        .map(|(id, nack)| {
+                            Reply::Array(vec![
+                                Reply::bulk(id.to_string()),
+                                Reply::Integer(nack.delivery_time as i64),
+                                Reply::Integer(nack.delivery_count as i64),
+                            ])
+                        })
+                        .collect();
+                    Reply::fields([
+                        ("name", Reply::bulk(name)),
+                        ("seen-time", Reply::Integer(consumer.seen_time as i64)),
+                        (
+                            "active-time",
+                            Reply::Integer(consumer.active_time.map_or(-1, |t| t as i64)),
+                        ),
+                        ("pel-count", Reply::Integer(consumer.pending.len() as i64)),
+                        ("pending", Reply::Array(pending)),
+                    ])
+                })
+                .collect();
+            Reply::fields([
+                ("name", Reply::bulk(name)),
+                ("last-delivered-id", Reply::bulk(group.last_id.to_string())),
+                ("entries-read", Reply::optional_int(group.entries_read)),
+                ("lag", Reply::optional_int(stream.group_lag(group))),
+                ("pel-count", Reply::Integer(group.pel.len() as i64)),
+                ("pending", Reply::Array(pending)),
+                ("consumers", Reply::Array(consumers)),
+            ])
+        })
+        .collect();
+
+    fields.extend([
+        (
+            "entries",
+            entries_to_resp(&stream.range(StreamId::MIN, StreamId::MAX, count, false)),
+        ),
+        ("groups", Reply::Array(groups)),
+    ]);
+    Ok(Reply::fields(fields))
+}
+
+async fn xadd(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match xadd_inner(command).await {
+        Ok(Some(id)) => {
+            STREAM_ADDED.notify_waiters();
+            Reply::bulk(id.to_string())
+        }
+        Ok(None) => Reply::Null,
+        Err(e) => e.into(),
+    }
+}
+
+/// Position in `args` of the ID of an `XADD`, past its options.
+pub fn xadd_id_index(args: &[String]) -> Option<usize> {
+    let mut pos = 1;
+    while let Some(arg) = args.get(pos) {
+        match arg.to_lowercase().as_str() {
+            "nomkstream" => pos += 1,
+            "maxlen" | "minid" => pos = _parse_trim_args(args, pos).ok()?.1,
+            _ => return Some(pos),
+        }
+    }
+    None
+}
+
+/// Returns `None` when `NOMKSTREAM` was given and the stream does not exist.
+async fn xadd_inner(command: Command) -> Result<Option<StreamId>, CommandError> {
+    let args = command.args;
+
+    let key = args.first().ok_or_else(|| _wrong_args("xadd"))?;
+    let mut pos = 1;
+    let mut nomkstream = false;
+    let mut trim = None;
+    while let Some(arg) = args.get(pos) {
+        match arg.to_lowercase().as_str() {
+            "nomkstream" => {
+                nomkstream = true;
+                pos += 1;
+            }
+            "maxlen" | "minid" => {
+                let (opts, next) = _parse_trim_args(&args, pos)?;
+                trim = Some((opts, pos..next));
+                pos = next;
+            }
+            _ => break,
+        }
+    }
+    let stream_id_str = args.get(pos).ok_or_else(|| _wrong_args("xadd"))?;
+    let rest = args.get(pos + 1..).unwrap_or(&[]);
+    if rest.is_empty() || rest.len() % 2 == 1 {
+        return Err(_wrong_args("xadd"));
+    }
+
+    let mut storage = STORAGE.lock().await;
+    expire_if_needed(&mut storage, key).await;
+    let created = !storage.contains_key(key);
+    if created && nomkstream {
+        return Ok(None);
+    }
+    let entry = storage
+        .entry(key.clone())
+        .or_insert_with(|| DBEntry::from_stream(StreamType::default()));
+    let stream = entry
+        .value_mut()?
+        .as_any_mut()
+        .downcast_mut::<StreamType>()
+        .ok_or_else(_wrong_type)?;
+
+    let fields: Vec<(String, String)> = rest
+        .chunks_exact(2)
+        .map(|c| (c[0].clone(), c[1].clone()))
+        .collect();
+
+    let added = stream
+        .parse_stream_id(stream_id_str)
+        .and_then(|id| stream.add(id, fields));
+    match added {
+        Ok(id) => {
+            let trimmed = trim.is_some_and(|(opts, trim_args)| {
+                let removed = stream.trim(&opts);
+                _rewrite_approx_trim(&args, trim_args, &opts, stream);
+                removed > 0
+            });
+            entry.touch();
+            if created {
+                notify_keyspace_event(notify::NEW, "new", key).await;
+            }
+            notify_keyspace_event(notify::STREAM, "xadd", key).await;
+            if trimmed {
+                notify_keyspace_event(notify::STREAM, "xtrim", key).await;
+            }
+            Ok(Some(id))
+        }
+        Err(e) => {
+            // Do not leave an empty stream behind for a rejected XADD.
+            if created {
+                storage.remove(key);
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn get(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let args = command.args;
+    let key = args.first().unwrap();
+    let mut storage = STORAGE.lock().await;
+    expire_if_needed(&mut storage, key).await;
+    match storage.get(key) {
+        Some(entry) => format_result(entry),
+        None => {
+            notify_keyspace_event(notify::KEY_MISS, "keymiss", key).await;
+            Reply::Null
+        }
+    }
+}
+
+async fn type_fn(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let args = command.args;
+    let key = args.first().unwrap();
+    let storage = STORAGE.lock().await;
+    let type_name = match storage.get(key).map(DBEntry::value) {
+        Some(Ok(value)) => value.type_name(),
+        _ => "none",
+    };
+    Reply::Simple(type_name.to_string())
+}
+
+async fn info(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let metadata = server_metadata.read().await;
+    match server_info::get_server_info(&metadata, &command.args) {
+        Ok(reply) => reply,
+        Err(e) => {
+            eprintln!("Cannot return server info: {}", e);
+            CommandError::_ErrorWhileExecution(e.to_string()).into()
+        }
+    }
+}
+
+async fn save(_command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let metadata = server_metadata.read().await;
+    let (dir, dbfilename) = (metadata.dir.clone(), metadata.dbfilename.clone());
+    drop(metadata);
+    match persistence::save(&dir, &dbfilename).await {
+        Ok(()) => Reply::ok(),
+        Err(e) => CommandError::InvalidArgument(e).into(),
+    }
+}
+
+async fn bgsave(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let schedule = match command.args.as_slice() {
+        [] => false,
+        [arg] if arg.eq_ignore_ascii_case("schedule") => true,
+        _ => return _syntax_error().into(),
+    };
+    let metadata = server_metadata.read().await;
+    let (dir, dbfilename) = (metadata.dir.clone(), metadata.dbfilename.clone());
+    drop(metadata);
+    match persistence::bgsave(dir, dbfilename, schedule).await {
+        Ok(status) => Reply::Simple(status.to_string()),
+        Err(e) => CommandError::InvalidArgument(e).into(),
+    }
+}
+
+async fn bgrewriteaof(_command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let dir = server_metadata.read().await.dir.clone();
+    match aof::bgrewrite(dir).await {
+        Ok(status) => Reply::Simple(status.to_string()),
+        Err(e) => CommandError::InvalidArgument(e).into(),
+    }
+}
+
+async fn lastsave(_command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    Reply::Integer(persistence::last_save() as i64)
+}
+
+async fn keys(_command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let storage = STORAGE.lock().await;
+    Reply::bulks(storage.keys().cloned())
+}
+
+async fn del(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let deleted = delete_keys(&command.args).await;
+    Reply::Integer(deleted.len() as i64)
+}
+
+async fn pexpireat(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match pexpireat_inner(&command).await {
+        Ok(set) => Reply::Integer(set as i64),
+        Err(e) => e.into(),
+    }
+}
+
+/// Sets the key's expiry time, returning whether it was set: not when the
+/// key is missing or the `NX`/`XX`/`GT`/`LT` condition doesn't hold. A time
+/// in the past deletes the key.
+async fn pexpireat_inner(command: &Command) -> Result<bool, CommandError> {
+    let args = &command.args;
+    let key = &args[0];
+    let at: u64 = args[1].parse().map_err(|_| _not_integer())?;
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for option in &args[2..] {
+        match option.to_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            _ => {
+                return Err(CommandError::InvalidArgument(format!(
+                    "Unsupported option {}",
+                    option
+                )))
+            }
+        }
+    }
+    if nx && (xx || gt || lt) {
+        return Err(CommandError::InvalidArgument(
+            "NX and XX, GT or LT options at the same time are not compatible".to_string(),
+        ));
+    }
+    if gt && lt {
+        return Err(CommandError::InvalidArgument(
+            "GT and LT options at the same time are not compatible".to_string(),
+        ));
+    }
+
+    let mut storage = STORAGE.lock().await;
+    expire_if_needed(&mut storage, key).await;
+    let Some(entry) = storage.get_mut(key) else {
+        return Ok(false);
+    };
+    let new = UNIX_EPOCH + Duration::from_millis(at);
+    // A key without a TTL counts as never expiring.
+    let allowed = match entry.expiry() {
+        None => !xx && !gt,
+        Some(current) => !nx && (!gt || new > current) && (!lt || new < current),
+    };
+    if !allowed {
+        return Ok(false);
+    }
+    if at <= now_millis() {
+        storage.remove(key);
+        notify_keyspace_event(notify::GENERIC, "del", key).await;
+    } else {
+        entry.set_expiry_at(new);
+        storage::track_expiry(key);
+        notify_keyspace_event(notify::GENERIC, "expire", key).await;
+    }
+    Ok(true)
+}
+
+/// Removes the keys that exist, returning them.
+async fn delete_keys(keys: &[String]) -> Vec<String> {
+    let mut storage = STORAGE.lock().await;
+    let mut deleted = Vec::new();
+    for key in keys {
+        expire_if_needed(&mut storage, key).await;
+        if storage.remove(key).is_some() {
+            notify_keyspace_event(notify::GENERIC, "del", key).await;
+            deleted.push(key.clone());
+        }
+    }
+    deleted
+}
+
+async fn dump(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let key = &command.args[0];
+    let storage = STORAGE.lock().await;
+    match storage.get(key).map(DBEntry::value) {
+        Some(Ok(value)) => Reply::BulkBytes(rdb::dump(value)),
+        _ => Reply::Null,
+    }
+}
+
+async fn restore(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match restore_inner(&command).await {
+        Ok(()) => Reply::ok(),
+        Err(e) => e.into(),
+    }
+}
+
+async fn restore_inner(command: &Command) -> Result<(), CommandError> {
+    let args = &command.args;
+    let key = &args[0];
+    let (mut replace, mut absttl, mut idle, mut freq) = (false, false, false, false);
+    let mut options = args[3..].iter();
+    while let Some(option) = options.next() {
+        match option.to_lowercase().as_str() {
+            "replace" => replace = true,
+            "absttl" => absttl = true,
+            // Accepted for compatibility: keys don't track their idle time
+            // or access frequency since nothing is ever evicted.
+            "idletime" if !freq => {
+                let seconds: i64 = options
+                    .next()
+                    .ok_or_else(_syntax_error)?
+                    .parse()
+                    .map_err(|_| _not_integer())?;
+                if seconds < 0 {
+                    return Err(CommandError::InvalidArgument(
+                        "Invalid IDLETIME value, must be >= 0".to_string(),
+                    ));
+                }
+                idle = true;
+            }
+            "freq" if !idle => {
+                let frequency: i64 = options
+                    .next()
+                    .ok_or_else(_syntax_error)?
+                    .parse()
+                    .map_err(|_| _not_integer())?;
+                if !(0..=255).contains(&frequency) {
+                    return Err(CommandError::InvalidArgument(
+                        "Invalid FREQ value, must be >= 0 and <= 255".to_string(),
+                    ));
+                }
+                freq = true;
+            }
+            _ => return Err(_syntax_error()),
+        }
+    }
+
+    let mut storage = STORAGE.lock().await;
+    expire_if_needed(&mut storage, key).await;
+    if !replace && storage.contains_key(key) {
+        return Err(CommandError::WithCode(
+            "BUSYKEY",
+            "Target key name already exists.".to_string(),
+        ));
+    }
+    let ttl: i64 = args[1].parse().map_err(|_| _not_integer())?;
+    if ttl < 0 {
+        return Err(CommandError::InvalidArgument(
+            "Invalid TTL value, must be >= 0".to_string(),
+        ));
+    }
+    let payload = command.arg_bytes(2).unwrap_or_default();
+    let mut entry = rdb::restore(key, &payload).map_err(CommandError::InvalidArgument)?;
+
+    let expire_at = match (ttl, absttl) {
+        (0, _) => None,
+        (at, true) => Some(at as u64),
+        (ttl, false) => Some(now_millis() + ttl as u64),
+    };
+    if let Some(at) = expire_at {
+        if at <= now_millis() {
+            // Already expired: the key is only deleted, if it was replaced.
+            if storage.remove(key).is_some() {
+                notify_keyspace_event(notify::GENERIC, "del", key).await;
+            }
+            return Ok(());
+        }
+        entry.set_expiry_at(UNIX_EPOCH + Duration::from_millis(at));
+        storage::track_expiry(key);
+    }
+    storage.insert(key.clone(), entry);
+    notify_keyspace_event(notify::GENERIC, "restore", key).await;
+    Ok(())
+}
+
+async fn migrate(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match migrate_inner(&command, server_metadata).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+async fn migrate_inner(
+    command: &Command,
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+) -> Result<Reply, CommandError> {
+    let args = &command.args;
+    let (mut copy, mut replace) = (false, false);
+    let mut auth = Vec::new();
+    let mut keys = std::slice::from_ref(&args[2]);
+    let mut i = 5;
+    while i < args.len() {
+        match args[i].to_lowercase().as_str() {
+            "copy" => copy = true,
+            "replace" => replace = true,
+            "auth" if i + 1 < args.len() => {
+                auth = args[i + 1..i + 2].to_vec();
+                i += 1;
+            }
+            "auth2" if i + 2 < args.len() => {
+                auth = args[i + 1..i + 3].to_vec();
+                i += 2;
+            }
+            "keys" => {
+                if !args[2].is_empty() {
+                    return Err(CommandError::InvalidArgument(
+                        "When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                            .to_string(),
+                    ));
+                }
+                keys = &args[i + 1..];
+                break;
+            }
+            _ => return Err(_syntax_error()),
+        }
+        i += 1;
+    }
+    let db: i64 = args[3].parse().map_err(|_| _not_integer())?;
+    let timeout: i64 = args[4].parse().map_err(|_| _not_integer())?;
+    let port: u16 = args[1].parse().map_err(|_| _not_integer())?;
+
+    // Serialised up front, so the keyspace isn't locked while talking to the
+    // target. A key changed in the meantime is not deleted afterwards.
+    let mut payloads = Vec::new();
+    let mut versions = Vec::new();
+    {
+        let storage = STORAGE.lock().await;
+        for key in keys {
+            let Some(entry) = storage.get(key) else {
+                continue;
+            };
+            let (Ok(value), Some(version)) = (entry.value(), entry.version()) else {
+                continue;
+            };
+            let ttl = entry.expiry().map_or(0, |at| {
+                at.duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .as_millis()
+                    .max(1) as u64
+            });
+            payloads.push(migrate::Payload {
+                key: key.clone(),
+                ttl,
+                dump: rdb::dump(value),
+            });
+            versions.push(version);
+        }
+    }
+    if payloads.is_empty() {
+        return Ok(Reply::Simple("NOKEY".to_string()));
+    }
+
+    let migration = migrate::Migration {
+        host: args[0].clone(),
+        port,
+        db,
+        timeout: Duration::from_millis(if timeout <= 0 { 1000 } else { timeout as u64 }),
+        auth,
+        replace,
+    };
+    let replies = migration.run(&payloads).await.map_err(|e| match e {
+        MigrateError::Io(msg) => CommandError::WithCode("IOERR", msg.to_string()),
+        MigrateError::Target(msg) => {
+            CommandError::InvalidArgument(format!("Target instance replied with error: {}", msg))
+        }
+    })?;
+
+    let mut error = None;
+    let mut moved = Vec::new();
+    for ((payload, version), reply) in payloads.into_iter().zip(versions).zip(replies) {
+        match reply {
+            Some(e) => error = Some(e),
+            None => moved.push((payload.key, version)),
+        }
+    }
+    if !copy && !moved.is_empty() {
+        let mut storage = STORAGE.lock().await;
+        let mut deleted = Vec::new();
+        for (key, version) in moved {
+            if storage.get(&key).and_then(DBEntry::version) == Some(version) {
+                storage.remove(&key);
+                notify_keyspace_event(notify::GENERIC, "del", &key).await;
+                deleted.push(key);
+            }
+        }
+        drop(storage);
+        // Replicas and the AOF see the keys deleted rather than migrated
+        // again, see `dispatch`.
+        if !deleted.is_empty() {
+            tracking::invalidate(&deleted, Some(client::current().id));
+            let del = std::iter::once("DEL".to_string())
+                .chain(deleted)
+                .collect::<Vec<_>>();
+            let raw_cmd = Reply::bulks(del).encode(2);
+            aof::feed(&raw_cmd);
+            _propagate(raw_cmd, server_metadata).await;
+        }
+    }
+    match error {
+        Some(e) => Err(CommandError::InvalidArgument(format!(
+            "Target instance replied with error: {}",
+            e
+        ))),
+        None => Ok(Reply::ok()),
+    }
+}
+
+async fn publish(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match publish_inner(&command).await {
+        Ok(receivers) => {
+            // Replicas deliver published messages to their own subscribers.
+            _propagate(command.raw_cmd, server_metadata).await;
+            Reply::Integer(receivers as i64)
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// `PUBLISH` as streamed by the master: delivered without a reply.
+async fn publish_replicated(
+    command: Command,
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+) -> Reply {
+    let _ = publish_inner(&command).await;
+    _count_replicated_message(&command, server_metadata).await;
+    Reply::Nothing
+}
+
+async fn publish_inner(command: &Command) -> Result<usize, CommandError> {
+    let [channel, message] = command.args.as_slice() else {
+        return Err(_wrong_args("publish"));
+    };
+    Ok(PUBSUB.lock().await.publish(channel, message))
+}
+
+async fn spublish(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match spublish_inner(&command).await {
+        Ok(receivers) => {
+            // Replicas deliver published messages to their own subscribers.
+            _propagate(command.raw_cmd, server_metadata).await;
+            Reply::Integer(receivers as i64)
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// `SPUBLISH` as streamed by the master: delivered without a reply.
+async fn spublish_replicated(
+    command: Command,
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+) -> Reply {
+    let _ = spublish_inner(&command).await;
+    _count_replicated_message(&command, server_metadata).await;
+    Reply::Nothing
+}
+
+async fn spublish_inner(command: &Command) -> Result<usize, CommandError> {
+    let [channel, message] = command.args.as_slice() else {
+        return Err(_wrong_args("spublish"));
+    };
+    Ok(PUBSUB.lock().await.spublish(channel, message))
+}
+
+async fn _count_replicated_message(
+    command: &Command,
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+) {
+    let metadata = server_metadata.read().await;
+    metadata
+        .master_repl_offset
+        .fetch_add(command.raw_cmd.len() as u64, Ordering::SeqCst);
+}
+
+async fn pubsub(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match pubsub_inner(command).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+async fn pubsub_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    let sub = args.first().ok_or_else(|| _wrong_args("pubsub"))?;
+    let pubsub = PUBSUB.lock().await;
+    match (sub.to_lowercase().as_str(), &args[1..]) {
+        ("channels", [] | [_]) => {
+            let channels: Vec<Reply> = pubsub
+                .active_channels(args.get(1).map(String::as_str))
+                .iter()
+                .map(Reply::bulk)
+                .collect();
+            Ok(Reply::Array(channels))
+        }
+        ("channels", _) => Err(_wrong_args("pubsub|channels")),
+        ("numsub", channels) => {
+            let counts: Vec<Reply> = channels
+                .iter()
+                .flat_map(|channel| {
+                    [
+                        Reply::bulk(channel),
+                        Reply::Integer(pubsub.numsub(channel) as i64),
+                    ]
+                })
+                .collect();
+            Ok(Reply::Array(counts))
+        }
+        ("numpat", []) => Ok(Reply::Integer(pubsub.numpat() as i64)),
+        ("numpat", _) => Err(_wrong_args("pubsub|numpat")),
+        ("shardchannels", [] | [_]) => {
+            let channels: Vec<Reply> = pubsub
+                .active_shard_channels(args.get(1).map(String::as_str))
+                .iter()
+                .map(Reply::bulk)
+                .collect();
+            Ok(Reply::Array(channels))
+        }
+        ("shardchannels", _) => Err(_wrong_args("pubsub|shardchannels")),
+        ("shardnumsub", channels) => {
+            let counts: Vec<Reply> = channels
+                .iter()
+                .flat_map(|channel| {
+                    [
+                        Reply::bulk(channel),
+                        Reply::Integer(pubsub.shard_numsub(channel) as i64),
+                    ]
+                })
+                .collect();
+            Ok(Reply::Array(counts))
+        }
+        _ => Err(_unknown_subcommand(sub, "PUBSUB")),
+    }
+}
+
+async fn memory(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match memory_inner(command).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+async fn memory_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    let sub = args.first().ok_or_else(|| _wrong_args("memory"))?;
+    if !sub.eq_ignore_ascii_case("usage") {
+        return Err(_unknown_subcommand(sub, "MEMORY"));
+    }
+    let key = args.get(1).ok_or_else(|| _wrong_args("memory|usage"))?;
+    // Values are measured exactly, so `SAMPLES` is accepted but not needed.
+    match &args[2..] {
+        [] => {}
+        [opt, samples] if opt.eq_ignore_ascii_case("samples") => {
+            samples.parse::<i64>().map_err(|_| _not_integer())?;
+        }
+        _ => return Err(_syntax_error()),
+    }
+
+    let storage = STORAGE.lock().await;
+    let Some(value) = storage.get(key).and_then(|entry| entry.value().ok()) else {
+        return Ok(Reply::Null);
+    };
+    let usage = key.len() + std::mem::size_of::<DBEntry>() + value.memory_usage();
+    Ok(Reply::Integer(usage as i64))
+}
+
+async fn hello(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match hello_inner(command, server_metadata).await {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+async fn auth(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match auth_inner(command) {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+/// `AUTH [username] password`, the single argument form authenticating as
+/// `default`.
+fn auth_inner(command: Command) -> Result<Reply, CommandError> {
+    let (username, password) = match command.args.as_slice() {
+        [password] => {
+            if acl::default_user_is_open() {
+                return Err(CommandError::InvalidArgument(
+                    "AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string(),
+                ));
+            }
+            (acl::DEFAULT_USER, password)
+        }
+        [username, password] => (username.as_str(), password),
+        _ => return Err(_syntax_error()),
+    };
+    if !acl::authenticate(username, password) {
+        return Err(_wrong_pass());
+    }
+    client::set_user(username);
+    Ok(Reply::ok())
+}
+
+async fn acl(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match acl_inner(command) {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+fn acl_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    let sub = args.first().ok_or_else(|| _wrong_args("acl"))?;
+    match (sub.to_lowercase().as_str(), &args[1..]) {
+        ("setuser", [name, rules @ ..]) => {
+            acl::set_user(name, rules).map_err(CommandError::InvalidArgument)?;
+            Ok(Reply::ok())
+        }
+        ("deluser", names) => {
+            let deleted = acl::delete_users(names).map_err(CommandError::InvalidArgument)?;
+            Ok(Reply::Integer(deleted as i64))
+        }
+        ("getuser", [name]) => Ok(acl::get_user(name)),
+        ("list", []) => Ok(Reply::bulks(acl::list())),
+        ("users", []) => Ok(Reply::bulks(acl::usernames())),
+        ("whoami", []) => Ok(Reply::bulk(client::current().user)),
+        ("cat", []) => Ok(Reply::bulks(acl::CATEGORIES)),
+        ("cat", [category]) => {
+            let commands = acl::category_commands(category).ok_or_else(|| {
+                CommandError::InvalidArgument(format!("Unknown category '{}'", category))
+            })?;
+            Ok(Reply::bulks(commands))
+        }
+        ("dryrun", [username, cmd, cmd_args @ ..]) => {
+            let command = Command {
+                cmd: cmd.clone(),
+                args: cmd_args.to_vec(),
+                raw_cmd: Vec::new(),
+            };
+            match acl::dry_run(username, &command).map_err(CommandError::InvalidArgument)? {
+                None => Ok(Reply::ok()),
+                Some(reason) => Ok(Reply::bulk(reason)),
+            }
+        }
+        ("log", []) => Ok(acl::log_reply(10)),
+        ("log", [arg]) if arg.eq_ignore_ascii_case("reset") => {
+            acl::reset_log();
+            Ok(Reply::ok())
+        }
+        ("log", [count]) => {
+            let count: usize = count.parse().map_err(|_| {
+                CommandError::InvalidArgument("value is out of range, must be positive".to_string())
+            })?;
+            Ok(acl::log_reply(count))
+        }
+        ("save", []) => {
+            acl::save_file().map_err(CommandError::InvalidArgument)?;
+            Ok(Reply::ok())
+        }
+        ("load", []) => {
+            acl::load_file().map_err(CommandError::InvalidArgument)?;
+            Ok(Reply::ok())
+        }
+        (
+            "setuser" | "getuser" | "list" | "users" | "whoami" | "cat" | "dryrun" | "log" | "save"
+            | "load",
+            _,
+        ) => Err(_wrong_args(&format!("acl|{}", sub.to_lowercase()))),
+        _ => Err(_unknown_subcommand(sub, "ACL")),
+    }
+}
+
+async fn client_fn(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match client_inner(command) {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+fn client_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    let sub = args.first().ok_or_else(|| _wrong_args("client"))?;
+    match (sub.to_lowercase().as_str(), &args[1..]) {
+        ("id", []) => Ok(Reply::Integer(client::current().id as i64)),
+        ("setname", [name]) => {
+            _check_client_name(name)?;
+            client::set_name(name.clone());
+            Ok(Reply::ok())
+        }
+        ("getname", []) => Ok(client::current().name.map_or(Reply::Null, Reply::bulk)),
+        ("info", []) => Ok(Reply::Verbatim(
+            "txt",
+            format!("{}\n", client::current().describe()),
+        )),
+        ("list", filters) => {
+            let mut client_type = None;
+            let mut ids = None;
+            match filters {
+                [] => {}
+                [option, name] if option.eq_ignore_ascii_case("type") => {
+                    client_type = Some(client::ClientType::parse(name).ok_or_else(|| {
+                        CommandError::InvalidArgument(format!("Unknown client type '{}'", name))
+                    })?);
+                }
+                [option, list @ ..] if option.eq_ignore_ascii_case("id") && !list.is_empty() => {
+                    ids = Some(
+                        list.iter()
+                            .map(|id| id.parse::<u64>().ok().filter(|id| *id > 0))
+                            .collect::<Option<Vec<u64>>>()
+                            .ok_or_else(|| {
+                                CommandError::InvalidArgument("Invalid client ID".to_string())
+                            })?,
+                    );
+                }
+                _ => return Err(_syntax_error()),
+            }
+            let lines: String = client::list()
+                .iter()
+                .filter(|client| client_type.is_none_or(|t| client.is_type(t)))
+                .filter(|client| ids.as_ref().is_none_or(|ids| ids.contains(&client.id)))
+                .map(|client| format!("{}\n", client.describe()))
+                .collect();
+            Ok(Reply::Verbatim("txt", lines))
+        }
+        ("kill", [addr]) => {
+            let ids: Vec<u64> = client::list()
+                .iter()
+                .filter(|client| client.addr == *addr)
+                .map(|client| client.id)
+                .collect();
+            if client::kill(&ids) == 0 {
+                return Err(CommandError::InvalidArgument("No such client".to_string()));
+            }
+            Ok(Reply::ok())
+        }
+        ("kill", filters) => Ok(Reply::Integer(_client_kill(filters)? as i64)),
+        ("pause", [timeout, mode @ ..]) => {
+            let timeout: u64 = timeout.parse().map_err(|_| {
+                CommandError::InvalidArgument(
+                    "timeout is not an integer or out of range".to_string(),
+                )
+            })?;
+            let mode = match mode {
+                [] => client::PauseMode::All,
+                [mode] if mode.eq_ignore_ascii_case("all") => client::PauseMode::All,
+                [mode] if mode.eq_ignore_ascii_case("write") => client::PauseMode::Write,
+                _ => return Err(_syntax_error()),
+            };
+            client::pause(timeout, mode);
+            Ok(Reply::ok())
+        }
+        ("unpause", []) => {
+            client::unpause();
+            Ok(Reply::ok())
+        }
+        ("reply", [mode]) => match mode.to_lowercase().as_str() {
+            "on" => {
+                client::update(|client| client.reply = client::ReplyMode::On);
+                Ok(Reply::ok())
+            }
+            "off" => {
+                client::update(|client| client.reply = client::ReplyMode::Off);
+                Ok(Reply::Nothing)
+            }
+            "skip" => {
+                client::update(|client| {
+                    if client.reply != client::ReplyMode::Off {
+                        client.reply = client::ReplyMode::SkipNext;
+                    }
+                });
+                Ok(Reply::Nothing)
+            }
+            _ => Err(_syntax_error()),
+        },
+        ("tracking", [switch, options @ ..]) => {
+            if !_parse_switch(switch)? {
+                tracking::disable();
+                return Ok(Reply::ok());
+            }
+            tracking::enable(_tracking_options(options)?).map_err(CommandError::InvalidArgument)?;
+            Ok(Reply::ok())
+        }
+        ("caching", [switch]) => {
+            let yes = _parse_switch_as(switch, "yes", "no")?;
+            let options = client::current().tracking.filter(|o| o.optin || o.optout);
+            let Some(options) = options else {
+                return Err(CommandError::InvalidArgument(
+                    "CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled".to_string(),
+                ));
+            };
+            if yes && !options.optin {
+                return Err(CommandError::InvalidArgument(
+                    "CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode."
+                        .to_string(),
+                ));
+            }
+            if !yes && !options.optout {
+                return Err(CommandError::InvalidArgument(
+                    "CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode."
+                        .to_string(),
+                ));
+            }
+            client::update(|client| {
+                if let Some(tracking) = client.tracking.as_mut() {
+                    tracking.caching = Some(yes);
+                }
+            });
+            Ok(Reply::ok())
+        }
+        ("getredir", []) => Ok(Reply::Integer(match client::current().tracking {
+            Some(options) => options.redirect.map_or(0, |id| id as i64),
+            None => -1,
+        })),
+        ("trackinginfo", []) => Ok(_tracking_info()),
+        ("no-evict", [switch]) => {
+            let on = _parse_switch(switch)?;
+            client::update(|client| client.no_evict = on);
+            Ok(Reply::ok())
+        }
+        ("no-touch", [switch]) => {
+            let on = _parse_switch(switch)?;
+            client::update(|client| client.no_touch = on);
+            Ok(Reply::ok())
+        }
+        ("setinfo", [attr, value]) => {
+            if !value.chars().all(|c| ('!'..='~').contains(&c)) {
+                return Err(CommandError::InvalidArgument(format!(
+                    "{} cannot contain spaces, newlines or special characters.",
+                    attr
+                )));
+            }
+            let value = Some(value.clone()).filter(|value| !value.is_empty());
+            match attr.to_lowercase().as_str() {
+                "lib-name" => client::update(|client| client.lib_name = value),
+                "lib-ver" => client::update(|client| client.lib_ver = value),
+                _ => {
+                    return Err(CommandError::InvalidArgument(format!(
+                        "Unrecognized option '{}'",
+                        attr
+                    )))
+                }
+            }
+            Ok(Reply::ok())
+        }
+        ("unblock", [id, mode @ ..]) => {
+            let id: u64 = id.parse().map_err(|_| {
+                CommandError::InvalidArgument("value is not an integer or out of range".to_string())
+            })?;
+            let error = match mode {
+                [] => false,
+                [mode] if mode.eq_ignore_ascii_case("timeout") => false,
+                [mode] if mode.eq_ignore_ascii_case("error") => true,
+                _ => {
+                    return Err(CommandError::InvalidArgument(
+                        "CLIENT UNBLOCK reason should be TIMEOUT or ERROR".to_string(),
+                    ))
+                }
+            };
+            Ok(Reply::Integer(client::unblock(id, error) as i64))
+        }
+        (
+            "id" | "setname" | "getname" | "info" | "pause" | "unpause" | "reply" | "no-evict"
+            | "no-touch" | "setinfo" | "unblock" | "tracking" | "caching" | "getredir"
+            | "trackinginfo",
+            _,
+        ) => Err(_wrong_args(&format!("client|{}", sub.to_lowercase()))),
+        _ => Err(_unknown_subcommand(sub, "CLIENT")),
+    }
+}
+
+/// `CLIENT KILL <filter> <value> ...`, returning how many clients were
+/// killed. The calling client is spared unless `SKIPME no` is given.
+fn _client_kill(filters: &[String]) -> Result<usize, CommandError> {
+    if filters.len() % 2 == 1 {
+        return Err(_syntax_error());
+    }
+    let me = client::current().id;
+    let mut skip_me = true;
+    let mut clients = client::list();
+    for pair in filters.chunks_exact(2) {
+        let (filter, value) = (&pair[0], &pair[1]);
+        match filter.to_lowercase().as_str() {
+            "id" => {
+                let id: u64 = value.parse().ok().filter(|id| *id > 0).ok_or_else(|| {
+                    CommandError::InvalidArgument("client-id should be greater than 0".to_string())
+                })?;
+                clients.retain(|client| client.id == id);
+            }
+            "type" => {
+                let client_type = client::ClientType::parse(value).ok_or_else(|| {
+                    CommandError::InvalidArgument(format!("Unknown client type '{}'", value))
+                })?;
+                clients.retain(|client| client.is_type(client_type));
+            }
+            "user" => {
+                if !acl::usernames().contains(value) {
+                    return Err(CommandError::InvalidArgument(format!(
+                        "No such user '{}'",
+                        value
+                    )));
+                }
+                clients.retain(|client| client.user == *value);
+            }
+            "addr" => clients.retain(|client| client.addr == *value),
+            "laddr" => clients.retain(|client| client.laddr == *value),
+            "skipme" => skip_me = _parse_switch_as(value, "yes", "no")?,
+            "maxage" => {
+                let max_age: u64 = value.parse().map_err(|_| {
+                    CommandError::InvalidArgument(
+                        "value is not an integer or out of range".to_string(),
+                    )
+                })?;
+                let now = now_millis();
+                clients.retain(|client| now.saturating_sub(client.created) / 1000 >= max_age);
+            }
+            _ => return Err(_syntax_error()),
+        }
+    }
+    let ids: Vec<u64> = clients
+        .iter()
+        .map(|client| client.id)
+        .filter(|id| !skip_me || *id != me)
+        .collect();
+    Ok(client::kill(&ids))
+}
+
+/// Options of `CLIENT TRACKING ON`.
+fn _tracking_options(args: &[String]) -> Result<tracking::Options, CommandError> {
+    let mut options = tracking::Options::default();
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        match option.to_lowercase().as_str() {
+            "redirect" => {
+                let id = args.next().ok_or_else(_syntax_error)?;
+                let id: u64 = id.parse().map_err(|_| {
+                    CommandError::InvalidArgument(
+                        "value is not an integer or out of range".to_string(),
+                    )
+                })?;
+                if options.redirect.is_some() {
+                    return Err(CommandError::InvalidArgument(
+                        "A client can only redirect to a single other client".to_string(),
+                    ));
+                }
+                options.redirect = Some(id);
+            }
+            "prefix" => options
+                .prefixes
+                .push(args.next().ok_or_else(_syntax_error)?.clone()),
+            "bcast" => options.bcast = true,
+            "optin" => options.optin = true,
+            "optout" => options.optout = true,
+            "noloop" => options.noloop = true,
+            _ => return Err(_syntax_error()),
+        }
+    }
+    Ok(options)
+}
+
+/// `CLIENT TRACKINGINFO` for the current connection.
+fn _tracking_info() -> Reply {
+    let Some(options) = client::current().tracking else {
+        return Reply::fields([
+            ("flags", Reply::bulks(["off"])),
+            ("redirect", Reply::Integer(-1)),
+            ("prefixes", Reply::Array(vec![])),
+        ]);
+    };
+    let mut flags = vec!["on"];
+    if options.bcast {
+        flags.push("bcast");
+    }
+    if options.optin {
+        flags.push("optin");
+    }
+    if options.optout {
+        flags.push("optout");
+    }
+    match options.caching {
+        Some(true) => flags.push("caching-yes"),
+        Some(false) => flags.push("caching-no"),
+        None => {}
+    }
+    if options.noloop {
+        flags.push("noloop");
+    }
+    if options.redirect.is_some_and(|id| client::get(id).is_none()) {
+        flags.push("broken_redirect");
+    }
+    let prefixes = options
+        .prefixes
+        .iter()
+        .filter(|prefix| !prefix.is_empty() || options.bcast);
+    Reply::fields([
+        ("flags", Reply::bulks(flags)),
+        (
+            "redirect",
+            Reply::Integer(options.redirect.map_or(0, |id| id as i64)),
+        ),
+        ("prefixes", Reply::bulks(prefixes.cloned())),
+    ])
+}
+
+fn _parse_switch(value: &str) -> Result<bool, CommandError> {
+    _parse_switch_as(value, "on", "off")
+}
+
+fn _parse_switch_as(value: &str, on: &str, off: &str) -> Result<bool, CommandError> {
+    if value.eq_ignore_ascii_case(on) {
+        Ok(true)
+    } else if value.eq_ignore_ascii_case(off) {
+        Ok(false)
+    } else {
+        Err(_syntax_error())
+    }
+}
+
+fn _check_client_name(name: &str) -> Result<(), CommandError> {
+    if !name.chars().all(|c| ('!'..='~').contains(&c)) {
+        return Err(CommandError::InvalidArgument(
+            "Client names cannot contain spaces, newlines or special characters.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+async fn hello_inner(
+    command: Command,
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+) -> Result<Reply, CommandError> {
+    let mut args = command.args.iter();
+    let mut protocol = client::protocol();
+    let mut name = None;
+    if let Some(version) = args.next() {
+        let version: i64 = version.parse().map_err(|_| {
+            CommandError::InvalidArgument(
+                "Protocol version is not an integer or out of range".to_string(),
+            )
+        })?;
+        if !(2..=3).contains(&version) {
+            return Err(CommandError::WithCode(
+                "NOPROTO",
+                "unsupported protocol version".to_string(),
+            ));
+        }
+        protocol = version as u8;
+
+        let mut credentials = None;
+        while let Some(opt) = args.next() {
+            let option_error =
+                || CommandError::InvalidArgument(format!("Syntax error in HELLO option '{}'", opt));
+            match opt.to_lowercase().as_str() {
+                "auth" => {
+                    let username = args.next().ok_or_else(option_error)?;
+                    let password = args.next().ok_or_else(option_error)?;
+                    credentials = Some((username, password));
+                }
+                "setname" => {
+                    let client_name = args.next().ok_or_else(option_error)?;
+                    _check_client_name(client_name)?;
+                    name = Some(client_name.clone());
+                }
+                _ => return Err(option_error()),
+            }
+        }
+        if let Some((username, password)) = credentials {
+            if !acl::authenticate(username, password) {
+                return Err(_wrong_pass());
+            }
+            client::set_user(username);
+        }
+    }
+
+    client::set_protocol(protocol);
+    if let Some(name) = name {
+        client::set_name(name);
+    }
+    let metadata = server_metadata.read().await;
+    let role = if metadata.role == 0 {
+        "master"
+    } else {
+        "replica"
+    };
+    Ok(Reply::fields([
+        ("server", Reply::bulk("redis")),
+        ("version", Reply::bulk(REDIS_VERSION)),
+        ("proto", Reply::Integer(protocol as i64)),
+        ("id", Reply::Integer(client::current().id as i64)),
+        ("mode", Reply::bulk("standalone")),
+        ("role", Reply::bulk(role)),
+        ("modules", Reply::Array(vec![])),
+    ]))
+}
+
+async fn command(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match command_inner(command) {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+fn command_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    let Some(sub) = args.first() else {
+        return Ok(Reply::Array(
+            COMMAND_TABLE.iter().map(CommandSpec::info).collect(),
+        ));
+    };
+    let names = &args[1..];
+    match sub.to_lowercase().as_str() {
+        "count" => Ok(Reply::Integer(COMMAND_TABLE.len() as i64)),
+        "info" if names.is_empty() => Ok(Reply::Array(
+            COMMAND_TABLE.iter().map(CommandSpec::info).collect(),
+        )),
+        // Unknown names are replied as nil, in the order asked for.
+        "info" => Ok(Reply::Array(
+            names
+                .iter()
+                .map(|name| _command_spec(name).map_or(Reply::Null, CommandSpec::info))
+                .collect(),
+        )),
+        // Unknown names are left out of the map.
+        "docs" => {
+            let specs: Vec<&CommandSpec> = if names.is_empty() {
+                COMMAND_TABLE.iter().collect()
+            } else {
+                names
+                    .iter()
+                    .filter_map(|name| _command_spec(name))
+                    .collect()
+            };
+            Ok(Reply::Map(
+                specs
+                    .into_iter()
+                    .map(|spec| (Reply::bulk(spec.name), spec.docs()))
+                    .collect(),
+            ))
+        }
+        "getkeys" => {
+            let spec = command_table::lookup(&names[0]).ok_or_else(|| {
+                CommandError::InvalidArgument("Invalid command specified".to_string())
+            })?;
+            let target = Command {
+                cmd: names[0].clone(),
+                args: names[1..].to_vec(),
+                raw_cmd: Vec::new(),
+            };
+            if spec.check_arity(&target).is_err() {
+                return Err(CommandError::InvalidArgument(
+                    "Invalid number of arguments specified for command".to_string(),
+                ));
+            }
+            let spec = target
+                .args
+                .first()
+                .and_then(|sub| spec.subcommand(sub))
+                .unwrap_or(spec);
+            let keys = spec.keys(names);
+            if keys.is_empty() {
+                return Err(CommandError::InvalidArgument(
+                    "The command has no key arguments".to_string(),
+                ));
+            }
+            Ok(Reply::bulks(keys.into_iter().cloned()))
+        }
+        "list" => {
+            let filter: Box<dyn Fn(&CommandSpec) -> bool> = match names {
+                [] => Box::new(|_| true),
+                [filterby, kind, value] if filterby.eq_ignore_ascii_case("filterby") => {
+                    match kind.to_lowercase().as_str() {
+                        // No modules can be loaded.
+                        "module" => Box::new(|_| false),
+                        "aclcat" => {
+                            let category = value.to_lowercase();
+                            Box::new(move |spec| spec.categories().contains(&category.as_str()))
+                        }
+                        "pattern" => Box::new(move |spec| glob_match(value, spec.name)),
+                        _ => return Err(_syntax_error()),
+                    }
+                }
+                _ => return Err(_syntax_error()),
+            };
+            Ok(Reply::bulks(
+                COMMAND_TABLE
+                    .iter()
+                    .flat_map(|spec| std::iter::once(spec).chain(spec.subcommands))
+                    .filter(|spec| filter(spec))
+                    .map(|spec| spec.name),
+            ))
+        }
+        _ => Err(_unknown_subcommand(sub, "COMMAND")),
+    }
+}
+
+/// Looks up a command, or a subcommand given as `container|name`.
+fn _command_spec(name: &str) -> Option<&'static CommandSpec> {
+    match name.split_once('|') {
+        Some((container, sub)) => command_table::lookup(container)?.subcommand(sub),
+        None => command_table::lookup(name),
+    }
+}
+
+async fn debug(command: Command, _server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    match debug_inner(command) {
+        Ok(reply) => reply,
+        Err(e) => e.into(),
+    }
+}
+
+fn debug_inner(command: Command) -> Result<Reply, CommandError> {
+    let args = command.args;
+    let sub = args.first().ok_or_else(|| _wrong_args("debug"))?;
+    match (sub.to_lowercase().as_str(), &args[1..]) {
+        // Sample replies of every type, to exercise client RESP parsers.
+        ("protocol", [kind]) => _debug_protocol(kind),
+        _ => Err(_unknown_subcommand(sub, "DEBUG")),
+    }
+}
+
+fn _debug_protocol(kind: &str) -> Result<Reply, CommandError> {
+    let small_ints = || (0..3).map(Reply::Integer);
+    Ok(match kind.to_lowercase().as_str() {
+        "string" => Reply::bulk("Hello World"),
+        "integer" => Reply::Integer(12345),
+        "double" => Reply::Double(std::f64::consts::PI),
+        "bignum" => Reply::BigNumber("1234567999999999999999999999999999999".to_string()),
+        "null" => Reply::Null,
+        "array" => Reply::Array(small_ints().collect()),
+        "set" => Reply::Set(small_ints().collect()),
+        "map" => Reply::Map(
+            small_ints()
+                .enumerate()
+                .map(|(i, key)| (key, Reply::Boolean(i == 1)))
+                .collect(),
+        ),
+        "attrib" => Reply::Attribute(
+            vec![(
+                Reply::bulk("key-popularity"),
+                Reply::Array(vec![Reply::bulk("key:123"), Reply::Integer(90)]),
+            )],
+            Box::new(Reply::bulk("Some real reply following the attribute")),
+        ),
+        "push" => {
+            if client::protocol() == 2 {
+                return Err(CommandError::InvalidArgument(
+                    "RESP2 is not supported by this command".to_string(),
+                ));
+            }
+            Reply::Sequence(vec![
+                Reply::Push(vec![Reply::bulk("server-cpu-usage"), Reply::Integer(42)]),
+                Reply::bulk("Some real reply following the push reply"),
+            ])
+        }
+        "true" => Reply::Boolean(true),
+        "false" => Reply::Boolean(false),
+        "verbatim" => Reply::Verbatim("txt", "This is a verbatim\nstring".to_string()),
+        _ => return Err(CommandError::InvalidArgument(
+            "Wrong protocol type name. Please use one of the following: string|integer|double|bignum|null|array|set|map|attrib|push|verbatim|true|false"
+                .to_string(),
+        )),
+    })
+}
+
+async fn config(command: Command, server_metadata: &Arc<RwLock<ServerMetadata>>) -> Reply {
+    let metadata = server_metadata.read().await;
+    let operation = command.args.first().unwrap();
+    if operation.to_lowercase() == "get" {
+        let config_name = command.args.get(1).unwrap();
+        let config_val = match config_name.to_lowercase().as_str() {
+            "dir" => metadata.dir.to_string_lossy().to_string(),
+            "dbfilename" => metadata.dbfilename.clone(),
+            "notify-keyspace-events" => notify::flags_to_string(notify::flags()),
+            "requirepass" => acl::requirepass(),
+            "tracking-table-max-keys" => tracking::max_keys().to_string(),
+            "rdbchecksum" => if rdb::checksum_enabled() { "yes" } else { "no" }.to_string(),
+            "rdbcompression" => if rdb::compression_enabled() {
+                "yes"
+            } else {
+                "no"
+            }
+            .to_string(),
+            "save" => persistence::save_params(),
+            "appendonly" => if aof::enabled() { "yes" } else { "no" }.to_string(),
+            "appendfilename" => aof::filename(),
+            "appenddirname" => aof::dirname(),
+            "auto-aof-rewrite-percentage" => aof::rewrite_percentage().to_string(),
+            "auto-aof-rewrite-min-size" => aof::rewrite_min_size().to_string(),
+            "appendfsync" => aof::fsync_policy().to_string(),
+            "aof-load-truncated" => if aof::load_truncated() { "yes" } else { "no" }.to_string(),
+            "aclfile" => acl::aclfile()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        Reply::Map(vec![(Reply::bulk(config_name), Reply::bulk(config_val))])
+    } else if operation.to_lowercase() == "set" {
+        match _config_set(&command.args[1..], &metadata.dir).await {
+            Ok(()) => Reply::ok(),
+            Err(e) => e.into(),
+        }
+    } else {
+        _unknown_subcommand(operation, "CONFIG").into()
+    }
+}
+
+async fn _config_set(args: &[String], dir: &Path) -> Result<(), CommandError> {
+    if args.is_empty() || args.len() % 2 == 1 {
+        return Err(_wrong_args("config|set"));
+    }
+    for pair in args.chunks_exact(2) {
+        let (name, value) = (&pair[0], &pair[1]);
+        match name.to_lowercase().as_str() {
+            "notify-keyspace-events" => {
+                let flags = notify::parse_flags(value).ok_or_else(|| {
+                    CommandError::InvalidArgument(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - Invalid event class character. Use 'Ag$lshzxeKEtmn'.",
+                        name
+                    ))
+                })?;
+                notify::set_flags(flags);
+            }
+            "requirepass" => acl::set_requirepass(value),
+            "tracking-table-max-keys" => {
+                let max_keys = value.parse().map_err(|_| {
+                    CommandError::InvalidArgument(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer",
+                        name
+                    ))
+                })?;
+                tracking::set_max_keys(max_keys);
+            }
+            "rdbchecksum" => {
+                let enabled = cli::parse_yes_no(value).map_err(|e| {
+                    CommandError::InvalidArgument(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, e
+                    ))
+                })?;
+                rdb::set_checksum(enabled);
+            }
+            "rdbcompression" => {
+                let enabled = cli::parse_yes_no(value).map_err(|e| {
+                    CommandError::InvalidArgument(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, e
+                    ))
+                })?;
+                rdb::set_compression(enabled);
+            }
+            "appendonly" => {
+                let enabled = cli::parse_yes_no(value).map_err(|e| {
+                    CommandError::InvalidArgument(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, e
+                    ))
+                })?;
+                if enabled && !aof::enabled() {
+                    aof::start(dir, true).await.map_err(|e| {
+                        CommandError::InvalidArgument(format!(
+                            "CONFIG SET failed (possibly related to argument '{}') - {}",
+                            name, e
+                        ))
+                    })?;
+                } else if !enabled {
+                    aof::stop();
+                }
+                aof::set_enabled(enabled);
+            }
+            "appendfsync" => {
+                let policy = value.parse().map_err(|e| {
+                    CommandError::InvalidArgument(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, e
+                    ))
+                })?;
+                aof::set_fsync_policy(policy);
+            }
+            "auto-aof-rewrite-percentage" => {
+                let percentage = value.parse().map_err(|_| {
+                    CommandError::InvalidArgument(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer",
+                        name
+                    ))
+                })?;
+                aof::set_rewrite_percentage(percentage);
+            }
+            "auto-aof-rewrite-min-size" => {
+                let size = cli::parse_memory(value).map_err(|e| {
+                    CommandError::InvalidArgument(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, e
+                    ))
+                })?;
+                aof::set_rewrite_min_size(size);
+            }
+            "aof-load-truncated" => {
+                let enabled = cli::parse_yes_no(value).map_err(|e| {
+                    CommandError::InvalidArgument(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, e
+                    ))
+                })?;
+                aof::set_load_truncated(enabled);
+            }
+            "save" => persistence::set_save_params(value).map_err(|e| {
+                CommandError::InvalidArgument(format!(
+                    "CONFIG SET failed (possibly related to argument '{}') - {}",
+                    name, e
+                ))
+            })?,
+            _ => {
+                return Err(CommandError::InvalidArgument(format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn format_result(value: &DBEntry) -> Reply {
+    match value.value() {
+        Ok(v) => match v.as_any().downcast_ref::<StringType>() {
+            Some(s) => Reply::BulkBytes(s.bytes.clone()),
+            None => _wrong_type().into(),
+        },
+        Err(_) => Reply::Null,
+    }
+}
+
+/// Encodes the reply for the protocol negotiated by the current connection.
+pub async fn write_reply(stream: &Arc<RwLock<TcpStream>>, reply: &Reply) {
+    let errors = server_info::record_errors(reply);
+    if errors > 0 {
+        client::update(|client| client.errors += errors);
+    }
+    if *reply != Reply::Nothing && client::replies_enabled() {
+        write_stream_and_flush(stream, &reply.encode(client::protocol())).await;
+    }
+}
+
+pub async fn write_stream_and_flush(stream: &Arc<RwLock<TcpStream>>, res: &[u8]) {
     let mut stream = stream.write().await;
     let _ = stream
-        .write_all(res.as_bytes())
+        .write_all(res)
         .await
         .map_err(|e| format!("Error while writing to the stream: {}", e));
     let _ = stream
@@ -591,11 +3215,39 @@ fn _wrong_args(cmd: &str) -> CommandError {
 }
 
 fn _wrong_type() -> CommandError {
-    CommandError::StorageError(
-        "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+    CommandError::WrongType
+}
+
+fn _no_such_key() -> CommandError {
+    CommandError::InvalidArgument("no such key".to_string())
+}
+
+fn _no_group(key: &str, group: &str) -> CommandError {
+    CommandError::WithCode(
+        "NOGROUP",
+        format!("No such consumer group '{}' for key name '{}'", group, key),
+    )
+}
+
+fn _wrong_pass() -> CommandError {
+    CommandError::WithCode(
+        "WRONGPASS",
+        "invalid username-password pair or user is disabled.".to_string(),
     )
 }
 
+fn _unknown_subcommand(sub: &str, cmd: &str) -> CommandError {
+    CommandError::InvalidArgument(format!("unknown subcommand '{}'. Try {} HELP.", sub, cmd))
+}
+
+fn _syntax_error() -> CommandError {
+    CommandError::InvalidArgument("syntax error".to_string())
+}
+
+fn _not_integer() -> CommandError {
+    CommandError::InvalidArgument("value is not an integer or out of range".to_string())
+}
+
 fn _missing_entry(cmd: &str) -> CommandError {
     CommandError::StorageError(format!(
         "The ID sent in {} command id not found in the storage",
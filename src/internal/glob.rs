@@ -0,0 +1,84 @@
+/// Glob-style matching as done by Redis' `stringmatchlen`: `*`, `?`,
+/// `[abc]`, `[^abc]`, `[a-z]` and `\` to escape the next character.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    match_from(&pattern, &s)
+}
+
+fn match_from(pattern: &[char], s: &[char]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            '*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == '*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (i..=s.len()).any(|start| match_from(&pattern[p + 1..], &s[start..]));
+            }
+            '?' => {
+                if i >= s.len() {
+                    return false;
+                }
+                i += 1;
+            }
+            '[' => {
+                if i >= s.len() {
+                    return false;
+                }
+                let (matched, next) = match_class(pattern, p + 1, s[i]);
+                if !matched {
+                    return false;
+                }
+                p = next;
+                i += 1;
+                continue;
+            }
+            c => {
+                let c = if c == '\\' && p + 1 < pattern.len() {
+                    p += 1;
+                    pattern[p]
+                } else {
+                    c
+                };
+                if i >= s.len() || s[i] != c {
+                    return false;
+                }
+                i += 1;
+            }
+        }
+        p += 1;
+    }
+    i == s.len()
+}
+
+/// Matches `c` against the class starting right after `[`, returning whether
+/// it matched and the pattern position after the closing `]`.
+fn match_class(pattern: &[char], mut p: usize, c: char) -> (bool, usize) {
+    let negate = pattern.get(p) == Some(&'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != ']' {
+        if pattern[p] == '\\' && p + 1 < pattern.len() {
+            p += 1;
+            matched |= pattern[p] == c;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == '-' && pattern[p + 2] != ']' {
+            let (mut start, mut end) = (pattern[p], pattern[p + 2]);
+            if start > end {
+                std::mem::swap(&mut start, &mut end);
+            }
+            matched |= start <= c && c <= end;
+            p += 2;
+        } else {
+            matched |= pattern[p] == c;
+        }
+        p += 1;
+    }
+    // Skip the closing bracket, if any.
+    (matched != negate, p + 1)
+}
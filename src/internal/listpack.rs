@@ -0,0 +1,185 @@
+//! Listpacks, the compact encoding Redis uses for small lists, sets, sorted
+//! sets, hashes and for stream nodes, as found in RDB files.
+//!
+//! ```text
+//! total-bytes (u32) | num-elements (u16) | entry ... | 0xFF
+//! ```
+//!
+//! Each entry is an encoding byte, possibly followed by more length or integer
+//! bytes and the string data, then a back-length of 1 to 5 bytes that allows
+//! walking the listpack from the end.
+
+const HEADER_LEN: usize = 6;
+const EOF: u8 = 0xFF;
+
+/// A listpack element, stored either as a string or as an integer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListpackEntry {
+    String(String),
+    Integer(i64),
+}
+
+impl ListpackEntry {
+    pub fn into_string(self) -> String {
+        match self {
+            ListpackEntry::String(s) => s,
+            ListpackEntry::Integer(i) => i.to_string(),
+        }
+    }
+
+    /// The entry as an integer, parsing string entries.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            ListpackEntry::String(s) => s.parse().ok(),
+            ListpackEntry::Integer(i) => Some(*i),
+        }
+    }
+}
+
+impl From<&str> for ListpackEntry {
+    /// Strings holding a canonical integer are stored as integers, as Redis
+    /// does.
+    fn from(s: &str) -> Self {
+        match s.parse::<i64>() {
+            Ok(i) if i.to_string() == s => ListpackEntry::Integer(i),
+            _ => ListpackEntry::String(s.to_string()),
+        }
+    }
+}
+
+/// Encodes entries into a listpack.
+pub fn encode(entries: impl IntoIterator<Item = ListpackEntry>) -> Vec<u8> {
+    let mut lp = vec![0; HEADER_LEN];
+    let mut count = 0usize;
+    for entry in entries {
+        let start = lp.len();
+        match entry {
+            ListpackEntry::Integer(i) => encode_int(&mut lp, i),
+            ListpackEntry::String(s) => {
+                let len = s.len();
+                if len < 64 {
+                    lp.push(0x80 | len as u8);
+                } else if len < 4096 {
+                    lp.extend_from_slice(&[0xE0 | (len >> 8) as u8, len as u8]);
+                } else {
+                    lp.push(0xF0);
+                    lp.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                lp.extend_from_slice(s.as_bytes());
+            }
+        }
+        let len = lp.len() - start;
+        encode_backlen(&mut lp, len);
+        count += 1;
+    }
+    lp.push(EOF);
+    let total = lp.len() as u32;
+    lp[0..4].copy_from_slice(&total.to_le_bytes());
+    // Past 65534 entries the count is only known by walking the listpack.
+    let count = count.min(u16::MAX as usize) as u16;
+    lp[4..6].copy_from_slice(&count.to_le_bytes());
+    lp
+}
+
+fn encode_int(lp: &mut Vec<u8>, i: i64) {
+    match i {
+        0..=127 => lp.push(i as u8),
+        -4096..=4095 => lp.extend_from_slice(&[0xC0 | ((i >> 8) as u8 & 0x1F), i as u8]),
+        _ => {
+            let (tag, width) = match i {
+                -32768..=32767 => (0xF1, 2),
+                -8388608..=8388607 => (0xF2, 3),
+                -2147483648..=2147483647 => (0xF3, 4),
+                _ => (0xF4, 8),
+            };
+            lp.push(tag);
+            lp.extend_from_slice(&i.to_le_bytes()[..width]);
+        }
+    }
+}
+
+/// Appends the back-length of an entry: its length in 7 bit groups, most
+/// significant first, every byte but the first having its high bit set.
+fn encode_backlen(lp: &mut Vec<u8>, len: usize) {
+    let size = backlen_size(len);
+    for i in (0..size).rev() {
+        let group = ((len >> (7 * i)) & 0x7F) as u8;
+        lp.push(if i == size - 1 { group } else { group | 0x80 });
+    }
+}
+
+/// Decodes every entry of a listpack, `None` when it is malformed.
+pub fn entries(lp: &[u8]) -> Option<Vec<ListpackEntry>> {
+    let mut entries = Vec::new();
+    let mut pos = HEADER_LEN;
+    while *lp.get(pos)? != EOF {
+        let (entry, len) = decode_entry(&lp[pos..])?;
+        entries.push(entry);
+        pos += len + backlen_size(len);
+    }
+    Some(entries)
+}
+
+/// Decodes the entry at the start of `data`, returning it along with its
+/// encoded length, back-length excluded.
+fn decode_entry(data: &[u8]) -> Option<(ListpackEntry, usize)> {
+    let b = data[0];
+    let byte = |i: usize| data.get(i).copied();
+    let entry = if b & 0x80 == 0 {
+        // 7 bit unsigned integer.
+        (ListpackEntry::Integer((b & 0x7F) as i64), 1)
+    } else if b & 0xC0 == 0x80 {
+        // String up to 63 bytes.
+        let len = (b & 0x3F) as usize;
+        (string(data.get(1..1 + len)?), 1 + len)
+    } else if b & 0xE0 == 0xC0 {
+        // 13 bit signed integer.
+        let value = (((b & 0x1F) as i64) << 8) | byte(1)? as i64;
+        (ListpackEntry::Integer(sign_extend(value, 13)), 2)
+    } else if b & 0xF0 == 0xE0 {
+        // String up to 4095 bytes.
+        let len = (((b & 0x0F) as usize) << 8) | byte(1)? as usize;
+        (string(data.get(2..2 + len)?), 2 + len)
+    } else {
+        match b {
+            0xF0 => {
+                let len = u32::from_le_bytes(data.get(1..5)?.try_into().ok()?) as usize;
+                (string(data.get(5..5 + len)?), 5 + len)
+            }
+            0xF1 => (integer(data.get(1..3)?), 3),
+            0xF2 => (integer(data.get(1..4)?), 4),
+            0xF3 => (integer(data.get(1..5)?), 5),
+            0xF4 => (integer(data.get(1..9)?), 9),
+            _ => return None,
+        }
+    };
+    Some(entry)
+}
+
+fn string(bytes: &[u8]) -> ListpackEntry {
+    ListpackEntry::String(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// A little endian signed integer of 2, 3, 4 or 8 bytes.
+fn integer(bytes: &[u8]) -> ListpackEntry {
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let value = i64::from_le_bytes(buf);
+    ListpackEntry::Integer(sign_extend(value, bytes.len() as u32 * 8))
+}
+
+fn sign_extend(value: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (value << shift) >> shift
+}
+
+/// Bytes taken by the back-length of an entry `len` bytes long.
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
@@ -0,0 +1,169 @@
+//! LZF, the compression Redis applies to long strings in RDB files.
+//!
+//! A compressed buffer is a sequence of chunks, each starting with a control
+//! byte. Below 32 it announces a literal run of `ctrl + 1` bytes; otherwise
+//! its top 3 bits hold a back-reference length (7 meaning an extra length
+//! byte follows) and its low 5 bits, with the next byte, the distance back
+//! into the output to copy from.
+
+/// Decompresses `input` into a buffer of exactly `len` bytes, `None` when
+/// the data is malformed or doesn't decompress to that length.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < 32 {
+            let run = input.get(ip..ip + ctrl + 1)?;
+            out.extend_from_slice(run);
+            ip += ctrl + 1;
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(ip)? as usize;
+                ip += 1;
+            }
+            let distance = ((ctrl & 0x1F) << 8) + *input.get(ip)? as usize + 1;
+            ip += 1;
+            let start = out.len().checked_sub(distance)?;
+            // The reference may overlap the bytes being written.
+            for i in 0..run + 2 {
+                out.push(out[start + i]);
+            }
+        }
+        if out.len() > len {
+            return None;
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+/// Longest back-reference distance a chunk can express.
+const MAX_OFFSET: usize = 1 << 13;
+/// Longest back-reference: a length of 7 plus the extra byte, plus 2.
+const MAX_REF: usize = (1 << 8) + (1 << 3);
+const MAX_LITERAL: usize = 1 << 5;
+const HASH_LOG: u32 = 14;
+
+/// Compresses `input`, `None` when that wouldn't save at least 4 bytes, in
+/// which case Redis stores the string as is.
+pub fn compress(input: &[u8]) -> Option<Vec<u8>> {
+    let max_len = input.len().checked_sub(4)?;
+    let mut out = Vec::with_capacity(max_len);
+    // Last position each 3 byte sequence was seen at.
+    let mut table = vec![usize::MAX; 1 << HASH_LOG];
+    let mut literals: Vec<u8> = Vec::with_capacity(MAX_LITERAL);
+    let mut ip = 0;
+    while ip < input.len() {
+        let candidate = (ip + 2 < input.len()).then(|| {
+            let hash = hash(&input[ip..ip + 3]);
+            std::mem::replace(&mut table[hash], ip)
+        });
+        let reference = candidate.filter(|&r| {
+            r != usize::MAX && ip - r <= MAX_OFFSET && input[r..r + 3] == input[ip..ip + 3]
+        });
+        match reference {
+            Some(r) => {
+                let max = MAX_REF.min(input.len() - ip);
+                let mut len = 3;
+                while len < max && input[r + len] == input[ip + len] {
+                    len += 1;
+                }
+                flush_literals(&mut out, &mut literals);
+                let distance = ip - r - 1;
+                let encoded_len = len - 2;
+                if encoded_len < 7 {
+                    out.push(((encoded_len << 5) | (distance >> 8)) as u8);
+                } else {
+                    out.push(((7 << 5) | (distance >> 8)) as u8);
+                    out.push((encoded_len - 7) as u8);
+                }
+                out.push(distance as u8);
+                ip += len;
+            }
+            None => {
+                literals.push(input[ip]);
+                if literals.len() == MAX_LITERAL {
+                    flush_literals(&mut out, &mut literals);
+                }
+                ip += 1;
+            }
+        }
+        if out.len() + literals.len() > max_len {
+            return None;
+        }
+    }
+    flush_literals(&mut out, &mut literals);
+    (out.len() <= max_len).then_some(out)
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &mut Vec<u8>) {
+    if !literals.is_empty() {
+        out.push((literals.len() - 1) as u8);
+        out.append(literals);
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (v.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) {
+        let compressed = compress(input).expect("compressible input");
+        assert!(compressed.len() + 4 <= input.len());
+        assert_eq!(decompress(&compressed, input.len()).as_deref(), Some(input));
+    }
+
+    #[test]
+    fn round_trips_repetitive_data() {
+        round_trip(&[b'a'; 100]);
+        round_trip(&b"abcabcabcabcabcabcabcabcabcabc".repeat(50));
+        // Long runs need the extra length byte, and distant repeats the
+        // high distance bits.
+        round_trip(&[0u8; 100_000]);
+        let text: Vec<u8> = (0..20_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        round_trip(&text);
+    }
+
+    #[test]
+    fn declines_incompressible_data() {
+        assert_eq!(compress(b""), None);
+        assert_eq!(compress(b"abc"), None);
+        assert_eq!(compress(b"abcdefghijklmnopqrstuvwxyz"), None);
+    }
+
+    #[test]
+    fn decompresses_literals_and_overlapping_references() {
+        // "ab" as a literal, then a 6 byte reference 2 bytes back.
+        assert_eq!(
+            decompress(&[1, b'a', b'b', 4 << 5, 1], 8).as_deref(),
+            Some(&b"abababab"[..])
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_input() {
+        let compressed = compress(&[b'x'; 64]).unwrap();
+        // Wrong expected length.
+        assert_eq!(decompress(&compressed, 63), None);
+        assert_eq!(decompress(&compressed, 65), None);
+        // Truncated at every position.
+        for end in 0..compressed.len() {
+            assert_eq!(decompress(&compressed[..end], 64), None);
+        }
+        // A literal run longer than the input.
+        assert_eq!(decompress(&[5, b'a'], 6), None);
+        // A reference before the start of the output.
+        assert_eq!(decompress(&[0, b'a', 1 << 5, 4], 4), None);
+        // A long reference missing its length byte.
+        assert_eq!(decompress(&[0, b'a', 7 << 5], 12), None);
+    }
+}
@@ -0,0 +1,232 @@
+//! The client side of `MIGRATE`: sends `RESTORE` commands to another
+//! instance over connections cached per target, as Redis does so moving many
+//! keys one call at a time doesn't reconnect every time.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// Cached connections idle for longer than this are closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most connections kept, the least recently used one making room.
+const MAX_CACHED: usize = 64;
+
+const CRON_PERIOD: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// Idle connections by `host:port`. A connection in use is taken out
+    /// and put back once the exchange succeeded.
+    static ref CACHE: Mutex<HashMap<String, Connection>> = Mutex::new(HashMap::new());
+}
+
+struct Connection {
+    stream: BufReader<TcpStream>,
+    /// Database selected on the target, 0 for a new connection.
+    db: i64,
+    last_use: Instant,
+}
+
+/// A key to move, as sent in its `RESTORE` command.
+pub struct Payload {
+    pub key: String,
+    /// Milliseconds to live, 0 when the key doesn't expire.
+    pub ttl: u64,
+    pub dump: Vec<u8>,
+}
+
+pub struct Migration {
+    pub host: String,
+    pub port: u16,
+    pub db: i64,
+    pub timeout: Duration,
+    /// `AUTH` arguments, empty when the target needs none.
+    pub auth: Vec<String>,
+    pub replace: bool,
+}
+
+#[derive(Debug)]
+pub enum MigrateError {
+    /// The connection failed or timed out.
+    Io(&'static str),
+    /// The target refused `AUTH` or `SELECT`.
+    Target(String),
+}
+
+impl Migration {
+    /// Restores the keys on the target. Returns, in order, the error the
+    /// target replied for each key or `None` when it was restored.
+    pub async fn run(&self, keys: &[Payload]) -> Result<Vec<Option<String>>, MigrateError> {
+        let address = format!("{}:{}", self.host, self.port);
+        let cached = CACHE.lock().unwrap().remove(&address);
+        let (mut connection, mut may_retry) = match cached {
+            Some(connection) => (connection, true),
+            None => (self.connect(&address).await?, false),
+        };
+        loop {
+            match self.exchange(&mut connection, keys).await {
+                Ok(replies) => {
+                    connection.last_use = Instant::now();
+                    cache(address, connection);
+                    return Ok(replies);
+                }
+                // The target may have closed an idle cached connection:
+                // try again once on a new one, as long as nothing was read.
+                Err(Exchange::Stale(_)) if may_retry => {
+                    may_retry = false;
+                    connection = self.connect(&address).await?;
+                }
+                Err(Exchange::Stale(e) | Exchange::Io(e)) => return Err(MigrateError::Io(e)),
+                Err(Exchange::Target(e)) => return Err(MigrateError::Target(e)),
+            }
+        }
+    }
+
+    async fn connect(&self, address: &str) -> Result<Connection, MigrateError> {
+        let stream = timeout(self.timeout, TcpStream::connect(address))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .ok_or(MigrateError::Io(
+                "error or timeout connecting to the client",
+            ))?;
+        Ok(Connection {
+            stream: BufReader::new(stream),
+            db: 0,
+            last_use: Instant::now(),
+        })
+    }
+
+    /// Pipelines `AUTH`, `SELECT` and the `RESTORE` commands, then reads
+    /// their replies.
+    async fn exchange(
+        &self,
+        connection: &mut Connection,
+        keys: &[Payload],
+    ) -> Result<Vec<Option<String>>, Exchange> {
+        let select = connection.db != self.db;
+        let mut out = Vec::new();
+        if !self.auth.is_empty() {
+            let mut auth = vec![b"AUTH".to_vec()];
+            auth.extend(self.auth.iter().map(|arg| arg.as_bytes().to_vec()));
+            encode(&mut out, &auth);
+        }
+        if select {
+            encode(
+                &mut out,
+                &[b"SELECT".to_vec(), self.db.to_string().into_bytes()],
+            );
+        }
+        for payload in keys {
+            let mut restore = vec![
+                b"RESTORE".to_vec(),
+                payload.key.as_bytes().to_vec(),
+                payload.ttl.to_string().into_bytes(),
+                payload.dump.clone(),
+            ];
+            if self.replace {
+                restore.push(b"REPLACE".to_vec());
+            }
+            encode(&mut out, &restore);
+        }
+        let written = timeout(self.timeout, connection.stream.get_mut().write_all(&out)).await;
+        if !matches!(written, Ok(Ok(()))) {
+            return Err(Exchange::Stale(
+                "error or timeout writing to target instance",
+            ));
+        }
+
+        let mut first = true;
+        if !self.auth.is_empty() {
+            let reply = self.read_reply(connection, &mut first).await?;
+            if let Some(error) = reply.strip_prefix('-') {
+                return Err(Exchange::Target(error.to_string()));
+            }
+        }
+        if select {
+            let reply = self.read_reply(connection, &mut first).await?;
+            if let Some(error) = reply.strip_prefix('-') {
+                return Err(Exchange::Target(error.to_string()));
+            }
+            connection.db = self.db;
+        }
+        let mut replies = Vec::with_capacity(keys.len());
+        for _ in keys {
+            let reply = self.read_reply(connection, &mut first).await?;
+            replies.push(reply.strip_prefix('-').map(str::to_string));
+        }
+        Ok(replies)
+    }
+
+    /// Reads a status or error reply line. `first` tells whether nothing was
+    /// read yet, in which case a closed connection is a stale one.
+    async fn read_reply(
+        &self,
+        connection: &mut Connection,
+        first: &mut bool,
+    ) -> Result<String, Exchange> {
+        let mut line = String::new();
+        let read = timeout(self.timeout, connection.stream.read_line(&mut line)).await;
+        let error = "error or timeout reading to target instance";
+        match read {
+            Ok(Ok(n)) if n > 0 => {
+                *first = false;
+                Ok(line.trim_end().to_string())
+            }
+            Ok(Ok(_)) if *first => Err(Exchange::Stale(error)),
+            _ => Err(Exchange::Io(error)),
+        }
+    }
+}
+
+enum Exchange {
+    /// Writing failed or the connection was closed before anything was
+    /// read back.
+    Stale(&'static str),
+    Io(&'static str),
+    Target(String),
+}
+
+/// Appends a command as a RESP array of bulk strings.
+fn encode(out: &mut Vec<u8>, args: &[Vec<u8>]) {
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+fn cache(address: String, connection: Connection) {
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHED && !cache.contains_key(&address) {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, connection)| connection.last_use)
+            .map(|(address, _)| address.clone());
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(address, connection);
+}
+
+/// Closes the cached connections that stayed idle for too long.
+pub async fn cron() {
+    let mut interval = tokio::time::interval(CRON_PERIOD);
+    loop {
+        interval.tick().await;
+        CACHE
+            .lock()
+            .unwrap()
+            .retain(|_, connection| connection.last_use.elapsed() <= IDLE_TIMEOUT);
+    }
+}
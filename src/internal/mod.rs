@@ -1,8 +1,27 @@
+pub mod acl;
+pub mod aof;
 pub mod cli;
+pub mod client;
+pub mod cluster;
+pub mod command_table;
 pub mod commands;
+pub mod crc64;
+pub mod glob;
+pub mod json;
+pub mod listpack;
+pub mod lzf;
+pub mod migrate;
+pub mod notify;
 pub mod parser;
+pub mod persistence;
+pub mod pubsub;
 pub mod rdb;
+pub mod resp;
 pub mod server;
 pub mod server_info;
+pub mod sha256;
 pub mod storage;
+pub mod stream_node;
+pub mod tracking;
+pub mod transaction;
 pub mod types;
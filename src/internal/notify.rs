@@ -0,0 +1,123 @@
+//! Keyspace notifications: Pub/Sub messages emitted when keys change, so
+//! clients can follow writes, expirations and misses without polling.
+//!
+//! Every event is published on `__keyspace@<db>__:<key>` with the event name as
+//! the message, and on `__keyevent@<db>__:<event>` with the key as the message,
+//! depending on which classes are enabled through `notify-keyspace-events`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::internal::pubsub::PUBSUB;
+
+/// `K`: publish on `__keyspace@<db>__:<key>`.
+pub const KEYSPACE: u32 = 1 << 0;
+/// `E`: publish on `__keyevent@<db>__:<event>`.
+pub const KEYEVENT: u32 = 1 << 1;
+/// `g`: generic commands such as `DEL`, `EXPIRE` or `RENAME`.
+pub const GENERIC: u32 = 1 << 2;
+/// `$`: string commands.
+pub const STRING: u32 = 1 << 3;
+/// `l`: list commands.
+pub const LIST: u32 = 1 << 4;
+/// `s`: set commands.
+pub const SET: u32 = 1 << 5;
+/// `h`: hash commands.
+pub const HASH: u32 = 1 << 6;
+/// `z`: sorted set commands.
+pub const ZSET: u32 = 1 << 7;
+/// `x`: keys removed once their TTL elapsed.
+pub const EXPIRED: u32 = 1 << 8;
+/// `e`: keys evicted to honour the memory limit.
+pub const EVICTED: u32 = 1 << 9;
+/// `t`: stream commands.
+pub const STREAM: u32 = 1 << 10;
+/// `m`: reads of keys that do not exist. Not part of `A`.
+pub const KEY_MISS: u32 = 1 << 11;
+/// `n`: keys being created. Not part of `A`.
+pub const NEW: u32 = 1 << 12;
+/// `A`: alias for `g$lshzxet`.
+pub const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM;
+
+/// Only one database is supported for now.
+const DB: usize = 0;
+
+/// Enabled classes, empty by default as in Redis.
+static FLAGS: AtomicU32 = AtomicU32::new(0);
+
+/// Classes covered by `A`, in the order `CONFIG GET` lists them.
+const CLASSES: [(char, u32); 9] = [
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+];
+
+pub fn flags() -> u32 {
+    FLAGS.load(Ordering::Relaxed)
+}
+
+pub fn set_flags(flags: u32) {
+    FLAGS.store(flags, Ordering::Relaxed);
+}
+
+/// Parses a `notify-keyspace-events` value, `None` on an unknown character.
+pub fn parse_flags(s: &str) -> Option<u32> {
+    s.chars().try_fold(0, |flags, c| {
+        let flag = match c {
+            'A' => ALL,
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            'm' => KEY_MISS,
+            'n' => NEW,
+            c => CLASSES.iter().find(|(class, _)| *class == c)?.1,
+        };
+        Some(flags | flag)
+    })
+}
+
+/// Formats flags back the way `CONFIG GET` reports them.
+pub fn flags_to_string(flags: u32) -> String {
+    let mut res = String::new();
+    if flags & ALL == ALL {
+        res.push('A');
+    } else {
+        res.extend(
+            CLASSES
+                .iter()
+                .filter(|(_, flag)| flags & flag != 0)
+                .map(|(class, _)| class),
+        );
+    }
+    for (class, flag) in [
+        ('K', KEYSPACE),
+        ('E', KEYEVENT),
+        ('m', KEY_MISS),
+        ('n', NEW),
+    ] {
+        if flags & flag != 0 {
+            res.push(class);
+        }
+    }
+    res
+}
+
+/// Publishes `event` for `key` if its class is enabled, along with at least
+/// one of `K` and `E`.
+pub async fn notify_keyspace_event(class: u32, event: &str, key: &str) {
+    let flags = flags();
+    if flags & class == 0 || flags & (KEYSPACE | KEYEVENT) == 0 {
+        return;
+    }
+    let mut pubsub = PUBSUB.lock().await;
+    if flags & KEYSPACE != 0 {
+        pubsub.publish(&format!("__keyspace@{}__:{}", DB, key), event);
+    }
+    if flags & KEYEVENT != 0 {
+        pubsub.publish(&format!("__keyevent@{}__:{}", DB, event), key);
+    }
+}
@@ -1,26 +1,76 @@
-#[derive(Debug)]
+use crate::internal::resp::Reply;
+
+#[derive(Debug, Clone)]
 pub struct Command {
     pub cmd: String,
     pub args: Vec<String>,
-    pub raw_cmd: String,
+    /// The request as received, which propagation and the AOF replay as is.
+    pub raw_cmd: Vec<u8>,
+}
+
+impl Command {
+    /// A command as a client would send it, e.g. to log a rewritten form.
+    pub fn from_args(cmd: String, args: Vec<String>) -> Self {
+        let raw_cmd = Reply::bulks(std::iter::once(&cmd).chain(&args).cloned()).encode(2);
+        Command { cmd, args, raw_cmd }
+    }
+
+    /// Argument `index` as the bytes the client sent. `args` holds a lossy
+    /// UTF-8 version of each argument, which binary values such as `DUMP`
+    /// payloads don't survive.
+    pub fn arg_bytes(&self, index: usize) -> Option<Vec<u8>> {
+        if !self.raw_cmd.starts_with(b"*") {
+            // Inline arguments can hold any byte through `\xHH` escapes.
+            let line = self.raw_cmd.strip_suffix(b"\n").unwrap_or(&self.raw_cmd);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            return split_args(line)?.into_iter().nth(index + 1);
+        }
+        let (_, mut cursor) = read_line(&self.raw_cmd, 0)?;
+        // The command name comes first.
+        for _ in 0..=index {
+            let (len_line, next) = read_line(&self.raw_cmd, cursor)?;
+            cursor = next + parse_len(len_line.get(1..)?)? as usize + 2;
+        }
+        let (len_line, next) = read_line(&self.raw_cmd, cursor)?;
+        let len = parse_len(len_line.get(1..)?)? as usize;
+        self.raw_cmd.get(next..next + len).map(<[u8]>::to_vec)
+    }
 }
 
-pub fn parse_request(buf: &[u8]) -> Result<Vec<Command>, String> {
-    // let body = String::from_utf8_lossy(buf).into_owned();
-    // FIXME: spliting the commands with '*' breaks the command if it contains
-    // an astrisk and example is `REPLCONF GETACK *`
+/// Largest number of arguments accepted in a request, as in Redis.
+const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+/// Largest argument accepted, Redis' default `proto-max-bulk-len`.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Longest inline request, so a client never sending a newline cannot make
+/// the buffer grow forever.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// Parses the complete requests at the start of `buf`, returning them along
+/// with the number of bytes they took. A trailing incomplete request is left
+/// for the caller to complete with the next read.
+///
+/// Errors are unrecoverable framing errors, after which the connection must
+/// be closed since the next request cannot be located.
+pub fn parse_request(buf: &[u8]) -> Result<(Vec<Command>, usize), String> {
     let mut cursor = 0;
     let mut commands: Vec<Command> = Vec::new();
     while cursor < buf.len() {
-        match parse_command(buf, cursor)? {
-            Some((command, new_cursor)) => {
+        let parsed = if buf[cursor] == b'*' {
+            parse_command(buf, cursor)?
+        } else {
+            parse_inline(buf, cursor)?
+        };
+        match parsed {
+            Some((Some(command), new_cursor)) => {
                 commands.push(command);
                 cursor = new_cursor;
             }
+            // Empty multibulk, ignored like Redis does.
+            Some((None, new_cursor)) => cursor = new_cursor,
             None => break,
         }
     }
-    Ok(commands)
+    Ok((commands, cursor))
 }
 
 fn read_line(buf: &[u8], cursor: usize) -> Option<(&[u8], usize)> {
@@ -32,53 +82,299 @@ fn read_line(buf: &[u8], cursor: usize) -> Option<(&[u8], usize)> {
     None
 }
 
-fn parse_command(buf: &[u8], cursor: usize) -> Result<Option<(Command, usize)>, String> {
-    if cursor >= buf.len() || buf[cursor] != b'*' {
-        return Ok(None);
-    }
+fn parse_len(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Parses the multibulk request at `cursor`, `None` when it is incomplete.
+pub fn parse_command(
+    buf: &[u8],
+    cursor: usize,
+) -> Result<Option<(Option<Command>, usize)>, String> {
     let start = cursor;
-    let (header, mut cursor) =
-        read_line(buf, cursor).ok_or_else(|| "Incomplete array header".to_string())?;
-    let n_args: usize = std::str::from_utf8(&header[1..])
-        .map_err(|e| e.to_string())?
-        .parse()
-        .map_err(|_| "Invalid array length".to_string())?;
-
-    if n_args == 0 {
+    let Some((header, mut cursor)) = read_line(buf, cursor) else {
         return Ok(None);
+    };
+    let n_args = match parse_len(&header[1..]) {
+        Some(n) if n <= MAX_MULTIBULK_LEN as i64 => n,
+        _ => return Err("Protocol error: invalid multibulk length".to_string()),
+    };
+    if n_args <= 0 {
+        return Ok(Some((None, cursor)));
     }
 
-    let mut parts: Vec<String> = Vec::with_capacity(n_args);
+    let mut parts: Vec<String> = Vec::with_capacity(n_args as usize);
     for _ in 0..n_args {
-        let (len_line, next) =
-            read_line(buf, cursor).ok_or_else(|| "Incomplete bulk string length".to_string())?;
+        let Some((len_line, next)) = read_line(buf, cursor) else {
+            return Ok(None);
+        };
         cursor = next;
 
-        if len_line.is_empty() || len_line[0] != b'$' {
-            return Err("Expected bulk string".to_string());
+        match len_line.first() {
+            Some(b'$') => {}
+            other => {
+                return Err(format!(
+                    "Protocol error: expected '$', got '{}'",
+                    other.map_or(' ', |b| *b as char)
+                ))
+            }
         }
-
-        let len: usize = std::str::from_utf8(&len_line[1..])
-            .map_err(|e| e.to_string())?
-            .parse()
-            .map_err(|_| "Invalid bulk string length".to_string())?;
+        let len = match parse_len(&len_line[1..]) {
+            Some(len) if (0..=MAX_BULK_LEN as i64).contains(&len) => len as usize,
+            _ => return Err("Protocol error: invalid bulk length".to_string()),
+        };
 
         if cursor + len + 2 > buf.len() {
-            return Err("Incomplete bulk string data".to_string());
+            return Ok(None);
         }
 
         let data = &buf[cursor..cursor + len];
         cursor += len + 2;
-        let s = std::str::from_utf8(data)
-            .map_err(|e| e.to_string())?
-            .to_string();
-        parts.push(s);
+        parts.push(String::from_utf8_lossy(data).into_owned());
+    }
+
+    let cmd = parts.remove(0);
+    let args = parts;
+
+    let raw_cmd = buf[start..cursor].to_vec();
+
+    Ok(Some((Some(Command { cmd, args, raw_cmd }), cursor)))
+}
+
+/// Parses an inline request, as typed over telnet: a single line of
+/// whitespace separated arguments. `None` when the line is incomplete.
+fn parse_inline(buf: &[u8], cursor: usize) -> Result<Option<(Option<Command>, usize)>, String> {
+    let Some(end) = buf[cursor..].iter().position(|b| *b == b'\n') else {
+        if buf.len() - cursor > MAX_INLINE_LEN {
+            return Err("Protocol error: too big inline request".to_string());
+        }
+        return Ok(None);
+    };
+    let line = &buf[cursor..cursor + end];
+    let next = cursor + end + 1;
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let mut parts: Vec<String> = split_args(line)
+        .ok_or_else(|| "Protocol error: unbalanced quotes in request".to_string())?
+        .iter()
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    if parts.is_empty() {
+        return Ok(Some((None, next)));
     }
 
     let cmd = parts.remove(0);
     let args = parts;
 
-    let raw_cmd = String::from_utf8_lossy(&buf[start..cursor]).into_owned();
+    let raw_cmd = buf[cursor..next].to_vec();
+
+    Ok(Some((Some(Command { cmd, args, raw_cmd }), next)))
+}
 
-    Ok(Some((Command { cmd, args, raw_cmd }, cursor)))
+/// Splits a line into arguments the way `redis-cli` and Redis do: double
+/// quoted arguments support `\n`-like and `\xHH` escapes, single quoted ones
+/// only `\'`. `None` on unbalanced quotes.
+fn split_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Some(args);
+        }
+        let mut arg = Vec::new();
+        match line[i] {
+            b'"' => {
+                i += 1;
+                loop {
+                    match *line.get(i)? {
+                        b'\\'
+                            if i + 3 < line.len()
+                                && line[i + 1] == b'x'
+                                && line[i + 2].is_ascii_hexdigit()
+                                && line[i + 3].is_ascii_hexdigit() =>
+                        {
+                            let hex = std::str::from_utf8(&line[i + 2..i + 4]).ok()?;
+                            arg.push(u8::from_str_radix(hex, 16).ok()?);
+                            i += 4;
+                        }
+                        b'\\' if i + 1 < line.len() => {
+                            arg.push(match line[i + 1] {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                c => c,
+                            });
+                            i += 2;
+                        }
+                        b'"' => {
+                            i += 1;
+                            break;
+                        }
+                        c => {
+                            arg.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            b'\'' => {
+                i += 1;
+                loop {
+                    match *line.get(i)? {
+                        b'\\' if line.get(i + 1) == Some(&b'\'') => {
+                            arg.push(b'\'');
+                            i += 2;
+                        }
+                        b'\'' => {
+                            i += 1;
+                            break;
+                        }
+                        c => {
+                            arg.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            _ => {
+                while i < line.len() && !line[i].is_ascii_whitespace() {
+                    arg.push(line[i]);
+                    i += 1;
+                }
+            }
+        }
+        // A closing quote must be followed by a space or the end of line.
+        if i < line.len() && !line[i].is_ascii_whitespace() {
+            return None;
+        }
+        args.push(arg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::commands;
+
+    fn parse(buf: &[u8]) -> (Vec<(String, Vec<String>)>, usize) {
+        let (commands, consumed) = parse_request(buf).unwrap();
+        let commands = commands
+            .into_iter()
+            .map(|command| (command.cmd, command.args))
+            .collect();
+        (commands, consumed)
+    }
+
+    fn command(cmd: &str, args: &[&str]) -> (String, Vec<String>) {
+        (
+            cmd.to_string(),
+            args.iter().map(|arg| arg.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn parses_pipelined_multibulk_requests() {
+        let buf = b"*1\r\n$4\r\nPING\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$0\r\n\r\n";
+        assert_eq!(
+            parse(buf),
+            (
+                vec![command("PING", &[]), command("SET", &["k", ""])],
+                buf.len()
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_incomplete_requests_in_the_buffer() {
+        let buf = b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$1\r\nk";
+        for end in 14..buf.len() {
+            assert_eq!(parse(&buf[..end]), (vec![command("PING", &[])], 14));
+        }
+        for end in 0..14 {
+            assert_eq!(parse(&buf[..end]), (vec![], 0));
+        }
+    }
+
+    #[test]
+    fn skips_empty_multibulks() {
+        assert_eq!(parse(b"*0\r\n*-1\r\n"), (vec![], 9));
+    }
+
+    #[test]
+    fn rejects_malformed_multibulks() {
+        for buf in [
+            &b"*x\r\n"[..],
+            b"*2000000\r\n",
+            b"*1\r\n:4\r\nPING\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*1\r\n$abc\r\n",
+            b"*1\r\n$536870913\r\n",
+        ] {
+            assert!(parse_request(buf).is_err(), "{:?}", buf);
+        }
+    }
+
+    #[test]
+    fn multibulk_arguments_keep_their_bytes() {
+        let buf = b"*2\r\n$4\r\nECHO\r\n$3\r\n\xff\x00\r\r\n";
+        let (commands, _) = parse_request(buf).unwrap();
+        assert_eq!(commands[0].arg_bytes(0), Some(b"\xff\x00\r".to_vec()));
+        assert_eq!(commands[0].arg_bytes(1), None);
+        assert_eq!(commands[0].raw_cmd, buf);
+    }
+
+    #[test]
+    fn parses_inline_requests() {
+        let buf = b"PING\r\nSET  k \"a b\"\n\r\nGET 'it\\'s'\r\n";
+        assert_eq!(
+            parse(buf),
+            (
+                vec![
+                    command("PING", &[]),
+                    command("SET", &["k", "a b"]),
+                    command("GET", &["it's"]),
+                ],
+                buf.len()
+            )
+        );
+        assert_eq!(parse(b"PING"), (vec![], 0));
+    }
+
+    #[test]
+    fn inline_escapes_keep_their_bytes() {
+        let buf = b"SET k \"\\x00\\xff\\n\\\"\"\r\n";
+        let (commands, _) = parse_request(buf).unwrap();
+        assert_eq!(commands[0].arg_bytes(0), Some(b"k".to_vec()));
+        assert_eq!(commands[0].arg_bytes(1), Some(b"\x00\xff\n\"".to_vec()));
+        assert_eq!(commands[0].arg_bytes(2), None);
+    }
+
+    #[test]
+    fn unknown_command_errors_stay_on_one_line() {
+        let long = "x".repeat(200);
+        let buf = format!("*3\r\n$3\r\nFOO\r\n$4\r\na\r\nb\r\n$200\r\n{}\r\n", long);
+        let (commands, _) = parse_request(buf.as_bytes()).unwrap();
+        let reply: Reply = commands::unknown_command(&commands[0]).into();
+        let expected = format!(
+            "-ERR unknown command 'FOO', with args beginning with: 'a  b' '{}' \r\n",
+            &long[..128]
+        );
+        assert_eq!(reply.encode(2), expected.into_bytes());
+        assert_eq!(
+            Reply::Error("ERR a\r\nb".to_string()).encode(2),
+            b"-ERR a  b\r\n"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_inline_requests() {
+        for buf in [&b"GET \"k\r\n"[..], b"GET 'k\r\n", b"GET \"k\"x\r\n"] {
+            assert!(parse_request(buf).is_err(), "{:?}", buf);
+        }
+        assert!(parse_request(&vec![b'a'; MAX_INLINE_LEN + 1]).is_err());
+    }
 }
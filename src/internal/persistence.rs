@@ -0,0 +1,323 @@
+//! RDB snapshots on disk: `SAVE`, `BGSAVE`, the `save` points triggering
+//! background saves, and the `persistence` section of `INFO`.
+//!
+//! A background save copies the keyspace while holding the storage lock, the
+//! way a forked child shares memory with Redis, then serialises and writes the
+//! copy on a blocking thread while clients keep being served.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::RwLock;
+
+use crate::internal::{
+    aof, rdb,
+    server::ServerMetadata,
+    storage::{DBEntry, STORAGE},
+    transaction::EXEC_LOCK,
+    types::now_millis,
+};
+
+/// Default `save` points: after an hour if anything changed, 5 minutes
+/// after 100 changes, a minute after 10000.
+const DEFAULT_SAVE_PARAMS: [(u64, u64); 3] = [(3600, 1), (300, 100), (60, 10000)];
+
+/// Save points don't retry a failed background save more often than this.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+const CRON_PERIOD: Duration = Duration::from_millis(100);
+
+/// Changes to the keyspace since the last successful save.
+static DIRTY: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a dataset is being loaded, as when a replica loads the snapshot
+/// sent by its master.
+static LOADING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+struct State {
+    /// `save` points as (seconds, changes).
+    save_params: Vec<(u64, u64)>,
+    /// Unix time of the last successful save, in seconds.
+    last_save: u64,
+    last_bgsave_try: u64,
+    last_bgsave_ok: bool,
+    last_bgsave_time: Option<Duration>,
+    bgsave: Option<Bgsave>,
+    /// `BGSAVE SCHEDULE` asked for a save while another one was running.
+    bgsave_scheduled: bool,
+    saves: u64,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            save_params: DEFAULT_SAVE_PARAMS.to_vec(),
+            last_save: now_secs(),
+            last_bgsave_try: 0,
+            last_bgsave_ok: true,
+            last_bgsave_time: None,
+            bgsave: None,
+            bgsave_scheduled: false,
+            saves: 0,
+        }
+    }
+}
+
+struct Bgsave {
+    started: Instant,
+    /// Changes counted when the snapshot was taken: the ones made since
+    /// remain unsaved once it completes.
+    dirty: u64,
+}
+
+fn now_secs() -> u64 {
+    now_millis() / 1000
+}
+
+/// Accounts for changes made to the keyspace.
+pub fn mark_dirty(changes: u64) {
+    DIRTY.fetch_add(changes, Ordering::Relaxed);
+}
+
+pub fn loading() -> bool {
+    LOADING.load(Ordering::Relaxed)
+}
+
+pub fn set_loading(loading: bool) {
+    LOADING.store(loading, Ordering::Relaxed);
+}
+
+pub fn bgsave_in_progress() -> bool {
+    STATE.lock().unwrap().bgsave.is_some()
+}
+
+/// `LASTSAVE`.
+pub fn last_save() -> u64 {
+    STATE.lock().unwrap().last_save
+}
+
+/// The `save` config, as `<seconds> <changes>` pairs.
+pub fn save_params() -> String {
+    STATE
+        .lock()
+        .unwrap()
+        .save_params
+        .iter()
+        .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Sets the `save` config, an empty string disabling save points.
+pub fn set_save_params(value: &str) -> Result<(), String> {
+    let numbers = value
+        .split_whitespace()
+        .map(|n| n.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "Invalid save parameters".to_string())?;
+    if numbers.len() % 2 == 1 {
+        return Err("Invalid save parameters".to_string());
+    }
+    STATE.lock().unwrap().save_params = numbers
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .collect();
+    Ok(())
+}
+
+/// Writes `data` to `dir/dbfilename` through a temporary file renamed over
+/// it, so a crash never leaves a partial dump behind.
+fn write_file(dir: &Path, dbfilename: &str, data: &[u8]) -> std::io::Result<()> {
+    let temp = dir.join(format!("temp-{}.rdb", std::process::id()));
+    let result = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, dir.join(dbfilename)));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// `SAVE`: writes the dump while holding the keyspace, blocking clients.
+pub async fn save(dir: &Path, dbfilename: &str) -> Result<(), String> {
+    if STATE.lock().unwrap().bgsave.is_some() {
+        return Err("Background save already in progress".to_string());
+    }
+    let storage = STORAGE.lock().await;
+    let data = rdb::encode(storage.iter());
+    let result = write_file(dir, dbfilename, &data);
+    drop(storage);
+    match result {
+        Ok(()) => {
+            DIRTY.store(0, Ordering::Relaxed);
+            let mut state = STATE.lock().unwrap();
+            state.last_save = now_secs();
+            state.last_bgsave_ok = true;
+            state.saves += 1;
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Failed saving the DB: {}", e);
+            Err(format!("Failed saving the DB: {}", e))
+        }
+    }
+}
+
+/// `BGSAVE [SCHEDULE]`, returning the status to reply with.
+pub async fn bgsave(
+    dir: PathBuf,
+    dbfilename: String,
+    schedule: bool,
+) -> Result<&'static str, String> {
+    let rewrite = aof::rewrite_in_progress();
+    {
+        let mut state = STATE.lock().unwrap();
+        if state.bgsave.is_some() && !schedule {
+            return Err("Background save already in progress".to_string());
+        }
+        if rewrite && !schedule {
+            return Err(
+                "Another child process is active (AOF?): can't BGSAVE right now. \
+                 Use BGSAVE SCHEDULE in order to schedule a BGSAVE whenever possible"
+                    .to_string(),
+            );
+        }
+        if state.bgsave.is_some() || rewrite {
+            state.bgsave_scheduled = true;
+            return Ok("Background saving scheduled");
+        }
+    }
+    start_bgsave(dir, dbfilename).await;
+    Ok("Background saving started")
+}
+
+async fn start_bgsave(dir: PathBuf, dbfilename: String) {
+    {
+        let mut state = STATE.lock().unwrap();
+        state.bgsave = Some(Bgsave {
+            started: Instant::now(),
+            dirty: 0,
+        });
+        state.last_bgsave_try = now_secs();
+    }
+    let snapshot: Vec<(String, DBEntry)> = {
+        let storage = STORAGE.lock().await;
+        let dirty = DIRTY.load(Ordering::Relaxed);
+        if let Some(bgsave) = STATE.lock().unwrap().bgsave.as_mut() {
+            bgsave.dirty = dirty;
+        }
+        storage
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect()
+    };
+    tokio::spawn(async move {
+        let result = tokio::task::spawn_blocking(move || {
+            let data = rdb::encode(snapshot.iter().map(|(key, entry)| (key, entry)));
+            write_file(&dir, &dbfilename, &data)
+        })
+        .await;
+        let mut state = STATE.lock().unwrap();
+        let Some(bgsave) = state.bgsave.take() else {
+            return;
+        };
+        state.last_bgsave_time = Some(bgsave.started.elapsed());
+        match result {
+            Ok(Ok(())) => {
+                let _ = DIRTY.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |dirty| {
+                    Some(dirty.saturating_sub(bgsave.dirty))
+                });
+                state.last_save = now_secs();
+                state.last_bgsave_ok = true;
+                state.saves += 1;
+            }
+            Ok(Err(e)) => {
+                eprintln!("Background saving error: {}", e);
+                state.last_bgsave_ok = false;
+            }
+            Err(e) => {
+                eprintln!("Background saving terminated with failure: {}", e);
+                state.last_bgsave_ok = false;
+            }
+        }
+    });
+}
+
+/// Starts background saves when a `save` point is reached or one was
+/// scheduled, like Redis' `serverCron`.
+pub async fn cron(server_metadata: Arc<RwLock<ServerMetadata>>) {
+    let mut interval = tokio::time::interval(CRON_PERIOD);
+    loop {
+        interval.tick().await;
+        let rewrite = aof::rewrite_in_progress();
+        let due = {
+            let mut state = STATE.lock().unwrap();
+            if state.bgsave.is_some() || rewrite {
+                false
+            } else if state.bgsave_scheduled {
+                state.bgsave_scheduled = false;
+                true
+            } else {
+                let now = now_secs();
+                let dirty = DIRTY.load(Ordering::Relaxed);
+                let can_retry =
+                    state.last_bgsave_ok || now - state.last_bgsave_try > BGSAVE_RETRY_DELAY;
+                can_retry
+                    && state.save_params.iter().any(|&(seconds, changes)| {
+                        dirty >= changes && now - state.last_save >= seconds
+                    })
+            }
+        };
+        if due {
+            let metadata = server_metadata.read().await;
+            let (dir, dbfilename) = (metadata.dir.clone(), metadata.dbfilename.clone());
+            drop(metadata);
+            // Keeps a half-applied transaction out of the snapshot.
+            let _exclusive = EXEC_LOCK.write().await;
+            start_bgsave(dir, dbfilename).await;
+        }
+    }
+}
+
+/// The `rdb_*` fields of `INFO persistence`.
+pub fn info() -> Vec<String> {
+    let state = STATE.lock().unwrap();
+    let seconds = |time: Option<Duration>| time.map_or(-1, |time| time.as_secs() as i64);
+    vec![
+        format!("loading:{}", loading() as u8),
+        format!(
+            "rdb_changes_since_last_save:{}",
+            DIRTY.load(Ordering::Relaxed)
+        ),
+        format!("rdb_bgsave_in_progress:{}", state.bgsave.is_some() as u8),
+        format!("rdb_last_save_time:{}", state.last_save),
+        format!(
+            "rdb_last_bgsave_status:{}",
+            if state.last_bgsave_ok { "ok" } else { "err" }
+        ),
+        format!(
+            "rdb_last_bgsave_time_sec:{}",
+            seconds(state.last_bgsave_time)
+        ),
+        format!(
+            "rdb_current_bgsave_time_sec:{}",
+            seconds(state.bgsave.as_ref().map(|bgsave| bgsave.started.elapsed()))
+        ),
+        format!("rdb_saves:{}", state.saves),
+    ]
+}
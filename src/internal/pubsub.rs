@@ -0,0 +1,443 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::internal::{
+    acl, client, cluster, commands, glob::glob_match, parser::Command, resp::Reply,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Mutex, RwLock,
+    },
+};
+
+/// Messages buffered for a subscriber before it is considered too slow and
+/// disconnected, so publishers never wait on a subscriber's socket.
+pub const SUBSCRIBER_QUEUE_LEN: usize = 1024;
+
+lazy_static! {
+    pub static ref PUBSUB: Mutex<PubSub> = Mutex::new(PubSub::default());
+}
+
+/// Sending side of a connection's message queue.
+#[derive(Clone)]
+pub struct Subscriber {
+    sender: mpsc::Sender<Arc<Reply>>,
+    evicted: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    /// Queues the frame, flagging the subscriber for eviction when its queue
+    /// is full. Returns whether the frame was queued.
+    pub fn deliver(&self, frame: &Arc<Reply>) -> bool {
+        match self.sender.try_send(Arc::clone(frame)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.evicted.store(true, Ordering::SeqCst);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// The flavours of subscription a connection can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Channel,
+    Pattern,
+    /// Shard channels, scoped to the node owning the channel's slot.
+    Shard,
+}
+
+impl Kind {
+    fn subscribe_reply(self) -> &'static str {
+        match self {
+            Kind::Channel => "subscribe",
+            Kind::Pattern => "psubscribe",
+            Kind::Shard => "ssubscribe",
+        }
+    }
+
+    fn unsubscribe_reply(self) -> &'static str {
+        match self {
+            Kind::Channel => "unsubscribe",
+            Kind::Pattern => "punsubscribe",
+            Kind::Shard => "sunsubscribe",
+        }
+    }
+}
+
+type SubscriberMap = HashMap<String, HashMap<u64, Subscriber>>;
+
+/// Channel, pattern and shard channel registry shared by all connections.
+#[derive(Default)]
+pub struct PubSub {
+    channels: SubscriberMap,
+    patterns: SubscriberMap,
+    shard_channels: SubscriberMap,
+}
+
+impl PubSub {
+    fn map_mut(&mut self, kind: Kind) -> &mut SubscriberMap {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    fn subscribe(&mut self, kind: Kind, name: &str, id: u64, subscriber: &Subscriber) {
+        self.map_mut(kind)
+            .entry(name.to_string())
+            .or_default()
+            .insert(id, subscriber.clone());
+    }
+
+    fn unsubscribe(&mut self, kind: Kind, name: &str, id: u64) {
+        let map = self.map_mut(kind);
+        if let Some(subscribers) = map.get_mut(name) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                map.remove(name);
+            }
+        }
+    }
+
+    /// Delivers the message to channel and pattern subscribers, returning how
+    /// many of them received it.
+    pub fn publish(&mut self, channel: &str, message: &str) -> usize {
+        let mut receivers = 0;
+        let mut evicted = Vec::new();
+
+        if let Some(subscribers) = self.channels.get(channel) {
+            let frame = Arc::new(message_frame(channel, message));
+            for (id, subscriber) in subscribers {
+                if subscriber.deliver(&frame) {
+                    receivers += 1;
+                } else {
+                    evicted.push(*id);
+                }
+            }
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            let frame = Arc::new(pmessage_frame(pattern, channel, message));
+            for (id, subscriber) in subscribers {
+                if subscriber.deliver(&frame) {
+                    receivers += 1;
+                } else {
+                    evicted.push(*id);
+                }
+            }
+        }
+
+        for id in evicted {
+            self.evict(id);
+        }
+        receivers
+    }
+
+    /// Delivers the message to the shard channel subscribers only, patterns
+    /// never match shard channels.
+    pub fn spublish(&mut self, channel: &str, message: &str) -> usize {
+        let Some(subscribers) = self.shard_channels.get(channel) else {
+            return 0;
+        };
+        let frame = Arc::new(smessage_frame(channel, message));
+        let mut receivers = 0;
+        let mut evicted = Vec::new();
+        for (id, subscriber) in subscribers {
+            if subscriber.deliver(&frame) {
+                receivers += 1;
+            } else {
+                evicted.push(*id);
+            }
+        }
+        for id in evicted {
+            self.evict(id);
+        }
+        receivers
+    }
+
+    /// Drops every subscription of a connection that could not keep up.
+    fn evict(&mut self, id: u64) {
+        for map in [
+            &mut self.channels,
+            &mut self.patterns,
+            &mut self.shard_channels,
+        ] {
+            map.retain(|_, subscribers| {
+                subscribers.remove(&id);
+                !subscribers.is_empty()
+            });
+        }
+    }
+
+    /// Channels with at least one subscriber, optionally filtered by a pattern.
+    pub fn active_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        _active(&self.channels, pattern)
+    }
+
+    /// Shard channels with at least one subscriber, optionally filtered by a
+    /// pattern.
+    pub fn active_shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        _active(&self.shard_channels, pattern)
+    }
+
+    pub fn numsub(&self, channel: &str) -> usize {
+        self.channels.get(channel).map_or(0, HashMap::len)
+    }
+
+    pub fn shard_numsub(&self, channel: &str) -> usize {
+        self.shard_channels.get(channel).map_or(0, HashMap::len)
+    }
+
+    /// Number of distinct patterns subscribed to.
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+fn _active(map: &SubscriberMap, pattern: Option<&str>) -> Vec<String> {
+    map.keys()
+        .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+        .cloned()
+        .collect()
+}
+
+pub fn message_frame(channel: &str, message: &str) -> Reply {
+    Reply::Push(vec![
+        Reply::bulk("message"),
+        Reply::bulk(channel),
+        Reply::bulk(message),
+    ])
+}
+
+pub fn smessage_frame(channel: &str, message: &str) -> Reply {
+    Reply::Push(vec![
+        Reply::bulk("smessage"),
+        Reply::bulk(channel),
+        Reply::bulk(message),
+    ])
+}
+
+pub fn pmessage_frame(pattern: &str, channel: &str, message: &str) -> Reply {
+    Reply::Push(vec![
+        Reply::bulk("pmessage"),
+        Reply::bulk(pattern),
+        Reply::bulk(channel),
+        Reply::bulk(message),
+    ])
+}
+
+/// Per-connection subscription state and the receiving side of its queue.
+pub struct Subscriptions {
+    id: u64,
+    subscriber: Subscriber,
+    receiver: mpsc::Receiver<Arc<Reply>>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+    shard_channels: BTreeSet<String>,
+}
+
+impl Subscriptions {
+    pub fn new(id: u64) -> Self {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_QUEUE_LEN);
+        Subscriptions {
+            id,
+            subscriber: Subscriber {
+                sender,
+                evicted: Arc::new(AtomicBool::new(false)),
+            },
+            receiver,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            shard_channels: BTreeSet::new(),
+        }
+    }
+
+    /// Sending side of the connection's queue, for messages beyond its
+    /// subscriptions such as tracking invalidations.
+    pub fn subscriber(&self) -> Subscriber {
+        self.subscriber.clone()
+    }
+
+    /// Whether the connection is in subscriber mode.
+    pub fn is_active(&self) -> bool {
+        self.count() > 0
+    }
+
+    /// Channels, patterns and shard channels subscribed to.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        (
+            self.channels.len(),
+            self.patterns.len(),
+            self.shard_channels.len(),
+        )
+    }
+
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    fn names_mut(&mut self, kind: Kind) -> &mut BTreeSet<String> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// Count reported in (un)subscribe replies: shard channels are accounted
+    /// for separately from channels and patterns.
+    fn reply_count(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Shard => self.shard_channels.len(),
+            _ => self.channels.len() + self.patterns.len(),
+        }
+    }
+
+    /// Whether publishers dropped this connection for falling behind.
+    pub fn evicted(&self) -> bool {
+        self.subscriber.evicted.load(Ordering::SeqCst)
+    }
+
+    /// Next message published to one of the connection's subscriptions.
+    pub async fn recv(&mut self) -> Option<Arc<Reply>> {
+        self.receiver.recv().await
+    }
+
+    /// Handles the subscribe family and enforces subscriber mode, where only
+    /// a handful of commands are accepted. Returns the command back when it
+    /// should go through the regular dispatch.
+    pub async fn process(
+        &mut self,
+        stream: &Arc<RwLock<TcpStream>>,
+        command: Command,
+        in_multi: bool,
+    ) -> Option<Command> {
+        let name = command.cmd.to_lowercase();
+        // Commands answered here rather than by the dispatch are checked
+        // against the user's permissions here too.
+        let answered_here = match name.as_str() {
+            "subscribe" | "psubscribe" | "ssubscribe" | "unsubscribe" | "punsubscribe"
+            | "sunsubscribe" => !in_multi,
+            "ping" => self.is_active() && client::protocol() == 2,
+            _ => false,
+        };
+        if answered_here {
+            if let Err(e) = acl::authorize(&command, false) {
+                commands::write_reply(stream, &e.into()).await;
+                return None;
+            }
+        }
+        let res = match name.as_str() {
+            "subscribe" | "psubscribe" | "ssubscribe" | "unsubscribe" | "punsubscribe"
+            | "sunsubscribe"
+                if in_multi =>
+            {
+                Reply::Error("ERR Command not allowed inside a transaction".to_string())
+            }
+            "subscribe" | "psubscribe" | "ssubscribe" if command.args.is_empty() => Reply::Error(
+                format!("ERR wrong number of arguments for '{}' command", name),
+            ),
+            "subscribe" => self.subscribe(&command.args, Kind::Channel).await,
+            "psubscribe" => self.subscribe(&command.args, Kind::Pattern).await,
+            "ssubscribe" => self.subscribe(&command.args, Kind::Shard).await,
+            "unsubscribe" => self.unsubscribe(&command.args, Kind::Channel).await,
+            "punsubscribe" => self.unsubscribe(&command.args, Kind::Pattern).await,
+            "sunsubscribe" => self.unsubscribe(&command.args, Kind::Shard).await,
+            // RESP3 clients can mix regular commands with their subscriptions
+            // since pushed messages are told apart from replies.
+            "ping" if self.is_active() && client::protocol() == 2 => {
+                let arg = command.args.first().map_or("", String::as_str);
+                Reply::bulks(["pong", arg])
+            }
+            "quit" | "reset" => return Some(command),
+            _ if self.is_active() && client::protocol() == 2 => Reply::Error(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                name
+            )),
+            _ => return Some(command),
+        };
+        commands::write_reply(stream, &res).await;
+        None
+    }
+
+    async fn subscribe(&mut self, names: &[String], kind: Kind) -> Reply {
+        if kind == Kind::Shard {
+            if let Err(e) = cluster::check_single_slot(names) {
+                return e.into();
+            }
+        }
+        let mut pubsub = PUBSUB.lock().await;
+        let mut res = Vec::new();
+        for name in names {
+            self.names_mut(kind).insert(name.clone());
+            pubsub.subscribe(kind, name, self.id, &self.subscriber);
+            res.push(Reply::Push(vec![
+                Reply::bulk(kind.subscribe_reply()),
+                Reply::bulk(name),
+                Reply::Integer(self.reply_count(kind) as i64),
+            ]));
+        }
+        Reply::Sequence(res)
+    }
+
+    /// Unsubscribes from the given channels (or patterns), or from all of
+    /// them when none is given.
+    async fn unsubscribe(&mut self, names: &[String], kind: Kind) -> Reply {
+        if kind == Kind::Shard {
+            if let Err(e) = cluster::check_single_slot(names) {
+                return e.into();
+            }
+        }
+        let names: Vec<String> = if names.is_empty() {
+            self.names_mut(kind).iter().cloned().collect()
+        } else {
+            names.to_vec()
+        };
+        if names.is_empty() {
+            return Reply::Push(vec![
+                Reply::bulk(kind.unsubscribe_reply()),
+                Reply::Null,
+                Reply::Integer(self.reply_count(kind) as i64),
+            ]);
+        }
+
+        let mut pubsub = PUBSUB.lock().await;
+        let mut res = Vec::new();
+        for name in names {
+            self.names_mut(kind).remove(&name);
+            pubsub.unsubscribe(kind, &name, self.id);
+            res.push(Reply::Push(vec![
+                Reply::bulk(kind.unsubscribe_reply()),
+                Reply::bulk(name),
+                Reply::Integer(self.reply_count(kind) as i64),
+            ]));
+        }
+        Reply::Sequence(res)
+    }
+
+    /// Silently drops every subscription, on `RESET` or disconnection.
+    pub async fn clear(&mut self) {
+        if !self.is_active() {
+            return;
+        }
+        let mut pubsub = PUBSUB.lock().await;
+        for kind in [Kind::Channel, Kind::Pattern, Kind::Shard] {
+            for name in std::mem::take(self.names_mut(kind)) {
+                pubsub.unsubscribe(kind, &name, self.id);
+            }
+        }
+    }
+}
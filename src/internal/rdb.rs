@@ -1,12 +1,155 @@
 use tokio::sync::MutexGuard;
 
-use crate::internal::storage::{DBEntry, STORAGE};
+use crate::internal::{
+    commands, crc64,
+    listpack::{self, ListpackEntry},
+    lzf,
+    storage::{self, DBEntry, STORAGE},
+    types::{
+        now_millis, Consumer, ConsumerGroup, DBValue, HashType, ListType, PendingEntry, SetType,
+        SortedSetType, StreamFields, StreamId, StreamType, StringType, STREAM_NODE_MAX_ENTRIES,
+    },
+};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error::Error,
+    fmt::{Display, Formatter},
     path::Path,
+    sync::atomic::{AtomicBool, Ordering},
     time::{Duration, UNIX_EPOCH},
 };
 
+// Value types
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE: u8 = 6;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const TYPE_HASH_METADATA_PRE_GA: u8 = 22;
+const TYPE_HASH_LISTPACK_EX_PRE_GA: u8 = 23;
+const TYPE_HASH_METADATA: u8 = 24;
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+// Opcodes
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xF6;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+/// Quicklist node holding a single large element rather than a listpack.
+const QUICKLIST_NODE_PLAIN: usize = 1;
+
+/// Stream entry flags, see `stream_node`.
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 1 << 1;
+
+// Module value opcodes
+const MODULE_OPCODE_EOF: usize = 0;
+const MODULE_OPCODE_SINT: usize = 1;
+const MODULE_OPCODE_UINT: usize = 2;
+const MODULE_OPCODE_FLOAT: usize = 3;
+const MODULE_OPCODE_DOUBLE: usize = 4;
+const MODULE_OPCODE_STRING: usize = 5;
+
+/// Newest RDB format version understood.
+const RDB_VERSION: u32 = 12;
+
+/// First RDB version ending with a checksum.
+const RDB_CHECKSUM_VERSION: u32 = 5;
+
+/// `rdbchecksum`: whether checksums are verified on load.
+static CHECKSUM: AtomicBool = AtomicBool::new(true);
+
+pub fn checksum_enabled() -> bool {
+    CHECKSUM.load(Ordering::Relaxed)
+}
+
+pub fn set_checksum(enabled: bool) {
+    CHECKSUM.store(enabled, Ordering::Relaxed);
+}
+
+/// Why an RDB file couldn't be loaded, with the offset in the file where the
+/// problem was found.
+#[derive(Debug)]
+pub enum RdbError {
+    Truncated {
+        offset: usize,
+    },
+    BadMagic,
+    UnsupportedVersion {
+        version: String,
+    },
+    BadOpcode {
+        opcode: u8,
+        offset: usize,
+    },
+    ChecksumMismatch {
+        expected: u64,
+        actual: u64,
+        offset: usize,
+    },
+    /// Contents that can't be decoded, e.g. a malformed listpack.
+    Invalid {
+        reason: String,
+        offset: usize,
+    },
+}
+
+impl Error for RdbError {}
+
+impl Display for RdbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RdbError::Truncated { offset } => {
+                write!(f, "Unexpected end of RDB file at offset {}", offset)
+            }
+            RdbError::BadMagic => write!(f, "Wrong signature at offset 0, not an RDB file"),
+            RdbError::UnsupportedVersion { version } => {
+                write!(f, "Can't handle RDB format version {} at offset 5", version)
+            }
+            RdbError::BadOpcode { opcode, offset } => {
+                write!(f, "Unknown RDB opcode {:#04x} at offset {}", opcode, offset)
+            }
+            RdbError::ChecksumMismatch {
+                expected,
+                actual,
+                offset,
+            } => write!(
+                f,
+                "Wrong RDB checksum at offset {}: expected {:016x}, got {:016x}",
+                offset, expected, actual
+            ),
+            RdbError::Invalid { reason, offset } => {
+                write!(f, "Invalid RDB contents at offset {}: {}", offset, reason)
+            }
+        }
+    }
+}
+
 // Create RDB reader
 struct RdbReader<'a> {
     data: &'a [u8],
@@ -18,136 +161,1711 @@ impl<'a> RdbReader<'a> {
         Self { data, pos: 0 }
     }
 
-    fn read_u8(&mut self) -> u8 {
-        let rtn = self.data[self.pos];
+    fn invalid(&self, offset: usize, reason: impl Into<String>) -> RdbError {
+        RdbError::Invalid {
+            reason: reason.into(),
+            offset,
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, RdbError> {
+        let rtn = *self
+            .data
+            .get(self.pos)
+            .ok_or(RdbError::Truncated { offset: self.pos })?;
         self.pos += 1;
-        rtn
+        Ok(rtn)
+    }
+
+    fn peek_u8(&self) -> Result<u8, RdbError> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or(RdbError::Truncated { offset: self.pos })
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], RdbError> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or(RdbError::Truncated {
+                offset: self.data.len(),
+            })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    /// Unix time in milliseconds, stored little endian.
+    fn read_millis(&mut self) -> Result<u64, RdbError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
     }
 
-    fn read_size(&mut self) -> usize {
-        let first = self.read_u8();
-        match first >> 6 {
+    fn read_size(&mut self) -> Result<usize, RdbError> {
+        let offset = self.pos;
+        let first = self.read_u8()?;
+        let size = match first >> 6 {
             0b00 => (first & 0x3F) as usize,
             0b01 => {
-                let second = self.read_u8();
+                let second = self.read_u8()?;
                 (((first & 0x3F) as usize) << 8) | second as usize
             }
-            0b10 => {
-                let bytes = [
-                    self.read_u8(),
-                    self.read_u8(),
-                    self.read_u8(),
-                    self.read_u8(),
-                ];
-                u32::from_be_bytes(bytes) as usize
-            }
-            _ => unreachable!("0b11 prefix is integer-string encoding, not a size"),
+            0b10 if first == 0x80 => u32::from_be_bytes(self.read_array()?) as usize,
+            0b10 if first == 0x81 => u64::from_be_bytes(self.read_array()?) as usize,
+            _ => return Err(self.invalid(offset, format!("bad length encoding {:#04x}", first))),
+        };
+        Ok(size)
+    }
+
+    /// A string as raw bytes, integer encoded strings being turned back into
+    /// their decimal representation and compressed ones decompressed.
+    fn read_blob(&mut self) -> Result<Vec<u8>, RdbError> {
+        let offset = self.pos;
+        let marker = self.peek_u8()?;
+        if marker >> 6 != 0b11 {
+            let len = self.read_size()?;
+            return Ok(self.read_bytes(len)?.to_vec());
         }
+        self.read_u8()?;
+        let value = match marker & 0x3f {
+            0 => self.read_u8()? as i8 as i64,
+            1 => i16::from_le_bytes(self.read_array()?) as i64,
+            2 => i32::from_le_bytes(self.read_array()?) as i64,
+            3 => {
+                let compressed_len = self.read_size()?;
+                let len = self.read_size()?;
+                let compressed = self.read_bytes(compressed_len)?;
+                return lzf::decompress(compressed, len)
+                    .ok_or_else(|| self.invalid(offset, "malformed LZF string"));
+            }
+            _ => return Err(self.invalid(offset, format!("bad string encoding {:#04x}", marker))),
+        };
+        Ok(value.to_string().into_bytes())
     }
 
-    fn read_string(&mut self) -> String {
-        let marker = self.data[self.pos];
-        if marker >> 6 == 0b11 {
-            self.read_u8();
-            match marker & 0x3f {
-                0 => {
-                    let value = self.read_u8();
-                    value.to_string()
-                }
-                1 => {
-                    let bytes = [self.read_u8(), self.read_u8()];
-                    u16::from_le_bytes(bytes).to_string()
-                }
-                2 => {
-                    let bytes = [
-                        self.read_u8(),
-                        self.read_u8(),
-                        self.read_u8(),
-                        self.read_u8(),
-                    ];
-                    u32::from_le_bytes(bytes).to_string()
-                }
-                _ => unreachable!("C3 is LZF compression, not used in this challenge"),
+    fn read_string(&mut self) -> Result<String, RdbError> {
+        Ok(String::from_utf8_lossy(&self.read_blob()?).into_owned())
+    }
+
+    /// Reads a string holding one of Redis' compact encodings and decodes
+    /// it, reporting malformed contents at the offset of the string.
+    fn read_encoded<T>(
+        &mut self,
+        encoding: &str,
+        decode: impl FnOnce(&[u8]) -> Option<T>,
+    ) -> Result<T, RdbError> {
+        let offset = self.pos;
+        let blob = self.read_blob()?;
+        decode(&blob).ok_or_else(|| self.invalid(offset, format!("malformed {}", encoding)))
+    }
+
+    /// A sorted set score of the original `ZSET` type, stored as a length
+    /// prefixed string with special lengths for NaN and infinities.
+    fn read_double(&mut self) -> Result<f64, RdbError> {
+        let score = match self.read_u8()? {
+            253 => f64::NAN,
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            len => parse_score(&String::from_utf8_lossy(self.read_bytes(len as usize)?)),
+        };
+        Ok(score)
+    }
+
+    fn read_binary_double(&mut self) -> Result<f64, RdbError> {
+        Ok(f64::from_le_bytes(self.read_array()?))
+    }
+
+    /// A stream ID in its raw 128 bit big endian form.
+    fn read_raw_stream_id(&mut self) -> Result<StreamId, RdbError> {
+        Ok(StreamId {
+            millis: u64::from_be_bytes(self.read_array()?),
+            seq: u64::from_be_bytes(self.read_array()?),
+        })
+    }
+
+    fn read_stream_id(&mut self) -> Result<StreamId, RdbError> {
+        Ok(StreamId {
+            millis: self.read_size()? as u64,
+            seq: self.read_size()? as u64,
+        })
+    }
+
+    /// Checks the `REDIS` signature and the format version that follows.
+    fn read_header(&mut self) -> Result<u32, RdbError> {
+        if self.read_bytes(5).ok() != Some(b"REDIS".as_slice()) {
+            return Err(RdbError::BadMagic);
+        }
+        let version = String::from_utf8_lossy(self.read_bytes(4)?).into_owned();
+        match version.parse() {
+            Ok(number @ 1..=RDB_VERSION) if version.bytes().all(|b| b.is_ascii_digit()) => {
+                Ok(number)
             }
-        } else {
-            let len = self.read_size();
-            let bytes = &self.data[self.pos..self.pos + len];
-            let string = String::from_utf8_lossy(bytes).into_owned();
-            self.pos += len;
-            string
+            _ => Err(RdbError::UnsupportedVersion { version }),
         }
     }
 }
 
-pub async fn load_rdb(dir: &Path, dbfilename: &str) {
+/// Loads the RDB file from `dir` into the keyspace. A missing file is an
+/// empty dataset; a corrupt one is an error.
+pub async fn load_rdb(dir: &Path, dbfilename: &str) -> Result<(), RdbError> {
     let path = dir.join(dbfilename);
     let data = match std::fs::read(&path) {
         Ok(bytes) => bytes,
-        Err(_) => return,
+        Err(_) => return Ok(()),
     };
+    load(&data).await.map(|_| ())
+}
 
-    let mut reader = RdbReader::new(&data);
-    reader.pos = 9;
+/// Loads the RDB at the start of `data` into the keyspace, returning its
+/// length: an AOF may go on with commands after it.
+pub async fn load(data: &[u8]) -> Result<usize, RdbError> {
+    let mut reader = RdbReader::new(data);
+    let version = reader.read_header()?;
     let mut storage = STORAGE.lock().await;
     let mut expiration: Option<u64> = None;
 
     loop {
-        let opcode = reader.read_u8();
+        let offset = reader.pos;
+        let opcode = reader.read_u8()?;
         match opcode {
-            0xFA => {
-                let _ = reader.read_string();
-                let _ = reader.read_string();
-            }
-            0xFE => {
-                let _ = reader.read_size();
-            }
-            0xFB => {
-                let _ = reader.read_size();
-                let _ = reader.read_size();
-            }
-            0xFC => {
-                let bytes = [
-                    reader.read_u8(),
-                    reader.read_u8(),
-                    reader.read_u8(),
-                    reader.read_u8(),
-                    reader.read_u8(),
-                    reader.read_u8(),
-                    reader.read_u8(),
-                    reader.read_u8(),
-                ];
-                expiration = Some(u64::from_le_bytes(bytes));
-            }
-            0xFD => {
-                let bytes = [
-                    reader.read_u8(),
-                    reader.read_u8(),
-                    reader.read_u8(),
-                    reader.read_u8(),
-                ];
-                let secs = u32::from_le_bytes(bytes);
+            OPCODE_AUX => {
+                let _ = reader.read_string()?;
+                let _ = reader.read_string()?;
+            }
+            OPCODE_SELECTDB => {
+                let _ = reader.read_size()?;
+            }
+            OPCODE_RESIZEDB => {
+                let db_size = reader.read_size()?;
+                let _expires_size = reader.read_size()?;
+                storage.reserve(db_size);
+            }
+            // Per cluster slot key counts, only useful to size hash tables.
+            OPCODE_SLOT_INFO => {
+                let _slot = reader.read_size()?;
+                let _slot_size = reader.read_size()?;
+                let _expires_slot_size = reader.read_size()?;
+            }
+            // Function libraries can't be run here: drop their code.
+            OPCODE_FUNCTION2 => {
+                let _library = reader.read_string()?;
+            }
+            OPCODE_FUNCTION_PRE_GA => {
+                return Err(reader.invalid(offset, "pre-release function format not supported"));
+            }
+            OPCODE_MODULE_AUX => {
+                let module_id = reader.read_size()?;
+                let when_offset = reader.pos;
+                if reader.read_size()? != MODULE_OPCODE_UINT {
+                    return Err(reader.invalid(when_offset, "bad module aux data"));
+                }
+                let _when = reader.read_size()?;
+                skip_module_value(&mut reader)?;
+                eprintln!(
+                    "Skipping auxiliary data of module type {}",
+                    module_type_name(module_id as u64)
+                );
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expiration = Some(reader.read_millis()?);
+            }
+            OPCODE_EXPIRETIME => {
+                let secs = u32::from_le_bytes(reader.read_array()?);
                 expiration = Some(secs as u64 * 1000);
             }
-            0x00 => {
-                create_value(&mut storage, &mut reader, expiration);
+            // LRU idle time and LFU counter of the next key, meaningless here.
+            OPCODE_IDLE => {
+                let _ = reader.read_size()?;
+            }
+            OPCODE_FREQ => {
+                let _ = reader.read_u8()?;
+            }
+            OPCODE_EOF => break,
+            value_type if is_value_type(value_type) => {
+                create_value(&mut storage, &mut reader, value_type, offset, expiration)?;
                 expiration = None;
             }
-            0xFF => break,
-            _ => break,
+            _ => return Err(RdbError::BadOpcode { opcode, offset }),
+        }
+    }
+
+    if version >= RDB_CHECKSUM_VERSION {
+        let computed = crc64::crc64(0, &data[..reader.pos]);
+        let offset = reader.pos;
+        let expected = u64::from_le_bytes(reader.read_array()?);
+        // A zero checksum means the file was saved with checksums disabled.
+        if checksum_enabled() && expected != 0 && expected != computed {
+            return Err(RdbError::ChecksumMismatch {
+                expected,
+                actual: computed,
+                offset,
+            });
         }
     }
+    Ok(reader.pos)
+}
+
+fn is_value_type(value_type: u8) -> bool {
+    matches!(
+        value_type,
+        TYPE_STRING..=TYPE_MODULE_2 | TYPE_HASH_ZIPMAP..=TYPE_HASH_LISTPACK_EX
+    )
 }
 
+/// Loads a key of the given type, found at `offset`.
 fn create_value(
     storage: &mut MutexGuard<'_, HashMap<String, DBEntry>>,
     reader: &mut RdbReader,
+    value_type: u8,
+    offset: usize,
     expiration_time: Option<u64>,
-) {
-    let key = reader.read_string();
-    let value = reader.read_string();
-    let mut db_entry = DBEntry::from_string(&value);
+) -> Result<(), RdbError> {
+    let key = reader.read_string()?;
+    let Some(mut db_entry) = read_value(reader, value_type, &key, offset)? else {
+        return Ok(());
+    };
     if let Some(ex_time) = expiration_time {
         db_entry.set_expiry_at(UNIX_EPOCH + Duration::from_millis(ex_time));
+        storage::track_expiry(&key);
     }
     storage.insert(key, db_entry);
+    Ok(())
+}
+
+/// Reads the value of `key`, of the given type. `None` for module values,
+/// which are skipped.
+fn read_value(
+    reader: &mut RdbReader,
+    value_type: u8,
+    key: &str,
+    offset: usize,
+) -> Result<Option<DBEntry>, RdbError> {
+    let db_entry = match value_type {
+        TYPE_STRING => DBEntry::from_string(&reader.read_blob()?),
+        TYPE_LIST => {
+            let len = reader.read_size()?;
+            let items = (0..len)
+                .map(|_| reader.read_string())
+                .collect::<Result<_, _>>()?;
+            DBEntry::from_value(ListType { items })
+        }
+        TYPE_LIST_ZIPLIST => DBEntry::from_value(ListType {
+            items: reader.read_encoded("ziplist", ziplist_entries)?.into(),
+        }),
+        TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+            let mut list = ListType::default();
+            let nodes = reader.read_size()?;
+            for _ in 0..nodes {
+                let container = match value_type {
+                    TYPE_LIST_QUICKLIST_2 => reader.read_size()?,
+                    _ => 0,
+                };
+                match (value_type, container) {
+                    (TYPE_LIST_QUICKLIST, _) => list
+                        .items
+                        .extend(reader.read_encoded("ziplist", ziplist_entries)?),
+                    (_, QUICKLIST_NODE_PLAIN) => list.items.push_back(reader.read_string()?),
+                    _ => list
+                        .items
+                        .extend(reader.read_encoded("listpack", listpack_strings)?),
+                }
+            }
+            DBEntry::from_value(list)
+        }
+        TYPE_SET => {
+            let len = reader.read_size()?;
+            let members = (0..len)
+                .map(|_| reader.read_string())
+                .collect::<Result<_, _>>()?;
+            DBEntry::from_value(SetType { members })
+        }
+        TYPE_SET_INTSET => DBEntry::from_value(SetType {
+            members: reader
+                .read_encoded("intset", intset_entries)?
+                .into_iter()
+                .collect(),
+        }),
+        TYPE_SET_LISTPACK => DBEntry::from_value(SetType {
+            members: reader
+                .read_encoded("listpack", listpack_strings)?
+                .into_iter()
+                .collect(),
+        }),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = reader.read_size()?;
+            let mut zset = SortedSetType::default();
+            for _ in 0..len {
+                let member = reader.read_string()?;
+                let score = match value_type {
+                    TYPE_ZSET_2 => reader.read_binary_double()?,
+                    _ => reader.read_double()?,
+                };
+                zset.scores.insert(member, score);
+            }
+            DBEntry::from_value(zset)
+        }
+        TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+            let entries = match value_type {
+                TYPE_ZSET_ZIPLIST => reader.read_encoded("ziplist", ziplist_entries)?,
+                _ => reader.read_encoded("listpack", listpack_strings)?,
+            };
+            let scores = entries
+                .chunks_exact(2)
+                .map(|pair| (pair[0].clone(), parse_score(&pair[1])))
+                .collect();
+            DBEntry::from_value(SortedSetType { scores })
+        }
+        TYPE_HASH => {
+            let len = reader.read_size()?;
+            let mut fields = HashMap::new();
+            for _ in 0..len {
+                fields.insert(reader.read_string()?, reader.read_string()?);
+            }
+            DBEntry::from_value(HashType {
+                fields,
+                ..Default::default()
+            })
+        }
+        TYPE_HASH_ZIPMAP | TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+            let fields = match value_type {
+                TYPE_HASH_ZIPMAP => reader.read_encoded("zipmap", zipmap_entries)?,
+                TYPE_HASH_ZIPLIST => pairs(reader.read_encoded("ziplist", ziplist_entries)?),
+                _ => pairs(reader.read_encoded("listpack", listpack_strings)?),
+            };
+            DBEntry::from_value(HashType {
+                fields,
+                ..Default::default()
+            })
+        }
+        TYPE_HASH_METADATA | TYPE_HASH_METADATA_PRE_GA => {
+            // TTLs are relative to the smallest one, saved up front.
+            let min_expire = match value_type {
+                TYPE_HASH_METADATA => reader.read_millis()?,
+                _ => 0,
+            };
+            let len = reader.read_size()?;
+            let mut hash = HashType::default();
+            for _ in 0..len {
+                let expire_at = match (value_type, reader.read_size()? as u64) {
+                    (_, 0) => None,
+                    (TYPE_HASH_METADATA, ttl) => Some(ttl + min_expire - 1),
+                    (_, at) => Some(at),
+                };
+                let field = reader.read_string()?;
+                let value = reader.read_string()?;
+                if let Some(at) = expire_at {
+                    hash.expires.insert(field.clone(), at);
+                }
+                hash.fields.insert(field, value);
+            }
+            DBEntry::from_value(hash)
+        }
+        TYPE_HASH_LISTPACK_EX | TYPE_HASH_LISTPACK_EX_PRE_GA => {
+            if value_type == TYPE_HASH_LISTPACK_EX {
+                let _min_expire = reader.read_millis()?;
+            }
+            let mut hash = HashType::default();
+            let entries = reader.read_encoded("listpack", listpack::entries)?;
+            for triplet in entries.chunks_exact(3) {
+                let field = triplet[0].clone().into_string();
+                match triplet[2].as_int() {
+                    Some(at) if at > 0 => {
+                        hash.expires.insert(field.clone(), at as u64);
+                    }
+                    _ => {}
+                }
+                hash.fields.insert(field, triplet[1].clone().into_string());
+            }
+            DBEntry::from_value(hash)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            DBEntry::from_stream(read_stream(reader, value_type)?)
+        }
+        TYPE_MODULE_2 => {
+            let module_id = reader.read_size()?;
+            skip_module_value(reader)?;
+            eprintln!(
+                "Skipping key '{}' of module type {}",
+                key,
+                module_type_name(module_id as u64)
+            );
+            return Ok(None);
+        }
+        TYPE_MODULE => {
+            return Err(reader.invalid(
+                offset,
+                format!(
+                    "module value of key '{}' uses the original module format, which can't be skipped",
+                    key
+                ),
+            ));
+        }
+        _ => unreachable!("{} is not a value type", value_type),
+    };
+    Ok(Some(db_entry))
+}
+
+fn parse_score(s: &str) -> f64 {
+    match s {
+        "inf" | "+inf" => f64::INFINITY,
+        "-inf" => f64::NEG_INFINITY,
+        _ => s.parse().unwrap_or(f64::NAN),
+    }
+}
+
+fn pairs(entries: Vec<String>) -> HashMap<String, String> {
+    let mut entries = entries.into_iter();
+    let mut pairs = HashMap::new();
+    while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+        pairs.insert(field, value);
+    }
+    pairs
+}
+
+fn listpack_strings(lp: &[u8]) -> Option<Vec<String>> {
+    let entries = listpack::entries(lp)?;
+    Some(
+        entries
+            .into_iter()
+            .map(ListpackEntry::into_string)
+            .collect(),
+    )
+}
+
+/// Reads a stream: its listpack nodes, metadata and consumer groups.
+fn read_stream(reader: &mut RdbReader, value_type: u8) -> Result<StreamType, RdbError> {
+    let mut stream = StreamType::default();
+    let nodes = reader.read_size()?;
+    for _ in 0..nodes {
+        let master_id = reader.read_encoded("stream node key", |key| {
+            RdbReader::new(key).read_raw_stream_id().ok()
+        })?;
+        let offset = reader.pos;
+        let entries =
+            reader.read_encoded("stream node", |lp| stream_node_entries(master_id, lp))?;
+        for (id, fields) in entries {
+            stream
+                .add(id, fields)
+                .map_err(|_| reader.invalid(offset, "stream entries out of order"))?;
+        }
+    }
+
+    let offset = reader.pos;
+    let length = reader.read_size()? as u64;
+    let last_id = reader.read_stream_id()?;
+    let (max_deleted, entries_added) = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        let _first_id = reader.read_stream_id()?;
+        let max_deleted = reader.read_stream_id()?;
+        (max_deleted, reader.read_size()? as u64)
+    } else {
+        // Older dumps don't track deletions: assume nothing was deleted.
+        (StreamId::MIN, length)
+    };
+    stream
+        .set_id(last_id, Some(entries_added), Some(max_deleted))
+        .map_err(|_| reader.invalid(offset, "stream metadata doesn't match its entries"))?;
+
+    let groups = reader.read_size()?;
+    for _ in 0..groups {
+        let name = reader.read_string()?;
+        let last_id = reader.read_stream_id()?;
+        let entries_read = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // -1, saved as an unsigned length, means unknown.
+            match reader.read_size()? as u64 {
+                u64::MAX => None,
+                read => Some(read),
+            }
+        } else {
+            None
+        };
+
+        let mut pel = BTreeMap::new();
+        for _ in 0..reader.read_size()? {
+            let id = reader.read_raw_stream_id()?;
+            let delivery_time = reader.read_millis()?;
+            let delivery_count = reader.read_size()? as u64;
+            pel.insert(
+                id,
+                PendingEntry {
+                    // Filled from the consumers' own PELs below.
+                    consumer: String::new(),
+                    delivery_time,
+                    delivery_count,
+                },
+            );
+        }
+
+        let mut consumers = BTreeMap::new();
+        for _ in 0..reader.read_size()? {
+            let consumer = reader.read_string()?;
+            let seen_time = reader.read_millis()?;
+            let active_time = if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                // -1 when the consumer never read anything.
+                Some(reader.read_millis()?).filter(|&time| time != u64::MAX)
+            } else {
+                None
+            };
+            let mut pending = BTreeSet::new();
+            for _ in 0..reader.read_size()? {
+                let offset = reader.pos;
+                let id = reader.read_raw_stream_id()?;
+                let entry = pel.get_mut(&id).ok_or_else(|| {
+                    reader.invalid(offset, "consumer pending entry missing from the group PEL")
+                })?;
+                entry.consumer = consumer.clone();
+                pending.insert(id);
+            }
+            consumers.insert(
+                consumer,
+                Consumer {
+                    seen_time,
+                    active_time,
+                    pending,
+                },
+            );
+        }
+
+        stream.insert_group(
+            name,
+            ConsumerGroup {
+                last_id,
+                entries_read,
+                pel,
+                consumers,
+            },
+        );
+    }
+    Ok(stream)
+}
+
+/// Live entries of a stream node listpack:
+///
+/// ```text
+/// count | deleted | n-fields | field ... | 0              (master entry)
+/// flags | ms-diff | seq-diff | value ... | lp-count       (SAMEFIELDS set)
+/// flags | ms-diff | seq-diff | n-fields | field value ... | lp-count
+/// ```
+fn stream_node_entries(master_id: StreamId, lp: &[u8]) -> Option<Vec<(StreamId, StreamFields)>> {
+    let mut lp = listpack::entries(lp)?.into_iter();
+    let int = |lp: &mut std::vec::IntoIter<ListpackEntry>| lp.next()?.as_int();
+
+    let count = int(&mut lp)? + int(&mut lp)?;
+    let master_fields: Vec<String> = (0..int(&mut lp)?)
+        .map(|_| lp.next().map(ListpackEntry::into_string))
+        .collect::<Option<_>>()?;
+    let _master_terminator = lp.next()?;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let flags = int(&mut lp)?;
+        // Sequence deltas are negative for entries with a greater millisecond
+        // part than the master entry.
+        let id = StreamId {
+            millis: master_id.millis.wrapping_add(int(&mut lp)? as u64),
+            seq: master_id.seq.wrapping_add(int(&mut lp)? as u64),
+        };
+        let fields: StreamFields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Some((field.clone(), lp.next()?.into_string())))
+                .collect::<Option<_>>()?
+        } else {
+            (0..int(&mut lp)?)
+                .map(|_| Some((lp.next()?.into_string(), lp.next()?.into_string())))
+                .collect::<Option<_>>()?
+        };
+        let _lp_count = lp.next()?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push((id, fields));
+        }
+    }
+    Some(entries)
+}
+
+/// Skips a module value of the `MODULE_2` type, self describing through
+/// per-element opcodes.
+fn skip_module_value(reader: &mut RdbReader) -> Result<(), RdbError> {
+    loop {
+        let offset = reader.pos;
+        match reader.read_size()? {
+            MODULE_OPCODE_EOF => return Ok(()),
+            MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                let _ = reader.read_size()?;
+            }
+            MODULE_OPCODE_FLOAT => {
+                let _ = reader.read_bytes(4)?;
+            }
+            MODULE_OPCODE_DOUBLE => {
+                let _ = reader.read_bytes(8)?;
+            }
+            MODULE_OPCODE_STRING => {
+                let _ = reader.read_blob()?;
+            }
+            opcode => {
+                return Err(reader.invalid(offset, format!("bad module value opcode {}", opcode)))
+            }
+        }
+    }
+}
+
+/// The 9 character name encoded in the upper 54 bits of a module type ID.
+fn module_type_name(module_id: u64) -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    (0..9)
+        .map(|i| CHARSET[((module_id >> (10 + (8 - i) * 6)) & 63) as usize] as char)
+        .collect()
+}
+
+/// Entries of a ziplist, the encoding listpacks replaced in RDB 10:
+///
+/// ```text
+/// zlbytes (u32) | zltail (u32) | zllen (u16) | entry ... | 0xFF
+/// entry: prevlen (1 or 5 bytes) | encoding | data
+/// ```
+fn ziplist_entries(zl: &[u8]) -> Option<Vec<String>> {
+    let mut reader = RdbReader::new(zl);
+    reader.pos = 10;
+    let mut entries = Vec::new();
+    while reader.peek_u8().ok()? != OPCODE_EOF {
+        if reader.read_u8().ok()? == 0xFE {
+            reader.read_bytes(4).ok()?;
+        }
+        let encoding = reader.read_u8().ok()?;
+        let len = match encoding >> 6 {
+            0b00 => (encoding & 0x3F) as usize,
+            0b01 => (((encoding & 0x3F) as usize) << 8) | reader.read_u8().ok()? as usize,
+            0b10 => u32::from_be_bytes(reader.read_array().ok()?) as usize,
+            _ => {
+                let value = match encoding {
+                    0xC0 => i16::from_le_bytes(reader.read_array().ok()?) as i64,
+                    0xD0 => i32::from_le_bytes(reader.read_array().ok()?) as i64,
+                    0xE0 => i64::from_le_bytes(reader.read_array().ok()?),
+                    0xF0 => {
+                        let [a, b, c] = reader.read_array().ok()?;
+                        i32::from_le_bytes([0, a, b, c]) as i64 >> 8
+                    }
+                    0xFE => reader.read_u8().ok()? as i8 as i64,
+                    // 4 bit immediate between 1 and 13, standing for 0 to 12.
+                    0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                    _ => return None,
+                };
+                entries.push(value.to_string());
+                continue;
+            }
+        };
+        let bytes = reader.read_bytes(len).ok()?;
+        entries.push(String::from_utf8_lossy(bytes).into_owned());
+    }
+    Some(entries)
+}
+
+/// Members of an intset: encoding (u32) | length (u32) | sorted integers,
+/// all little endian, each as wide as the encoding says.
+fn intset_entries(is: &[u8]) -> Option<Vec<String>> {
+    let mut reader = RdbReader::new(is);
+    let width = u32::from_le_bytes(reader.read_array().ok()?) as usize;
+    let len = u32::from_le_bytes(reader.read_array().ok()?) as usize;
+    (0..len)
+        .map(|_| {
+            let value = match width {
+                2 => i16::from_le_bytes(reader.read_array().ok()?) as i64,
+                4 => i32::from_le_bytes(reader.read_array().ok()?) as i64,
+                8 => i64::from_le_bytes(reader.read_array().ok()?),
+                _ => return None,
+            };
+            Some(value.to_string())
+        })
+        .collect()
+}
+
+/// Field-value pairs of a zipmap, the hash encoding of RDB versions before 4:
+///
+/// ```text
+/// zmlen | len field len free value free-bytes ... | 0xFF
+/// ```
+fn zipmap_entries(zm: &[u8]) -> Option<HashMap<String, String>> {
+    let mut reader = RdbReader::new(zm);
+    reader.pos = 1;
+    let read_len = |reader: &mut RdbReader| match reader.read_u8().ok()? {
+        254 => Some(Some(u32::from_le_bytes(reader.read_array().ok()?) as usize)),
+        255 => Some(None),
+        len => Some(Some(len as usize)),
+    };
+    let mut fields = HashMap::new();
+    while let Some(len) = read_len(&mut reader)? {
+        let field = String::from_utf8_lossy(reader.read_bytes(len).ok()?).into_owned();
+        let len = read_len(&mut reader)??;
+        let free = reader.read_u8().ok()? as usize;
+        let value = String::from_utf8_lossy(reader.read_bytes(len).ok()?).into_owned();
+        reader.read_bytes(free).ok()?;
+        fields.insert(field, value);
+    }
+    Some(fields)
+}
+
+/// Entries per quicklist node and stream node when saving.
+const QUICKLIST_NODE_MAX_ENTRIES: usize = 128;
+const QUICKLIST_NODE_PACKED: usize = 2;
+
+/// `rdbcompression`: whether long strings are LZF compressed when saving.
+static COMPRESSION: AtomicBool = AtomicBool::new(true);
+
+pub fn compression_enabled() -> bool {
+    COMPRESSION.load(Ordering::Relaxed)
+}
+
+pub fn set_compression(enabled: bool) {
+    COMPRESSION.store(enabled, Ordering::Relaxed);
+}
+
+// Create RDB writer
+struct RdbWriter {
+    buf: Vec<u8>,
+    compress: bool,
+}
+
+impl RdbWriter {
+    fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            compress: compression_enabled(),
+        }
+    }
+
+    fn write_u8(&mut self, b: u8) {
+        self.buf.push(b);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn write_millis(&mut self, millis: u64) {
+        self.write_bytes(&millis.to_le_bytes());
+    }
+
+    fn write_size(&mut self, size: usize) {
+        if size < 1 << 6 {
+            self.write_u8(size as u8);
+        } else if size < 1 << 14 {
+            self.write_bytes(&[0x40 | (size >> 8) as u8, size as u8]);
+        } else if size <= u32::MAX as usize {
+            self.write_u8(0x80);
+            self.write_bytes(&(size as u32).to_be_bytes());
+        } else {
+            self.write_u8(0x81);
+            self.write_bytes(&(size as u64).to_be_bytes());
+        }
+    }
+
+    /// Writes a string, as an integer when it holds a small canonical one and
+    /// compressed when that pays off.
+    fn write_blob(&mut self, bytes: &[u8]) {
+        if bytes.len() <= 11 {
+            if let Some(i) = std::str::from_utf8(bytes)
+                .ok()
+                .and_then(|s| s.parse::<i32>().ok().filter(|i| i.to_string() == s))
+            {
+                match i {
+                    -128..=127 => self.write_bytes(&[0xC0, i as i8 as u8]),
+                    -32768..=32767 => {
+                        self.write_u8(0xC1);
+                        self.write_bytes(&(i as i16).to_le_bytes());
+                    }
+                    _ => {
+                        self.write_u8(0xC2);
+                        self.write_bytes(&i.to_le_bytes());
+                    }
+                }
+                return;
+            }
+        }
+        if self.compress && bytes.len() > 20 {
+            if let Some(compressed) = lzf::compress(bytes) {
+                self.write_u8(0xC3);
+                self.write_size(compressed.len());
+                self.write_size(bytes.len());
+                self.write_bytes(&compressed);
+                return;
+            }
+        }
+        self.write_size(bytes.len());
+        self.write_bytes(bytes);
+    }
+
+    fn write_string(&mut self, s: &str) {
+        self.write_blob(s.as_bytes());
+    }
+
+    fn write_raw_stream_id(&mut self, id: StreamId) {
+        self.write_bytes(&id.millis.to_be_bytes());
+        self.write_bytes(&id.seq.to_be_bytes());
+    }
+
+    fn write_stream_id(&mut self, id: StreamId) {
+        self.write_size(id.millis as usize);
+        self.write_size(id.seq as usize);
+    }
+
+    fn write_aux(&mut self, key: &str, value: &str) {
+        self.write_u8(OPCODE_AUX);
+        self.write_string(key);
+        self.write_string(value);
+    }
+
+    /// Writes a value, without its type, in the encoding `value_type` picks.
+    fn write_value(&mut self, value: &dyn DBValue) {
+        let any = value.as_any();
+        if let Some(s) = any.downcast_ref::<StringType>() {
+            self.write_blob(&s.bytes);
+        } else if let Some(list) = any.downcast_ref::<ListType>() {
+            let items: Vec<&String> = list.items.iter().collect();
+            let nodes = items.chunks(QUICKLIST_NODE_MAX_ENTRIES);
+            self.write_size(nodes.len());
+            for node in nodes {
+                self.write_size(QUICKLIST_NODE_PACKED);
+                let lp =
+                    listpack::encode(node.iter().map(|item| ListpackEntry::from(item.as_str())));
+                self.write_blob(&lp);
+            }
+        } else if let Some(set) = any.downcast_ref::<SetType>() {
+            self.write_size(set.members.len());
+            for member in &set.members {
+                self.write_string(member);
+            }
+        } else if let Some(zset) = any.downcast_ref::<SortedSetType>() {
+            let members = zset.sorted();
+            self.write_size(members.len());
+            // Saved in reverse so loading inserts at the head of the skiplist.
+            for (member, score) in members.into_iter().rev() {
+                self.write_string(member);
+                self.write_bytes(&score.to_le_bytes());
+            }
+        } else if let Some(hash) = any.downcast_ref::<HashType>() {
+            // With field TTLs, these are saved relative to the smallest one.
+            let min_expire = hash.expires.values().min().copied();
+            if let Some(min_expire) = min_expire {
+                self.write_millis(min_expire);
+            }
+            self.write_size(hash.fields.len());
+            for (field, value) in &hash.fields {
+                if let Some(min_expire) = min_expire {
+                    let ttl = hash
+                        .expires
+                        .get(field)
+                        .map_or(0, |&at| (at - min_expire + 1) as usize);
+                    self.write_size(ttl);
+                }
+                self.write_string(field);
+                self.write_string(value);
+            }
+        } else if let Some(stream) = any.downcast_ref::<StreamType>() {
+            self.write_stream(stream);
+        }
+    }
+
+    fn write_stream(&mut self, stream: &StreamType) {
+        let entries = stream.range(StreamId::MIN, StreamId::MAX, None, false);
+        let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+        self.write_size(nodes.len());
+        for node in nodes {
+            let (master_id, master_fields) = &node[0];
+            self.write_blob(
+                &[master_id.millis.to_be_bytes(), master_id.seq.to_be_bytes()].concat(),
+            );
+            self.write_blob(&stream_node_listpack(*master_id, master_fields, node));
+        }
+
+        self.write_size(stream.len());
+        self.write_stream_id(stream.last_id());
+        self.write_stream_id(stream.recorded_first_id());
+        self.write_stream_id(stream.max_deleted_entry_id());
+        self.write_size(stream.entries_added() as usize);
+
+        self.write_size(stream.groups().len());
+        for (name, group) in stream.groups() {
+            self.write_string(name);
+            self.write_stream_id(group.last_id);
+            self.write_size(group.entries_read.map_or(usize::MAX, |read| read as usize));
+            self.write_size(group.pel.len());
+            for (id, pending) in &group.pel {
+                self.write_raw_stream_id(*id);
+                self.write_millis(pending.delivery_time);
+                self.write_size(pending.delivery_count as usize);
+            }
+            self.write_size(group.consumers.len());
+            for (name, consumer) in &group.consumers {
+                self.write_string(name);
+                self.write_millis(consumer.seen_time);
+                self.write_millis(consumer.active_time.unwrap_or(u64::MAX));
+                self.write_size(consumer.pending.len());
+                for id in &consumer.pending {
+                    self.write_raw_stream_id(*id);
+                }
+            }
+        }
+    }
+}
+
+/// The RDB type a value is saved as.
+fn value_type(value: &dyn DBValue) -> u8 {
+    let any = value.as_any();
+    if any.is::<ListType>() {
+        TYPE_LIST_QUICKLIST_2
+    } else if any.is::<SetType>() {
+        TYPE_SET
+    } else if any.is::<SortedSetType>() {
+        TYPE_ZSET_2
+    } else if let Some(hash) = any.downcast_ref::<HashType>() {
+        if hash.expires.is_empty() {
+            TYPE_HASH
+        } else {
+            TYPE_HASH_METADATA
+        }
+    } else if any.is::<StreamType>() {
+        TYPE_STREAM_LISTPACKS_3
+    } else {
+        TYPE_STRING
+    }
+}
+
+/// A stream node listpack, the layout `stream_node_entries` reads.
+fn stream_node_listpack(
+    master_id: StreamId,
+    master_fields: &StreamFields,
+    entries: &[(StreamId, StreamFields)],
+) -> Vec<u8> {
+    let int = |i: i64| ListpackEntry::Integer(i);
+    let mut lp = vec![
+        int(entries.len() as i64),
+        int(0),
+        int(master_fields.len() as i64),
+    ];
+    lp.extend(
+        master_fields
+            .iter()
+            .map(|(field, _)| ListpackEntry::from(field.as_str())),
+    );
+    lp.push(int(0));
+    for (id, fields) in entries {
+        let same_fields = fields.len() == master_fields.len()
+            && fields
+                .iter()
+                .zip(master_fields)
+                .all(|((field, _), (master, _))| field == master);
+        let flags = if same_fields {
+            STREAM_ITEM_FLAG_SAMEFIELDS
+        } else {
+            0
+        };
+        lp.push(int(flags));
+        lp.push(int(id.millis.wrapping_sub(master_id.millis) as i64));
+        lp.push(int(id.seq.wrapping_sub(master_id.seq) as i64));
+        if same_fields {
+            lp.extend(
+                fields
+                    .iter()
+                    .map(|(_, value)| ListpackEntry::from(value.as_str())),
+            );
+            lp.push(int(fields.len() as i64 + 3));
+        } else {
+            lp.push(int(fields.len() as i64));
+            for (field, value) in fields {
+                lp.push(ListpackEntry::from(field.as_str()));
+                lp.push(ListpackEntry::from(value.as_str()));
+            }
+            lp.push(int(2 * fields.len() as i64 + 4));
+        }
+    }
+    listpack::encode(lp)
+}
+
+/// Serialises the keyspace as an RDB file, leaving out expired keys.
+pub fn encode<'a>(entries: impl Iterator<Item = (&'a String, &'a DBEntry)>) -> Vec<u8> {
+    let mut writer = RdbWriter::new();
+    writer.write_bytes(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+    writer.write_aux("redis-ver", commands::REDIS_VERSION);
+    writer.write_aux("redis-bits", "64");
+    writer.write_aux("ctime", &(now_millis() / 1000).to_string());
+    writer.write_aux("aof-base", "0");
+
+    let entries: Vec<(&String, &DBEntry, &dyn DBValue)> = entries
+        .filter_map(|(key, entry)| Some((key, entry, entry.value().ok()?)))
+        .collect();
+    writer.write_u8(OPCODE_SELECTDB);
+    writer.write_size(0);
+    writer.write_u8(OPCODE_RESIZEDB);
+    writer.write_size(entries.len());
+    writer.write_size(
+        entries
+            .iter()
+            .filter(|(_, entry, _)| entry.expiry().is_some())
+            .count(),
+    );
+    for (key, entry, value) in entries {
+        if let Some(at) = entry.expiry() {
+            let millis = at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            writer.write_u8(OPCODE_EXPIRETIME_MS);
+            writer.write_millis(millis as u64);
+        }
+        writer.write_u8(value_type(value));
+        writer.write_string(key);
+        writer.write_value(value);
+    }
+
+    writer.write_u8(OPCODE_EOF);
+    let checksum = if checksum_enabled() {
+        crc64::crc64(0, &writer.buf)
+    } else {
+        0
+    };
+    writer.write_bytes(&checksum.to_le_bytes());
+    writer.buf
+}
+
+/// Serialises a value the way `DUMP` does: its RDB type and encoding,
+/// followed by a two byte RDB version and a CRC64 of everything before it.
+pub fn dump(value: &dyn DBValue) -> Vec<u8> {
+    let mut writer = RdbWriter::new();
+    writer.write_u8(value_type(value));
+    writer.write_value(value);
+    writer.write_bytes(&(RDB_VERSION as u16).to_le_bytes());
+    let checksum = crc64::crc64(0, &writer.buf);
+    writer.write_bytes(&checksum.to_le_bytes());
+    writer.buf
+}
+
+/// Decodes a `DUMP` payload into a fresh entry for `key`, refusing payloads
+/// from a newer RDB version, with a wrong checksum or with contents that
+/// don't decode to exactly one value.
+pub fn restore(key: &str, payload: &[u8]) -> Result<DBEntry, String> {
+    let footer_wrong = || "DUMP payload version or checksum are wrong".to_string();
+    let body_len = payload.len().checked_sub(10).ok_or_else(footer_wrong)?;
+    let (body, footer) = payload.split_at(body_len);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let checksum = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if version as u32 > RDB_VERSION || crc64::crc64(0, &payload[..body_len + 2]) != checksum {
+        return Err(footer_wrong());
+    }
+
+    let mut reader = RdbReader::new(body);
+    let value_type = reader.read_u8().ok().filter(|t| is_value_type(*t));
+    let entry = value_type.and_then(|value_type| read_value(&mut reader, value_type, key, 0).ok());
+    match entry {
+        Some(Some(entry)) if reader.pos == body.len() => Ok(entry),
+        _ => Err("Bad data format".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `DUMP` payload holding a value of `value_type` written by `write`.
+    fn payload(value_type: u8, write: impl FnOnce(&mut RdbWriter)) -> Vec<u8> {
+        let mut writer = RdbWriter::new();
+        writer.write_u8(value_type);
+        write(&mut writer);
+        writer.write_bytes(&(RDB_VERSION as u16).to_le_bytes());
+        let checksum = crc64::crc64(0, &writer.buf);
+        writer.write_bytes(&checksum.to_le_bytes());
+        writer.buf
+    }
+
+    /// An RDB file holding what `write` writes, with a valid checksum.
+    fn file(write: impl FnOnce(&mut RdbWriter)) -> Vec<u8> {
+        let mut writer = RdbWriter::new();
+        writer.write_bytes(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+        write(&mut writer);
+        writer.write_u8(OPCODE_EOF);
+        let checksum = crc64::crc64(0, &writer.buf);
+        writer.write_bytes(&checksum.to_le_bytes());
+        writer.buf
+    }
+
+    fn value<T: 'static>(entry: &DBEntry) -> &T {
+        entry.value().unwrap().as_any().downcast_ref::<T>().unwrap()
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn sorted<'a>(items: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+        let mut items: Vec<String> = items.into_iter().cloned().collect();
+        items.sort();
+        items
+    }
+
+    /// A value of every type, in the shapes the encoder has special cases
+    /// for: integer, compressed and binary strings, lists and streams
+    /// spanning several nodes, hashes with field TTLs, consumer groups.
+    fn sample_values() -> Vec<(&'static str, DBEntry)> {
+        let string = DBEntry::from_string;
+        let list = ListType {
+            items: (0..300)
+                .map(|i| match i % 3 {
+                    0 => i.to_string(),
+                    _ => format!("item-{}", i),
+                })
+                .collect(),
+        };
+        let set = SetType {
+            members: strings(&["a", "b", "-7", "1000000"]).into_iter().collect(),
+        };
+        let zset = SortedSetType {
+            scores: [("a", -0.5), ("b", 0.0), ("c", f64::INFINITY)]
+                .map(|(member, score)| (member.to_string(), score))
+                .into_iter()
+                .collect(),
+        };
+        let mut hash = HashType::default();
+        for (field, value) in [("f1", "v1"), ("f2", "12"), ("f3", "")] {
+            hash.fields.insert(field.to_string(), value.to_string());
+        }
+        let mut hash_ttl = hash.clone();
+        hash_ttl.expires.insert("f1".to_string(), 4_000_000_000_000);
+        hash_ttl.expires.insert("f3".to_string(), 4_000_000_000_123);
+
+        let mut stream = StreamType::default();
+        for i in 1..=250u64 {
+            let fields = match i % 10 {
+                0 => vec![("other".to_string(), i.to_string())],
+                _ => vec![
+                    ("n".to_string(), i.to_string()),
+                    ("name".to_string(), format!("entry-{}", i)),
+                ],
+            };
+            stream
+                .add(
+                    StreamId {
+                        millis: i,
+                        seq: i % 4,
+                    },
+                    fields,
+                )
+                .unwrap();
+        }
+        stream.delete(&[StreamId { millis: 5, seq: 1 }]);
+        stream.create_group("g1", StreamId::MIN, Some(0));
+        stream.create_group(
+            "g2",
+            StreamId {
+                millis: 100,
+                seq: 0,
+            },
+            None,
+        );
+        stream.read_group_new("g1", "alice", Some(3), false);
+        stream.read_group_new("g1", "bob", Some(2), false);
+        stream.create_consumer("g2", "carol");
+
+        vec![
+            ("string", string(b"hello")),
+            ("int", string(b"-12345")),
+            ("padded-int", string(b"007")),
+            ("compressible", string(&b"abc".repeat(100))),
+            ("binary", string(&(0..=255).collect::<Vec<u8>>())),
+            ("empty", string(b"")),
+            ("list", DBEntry::from_value(list)),
+            ("set", DBEntry::from_value(set)),
+            ("zset", DBEntry::from_value(zset)),
+            ("hash", DBEntry::from_value(hash)),
+            ("hash-ttl", DBEntry::from_value(hash_ttl)),
+            ("stream", DBEntry::from_stream(stream)),
+        ]
+    }
+
+    fn assert_same_value(expected: &dyn DBValue, actual: &dyn DBValue) {
+        let type_name = expected.type_name();
+        assert_eq!(type_name, actual.type_name());
+        let (expected, actual) = (expected.as_any(), actual.as_any());
+        if let Some(expected) = expected.downcast_ref::<StringType>() {
+            assert_eq!(expected, actual.downcast_ref::<StringType>().unwrap());
+        } else if let Some(expected) = expected.downcast_ref::<ListType>() {
+            assert_eq!(
+                expected.items,
+                actual.downcast_ref::<ListType>().unwrap().items
+            );
+        } else if let Some(expected) = expected.downcast_ref::<SetType>() {
+            assert_eq!(
+                expected.members,
+                actual.downcast_ref::<SetType>().unwrap().members
+            );
+        } else if let Some(expected) = expected.downcast_ref::<SortedSetType>() {
+            assert_eq!(
+                expected.scores,
+                actual.downcast_ref::<SortedSetType>().unwrap().scores
+            );
+        } else if let Some(expected) = expected.downcast_ref::<HashType>() {
+            let actual = actual.downcast_ref::<HashType>().unwrap();
+            assert_eq!(expected.fields, actual.fields);
+            assert_eq!(expected.expires, actual.expires);
+        } else if let Some(expected) = expected.downcast_ref::<StreamType>() {
+            let actual = actual.downcast_ref::<StreamType>().unwrap();
+            let all = |stream: &StreamType| stream.range(StreamId::MIN, StreamId::MAX, None, false);
+            assert_eq!(all(expected), all(actual));
+            assert_eq!(expected.len(), actual.len());
+            assert_eq!(expected.last_id(), actual.last_id());
+            assert_eq!(expected.recorded_first_id(), actual.recorded_first_id());
+            assert_eq!(
+                expected.max_deleted_entry_id(),
+                actual.max_deleted_entry_id()
+            );
+            assert_eq!(expected.entries_added(), actual.entries_added());
+            assert_eq!(
+                expected.groups().keys().collect::<Vec<_>>(),
+                actual.groups().keys().collect::<Vec<_>>()
+            );
+            for (name, expected) in expected.groups() {
+                let actual = actual.group(name).unwrap();
+                assert_eq!(expected.last_id, actual.last_id);
+                assert_eq!(expected.entries_read, actual.entries_read);
+                assert_eq!(
+                    expected.pel.keys().collect::<Vec<_>>(),
+                    actual.pel.keys().collect::<Vec<_>>()
+                );
+                for (id, expected) in &expected.pel {
+                    let actual = &actual.pel[id];
+                    assert_eq!(expected.consumer, actual.consumer);
+                    assert_eq!(expected.delivery_time, actual.delivery_time);
+                    assert_eq!(expected.delivery_count, actual.delivery_count);
+                }
+                assert_eq!(
+                    expected.consumers.keys().collect::<Vec<_>>(),
+                    actual.consumers.keys().collect::<Vec<_>>()
+                );
+                for (name, expected) in &expected.consumers {
+                    let actual = &actual.consumers[name];
+                    assert_eq!(expected.seen_time, actual.seen_time);
+                    assert_eq!(expected.active_time, actual.active_time);
+                    assert_eq!(expected.pending, actual.pending);
+                }
+            }
+        } else {
+            panic!("unexpected {} value", type_name);
+        }
+    }
+
+    /// A ziplist holding a string and each kind of integer encoding.
+    fn ziplist() -> Vec<u8> {
+        let mut zl = vec![0; 10];
+        zl.extend([0, 0x03, b'a', b'b', b'c']);
+        zl.extend([5, 0xF2]);
+        zl.extend([2, 0xFE, 0x80]);
+        zl.extend([3, 0xC0]);
+        zl.extend(1000i16.to_le_bytes());
+        zl.extend([4, 0xD0]);
+        zl.extend(100_000i32.to_le_bytes());
+        zl.extend([6, 0xE0]);
+        zl.extend(i64::MIN.to_le_bytes());
+        zl.push(OPCODE_EOF);
+        zl
+    }
+
+    #[test]
+    fn decodes_ziplist_lists() {
+        let entry = restore(
+            "k",
+            &payload(TYPE_LIST_ZIPLIST, |w| w.write_blob(&ziplist())),
+        )
+        .unwrap();
+        assert_eq!(
+            value::<ListType>(&entry).items,
+            strings(&["abc", "1", "-128", "1000", "100000", &i64::MIN.to_string()])
+        );
+    }
+
+    #[test]
+    fn decodes_quicklist_nodes() {
+        let entry = restore(
+            "k",
+            &payload(TYPE_LIST_QUICKLIST_2, |w| {
+                w.write_size(2);
+                w.write_size(QUICKLIST_NODE_PLAIN);
+                w.write_string("plain");
+                w.write_size(QUICKLIST_NODE_PACKED);
+                w.write_blob(&listpack::encode(["a", "7"].map(ListpackEntry::from)));
+            }),
+        )
+        .unwrap();
+        assert_eq!(
+            value::<ListType>(&entry).items,
+            strings(&["plain", "a", "7"])
+        );
+    }
+
+    #[test]
+    fn decodes_intset_sets() {
+        let mut is = Vec::new();
+        is.extend(2u32.to_le_bytes());
+        is.extend(3u32.to_le_bytes());
+        for i in [-2i16, 1, 300] {
+            is.extend(i.to_le_bytes());
+        }
+        let entry = restore("k", &payload(TYPE_SET_INTSET, |w| w.write_blob(&is))).unwrap();
+        assert_eq!(
+            sorted(&value::<SetType>(&entry).members),
+            strings(&["-2", "1", "300"])
+        );
+    }
+
+    #[test]
+    fn decodes_zipmap_hashes() {
+        let mut zm = vec![2, 1, b'a', 1, 0, b'1'];
+        // A value with two free bytes after it.
+        zm.extend([2, b'b', b'b', 3, 2, b'x', b'y', b'z', 0, 0]);
+        zm.push(255);
+        let entry = restore("k", &payload(TYPE_HASH_ZIPMAP, |w| w.write_blob(&zm))).unwrap();
+        let fields = &value::<HashType>(&entry).fields;
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields["a"], "1");
+        assert_eq!(fields["bb"], "xyz");
+    }
+
+    #[test]
+    fn decodes_string_scores() {
+        let entry = restore(
+            "k",
+            &payload(TYPE_ZSET, |w| {
+                w.write_size(3);
+                w.write_string("a");
+                w.write_u8(3);
+                w.write_bytes(b"1.5");
+                w.write_string("b");
+                w.write_u8(254);
+                w.write_string("c");
+                w.write_u8(255);
+            }),
+        )
+        .unwrap();
+        let scores = &value::<SortedSetType>(&entry).scores;
+        assert_eq!(scores["a"], 1.5);
+        assert_eq!(scores["b"], f64::INFINITY);
+        assert_eq!(scores["c"], f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn decodes_listpack_hashes_with_field_ttls() {
+        let lp =
+            listpack::encode(["f", "v", "0", "g", "w", "1700000000000"].map(ListpackEntry::from));
+        let entry = restore(
+            "k",
+            &payload(TYPE_HASH_LISTPACK_EX, |w| {
+                w.write_millis(1_700_000_000_000);
+                w.write_blob(&lp);
+            }),
+        )
+        .unwrap();
+        let hash = value::<HashType>(&entry);
+        assert_eq!(hash.fields["f"], "v");
+        assert_eq!(hash.fields["g"], "w");
+        assert_eq!(hash.expires.get("f"), None);
+        assert_eq!(hash.expires["g"], 1_700_000_000_000);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_headers() {
+        assert!(matches!(load(b"").await, Err(RdbError::BadMagic)));
+        assert!(matches!(
+            load(b"RUBIS0012\xff").await,
+            Err(RdbError::BadMagic)
+        ));
+        for version in ["0013", "0000", "00x1", "+012"] {
+            let data = format!("REDIS{}\u{ff}", version);
+            assert!(matches!(
+                load(data.as_bytes()).await,
+                Err(RdbError::UnsupportedVersion { .. })
+            ));
+        }
+        assert!(matches!(
+            load(b"REDIS00").await,
+            Err(RdbError::Truncated { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_truncated_files() {
+        let data = file(|w| {
+            w.write_aux("redis-ver", "7.4.0");
+            w.write_u8(OPCODE_EXPIRETIME_MS);
+            w.write_millis(u64::MAX / 2);
+            w.write_u8(TYPE_STRING);
+            w.write_string("rdb-test:truncated");
+            w.write_string(&"abc".repeat(20));
+            w.write_u8(TYPE_LIST_ZIPLIST);
+            w.write_string("rdb-test:truncated-list");
+            w.write_blob(&ziplist());
+        });
+        assert_eq!(load(&data).await.unwrap(), data.len());
+        // Cut anywhere past the header, even within the checksum.
+        for end in 9..data.len() {
+            assert!(
+                matches!(load(&data[..end]).await, Err(RdbError::Truncated { .. })),
+                "cut at {}",
+                end
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_opcodes() {
+        let data = file(|w| w.write_u8(0xF0));
+        assert!(matches!(
+            load(&data).await,
+            Err(RdbError::BadOpcode {
+                opcode: 0xF0,
+                offset: 9
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_malformed_values() {
+        let malformed_lzf = file(|w| {
+            w.write_u8(TYPE_STRING);
+            w.write_string("rdb-test:malformed");
+            w.write_bytes(&[0xC3, 3, 20, 0x1F, b'a', b'b']);
+        });
+        let bad_encoding = file(|w| {
+            w.write_u8(TYPE_STRING);
+            w.write_string("rdb-test:malformed");
+            w.write_u8(0xC4);
+        });
+        let malformed_ziplist = file(|w| {
+            w.write_u8(TYPE_LIST_ZIPLIST);
+            w.write_string("rdb-test:malformed");
+            w.write_blob(&ziplist()[..15]);
+        });
+        for (data, reason) in [
+            (malformed_lzf, "malformed LZF string"),
+            (bad_encoding, "bad string encoding 0xc4"),
+            (malformed_ziplist, "malformed ziplist"),
+        ] {
+            match load(&data).await {
+                // Right after the type byte and the key.
+                Err(RdbError::Invalid {
+                    reason: actual,
+                    offset,
+                }) => {
+                    assert_eq!(actual, reason);
+                    assert_eq!(offset, 9 + 1 + 1 + "rdb-test:malformed".len());
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_checksums() {
+        let mut data = file(|w| w.write_aux("redis-ver", "7.4.0"));
+        let len = data.len();
+        assert_eq!(load(&data).await.unwrap(), len);
+
+        data[len - 1] ^= 1;
+        assert!(matches!(
+            load(&data).await,
+            Err(RdbError::ChecksumMismatch { offset, .. }) if offset == len - 8
+        ));
+        // Files saved with `rdbchecksum no` carry a zero checksum.
+        data[len - 8..].fill(0);
+        assert_eq!(load(&data).await.unwrap(), len);
+        // Versions before 5 have no checksum at all.
+        assert_eq!(load(b"REDIS0004\xff").await.unwrap(), 10);
+    }
+
+    #[tokio::test]
+    async fn skips_functions_slot_info_and_module_data() {
+        let module_value = |w: &mut RdbWriter| {
+            w.write_size(MODULE_OPCODE_SINT);
+            w.write_size(7);
+            w.write_size(MODULE_OPCODE_DOUBLE);
+            w.write_bytes(&1.5f64.to_le_bytes());
+            w.write_size(MODULE_OPCODE_STRING);
+            w.write_string("state");
+            w.write_size(MODULE_OPCODE_EOF);
+        };
+        let data = file(|w| {
+            w.write_u8(OPCODE_FUNCTION2);
+            w.write_string("#!lua name=lib\nredis.register_function('f', function() end)");
+            w.write_u8(OPCODE_MODULE_AUX);
+            w.write_size(u64::MAX as usize >> 10 << 10);
+            w.write_size(MODULE_OPCODE_UINT);
+            w.write_size(2);
+            module_value(w);
+            w.write_u8(OPCODE_SELECTDB);
+            w.write_size(0);
+            w.write_u8(OPCODE_SLOT_INFO);
+            w.write_size(1234);
+            w.write_size(2);
+            w.write_size(0);
+            w.write_u8(TYPE_MODULE_2);
+            w.write_string("rdb-test:module");
+            w.write_size(u64::MAX as usize >> 10 << 10);
+            module_value(w);
+            w.write_u8(TYPE_STRING);
+            w.write_string("rdb-test:after-module");
+            w.write_string("v");
+        });
+        assert_eq!(load(&data).await.unwrap(), data.len());
+        let storage = STORAGE.lock().await;
+        assert!(!storage.contains_key("rdb-test:module"));
+        assert_eq!(
+            value::<StringType>(&storage["rdb-test:after-module"]).bytes,
+            b"v"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_pre_release_functions() {
+        let data = file(|w| {
+            w.write_u8(OPCODE_FUNCTION_PRE_GA);
+            w.write_string("lib");
+        });
+        assert!(matches!(
+            load(&data).await,
+            Err(RdbError::Invalid { offset: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn sizes_round_trip() {
+        for size in [
+            0,
+            63,
+            64,
+            16383,
+            16384,
+            u32::MAX as usize,
+            u32::MAX as usize + 1,
+        ] {
+            let mut writer = RdbWriter::new();
+            writer.write_size(size);
+            let mut reader = RdbReader::new(&writer.buf);
+            assert_eq!(reader.read_size().unwrap(), size);
+            assert_eq!(reader.pos, writer.buf.len());
+        }
+    }
+
+    #[test]
+    fn strings_round_trip_in_every_encoding() {
+        let long = "abc".repeat(100);
+        let cases: [(&str, Option<u8>); 9] = [
+            ("0", Some(0xC0)),
+            ("-128", Some(0xC0)),
+            ("-129", Some(0xC1)),
+            ("32767", Some(0xC1)),
+            ("-2147483648", Some(0xC2)),
+            // Not canonical or too wide, so kept as strings.
+            ("007", None),
+            ("2147483648", None),
+            ("", None),
+            (&long, Some(0xC3)),
+        ];
+        for (s, marker) in cases {
+            for compress in [false, true] {
+                let mut writer = RdbWriter::new();
+                writer.compress = compress;
+                writer.write_string(s);
+                let expected = match marker {
+                    Some(0xC3) if !compress => 0x40 | (s.len() >> 8) as u8,
+                    Some(marker) => marker,
+                    None => s.len() as u8,
+                };
+                assert_eq!(writer.buf[0], expected, "{:?}", s);
+                let mut reader = RdbReader::new(&writer.buf);
+                assert_eq!(reader.read_string().unwrap(), s);
+                assert_eq!(reader.pos, writer.buf.len());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keyspace_round_trips() {
+        let expire_at = UNIX_EPOCH + Duration::from_millis(4_000_000_000_000);
+        let keyspace: HashMap<String, DBEntry> = sample_values()
+            .into_iter()
+            .map(|(name, mut entry)| {
+                if name == "string" {
+                    entry.set_expiry_at(expire_at);
+                }
+                (format!("rdb-test:save:{}", name), entry)
+            })
+            .collect();
+
+        let data = encode(keyspace.iter());
+        assert_eq!(load(&data).await.unwrap(), data.len());
+
+        let storage = STORAGE.lock().await;
+        for (key, expected) in &keyspace {
+            let actual = &storage[key];
+            assert_same_value(expected.value().unwrap(), actual.value().unwrap());
+            assert_eq!(actual.expiry(), expected.expiry(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn dump_payloads_round_trip() {
+        for (name, entry) in sample_values() {
+            let value = entry.value().unwrap();
+            let payload = dump(value);
+            assert_eq!(payload[0], value_type(value), "{}", name);
+            let footer = &payload[payload.len() - 10..];
+            assert_eq!(footer[..2], (RDB_VERSION as u16).to_le_bytes());
+            let restored = restore(name, &payload).unwrap();
+            assert_same_value(value, restored.value().unwrap());
+            assert_eq!(restored.expiry(), None);
+        }
+    }
+
+    #[test]
+    fn restore_rejects_bad_footers() {
+        let footer_wrong = Some("DUMP payload version or checksum are wrong".to_string());
+        let dumped = dump(&StringType {
+            bytes: b"value".to_vec(),
+        });
+        for len in 0..10 {
+            assert_eq!(restore("k", &dumped[..len]).err(), footer_wrong);
+        }
+        for i in 0..dumped.len() {
+            let mut corrupt = dumped.clone();
+            corrupt[i] ^= 0x20;
+            assert_eq!(restore("k", &corrupt).err(), footer_wrong);
+        }
+        // A newer RDB version, even with a matching checksum.
+        let mut newer = dumped[..dumped.len() - 10].to_vec();
+        newer.extend((RDB_VERSION as u16 + 1).to_le_bytes());
+        newer.extend(crc64::crc64(0, &newer).to_le_bytes());
+        assert_eq!(restore("k", &newer).err(), footer_wrong);
+    }
+
+    #[test]
+    fn restore_rejects_bad_bodies() {
+        let bad_format = Some("Bad data format".to_string());
+        let trailing = payload(TYPE_STRING, |w| {
+            w.write_string("value");
+            w.write_u8(0);
+        });
+        let truncated = payload(TYPE_LIST, |w| {
+            w.write_size(2);
+            w.write_string("only one");
+        });
+        let unknown_type = payload(8, |w| w.write_string("value"));
+        let module = payload(TYPE_MODULE_2, |w| {
+            w.write_size(1 << 10);
+            w.write_size(MODULE_OPCODE_EOF);
+        });
+        let malformed_intset =
+            payload(TYPE_SET_INTSET, |w| w.write_blob(&[3, 0, 0, 0, 1, 0, 0, 0]));
+        for body in [trailing, truncated, unknown_type, module, malformed_intset] {
+            assert_eq!(restore("k", &body).err(), bad_format);
+        }
+    }
 }
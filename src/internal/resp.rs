@@ -0,0 +1,175 @@
+//! Typed replies and their encoding for the protocol negotiated by the
+//! connection.
+//!
+//! RESP2 has no dedicated type for most RESP3 replies, so they degrade the way
+//! Redis does: maps and sets become flat arrays, doubles and big numbers bulk
+//! strings, booleans integers and attributes are dropped altogether.
+
+use std::io::Write;
+
+use crate::internal::commands::CommandError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    /// Error line starting with its code, such as `ERR` or `WRONGTYPE`.
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    /// Bulk string of arbitrary bytes, such as a `DUMP` payload.
+    BulkBytes(Vec<u8>),
+    Null,
+    /// Null array, which RESP2 tells apart from a null bulk string.
+    NullArray,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+    Set(Vec<Reply>),
+    Double(f64),
+    Boolean(bool),
+    /// Integer beyond 64 bits, given as its decimal digits.
+    BigNumber(String),
+    /// Text meant to be shown as is, along with a three letter format hint
+    /// such as `txt` or `mkd`.
+    Verbatim(&'static str, String),
+    /// Out-of-band message, such as the ones delivered to subscribers.
+    Push(Vec<Reply>),
+    /// Auxiliary key/value data sent ahead of the reply it describes.
+    Attribute(Vec<(Reply, Reply)>, Box<Reply>),
+    /// Several replies written back to back, e.g. one per channel for
+    /// `SUBSCRIBE`.
+    Sequence(Vec<Reply>),
+    /// Nothing is sent back, as for the commands streamed by the master.
+    Nothing,
+}
+
+impl Reply {
+    pub fn ok() -> Self {
+        Reply::Simple("OK".to_string())
+    }
+
+    pub fn bulk(s: impl Into<String>) -> Self {
+        Reply::Bulk(s.into())
+    }
+
+    /// Integer reply, or a null one when the value is unknown.
+    pub fn optional_int(value: Option<u64>) -> Self {
+        value.map_or(Reply::Null, |v| Reply::Integer(v as i64))
+    }
+
+    /// Array of bulk strings.
+    pub fn bulks<I, S>(items: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Reply::Array(items.into_iter().map(Reply::bulk).collect())
+    }
+
+    /// Map keyed by plain names, as replied by `XINFO` or `HELLO`.
+    pub fn fields<I>(fields: I) -> Self
+    where
+        I: IntoIterator<Item = (&'static str, Reply)>,
+    {
+        Reply::Map(
+            fields
+                .into_iter()
+                .map(|(name, value)| (Reply::bulk(name), value))
+                .collect(),
+        )
+    }
+
+    pub fn encode(&self, protocol: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out, protocol >= 3);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>, resp3: bool) {
+        match self {
+            Reply::Simple(s) => _line(out, '+', s),
+            // A line break would end the error early and desync the stream.
+            Reply::Error(e) => _line(out, '-', e.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => _line(out, ':', n),
+            Reply::Bulk(s) => _bulk(out, s.as_bytes()),
+            Reply::BulkBytes(bytes) => _bulk(out, bytes),
+            Reply::Null | Reply::NullArray if resp3 => out.extend_from_slice(b"_\r\n"),
+            Reply::Null => out.extend_from_slice(b"$-1\r\n"),
+            Reply::NullArray => out.extend_from_slice(b"*-1\r\n"),
+            Reply::Array(items) => _aggregate(out, '*', items, resp3),
+            Reply::Set(items) => _aggregate(out, if resp3 { '~' } else { '*' }, items, resp3),
+            Reply::Push(items) => _aggregate(out, if resp3 { '>' } else { '*' }, items, resp3),
+            Reply::Map(pairs) if resp3 => _pairs(out, '%', pairs, resp3),
+            Reply::Map(pairs) => {
+                _line(out, '*', pairs.len() * 2);
+                _encode_pairs(out, pairs, resp3);
+            }
+            Reply::Double(value) => {
+                let s = if value.is_infinite() {
+                    if *value > 0.0 { "inf" } else { "-inf" }.to_string()
+                } else {
+                    value.to_string()
+                };
+                if resp3 {
+                    _line(out, ',', s)
+                } else {
+                    _bulk(out, s.as_bytes())
+                }
+            }
+            Reply::Boolean(value) if resp3 => _line(out, '#', if *value { 't' } else { 'f' }),
+            Reply::Boolean(value) => _line(out, ':', u8::from(*value)),
+            Reply::BigNumber(digits) if resp3 => _line(out, '(', digits),
+            Reply::BigNumber(digits) => _bulk(out, digits.as_bytes()),
+            Reply::Verbatim(format, text) if resp3 => {
+                let _ = write!(out, "={}\r\n{}:{}\r\n", text.len() + 4, format, text);
+            }
+            Reply::Verbatim(_, text) => _bulk(out, text.as_bytes()),
+            Reply::Attribute(attributes, reply) => {
+                if resp3 {
+                    _pairs(out, '|', attributes, resp3);
+                }
+                reply.encode_into(out, resp3);
+            }
+            Reply::Sequence(replies) => {
+                for reply in replies {
+                    reply.encode_into(out, resp3);
+                }
+            }
+            Reply::Nothing => {}
+        }
+    }
+}
+
+fn _line(out: &mut Vec<u8>, prefix: char, value: impl std::fmt::Display) {
+    let _ = write!(out, "{}{}\r\n", prefix, value);
+}
+
+fn _bulk(out: &mut Vec<u8>, bytes: &[u8]) {
+    let _ = write!(out, "${}\r\n", bytes.len());
+    out.extend_from_slice(bytes);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn _aggregate(out: &mut Vec<u8>, prefix: char, items: &[Reply], resp3: bool) {
+    _line(out, prefix, items.len());
+    for item in items {
+        item.encode_into(out, resp3);
+    }
+}
+
+fn _pairs(out: &mut Vec<u8>, prefix: char, pairs: &[(Reply, Reply)], resp3: bool) {
+    _line(out, prefix, pairs.len());
+    _encode_pairs(out, pairs, resp3);
+}
+
+fn _encode_pairs(out: &mut Vec<u8>, pairs: &[(Reply, Reply)], resp3: bool) {
+    for (key, value) in pairs {
+        key.encode_into(out, resp3);
+        value.encode_into(out, resp3);
+    }
+}
+
+impl From<CommandError> for Reply {
+    fn from(e: CommandError) -> Self {
+        Reply::Error(format!("{} {}", e.code(), e.message()))
+    }
+}
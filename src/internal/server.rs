@@ -1,21 +1,35 @@
-use crate::internal::{commands, parser, rdb};
+use crate::internal::{
+    acl, aof,
+    client::{self, Client, Role},
+    commands, migrate, parser, persistence,
+    pubsub::Subscriptions,
+    rdb,
+    resp::Reply,
+    storage::{self, DBEntry},
+    tracking,
+    transaction::{Transaction, EXEC_LOCK},
+};
 use std::{
     error::Error,
-    io::{Error as IOError, ErrorKind},
     path::PathBuf,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
-use super::cli::Replicaof;
+use super::cli::{CliArgs, Replicaof};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     sync::{broadcast, Notify, RwLock},
 };
 
+const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+
+static MASTER_SYNC_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
 #[derive(Debug)]
 pub struct ServerMetadata {
     pub role: u8,
@@ -30,6 +44,28 @@ pub struct ServerMetadata {
     _host: String,
 }
 
+impl ServerMetadata {
+    pub fn new(host: &str, port: u16, replica: bool, dir: PathBuf, dbfilename: String) -> Self {
+        ServerMetadata {
+            _port: port,
+            _host: host.to_string(),
+
+            master_replid: get_master_replid(),
+            master_repl_offset: get_master_repl_offset(),
+            role: match replica {
+                true => 1,
+                false => 0,
+            },
+            // The `0` here is to get the sender only, we don't need the receiver here.
+            broadcast: broadcast::channel(16).0,
+            replica_offsets: Vec::new(),
+            ack_notify: Arc::new(Notify::new()),
+            dir,
+            dbfilename,
+        }
+    }
+}
+
 fn get_master_replid() -> String {
     "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string()
 }
@@ -38,45 +74,91 @@ fn get_master_repl_offset() -> AtomicU64 {
     AtomicU64::new(0)
 }
 
-pub async fn start_server(
-    host: &str,
-    port: u16,
-    replicaof: Option<Replicaof>,
-    dir: Option<PathBuf>,
-    dbfilename: Option<String>,
-) -> Result<(), Box<dyn Error>> {
+pub async fn start_server(host: &str, args: CliArgs) -> Result<(), Box<dyn Error>> {
+    let CliArgs {
+        port,
+        replicaof,
+        dir,
+        dbfilename,
+        requirepass,
+        aclfile,
+        rdbchecksum,
+        rdbcompression,
+        save,
+        appendonly,
+        appendfilename,
+        appenddirname,
+        appendfsync,
+        aof_load_truncated,
+        auto_aof_rewrite_percentage,
+        auto_aof_rewrite_min_size,
+    } = args;
+    if let Some(enabled) = rdbchecksum {
+        rdb::set_checksum(enabled);
+    }
+    if let Some(enabled) = rdbcompression {
+        rdb::set_compression(enabled);
+    }
+    if let Some(save) = save {
+        persistence::set_save_params(&save)?;
+    }
+    if let Some(enabled) = appendonly {
+        aof::set_enabled(enabled);
+    }
+    if let Some(filename) = appendfilename {
+        aof::set_filename(filename);
+    }
+    if let Some(dirname) = appenddirname {
+        aof::set_dirname(dirname);
+    }
+    if let Some(policy) = appendfsync {
+        aof::set_fsync_policy(policy);
+    }
+    if let Some(enabled) = aof_load_truncated {
+        aof::set_load_truncated(enabled);
+    }
+    if let Some(percentage) = auto_aof_rewrite_percentage {
+        aof::set_rewrite_percentage(percentage);
+    }
+    if let Some(size) = auto_aof_rewrite_min_size {
+        aof::set_rewrite_min_size(size);
+    }
+    if let Some(password) = requirepass {
+        acl::set_requirepass(&password);
+    }
+    if aclfile.is_some() {
+        acl::set_aclfile(aclfile);
+        acl::load_file()?;
+    }
     let address = format!("{}:{}", host, port);
     let listener = TcpListener::bind(address).await?;
-    let metadata = Arc::new(RwLock::new(ServerMetadata {
-        _port: port,
-        _host: host.to_string(),
-
-        master_replid: get_master_replid(),
-        master_repl_offset: get_master_repl_offset(),
-        role: match replicaof {
-            Some(_) => 1,
-            None => 0,
-        },
-        // The `0` here is to get the sender only, we don't need the receiver here.
-        broadcast: broadcast::channel(16).0,
-        replica_offsets: Vec::new(),
-        ack_notify: Arc::new(Notify::new()),
-        dir: match dir {
-            Some(d) => d,
-            None => PathBuf::new(),
-        },
-        dbfilename: match dbfilename {
-            Some(db) => db,
-            None => "".to_string(),
-        },
-    }));
+    let metadata = Arc::new(RwLock::new(ServerMetadata::new(
+        host,
+        port,
+        replicaof.is_some(),
+        dir.unwrap_or_default(),
+        dbfilename.unwrap_or_else(|| "dump.rdb".to_string()),
+    )));
 
-    // Configuring the replica.
-    configure_replica(&replicaof, &metadata).await;
-    {
+    let (dir, dbfilename) = {
         let meta = metadata.read().await;
-        rdb::load_rdb(&meta.dir, &meta.dbfilename).await;
+        (meta.dir.clone(), meta.dbfilename.clone())
+    };
+    // With the AOF on, it holds the most recent data and the RDB is ignored.
+    if aof::enabled() && aof::exists(&dir) {
+        aof::load(&dir, &metadata).await?;
+    } else {
+        rdb::load_rdb(&dir, &dbfilename).await?;
+    }
+    if aof::enabled() {
+        aof::start(&dir, false).await?;
     }
+    // Configuring the replica.
+    configure_replica(&replicaof, &metadata);
+    tokio::spawn(active_expire());
+    tokio::spawn(persistence::cron(Arc::clone(&metadata)));
+    tokio::spawn(aof::cron(Arc::clone(&metadata)));
+    tokio::spawn(migrate::cron());
 
     while let Ok((stream, _)) = listener.accept().await {
         let cloned_metadata = Arc::clone(&metadata);
@@ -88,34 +170,57 @@ pub async fn start_server(
     Ok(())
 }
 
-async fn configure_replica(replicaof: &Option<Replicaof>, metadata: &Arc<RwLock<ServerMetadata>>) {
+/// Periodically reclaims expired keys, like Redis' active expire cycle.
+async fn active_expire() {
+    let mut interval = tokio::time::interval(ACTIVE_EXPIRE_PERIOD);
+    loop {
+        interval.tick().await;
+        storage::expire_keys().await;
+    }
+}
+
+/// Whether a replica is receiving the snapshot of its master.
+pub fn master_sync_in_progress() -> bool {
+    MASTER_SYNC_IN_PROGRESS.load(Ordering::Relaxed)
+}
+
+/// Connects to the master and, once its snapshot is loaded, applies the
+/// command stream it sends. Clients are served meanwhile.
+fn configure_replica(replicaof: &Option<Replicaof>, metadata: &Arc<RwLock<ServerMetadata>>) {
     if let Some(replicaof) = replicaof {
-        if let Ok(mut stream) =
-            TcpStream::connect(format!("{}:{}", replicaof.host, replicaof.port)).await
-        {
+        let address = format!("{}:{}", replicaof.host, replicaof.port);
+        let cloned_metadata = Arc::clone(metadata);
+        tokio::spawn(async move {
+            let Ok(mut stream) = TcpStream::connect(address).await else {
+                return;
+            };
             let _ = ping_master(&mut stream).await;
             let _ = replicaconf_master(&mut stream).await;
+            MASTER_SYNC_IN_PROGRESS.store(true, Ordering::Relaxed);
             let _ = psync_master(&mut stream).await;
-            if let Err(e) = consume_rdb_file(&mut stream).await {
-                eprintln!("Failed to consume RDB file: {}", e);
+            let synced = match consume_rdb_file(&mut stream).await {
+                Ok(rdb) => load_master_rdb(rdb, &cloned_metadata).await,
+                Err(e) => Err(format!("Failed to consume RDB file: {}", e)),
+            };
+            MASTER_SYNC_IN_PROGRESS.store(false, Ordering::Relaxed);
+            if let Err(e) = synced {
+                eprintln!("{}", e);
                 return;
             }
             let stream = Arc::new(RwLock::new(stream));
-            let cloned_metadata = Arc::clone(metadata);
-
-            tokio::spawn(async move {
-                handle_client(
-                    stream,
-                    &cloned_metadata,
-                    Some(&commands::MASTER_REPLICA_COMMANDS),
-                )
-                .await;
-            });
-        }
+            handle_client(
+                stream,
+                &cloned_metadata,
+                Some(&commands::MASTER_REPLICA_COMMANDS),
+            )
+            .await;
+        });
     }
 }
 
-async fn consume_rdb_file(stream: &mut TcpStream) -> Result<(), String> {
+/// Reads the RDB payload following `+FULLRESYNC`, sent as a bulk string
+/// without the trailing CRLF.
+async fn consume_rdb_file(stream: &mut TcpStream) -> Result<Vec<u8>, String> {
     let mut byte = [0u8; 1];
     loop {
         stream
@@ -153,6 +258,41 @@ async fn consume_rdb_file(stream: &mut TcpStream) -> Result<(), String> {
         .read_exact(&mut rdb)
         .await
         .map_err(|e| e.to_string())?;
+    Ok(rdb)
+}
+
+/// Replaces the dataset with the snapshot sent by the master. Clients get
+/// `-LOADING` meanwhile, and the AOF is started over from the new dataset.
+async fn load_master_rdb(
+    rdb: Vec<u8>,
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+) -> Result<(), String> {
+    persistence::set_loading(true);
+    let flushed: Vec<String> = {
+        let mut storage = storage::STORAGE.lock().await;
+        storage.drain().map(|(key, _)| key).collect()
+    };
+    tracking::invalidate(&flushed, None);
+    // Parsing a big dataset takes a while: it runs on a blocking thread so
+    // that clients are answered meanwhile.
+    let handle = tokio::runtime::Handle::current();
+    let loaded = tokio::task::spawn_blocking(move || handle.block_on(rdb::load(&rdb)))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|loaded| loaded.map_err(|e| e.to_string()));
+    persistence::set_loading(false);
+    if let Err(e) = loaded {
+        return Err(format!(
+            "Failed loading the RDB received from the master: {}",
+            e
+        ));
+    }
+    if aof::enabled() {
+        let dir = server_metadata.read().await.dir.clone();
+        aof::start(&dir, true)
+            .await
+            .map_err(|e| format!("Can't rewrite the AOF after the sync: {}", e))?;
+    }
     Ok(())
 }
 
@@ -161,33 +301,134 @@ async fn handle_client(
     server_metadata: &Arc<RwLock<ServerMetadata>>,
     command_registry: Option<&commands::CommandsReg>,
 ) {
-    let mut buf = [0u8; 255];
+    let (addr, laddr) = {
+        let stream = stream.read().await;
+        let address = |addr: std::io::Result<std::net::SocketAddr>| {
+            addr.map(|addr| addr.to_string()).unwrap_or_default()
+        };
+        (address(stream.peer_addr()), address(stream.local_addr()))
+    };
+    let role = match command_registry {
+        Some(_) => Role::Master,
+        None => Role::Normal,
+    };
+    client::scope(
+        Client::new(addr, laddr, role),
+        serve_client(stream, server_metadata, command_registry),
+    )
+    .await
+}
+
+async fn serve_client(
+    stream: Arc<RwLock<TcpStream>>,
+    server_metadata: &Arc<RwLock<ServerMetadata>>,
+    command_registry: Option<&commands::CommandsReg>,
+) {
+    let mut buf = [0u8; client::READ_BUFFER_LEN];
+    // Bytes read but not parsed yet, when a request spans several reads.
+    let mut pending: Vec<u8> = Vec::new();
     let command_reg = command_registry.unwrap_or(&commands::COMMANDS_REGISTRY);
+    // The replica doesn't answer the commands streamed by its master.
+    let replies = command_registry.is_none();
+    let mut transaction = Transaction::default();
+    let mut subscriptions = Subscriptions::new(client::current().id);
+    tracking::connect(client::current().id, subscriptions.subscriber());
     let mut is_psync = false;
-    loop {
+    'connection: loop {
         let mut locked_stream = stream.write().await;
-        match locked_stream.read(&mut buf).await {
-            Ok(0) => continue,
-            Ok(length) => {
+        tokio::select! {
+            read = locked_stream.read(&mut buf) => {
                 drop(locked_stream);
-                let commands = parser::parse_request(&buf[..length]).unwrap();
+                let length = match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(length) => length,
+                };
+                pending.extend_from_slice(&buf[..length]);
+                let commands = match parser::parse_request(&pending) {
+                    Ok((commands, consumed)) => {
+                        pending.drain(..consumed);
+                        commands
+                    }
+                    Err(e) => {
+                        if replies {
+                            commands::write_reply(&stream, &Reply::Error(format!("ERR {}", e)))
+                                .await;
+                        }
+                        break;
+                    }
+                };
                 for command in commands {
+                    client::begin_command(&command, pending.len());
                     if command.cmd.to_lowercase() == "psync" {
+                        // Takes the connection over rather than going
+                        // through the dispatch, which checks the others.
+                        if let Err(e) = acl::authorize(&command, false) {
+                            commands::write_reply(&stream, &e.into()).await;
+                            continue;
+                        }
                         is_psync = true;
                         break;
                     }
+                    let Some(command) = subscriptions
+                        .process(&stream, command, transaction.in_multi())
+                        .await
+                    else {
+                        continue;
+                    };
+                    match command.cmd.to_lowercase().as_str() {
+                        "quit" => {
+                            commands::write_reply(&stream, &Reply::ok()).await;
+                            break 'connection;
+                        }
+                        "reset" => {
+                            transaction.reset();
+                            subscriptions.clear().await;
+                            client::set_protocol(2);
+                            client::reset_user();
+                            commands::write_reply(&stream, &Reply::Simple("RESET".to_string())).await;
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    if replies {
+                        client::wait_unpaused(&command).await;
+                    }
+                    let Some(command) = transaction
+                        .process(&stream, command, server_metadata, command_reg, replies)
+                        .await
+                    else {
+                        continue;
+                    };
                     let stream_clone = Arc::clone(&stream);
-                    commands::run_command(stream_clone, command, server_metadata, command_reg).await
+                    commands::run_command(stream_clone, command, server_metadata, command_reg, replies)
+                        .await
                 }
+                // Published for other connections' `CLIENT LIST`.
+                client::update(|client| {
+                    client.multi = transaction.queued();
+                    client.subscriptions = subscriptions.counts();
+                });
                 if is_psync {
                     break;
                 }
             }
-            Err(_) => break,
+            _ = client::killed() => break,
+            Some(message) = subscriptions.recv() => {
+                let _ = locked_stream
+                    .write_all(&message.encode(client::protocol()))
+                    .await;
+                let _ = locked_stream.flush().await;
+                if subscriptions.evicted() {
+                    break;
+                }
+            }
         }
     }
+    subscriptions.clear().await;
+    tracking::disconnect(client::current().id);
 
     if is_psync {
+        client::update(|client| client.role = Role::Replica);
         let tcp_stream = Arc::try_unwrap(stream)
             .expect("Should be sole owner")
             .into_inner();
@@ -241,15 +482,36 @@ async fn psync(mut stream: TcpStream, server_metadata: &Arc<RwLock<ServerMetadat
         let mut metadata = server_metadata.write().await;
         metadata.replica_offsets.push(Arc::clone(&replica_offset));
     }
+    // Taken before `server_metadata`, in the order commands take them.
+    let exclusive = EXEC_LOCK.write().await;
     let metadata = server_metadata.read().await;
-    let repl_offset = metadata.master_repl_offset.load(Ordering::SeqCst);
+    // The keyspace is copied, the subscription taken and the offset read
+    // together under `EXEC_LOCK`: no command sits between changing the
+    // keyspace and sending itself to replicas, so each write is either in
+    // the snapshot or comes through the subscription, never both. The copy
+    // is then serialised off the lock, like a background save.
+    let (snapshot, mut receiver, repl_offset) = {
+        let storage = storage::STORAGE.lock().await;
+        let snapshot: Vec<(String, DBEntry)> = storage
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect();
+        (
+            snapshot,
+            metadata.broadcast.subscribe(),
+            metadata.master_repl_offset.load(Ordering::SeqCst),
+        )
+    };
+    drop(exclusive);
+    let rdb_file = tokio::task::spawn_blocking(move || {
+        rdb::encode(snapshot.iter().map(|(key, entry)| (key, entry)))
+    })
+    .await
+    .unwrap_or_default();
     let res = format!("+FULLRESYNC {} {}\r\n", metadata.master_replid, repl_offset);
     let _ = stream.write_all(res.as_bytes()).await;
     let _ = stream.flush().await;
 
-    let rdb_file = hex::decode("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2")
-    .map_err(|decode_err| IOError::new(ErrorKind::InvalidData, decode_err.to_string())).unwrap();
-
     let _ = stream
         .write_all(format!("${}\r\n", rdb_file.len()).as_bytes())
         .await;
@@ -257,10 +519,8 @@ async fn psync(mut stream: TcpStream, server_metadata: &Arc<RwLock<ServerMetadat
     let _ = stream.flush().await;
     let (read_half, write_half) = stream.into_split();
 
-    let mut receiver = metadata.broadcast.subscribe();
-
     // Writer: forwards broadcast messages to replica.
-    tokio::spawn(async move {
+    let writer = tokio::spawn(async move {
         let mut writer = write_half;
         while let Ok(data) = receiver.recv().await {
             let _ = writer.write_all(data.as_slice()).await;
@@ -268,33 +528,37 @@ async fn psync(mut stream: TcpStream, server_metadata: &Arc<RwLock<ServerMetadat
         }
     });
 
-    // Reader: reads ACK responses from replica.
-    let replica_offset_clone = Arc::clone(&replica_offset);
-    let ack_notify_clone = Arc::clone(&metadata.ack_notify);
-    tokio::spawn(async move {
-        let mut reader = read_half;
-        let mut buf = [0u8; 256];
-        loop {
-            match reader.read(&mut buf).await {
-                Ok(0) => break,
-                Ok(n) => {
-                    if let Ok(commands) = parser::parse_request(&buf[..n]) {
-                        for cmd in commands {
-                            if cmd.cmd.to_lowercase() == "replconf" {
-                                if let Some(offset_str) = cmd.args.get(1) {
-                                    if let Ok(offset) = offset_str.parse::<u64>() {
-                                        replica_offset_clone.store(offset, Ordering::SeqCst);
-                                        ack_notify_clone.notify_waiters();
-                                    }
+    // Reader: reads ACK responses from replica, on the connection's own task
+    // so that it stays in the client table.
+    let ack_notify = Arc::clone(&metadata.ack_notify);
+    drop(metadata);
+    let mut reader = read_half;
+    let mut buf = [0u8; 256];
+    loop {
+        let read = tokio::select! {
+            read = reader.read(&mut buf) => read,
+            _ = client::killed() => break,
+        };
+        match read {
+            Ok(0) => break,
+            Ok(n) => {
+                if let Ok((commands, _)) = parser::parse_request(&buf[..n]) {
+                    for cmd in commands {
+                        if cmd.cmd.to_lowercase() == "replconf" {
+                            if let Some(offset_str) = cmd.args.get(1) {
+                                if let Ok(offset) = offset_str.parse::<u64>() {
+                                    replica_offset.store(offset, Ordering::SeqCst);
+                                    ack_notify.notify_waiters();
                                 }
                             }
                         }
                     }
-                    let data = String::from_utf8_lossy(&buf[..n]);
-                    eprintln!("Replica responded: {}", data);
                 }
-                Err(_) => break,
+                let data = String::from_utf8_lossy(&buf[..n]);
+                eprintln!("Replica responded: {}", data);
             }
+            Err(_) => break,
         }
-    });
+    }
+    writer.abort();
 }
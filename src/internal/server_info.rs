@@ -1,10 +1,66 @@
-use crate::internal::server::ServerMetadata;
-use crate::Error;
-use std::sync::atomic::Ordering;
+use crate::internal::{
+    aof, client, persistence,
+    resp::Reply,
+    server::{self, ServerMetadata},
+    tracking,
+};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
 
-pub fn get_server_info(
-    server_metadata: &ServerMetadata,
-) -> Result<String, Box<dyn Error + Send + Sync>> {
+/// Error replies sent to clients, reported as `total_error_replies`.
+static TOTAL_ERROR_REPLIES: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Error replies by code, reported in the `errorstats` section.
+    static ref ERROR_STATS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+}
+
+/// Accounts for the error replies found in a reply about to be sent,
+/// including the ones nested in e.g. an `EXEC` result. Returns how many
+/// there were.
+pub fn record_errors(reply: &Reply) -> u64 {
+    match reply {
+        Reply::Error(message) => {
+            let code = message.split(' ').next().unwrap_or_default();
+            TOTAL_ERROR_REPLIES.fetch_add(1, Ordering::Relaxed);
+            *ERROR_STATS
+                .lock()
+                .unwrap()
+                .entry(code.to_string())
+                .or_default() += 1;
+            1
+        }
+        Reply::Array(replies)
+        | Reply::Set(replies)
+        | Reply::Push(replies)
+        | Reply::Sequence(replies) => replies.iter().map(record_errors).sum(),
+        _ => 0,
+    }
+}
+
+fn clients() -> Vec<String> {
+    let (connected, blocked) = client::stats();
+    vec![
+        format!("connected_clients:{}", connected),
+        format!("blocked_clients:{}", blocked),
+        format!(
+            "tracking_clients:{}",
+            client::list()
+                .iter()
+                .filter(|client| client.tracking.is_some())
+                .count()
+        ),
+        format!("client_error_replies:{}", client::current().errors),
+    ]
+}
+
+fn replication(server_metadata: &ServerMetadata) -> Vec<String> {
     let repl_offset = server_metadata.master_repl_offset.load(Ordering::SeqCst);
     let mut response = vec![
         format!("master_replid:{}", server_metadata.master_replid),
@@ -14,7 +70,69 @@ pub fn get_server_info(
         response.push("role:master".to_string());
     } else if server_metadata.role == 1 {
         response.push("role:slave".to_string());
+        response.push(format!(
+            "master_sync_in_progress:{}",
+            server::master_sync_in_progress() as u8
+        ));
+    }
+    response
+}
+
+fn stats() -> Vec<String> {
+    let (tracking_keys, tracking_prefixes) = tracking::stats();
+    vec![
+        format!("tracking_total_keys:{}", tracking_keys),
+        format!("tracking_total_prefixes:{}", tracking_prefixes),
+        format!(
+            "total_error_replies:{}",
+            TOTAL_ERROR_REPLIES.load(Ordering::Relaxed)
+        ),
+    ]
+}
+
+fn errorstats() -> Vec<String> {
+    ERROR_STATS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(code, count)| format!("errorstat_{}:count={}", code, count))
+        .collect()
+}
+
+/// `INFO` output for the requested sections, all of them when none is given.
+pub fn get_server_info(
+    server_metadata: &ServerMetadata,
+    sections: &[String],
+) -> Result<Reply, Box<dyn Error + Send + Sync>> {
+    let all = sections.is_empty()
+        || sections.iter().any(|section| {
+            ["all", "default", "everything"]
+                .iter()
+                .any(|all| section.eq_ignore_ascii_case(all))
+        });
+    let mut rtn = String::new();
+    for (name, lines) in [
+        ("Clients", clients()),
+        ("Persistence", [persistence::info(), aof::info()].concat()),
+        ("Replication", replication(server_metadata)),
+        ("Stats", stats()),
+        ("Errorstats", errorstats()),
+    ] {
+        if !all
+            && !sections
+                .iter()
+                .any(|section| section.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        if !rtn.is_empty() {
+            rtn.push_str("\r\n");
+        }
+        rtn.push_str(&format!("# {}\r\n", name));
+        for line in lines {
+            rtn.push_str(&line);
+            rtn.push_str("\r\n");
+        }
     }
-    let rtn = response.join(" ");
-    Ok(format!("${}\r\n{}\r\n", rtn.len(), rtn))
+    Ok(Reply::Verbatim("txt", rtn))
 }
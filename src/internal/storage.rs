@@ -1,18 +1,141 @@
 use crate::internal::{
+    aof,
     commands::CommandError::StorageError,
-    types::{DBValue, StreamType},
+    notify, persistence,
+    resp::Reply,
+    tracking,
+    types::{DBValue, StreamType, StringType},
 };
 use core::str;
 use std::{
-    collections::HashMap,
-    time::{Duration, SystemTime},
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex as SyncMutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::sync::Mutex;
 
 use super::commands::CommandError;
 
+/// Keys the active expire cycle checks at a time, as in Redis.
+const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+
+/// Most time a cycle runs for, a quarter of its period like Redis' slow
+/// cycle.
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
+
 lazy_static! {
     pub static ref STORAGE: Mutex<HashMap<String, DBEntry>> = Mutex::new(HashMap::new());
+    /// Keys stored with a TTL, which the active expire cycle samples.
+    static ref EXPIRES: SyncMutex<Expires> = SyncMutex::new(Expires::default());
+}
+
+/// Keys given a TTL, like Redis' `expires` dict, for the active expire cycle
+/// to pick random keys from. Keys deleted or overwritten without a TTL since
+/// are dropped once picked.
+#[derive(Default)]
+struct Expires {
+    keys: Vec<String>,
+    positions: HashMap<String, usize>,
+}
+
+impl Expires {
+    fn insert(&mut self, key: &str) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(key.to_string(), self.keys.len());
+            self.keys.push(key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(pos) = self.positions.remove(key) else {
+            return;
+        };
+        self.keys.swap_remove(pos);
+        if let Some(moved) = self.keys.get(pos) {
+            self.positions.insert(moved.clone(), pos);
+        }
+    }
+
+    /// Up to `count` distinct keys picked at random.
+    fn sample(&self, count: usize) -> Vec<String> {
+        if self.keys.len() <= count {
+            return self.keys.clone();
+        }
+        let mut sample: Vec<String> = (0..count)
+            .map(|_| self.keys[random() as usize % self.keys.len()].clone())
+            .collect();
+        sample.sort_unstable();
+        sample.dedup();
+        sample
+    }
+}
+
+/// A random number, good enough for sampling.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Registers a key just stored with a TTL, for the active expire cycle.
+pub fn track_expiry(key: &str) {
+    EXPIRES.lock().unwrap().insert(key);
+}
+
+/// Source of entry versions, bumped on every modification so `WATCH` can
+/// tell whether a key changed even if it was deleted and recreated meanwhile.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Drops the key if its TTL elapsed, firing the `expired` event and logging
+/// a `DEL` to the AOF. Returns whether it was removed.
+pub async fn expire_if_needed(storage: &mut HashMap<String, DBEntry>, key: &str) -> bool {
+    if storage.get(key).is_none_or(DBEntry::still_valid) {
+        return false;
+    }
+    storage.remove(key);
+    EXPIRES.lock().unwrap().remove(key);
+    persistence::mark_dirty(1);
+    aof::feed(&Reply::bulks(["DEL", key]).encode(2));
+    tracking::invalidate(&[key.to_string()], None);
+    notify::notify_keyspace_event(notify::EXPIRED, "expired", key).await;
+    true
+}
+
+/// Reclaims expired keys nobody accesses, like Redis' active expire cycle:
+/// checks random keys with a TTL and goes on while more than a quarter of
+/// them had expired, within a time limit so clients are not kept waiting.
+pub async fn expire_keys() {
+    let started = Instant::now();
+    loop {
+        let sample = EXPIRES.lock().unwrap().sample(ACTIVE_EXPIRE_KEYS_PER_LOOP);
+        if sample.is_empty() {
+            return;
+        }
+        let mut storage = STORAGE.lock().await;
+        let mut expired = 0;
+        for key in &sample {
+            if storage
+                .get(key)
+                .is_none_or(|entry| entry.expiry().is_none())
+            {
+                // Deleted or persisted since it was registered.
+                EXPIRES.lock().unwrap().remove(key);
+                expired += 1;
+            } else if expire_if_needed(&mut storage, key).await {
+                expired += 1;
+            }
+        }
+        drop(storage);
+        if expired * 4 <= sample.len() || started.elapsed() >= ACTIVE_EXPIRE_TIME_LIMIT {
+            return;
+        }
+    }
 }
 
 pub struct DBEntry {
@@ -21,18 +144,36 @@ pub struct DBEntry {
 }
 
 impl DBEntry {
-    pub fn from_string(value: &str) -> Self {
+    pub fn from_string(value: &[u8]) -> Self {
         // TODO: add check for `px` parameter
         DBEntry {
-            item: Box::new(value.to_string()),
-            metadata: DBEntryMetadata { expire_at: None },
+            item: Box::new(StringType {
+                bytes: value.to_vec(),
+            }),
+            metadata: DBEntryMetadata {
+                expire_at: None,
+                version: next_version(),
+            },
         }
     }
 
     pub fn from_stream(value: StreamType) -> Self {
         DBEntry {
             item: Box::new(value),
-            metadata: DBEntryMetadata { expire_at: None },
+            metadata: DBEntryMetadata {
+                expire_at: None,
+                version: next_version(),
+            },
+        }
+    }
+
+    pub fn from_value<V: DBValue + 'static>(value: V) -> Self {
+        DBEntry {
+            item: Box::new(value),
+            metadata: DBEntryMetadata {
+                expire_at: None,
+                version: next_version(),
+            },
         }
     }
 
@@ -44,6 +185,8 @@ impl DBEntry {
         Err(StorageError("Value has expired".to_string()))
     }
 
+    /// The value, to modify in place. Callers that did modify it `touch`
+    /// the entry afterwards.
     pub fn value_mut(&mut self) -> Result<&mut dyn DBValue, CommandError> {
         if self.still_valid() {
             return Ok(self.item.as_mut());
@@ -52,27 +195,24 @@ impl DBEntry {
         Err(StorageError("Value has expired".to_string()))
     }
 
-    pub fn set_ttl(&mut self, duration_str: Option<&String>) -> Result<(), CommandError> {
-        if let Some(duration_str) = duration_str {
-            if let Ok(millis) = duration_str.parse::<u64>() {
-                let duration = Duration::from_millis(millis);
-                self.metadata.expire_at = Some(SystemTime::now() + duration);
-                Ok(())
-            } else {
-                Err(CommandError::InvalidArgument(format!(
-                    "Invalid duration provided '{}'",
-                    duration_str
-                )))
-            }
-        } else {
-            Err(CommandError::InvalidArgument(
-                "Duration was not provided".to_string(),
-            ))
-        }
+    /// Bumps the version once the value was modified in place, so `WATCH`
+    /// notices.
+    pub fn touch(&mut self) {
+        self.metadata.version = next_version();
+    }
+
+    pub fn expiry(&self) -> Option<SystemTime> {
+        self.metadata.expire_at
     }
 
     pub fn set_expiry_at(&mut self, at: SystemTime) {
-        self.metadata.expire_at = Some(at)
+        self.metadata.expire_at = Some(at);
+        self.metadata.version = next_version();
+    }
+
+    /// Version of the entry, `None` once it has expired.
+    pub fn version(&self) -> Option<u64> {
+        self.still_valid().then_some(self.metadata.version)
     }
 
     fn still_valid(&self) -> bool {
@@ -85,7 +225,17 @@ impl DBEntry {
     }
 }
 
+impl Clone for DBEntry {
+    fn clone(&self) -> Self {
+        DBEntry {
+            item: self.item.boxed_clone(),
+            metadata: self.metadata,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct DBEntryMetadata {
     expire_at: Option<SystemTime>,
+    version: u64,
 }
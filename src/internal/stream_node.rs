@@ -0,0 +1,384 @@
+//! Compact storage for a run of stream entries, modelled after the listpacks
+//! Redis keeps in the stream radix tree.
+//!
+//! A node starts with a *master entry*: the ID of its first entry and that
+//! entry's field names. Every entry is then appended to a byte buffer as
+//!
+//! ```text
+//! flags | ms-delta | seq-delta | values...                 (SAMEFIELDS set)
+//! flags | ms-delta | seq-delta | n-fields | field value ... (otherwise)
+//! ```
+//!
+//! with IDs delta-encoded against the master ID, so entries sharing the master
+//! field names only pay for their values. Deleting an entry sets its `DELETED`
+//! flag in place; the node is dropped once no live entry remains.
+
+use crate::internal::types::{StreamFields, StreamId};
+
+/// Nodes stop accepting entries past this many bytes, mirroring Redis'
+/// `stream-node-max-bytes` default.
+pub const STREAM_NODE_MAX_BYTES: usize = 4096;
+
+const FLAG_DELETED: u8 = 1;
+const FLAG_SAMEFIELDS: u8 = 1 << 1;
+
+#[derive(Debug, Clone)]
+pub struct StreamNode {
+    master_id: StreamId,
+    master_fields: Vec<String>,
+    data: Vec<u8>,
+    /// Live entries.
+    count: usize,
+    /// Entries flagged as deleted but still taking space in `data`.
+    deleted: usize,
+    last_id: StreamId,
+}
+
+/// A decoded entry along with the offset of its flags byte in the node.
+struct RawEntry {
+    offset: usize,
+    deleted: bool,
+    id: StreamId,
+    fields: StreamFields,
+}
+
+impl StreamNode {
+    pub fn new(id: StreamId, fields: &[(String, String)]) -> Self {
+        let mut node = StreamNode {
+            master_id: id,
+            master_fields: fields.iter().map(|(field, _)| field.clone()).collect(),
+            data: Vec::new(),
+            count: 0,
+            deleted: 0,
+            last_id: id,
+        };
+        node.append(id, fields);
+        node
+    }
+
+    /// ID of the last entry appended, deleted or not.
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn is_full(&self, max_entries: usize) -> bool {
+        self.count + self.deleted >= max_entries || self.data.len() >= STREAM_NODE_MAX_BYTES
+    }
+
+    pub fn append(&mut self, id: StreamId, fields: &[(String, String)]) {
+        let same_fields = fields.len() == self.master_fields.len()
+            && fields
+                .iter()
+                .zip(&self.master_fields)
+                .all(|((field, _), master)| field == master);
+
+        self.data
+            .push(if same_fields { FLAG_SAMEFIELDS } else { 0 });
+        write_varint(&mut self.data, id.millis - self.master_id.millis);
+        write_varint(
+            &mut self.data,
+            zigzag(id.seq.wrapping_sub(self.master_id.seq) as i64),
+        );
+        if same_fields {
+            for (_, value) in fields {
+                write_str(&mut self.data, value);
+            }
+        } else {
+            write_varint(&mut self.data, fields.len() as u64);
+            for (field, value) in fields {
+                write_str(&mut self.data, field);
+                write_str(&mut self.data, value);
+            }
+        }
+        self.count += 1;
+        self.last_id = id;
+    }
+
+    fn raw_entries(&self) -> RawEntries<'_> {
+        RawEntries { node: self, pos: 0 }
+    }
+
+    /// Live entries in ascending ID order.
+    pub fn entries(&self) -> impl Iterator<Item = (StreamId, StreamFields)> + '_ {
+        self.raw_entries()
+            .filter(|entry| !entry.deleted)
+            .map(|entry| (entry.id, entry.fields))
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<StreamFields> {
+        self.entries()
+            .take_while(|(entry_id, _)| *entry_id <= id)
+            .find(|(entry_id, _)| *entry_id == id)
+            .map(|(_, fields)| fields)
+    }
+
+    /// Flags the entry as deleted, returning whether it was live.
+    pub fn delete(&mut self, id: StreamId) -> bool {
+        let offset = self
+            .raw_entries()
+            .take_while(|entry| entry.id <= id)
+            .find(|entry| entry.id == id && !entry.deleted)
+            .map(|entry| entry.offset);
+        match offset {
+            Some(offset) => {
+                self.data[offset] |= FLAG_DELETED;
+                self.count -= 1;
+                self.deleted += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Flags live entries as deleted from the head of the node for as long as
+    /// `evict` holds, returning how many were removed.
+    pub fn delete_head_while(&mut self, mut evict: impl FnMut(StreamId) -> bool) -> usize {
+        let offsets: Vec<usize> = self
+            .raw_entries()
+            .filter(|entry| !entry.deleted)
+            .take_while(|entry| evict(entry.id))
+            .map(|entry| entry.offset)
+            .collect();
+        for offset in &offsets {
+            self.data[*offset] |= FLAG_DELETED;
+        }
+        self.count -= offsets.len();
+        self.deleted += offsets.len();
+        offsets.len()
+    }
+
+    /// Approximate heap and inline size of the node in bytes.
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.data.capacity()
+            + self
+                .master_fields
+                .iter()
+                .map(|field| std::mem::size_of::<String>() + field.capacity())
+                .sum::<usize>()
+    }
+}
+
+struct RawEntries<'a> {
+    node: &'a StreamNode,
+    pos: usize,
+}
+
+impl Iterator for RawEntries<'_> {
+    type Item = RawEntry;
+
+    fn next(&mut self) -> Option<RawEntry> {
+        let data = &self.node.data;
+        if self.pos >= data.len() {
+            return None;
+        }
+        let offset = self.pos;
+        let flags = data[offset];
+        self.pos += 1;
+
+        let master = self.node.master_id;
+        let millis = master.millis + read_varint(data, &mut self.pos);
+        let seq = master
+            .seq
+            .wrapping_add(unzigzag(read_varint(data, &mut self.pos)) as u64);
+
+        let fields = if flags & FLAG_SAMEFIELDS != 0 {
+            self.node
+                .master_fields
+                .iter()
+                .map(|field| (field.clone(), read_str(data, &mut self.pos)))
+                .collect()
+        } else {
+            let n = read_varint(data, &mut self.pos) as usize;
+            (0..n)
+                .map(|_| {
+                    let field = read_str(data, &mut self.pos);
+                    (field, read_str(data, &mut self.pos))
+                })
+                .collect()
+        };
+
+        Some(RawEntry {
+            offset,
+            deleted: flags & FLAG_DELETED != 0,
+            id: StreamId { millis, seq },
+            fields,
+        })
+    }
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> u64 {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_varint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_str(buf: &[u8], pos: &mut usize) -> String {
+    let len = read_varint(buf, pos) as usize;
+    let s = String::from_utf8_lossy(&buf[*pos..*pos + len]).into_owned();
+    *pos += len;
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(millis: u64, seq: u64) -> StreamId {
+        StreamId { millis, seq }
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> StreamFields {
+        pairs
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn varint_round_trips() {
+        for n in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, n);
+            let mut pos = 0;
+            assert_eq!(read_varint(&buf, &mut pos), n);
+            assert_eq!(pos, buf.len());
+        }
+    }
+
+    #[test]
+    fn zigzag_round_trips() {
+        for n in [0, 1, -1, 63, -64, i64::MAX, i64::MIN] {
+            assert_eq!(unzigzag(zigzag(n)), n);
+        }
+        // Small magnitudes stay small either side of zero.
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let first = fields(&[("name", "a"), ("age", "1")]);
+        let same = fields(&[("name", "b"), ("age", "2")]);
+        let other = fields(&[("city", "c")]);
+        let empty = fields(&[("name", ""), ("age", "")]);
+        let mut node = StreamNode::new(id(1000, 5), &first);
+        node.append(id(1000, 6), &same);
+        // Lower sequence than the master ID, a negative delta.
+        node.append(id(1001, 0), &other);
+        // Far enough from the master ID to take a multi-byte varint.
+        node.append(id(u64::MAX, u64::MAX), &empty);
+
+        assert_eq!(node.len(), 4);
+        assert_eq!(node.last_id(), id(u64::MAX, u64::MAX));
+        assert_eq!(
+            node.entries().collect::<Vec<_>>(),
+            vec![
+                (id(1000, 5), first),
+                (id(1000, 6), same.clone()),
+                (id(1001, 0), other),
+                (id(u64::MAX, u64::MAX), empty),
+            ]
+        );
+        assert_eq!(node.get(id(1000, 6)), Some(same));
+        assert_eq!(node.get(id(1000, 7)), None);
+    }
+
+    #[test]
+    fn same_fields_entries_only_store_values() {
+        let mut node = StreamNode::new(id(1, 0), &fields(&[("field", "a")]));
+        let before = node.data.len();
+        node.append(id(1, 1), &fields(&[("field", "b")]));
+        // Flags, two one-byte deltas, then the value's length and byte.
+        assert_eq!(node.data.len() - before, 5);
+        assert_eq!(node.data[before], FLAG_SAMEFIELDS);
+    }
+
+    #[test]
+    fn delete_flags_entries_in_place() {
+        let mut node = StreamNode::new(id(1, 0), &fields(&[("f", "0")]));
+        node.append(id(1, 1), &fields(&[("f", "1")]));
+        node.append(id(1, 2), &fields(&[("f", "2")]));
+        let size = node.data.len();
+
+        assert!(node.delete(id(1, 1)));
+        assert!(!node.delete(id(1, 1)));
+        assert!(!node.delete(id(1, 3)));
+        assert_eq!(node.data.len(), size);
+        assert_eq!(node.len(), 2);
+        assert_eq!(node.get(id(1, 1)), None);
+        assert_eq!(
+            node.entries().map(|(id, _)| id).collect::<Vec<_>>(),
+            vec![id(1, 0), id(1, 2)]
+        );
+    }
+
+    #[test]
+    fn delete_head_while_skips_deleted_entries() {
+        let mut node = StreamNode::new(id(1, 0), &fields(&[("f", "0")]));
+        for seq in 1..5 {
+            node.append(id(1, seq), &fields(&[("f", "x")]));
+        }
+        node.delete(id(1, 1));
+
+        assert_eq!(node.delete_head_while(|id| id.seq < 3), 2);
+        assert_eq!(
+            node.entries().map(|(id, _)| id).collect::<Vec<_>>(),
+            vec![id(1, 3), id(1, 4)]
+        );
+        assert_eq!(node.delete_head_while(|_| true), 2);
+        assert!(node.is_empty());
+        // The last ID outlives its entry.
+        assert_eq!(node.last_id(), id(1, 4));
+    }
+
+    #[test]
+    fn deleted_entries_count_towards_fullness() {
+        let mut node = StreamNode::new(id(1, 0), &fields(&[("f", "0")]));
+        node.append(id(1, 1), &fields(&[("f", "1")]));
+        node.delete(id(1, 0));
+        assert!(node.is_full(2));
+        assert!(!node.is_full(3));
+
+        let big = "x".repeat(STREAM_NODE_MAX_BYTES);
+        let node = StreamNode::new(id(1, 0), &fields(&[("f", &big)]));
+        assert!(node.is_full(100));
+    }
+}
@@ -0,0 +1,325 @@
+//! Server assisted client side caching: connections enabling `CLIENT TRACKING`
+//! get told when keys they may have cached change.
+//!
+//! In the default mode the server remembers which connection read which key
+//! and notifies each of them once, on the next change. In `BCAST` mode
+//! connections rather register prefixes and hear about every key matching
+//! them. Invalidations are RESP3 pushes, or for RESP2 connections redirecting
+//! to a subscriber, messages on `__redis__:invalidate`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use crate::internal::{client, command_table, parser::Command, pubsub::Subscriber, resp::Reply};
+
+/// Channel RESP2 connections receive invalidations on.
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// Default `tracking-table-max-keys`.
+const DEFAULT_MAX_KEYS: usize = 1_000_000;
+
+lazy_static! {
+    static ref TABLE: Mutex<Table> = Mutex::new(Table::default());
+}
+
+/// A connection's `CLIENT TRACKING` options.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Connection invalidations are sent to instead of this one.
+    pub redirect: Option<u64>,
+    pub bcast: bool,
+    pub prefixes: Vec<String>,
+    pub optin: bool,
+    pub optout: bool,
+    /// Don't notify the connection about its own writes.
+    pub noloop: bool,
+    /// `CLIENT CACHING` answer for the next command.
+    pub caching: Option<bool>,
+}
+
+impl Options {
+    /// Whether keys read by the running command should be remembered.
+    fn tracks_reads(&self) -> bool {
+        !self.bcast
+            && if self.optin {
+                self.caching == Some(true)
+            } else if self.optout {
+                self.caching != Some(false)
+            } else {
+                true
+            }
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    /// Keys read in the default mode, with the connections that read them.
+    keys: HashMap<String, HashSet<u64>>,
+    /// `BCAST` prefixes, with the connections that registered them.
+    prefixes: BTreeMap<String, HashSet<u64>>,
+    /// Every open connection, as a possible invalidation target.
+    receivers: HashMap<u64, Subscriber>,
+    /// `tracking-table-max-keys`, 0 for no limit.
+    max_keys: Option<usize>,
+}
+
+impl Table {
+    fn max_keys(&self) -> usize {
+        self.max_keys.unwrap_or(DEFAULT_MAX_KEYS)
+    }
+
+    /// Sends `keys` (`None` for everything) to the connection tracking them,
+    /// or to the one it redirects to.
+    fn notify(&self, id: u64, keys: Option<&[String]>) {
+        let Some(tracker) = client::get(id) else {
+            return;
+        };
+        let Some(options) = tracker.tracking.as_ref() else {
+            return;
+        };
+        let keys = match keys {
+            Some(keys) => Reply::bulks(keys.iter().cloned()),
+            None => Reply::Null,
+        };
+        let target = options.redirect.unwrap_or(id);
+        let Some(target_client) = client::get(target) else {
+            // The redirect target is gone: tell RESP3 connections.
+            if tracker.protocol == 3 {
+                self.deliver(
+                    id,
+                    Reply::Push(vec![
+                        Reply::bulk("tracking-redir-broken"),
+                        Reply::Integer(target as i64),
+                    ]),
+                );
+            }
+            return;
+        };
+        if target_client.protocol == 3 {
+            self.deliver(target, Reply::Push(vec![Reply::bulk("invalidate"), keys]));
+        } else if options.redirect.is_some() && target_client.subscriptions != (0, 0, 0) {
+            self.deliver(
+                target,
+                Reply::Push(vec![
+                    Reply::bulk("message"),
+                    Reply::bulk(INVALIDATE_CHANNEL),
+                    keys,
+                ]),
+            );
+        }
+        // A RESP2 connection without a redirect cannot be told anything.
+    }
+
+    fn deliver(&self, id: u64, frame: Reply) {
+        if let Some(receiver) = self.receivers.get(&id) {
+            receiver.deliver(&Arc::new(frame));
+        }
+    }
+
+    /// Forgets `keys`, notifying who tracked them, as well as the `BCAST`
+    /// connections with a matching prefix.
+    fn invalidate(&mut self, keys: &[String], origin: Option<u64>) {
+        let mut targets: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for key in keys {
+            let readers = self.keys.remove(key).unwrap_or_default();
+            let bcast = self
+                .prefixes
+                .iter()
+                .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+                .flat_map(|(_, ids)| ids.iter().copied());
+            for id in readers.into_iter().chain(bcast) {
+                let keys = targets.entry(id).or_default();
+                if !keys.contains(key) {
+                    keys.push(key.clone());
+                }
+            }
+        }
+        for (id, keys) in targets {
+            let noloop = client::get(id)
+                .and_then(|client| client.tracking)
+                .is_some_and(|options| options.noloop);
+            if Some(id) == origin && noloop {
+                continue;
+            }
+            self.notify(id, Some(&keys));
+        }
+    }
+
+    /// Drops keys until the table fits `tracking-table-max-keys`, notifying
+    /// their readers as if the keys had changed.
+    fn shrink(&mut self) {
+        let max_keys = self.max_keys();
+        if max_keys == 0 || self.keys.len() <= max_keys {
+            return;
+        }
+        let evicted: Vec<String> = self
+            .keys
+            .keys()
+            .take(self.keys.len() - max_keys)
+            .cloned()
+            .collect();
+        self.invalidate(&evicted, None);
+    }
+}
+
+/// Makes the connection reachable by invalidation messages, its own or the
+/// ones redirected to it.
+pub fn connect(id: u64, subscriber: Subscriber) {
+    TABLE.lock().unwrap().receivers.insert(id, subscriber);
+}
+
+/// Forgets a closed connection. Keys it read are dropped lazily.
+pub fn disconnect(id: u64) {
+    let mut table = TABLE.lock().unwrap();
+    table.receivers.remove(&id);
+    table.prefixes.retain(|_, ids| {
+        ids.remove(&id);
+        !ids.is_empty()
+    });
+}
+
+/// `CLIENT TRACKING ON` for the current connection.
+pub fn enable(mut options: Options) -> Result<(), String> {
+    let current = client::current();
+    if let Some(redirect) = options.redirect {
+        if client::get(redirect).is_none() {
+            return Err("The client ID you want redirect to does not exist".to_string());
+        }
+    }
+    if !options.bcast && !options.prefixes.is_empty() {
+        return Err("PREFIX option requires BCAST mode to be enabled".to_string());
+    }
+    if options.optin && options.optout {
+        return Err("You can't use both OPTIN and OPTOUT options".to_string());
+    }
+    if options.bcast && (options.optin || options.optout) {
+        return Err("OPTIN and OPTOUT are not compatible with BCAST".to_string());
+    }
+    if let Some(enabled) = current.tracking.as_ref() {
+        if enabled.bcast != options.bcast {
+            return Err("You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.".to_string());
+        }
+        if enabled.optin != options.optin || enabled.optout != options.optout {
+            return Err("You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.".to_string());
+        }
+        // Prefixes accumulate across `CLIENT TRACKING ON` calls.
+        let mut prefixes = enabled.prefixes.clone();
+        prefixes.extend(options.prefixes);
+        options.prefixes = prefixes;
+    }
+    if options.bcast && options.prefixes.is_empty() {
+        options.prefixes.push(String::new());
+    }
+    options.prefixes.sort();
+    options.prefixes.dedup();
+    for (i, prefix) in options.prefixes.iter().enumerate() {
+        for other in &options.prefixes[i + 1..] {
+            if prefix.starts_with(other) || other.starts_with(prefix) {
+                return Err(format!(
+                    "Prefix '{}' overlaps with another provided prefix '{}'. Prefixes for a single client must not overlap.",
+                    prefix, other
+                ));
+            }
+        }
+    }
+    let mut table = TABLE.lock().unwrap();
+    for prefix in &options.prefixes {
+        table
+            .prefixes
+            .entry(prefix.clone())
+            .or_default()
+            .insert(current.id);
+    }
+    drop(table);
+    client::update(|client| client.tracking = Some(options));
+    Ok(())
+}
+
+/// `CLIENT TRACKING OFF` for the current connection.
+pub fn disable() {
+    let id = client::current().id;
+    TABLE.lock().unwrap().prefixes.retain(|_, ids| {
+        ids.remove(&id);
+        !ids.is_empty()
+    });
+    client::update(|client| client.tracking = None);
+}
+
+/// The keys a command reads or writes, captured before it runs since the
+/// handler consumes the command.
+pub enum Access {
+    Read(Vec<String>),
+    Write(Vec<String>),
+    Other,
+}
+
+impl Access {
+    pub fn of(command: &Command) -> Self {
+        let Some(spec) = command_table::lookup(&command.cmd) else {
+            return Access::Other;
+        };
+        let spec = command
+            .args
+            .first()
+            .and_then(|sub| spec.subcommand(sub))
+            .unwrap_or(spec);
+        let read = spec.has_flag(command_table::READONLY);
+        let write = spec.has_flag(command_table::WRITE);
+        if !read && !write {
+            return Access::Other;
+        }
+        let argv: Vec<String> = std::iter::once(command.cmd.clone())
+            .chain(command.args.iter().cloned())
+            .collect();
+        let keys = spec.keys(&argv).into_iter().cloned().collect();
+        if write {
+            Access::Write(keys)
+        } else {
+            Access::Read(keys)
+        }
+    }
+
+    /// Remembers the keys read by a tracking connection, or invalidates the
+    /// keys written.
+    pub fn apply(self) {
+        match self {
+            Access::Read(keys) => {
+                let current = client::current();
+                if !current.tracking.as_ref().is_some_and(Options::tracks_reads) {
+                    return;
+                }
+                let mut table = TABLE.lock().unwrap();
+                for key in keys {
+                    table.keys.entry(key).or_default().insert(current.id);
+                }
+                table.shrink();
+            }
+            Access::Write(keys) => invalidate(&keys, Some(client::current().id)),
+            Access::Other => {}
+        }
+    }
+}
+
+/// Tells the connections tracking `keys` that they changed. `origin` is the
+/// connection making the change, if any, for `NOLOOP`.
+pub fn invalidate(keys: &[String], origin: Option<u64>) {
+    TABLE.lock().unwrap().invalidate(keys, origin);
+}
+
+pub fn max_keys() -> usize {
+    TABLE.lock().unwrap().max_keys()
+}
+
+pub fn set_max_keys(max_keys: usize) {
+    let mut table = TABLE.lock().unwrap();
+    table.max_keys = Some(max_keys);
+    table.shrink();
+}
+
+/// Keys and prefixes tracked, as reported by `INFO`.
+pub fn stats() -> (usize, usize) {
+    let table = TABLE.lock().unwrap();
+    (table.keys.len(), table.prefixes.len())
+}
@@ -0,0 +1,290 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use crate::internal::{
+    acl, aof, client,
+    commands::{self, CommandsReg},
+    parser::Command,
+    resp::Reply,
+    server::ServerMetadata,
+    storage::STORAGE,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{RwLock, RwLockReadGuard},
+};
+
+lazy_static! {
+    /// Every command runs holding this for reading, blocking ones only between
+    /// waits, and `EXEC` takes it for writing so that no other client observes
+    /// a half-applied transaction.
+    pub static ref EXEC_LOCK: RwLock<()> = RwLock::new(());
+}
+
+/// `EXEC_LOCK` for a blocking command, which runs without it so that its
+/// waits don't hold up `EXEC`, and takes it between waits. Run by `EXEC`,
+/// which holds the lock already, there is nothing to take.
+pub async fn lock_for_blocking() -> Option<RwLockReadGuard<'static, ()>> {
+    match client::blocking_allowed() {
+        true => Some(EXEC_LOCK.read().await),
+        false => None,
+    }
+}
+
+const MULTI_RAW: &str = "*1\r\n$5\r\nMULTI\r\n";
+const EXEC_RAW: &str = "*1\r\n$4\r\nEXEC\r\n";
+
+/// Per-connection `MULTI`/`WATCH` state.
+#[derive(Default)]
+pub struct Transaction {
+    /// Commands queued since `MULTI`, `None` outside a transaction.
+    queued: Option<Vec<Command>>,
+    /// A command failed to queue, so `EXEC` must refuse to run.
+    aborted: bool,
+    /// Watched keys with the version they had when watched, `None` when the
+    /// key did not exist (or had expired).
+    watched: Vec<(String, Option<u64>)>,
+}
+
+impl Transaction {
+    /// Handles the transaction commands and queues everything else while in
+    /// `MULTI`. Returns the command back when it should run right away.
+    ///
+    /// `replies` is off for the master link, where the replica stays silent.
+    pub async fn process(
+        &mut self,
+        stream: &Arc<RwLock<TcpStream>>,
+        command: Command,
+        server_metadata: &Arc<RwLock<ServerMetadata>>,
+        command_reg: &CommandsReg,
+        replies: bool,
+    ) -> Option<Command> {
+        let name = command.cmd.to_lowercase();
+        let transactional = matches!(
+            name.as_str(),
+            "multi" | "exec" | "discard" | "watch" | "unwatch"
+        );
+        if transactional && replies {
+            if let Err(e) = acl::authorize(&command, self.in_multi()) {
+                if self.in_multi() {
+                    self.aborted = true;
+                }
+                commands::write_reply(stream, &e.into()).await;
+                return None;
+            }
+        }
+        if name == "multi" || name == "exec" {
+            _count_replicated(&command, server_metadata).await;
+        }
+        let res = match name.as_str() {
+            "multi" => self.multi(),
+            "exec" => self.exec(server_metadata, command_reg).await,
+            "discard" => self.discard(),
+            "watch" => self.watch(&command).await,
+            "unwatch" => {
+                self.watched.clear();
+                Reply::ok()
+            }
+            _ => {
+                let Some(queued) = self.queued.as_mut() else {
+                    return Some(command);
+                };
+                // Checked again by `EXEC`, since permissions may change
+                // meanwhile.
+                let checked = command_reg
+                    .check(&command)
+                    .and_then(|()| acl::authorize(&command, true));
+                match checked {
+                    Ok(()) => {
+                        queued.push(command);
+                        Reply::Simple("QUEUED".to_string())
+                    }
+                    Err(e) => {
+                        self.aborted = true;
+                        e.into()
+                    }
+                }
+            }
+        };
+        if replies {
+            commands::write_reply(stream, &res).await;
+        }
+        None
+    }
+
+    fn multi(&mut self) -> Reply {
+        if self.queued.is_some() {
+            return Reply::Error("ERR MULTI calls can not be nested".to_string());
+        }
+        self.queued = Some(Vec::new());
+        self.aborted = false;
+        Reply::ok()
+    }
+
+    fn discard(&mut self) -> Reply {
+        if self.queued.is_none() {
+            return Reply::Error("ERR DISCARD without MULTI".to_string());
+        }
+        self.reset();
+        Reply::ok()
+    }
+
+    async fn watch(&mut self, command: &Command) -> Reply {
+        if self.queued.is_some() {
+            return Reply::Error("ERR WATCH inside MULTI is not allowed".to_string());
+        }
+        if command.args.is_empty() {
+            return Reply::Error("ERR wrong number of arguments for 'watch' command".to_string());
+        }
+        let storage = STORAGE.lock().await;
+        for key in &command.args {
+            if self.watched.iter().all(|(watched, _)| watched != key) {
+                let version = storage.get(key).and_then(|entry| entry.version());
+                self.watched.push((key.clone(), version));
+            }
+        }
+        Reply::ok()
+    }
+
+    async fn exec(
+        &mut self,
+        server_metadata: &Arc<RwLock<ServerMetadata>>,
+        command_reg: &CommandsReg,
+    ) -> Reply {
+        let Some(queued) = self.queued.take() else {
+            return Reply::Error("ERR EXEC without MULTI".to_string());
+        };
+        let aborted = self.aborted;
+        let watched = std::mem::take(&mut self.watched);
+        self.reset();
+        if aborted {
+            return Reply::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            );
+        }
+
+        let _exclusive = EXEC_LOCK.write().await;
+        {
+            let storage = STORAGE.lock().await;
+            let touched = watched.iter().any(|(key, version)| {
+                storage.get(key).and_then(|entry| entry.version()) != *version
+            });
+            if touched {
+                return Reply::NullArray;
+            }
+        }
+
+        let propagate = queued.iter().any(commands::is_write_command);
+        if propagate {
+            aof::feed(MULTI_RAW.as_bytes());
+            _propagate(MULTI_RAW, server_metadata).await;
+        }
+        // Nothing may block while holding `EXEC_LOCK`: other clients would
+        // wait as long.
+        let denied = !client::blocking_allowed();
+        client::set_deny_blocking(true);
+        let mut res = Vec::with_capacity(queued.len());
+        for command in queued {
+            res.push(
+                commands::dispatch(
+                    command,
+                    server_metadata,
+                    command_reg,
+                    commands::Context::Multi,
+                )
+                .await,
+            );
+        }
+        client::set_deny_blocking(denied);
+        if propagate {
+            aof::feed(EXEC_RAW.as_bytes());
+            _propagate(EXEC_RAW, server_metadata).await;
+        }
+        Reply::Array(res)
+    }
+
+    pub fn in_multi(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    /// Number and size of the queued commands, `None` outside `MULTI`.
+    pub fn queued(&self) -> Option<(usize, usize)> {
+        self.queued.as_ref().map(|queued| {
+            let size = queued.iter().map(|command| command.raw_cmd.len()).sum();
+            (queued.len(), size)
+        })
+    }
+
+    /// Leaves the transaction and forgets watched keys, as done by `RESET`.
+    pub fn reset(&mut self) {
+        self.queued = None;
+        self.aborted = false;
+        self.watched.clear();
+    }
+}
+
+/// Sends a transaction marker to the replicas, accounting for it in the
+/// replication offset like any other propagated command.
+async fn _propagate(raw: &str, server_metadata: &Arc<RwLock<ServerMetadata>>) {
+    let metadata = server_metadata.read().await;
+    if metadata.role != 0 {
+        return;
+    }
+    commands::sync_replicas(raw.as_bytes().to_vec(), &metadata.broadcast).await;
+    metadata
+        .master_repl_offset
+        .fetch_add(raw.len() as u64, Ordering::SeqCst);
+}
+
+/// On a replica, transaction markers received from the master count towards
+/// the processed replication offset.
+async fn _count_replicated(command: &Command, server_metadata: &Arc<RwLock<ServerMetadata>>) {
+    let metadata = server_metadata.read().await;
+    if metadata.role == 1 {
+        metadata
+            .master_repl_offset
+            .fetch_add(command.raw_cmd.len() as u64, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::internal::{commands::COMMANDS_REGISTRY, parser::parse_request};
+
+    #[tokio::test]
+    async fn exec_propagates_queued_writes() {
+        let xadd = b"*5\r\n$4\r\nXADD\r\n$21\r\ntransaction-test:xadd\r\n$3\r\n1-1\r\n$1\r\nf\r\n$1\r\nv\r\n";
+        let (commands, _) = parse_request(xadd).unwrap();
+        let metadata = Arc::new(RwLock::new(ServerMetadata::new(
+            "127.0.0.1",
+            6379,
+            false,
+            PathBuf::new(),
+            "dump.rdb".to_string(),
+        )));
+        let mut replica = metadata.read().await.broadcast.subscribe();
+
+        let mut transaction = Transaction::default();
+        transaction.multi();
+        transaction.queued.as_mut().unwrap().extend(commands);
+        let reply = transaction.exec(&metadata, &COMMANDS_REGISTRY).await;
+        assert_eq!(reply, Reply::Array(vec![Reply::bulk("1-1")]));
+
+        let mut propagated = Vec::new();
+        while let Ok(raw) = replica.try_recv() {
+            propagated.extend_from_slice(&raw);
+        }
+        let expected = [MULTI_RAW.as_bytes(), xadd, EXEC_RAW.as_bytes()].concat();
+        assert_eq!(propagated, expected);
+        assert_eq!(
+            metadata
+                .read()
+                .await
+                .master_repl_offset
+                .load(Ordering::SeqCst),
+            expected.len() as u64
+        );
+    }
+}
@@ -1,24 +1,43 @@
 use std::{
     any::Any,
-    collections::BTreeMap,
-    fmt::{Display, Formatter, Result as FmtResult, Write},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::{Display, Formatter, Result as FmtResult},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::internal::commands::CommandError;
+use crate::internal::{commands::CommandError, resp::Reply, stream_node::StreamNode};
 
 pub trait DBValue: Sync + Send + Display {
     fn type_name(&self) -> &'static str;
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn as_any_mut(&mut self) -> &mut dyn Any;
     fn as_any(&self) -> &dyn Any;
+    /// Approximate number of bytes the value takes in memory.
+    fn memory_usage(&self) -> usize;
     #[allow(unused)]
-    fn as_resp(&self) -> String;
+    fn as_resp(&self) -> Reply;
+    fn boxed_clone(&self) -> Box<dyn DBValue>;
+}
+
+/// A string value, binary safe: values such as `DUMP` payloads or images
+/// needn't be valid UTF-8.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StringType {
+    pub bytes: Vec<u8>,
+}
+
+impl Display for StringType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", String::from_utf8_lossy(&self.bytes))
+    }
 }
 
-impl DBValue for String {
+impl DBValue for StringType {
     fn len(&self) -> usize {
-        self.len()
+        self.bytes.len()
     }
 
     fn type_name(&self) -> &'static str {
@@ -33,25 +52,270 @@ impl DBValue for String {
         self
     }
 
-    fn as_resp(&self) -> String {
-        todo!()
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + self.bytes.capacity()
+    }
+
+    fn as_resp(&self) -> Reply {
+        Reply::BulkBytes(self.bytes.clone())
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DBValue> {
+        Box::new(self.clone())
+    }
+}
+
+// Collection types. The server has no commands for them yet; they hold what
+// an RDB file brings in so the keys exist, expire and show up in `TYPE`.
+
+/// A list, head first.
+#[derive(Debug, Default, Clone)]
+pub struct ListType {
+    pub items: VecDeque<String>,
+}
+
+impl Display for ListType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{} items", self.items.len())
+    }
+}
+
+impl DBValue for ListType {
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn type_name(&self) -> &'static str {
+        "list"
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + strings_memory_usage(self.items.iter())
+    }
+
+    fn as_resp(&self) -> Reply {
+        Reply::bulks(self.items.iter().cloned())
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DBValue> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SetType {
+    pub members: HashSet<String>,
+}
+
+impl Display for SetType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{} members", self.members.len())
+    }
+}
+
+impl DBValue for SetType {
+    fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    fn type_name(&self) -> &'static str {
+        "set"
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + strings_memory_usage(self.members.iter())
+    }
+
+    fn as_resp(&self) -> Reply {
+        Reply::Set(self.members.iter().cloned().map(Reply::bulk).collect())
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DBValue> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SortedSetType {
+    pub scores: HashMap<String, f64>,
+}
+
+impl SortedSetType {
+    /// Members ordered by score, then lexicographically.
+    pub fn sorted(&self) -> Vec<(&String, f64)> {
+        let mut members: Vec<(&String, f64)> = self
+            .scores
+            .iter()
+            .map(|(member, score)| (member, *score))
+            .collect();
+        members.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+        members
+    }
+}
+
+impl Display for SortedSetType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{} members", self.scores.len())
+    }
+}
+
+impl DBValue for SortedSetType {
+    fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    fn type_name(&self) -> &'static str {
+        "zset"
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + strings_memory_usage(self.scores.keys())
+            + self.scores.len() * std::mem::size_of::<f64>()
+    }
+
+    fn as_resp(&self) -> Reply {
+        Reply::Array(
+            self.sorted()
+                .into_iter()
+                .flat_map(|(member, score)| [Reply::bulk(member.clone()), Reply::Double(score)])
+                .collect(),
+        )
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DBValue> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct HashType {
+    pub fields: HashMap<String, String>,
+    /// Unix time in milliseconds at which a field expires, for fields with a
+    /// TTL.
+    pub expires: HashMap<String, u64>,
+}
+
+impl Display for HashType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{} fields", self.fields.len())
+    }
+}
+
+impl DBValue for HashType {
+    fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    fn type_name(&self) -> &'static str {
+        "hash"
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + strings_memory_usage(self.fields.keys().chain(self.fields.values()))
+            + self.expires.len() * std::mem::size_of::<u64>()
+    }
+
+    fn as_resp(&self) -> Reply {
+        Reply::Map(
+            self.fields
+                .iter()
+                .map(|(field, value)| (Reply::bulk(field.clone()), Reply::bulk(value.clone())))
+                .collect(),
+        )
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DBValue> {
+        Box::new(self.clone())
     }
 }
 
+fn strings_memory_usage<'a>(strings: impl Iterator<Item = &'a String>) -> usize {
+    strings
+        .map(|s| std::mem::size_of::<String>() + s.capacity())
+        .sum()
+}
+
 // StreamId implementation
 /// StreamId is meant for parsing and retrieving a stream id.
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct StreamId {
     pub millis: u64,
     pub seq: u64,
 }
 
-impl From<&String> for StreamId {
-    fn from(s: &String) -> Self {
-        let (ms, seq) = s.split_once('-').expect("Invalid format");
-        StreamId {
-            millis: ms.parse().expect("invalid millis"),
-            seq: seq.parse().expect("invalid seq"),
+impl StreamId {
+    pub const MIN: StreamId = StreamId { millis: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        millis: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses `<ms>-<seq>` or an incomplete `<ms>`, in which case the sequence
+    /// part is filled with `missing_seq`.
+    pub fn parse(s: &str, missing_seq: u64) -> Result<StreamId, CommandError> {
+        let (millis, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (
+                ms.parse().map_err(|_| invalid_id())?,
+                seq.parse().map_err(|_| invalid_id())?,
+            ),
+            None => (s.parse().map_err(|_| invalid_id())?, missing_seq),
+        };
+        Ok(StreamId { millis, seq })
+    }
+
+    /// Smallest ID strictly greater than this one.
+    pub fn incr(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { seq, ..self }),
+            None => self
+                .millis
+                .checked_add(1)
+                .map(|millis| StreamId { millis, seq: 0 }),
+        }
+    }
+
+    /// Largest ID strictly smaller than this one.
+    pub fn decr(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { seq, ..self }),
+            None => self.millis.checked_sub(1).map(|millis| StreamId {
+                millis,
+                seq: u64::MAX,
+            }),
         }
     }
 }
@@ -62,6 +326,12 @@ fn invalid_id() -> CommandError {
     )
 }
 
+fn not_greater_id() -> CommandError {
+    CommandError::InvalidArgument(
+        "The ID specified in XADD is equal or smaller than the target stream top item".to_string(),
+    )
+}
+
 fn exhausted_id() -> CommandError {
     CommandError::InvalidArgument(
         "The stream has exhausted the last possible ID, unable to add more items".to_string(),
@@ -74,45 +344,127 @@ impl Display for StreamId {
     }
 }
 
+/// Which entries `XTRIM` (or `XADD` with a trimming clause) evicts.
+#[derive(Debug, Clone, Copy)]
+pub enum TrimStrategy {
+    /// Keep at most this many entries.
+    MaxLen(usize),
+    /// Evict entries with an ID lower than this one.
+    MinId(StreamId),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrimOptions {
+    pub strategy: TrimStrategy,
+    /// `~` was given: trimming may stop early, bounded by `limit`.
+    pub approx: bool,
+    /// Max number of entries to evict, `None` meaning unbounded.
+    pub limit: Option<usize>,
+}
+
+/// What `XCLAIM` does to the entries it claims.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClaimOptions {
+    /// Entries delivered more recently than this many milliseconds are left
+    /// to their current owner.
+    pub min_idle: u64,
+    /// Delivery time recorded for claimed entries, now if `None`.
+    pub delivery_time: Option<u64>,
+    /// Delivery count to set, instead of counting the claim as a delivery.
+    pub retry_count: Option<u64>,
+    /// Entries missing from the PEL are claimed too, if still in the stream.
+    pub force: bool,
+    /// Claiming doesn't count as a delivery.
+    pub justid: bool,
+    /// Raises the group's last delivered ID.
+    pub last_id: Option<StreamId>,
+}
+
+/// Entries per stream node. Approximate trimming evicts at most 100 nodes
+/// worth of entries unless an explicit `LIMIT` is given.
+pub const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before UNIX_EPOCH")
+        .as_millis() as u64
+}
+
+/// Field-value pairs of a single stream entry.
+pub type StreamFields = Vec<(String, String)>;
+
+/// An entry delivered to a consumer and not acknowledged yet.
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    /// Last time the consumer was mentioned by a command.
+    pub seen_time: u64,
+    /// Last time the consumer actually read new entries.
+    pub active_time: Option<u64>,
+    pub pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    fn new() -> Self {
+        Consumer {
+            seen_time: now_millis(),
+            active_time: None,
+            pending: BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    pub last_id: StreamId,
+    /// Logical read counter, `None` when it can't be known (e.g. after an
+    /// arbitrary `XGROUP SETID`).
+    pub entries_read: Option<u64>,
+    pub pel: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
+
 // StreamType implementation
 #[derive(Debug, Default, Clone)]
 pub struct StreamType {
-    entries: BTreeMap<StreamId, Vec<(String, String)>>,
+    /// Nodes keyed by their master ID, the role the radix tree plays in Redis.
+    nodes: BTreeMap<StreamId, StreamNode>,
+    length: usize,
+    /// Last generated ID, which survives deletion of the top entry.
+    last_id: StreamId,
+    max_deleted_entry_id: StreamId,
+    /// Count of all entries ever added to the stream.
+    entries_added: u64,
+    groups: BTreeMap<String, ConsumerGroup>,
 }
 
 impl Display for StreamType {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "{} entries", self.entries.len())
+        write!(f, "{} entries", self.length)
     }
 }
 
 impl StreamType {
     ///Sequence number to use when the caller writes `<ms>-*`.
     fn next_seq(&self, millis: u64) -> Result<u64, CommandError> {
-        match self.entries.last_key_value() {
-            Some((last, _)) if last.millis == millis => {
-                last.seq.checked_add(1).ok_or_else(exhausted_id)
-            }
-            Some(_) => Ok(0),
-            None if millis == 0 => Ok(1),
-            None => Ok(0),
+        if self.last_id.millis == millis {
+            // No sequence is left after the top item's.
+            return self.last_id.seq.checked_add(1).ok_or_else(not_greater_id);
         }
+        Ok(0)
     }
 
     /// ID to use when the caller writes `*`
     fn next_auto_id(&self) -> Result<StreamId, CommandError> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("system clock is before UNIX_EPOCH")
-            .as_millis() as u64;
-
-        let Some((last, _)) = self.entries.last_key_value() else {
-            return Ok(StreamId {
-                millis: now,
-                seq: u64::from(now == 0),
-            });
-        };
-
+        let now = now_millis();
+        let last = self.last_id;
         if last.millis < now {
             return Ok(StreamId {
                 millis: now,
@@ -143,7 +495,7 @@ impl StreamType {
         let (ms_str, seq_str) = s.split_once('-').ok_or_else(invalid_id)?;
         let millis = ms_str.parse().map_err(|_| invalid_id())?;
         let seq = match seq_str {
-            "*" => self.next_seq(millis).unwrap(),
+            "*" => self.next_seq(millis)?,
             other => other.parse().map_err(|_| invalid_id())?,
         };
         if millis == 0 && seq == 0 {
@@ -160,43 +512,552 @@ impl StreamType {
         id: StreamId,
         fields: Vec<(String, String)>,
     ) -> Result<StreamId, CommandError> {
-        if let Some((last, _)) = self.entries.last_key_value() {
-            if id <= *last {
+        if id <= self.last_id {
+            return Err(not_greater_id());
+        }
+        match self.nodes.last_entry() {
+            Some(mut node) if !node.get().is_full(STREAM_NODE_MAX_ENTRIES) => {
+                node.get_mut().append(id, &fields)
+            }
+            _ => {
+                self.nodes.insert(id, StreamNode::new(id, &fields));
+            }
+        }
+        self.length += 1;
+        self.last_id = id;
+        self.entries_added += 1;
+        Ok(id)
+    }
+
+    /// Removes the given IDs, returning how many of them existed.
+    pub fn delete(&mut self, ids: &[StreamId]) -> usize {
+        let mut deleted = 0;
+        for id in ids {
+            let Some((&master_id, node)) = self.nodes.range_mut(..=*id).next_back() else {
+                continue;
+            };
+            if node.delete(*id) {
+                if node.is_empty() {
+                    self.nodes.remove(&master_id);
+                }
+                self.length -= 1;
+                self.max_deleted_entry_id = self.max_deleted_entry_id.max(*id);
+                deleted += 1;
+            }
+        }
+        deleted
+    }
+
+    /// Evicts entries from the head of the stream, returning how many were removed.
+    ///
+    /// Approximate trimming only drops whole nodes and stops before going past
+    /// `limit` evicted entries, so the stream may end up longer than asked
+    /// for; exact trimming also flags entries inside the first node as deleted.
+    pub fn trim(&mut self, opts: &TrimOptions) -> usize {
+        let limit = match (opts.approx, opts.limit) {
+            (false, _) | (true, Some(0)) => usize::MAX,
+            (true, Some(limit)) => limit,
+            (true, None) => 100 * STREAM_NODE_MAX_ENTRIES,
+        };
+        let mut removed = 0;
+        while let Some(mut first) = self.nodes.first_entry() {
+            let node = first.get_mut();
+            let whole_node = match opts.strategy {
+                TrimStrategy::MaxLen(max) => self.length - node.len() >= max,
+                TrimStrategy::MinId(min) => node.last_id() < min,
+            };
+            if whole_node {
+                if removed + node.len() > limit {
+                    break;
+                }
+                removed += node.len();
+                self.length -= node.len();
+                first.remove();
+                continue;
+            }
+            if opts.approx {
+                break;
+            }
+
+            let evicted = match opts.strategy {
+                TrimStrategy::MaxLen(max) => {
+                    let mut excess = self.length.saturating_sub(max);
+                    node.delete_head_while(|_| {
+                        let evict = excess > 0;
+                        excess = excess.saturating_sub(1);
+                        evict
+                    })
+                }
+                TrimStrategy::MinId(min) => node.delete_head_while(|id| id < min),
+            };
+            removed += evicted;
+            self.length -= evicted;
+            // Only deleted entries may be left, which no node is kept for.
+            if node.is_empty() {
+                first.remove();
+            }
+            break;
+        }
+        removed
+    }
+
+    /// Entries between `start` and `end` (both inclusive), in ascending order
+    /// or descending when `rev` is set, capped to `count` entries.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Vec<(StreamId, StreamFields)> {
+        if start > end {
+            return Vec::new();
+        }
+        let count = count.unwrap_or(usize::MAX);
+        // The node holding `start` is the last one whose master ID is not
+        // greater than it.
+        let from = self
+            .nodes
+            .range(..=start)
+            .next_back()
+            .map_or(StreamId::MIN, |(master_id, _)| *master_id);
+        let nodes = self.nodes.range(from..=end).map(|(_, node)| node);
+        let in_range = |(id, _): &(StreamId, StreamFields)| start <= *id && *id <= end;
+
+        if rev {
+            nodes
+                .rev()
+                .flat_map(|node| {
+                    let mut entries: Vec<_> = node.entries().filter(in_range).collect();
+                    entries.reverse();
+                    entries
+                })
+                .take(count)
+                .collect()
+        } else {
+            nodes
+                .flat_map(StreamNode::entries)
+                .filter(in_range)
+                .take(count)
+                .collect()
+        }
+    }
+
+    pub fn to_resp_range(&self, start: StreamId, end: StreamId) -> Reply {
+        entries_to_resp(&self.range(start, end, None, false))
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn first_entry(&self) -> Option<(StreamId, StreamFields)> {
+        self.nodes.values().next()?.entries().next()
+    }
+
+    pub fn last_entry(&self) -> Option<(StreamId, StreamFields)> {
+        self.nodes.values().next_back()?.entries().last()
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<StreamFields> {
+        self.nodes.range(..=id).next_back()?.1.get(id)
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn max_deleted_entry_id(&self) -> StreamId {
+        self.max_deleted_entry_id
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    /// ID of the first entry still in the stream, `0-0` when it is empty.
+    pub fn recorded_first_id(&self) -> StreamId {
+        self.first_entry().map(|(id, _)| id).unwrap_or_default()
+    }
+
+    /// Overrides the stream metadata, as done by `XSETID`.
+    pub fn set_id(
+        &mut self,
+        last_id: StreamId,
+        entries_added: Option<u64>,
+        max_deleted_entry_id: Option<StreamId>,
+    ) -> Result<(), CommandError> {
+        if let Some(max_deleted) = max_deleted_entry_id {
+            if last_id < max_deleted {
                 return Err(CommandError::InvalidArgument(
-                    "The ID specified in XADD is equal or smaller than the target stream top item"
+                    "The ID specified in XSETID is smaller than the provided max_deleted_entry_id"
                         .to_string(),
                 ));
             }
         }
-        self.entries.insert(id, fields);
-        Ok(id)
+        if let Some(added) = entries_added {
+            if (added as usize) < self.length {
+                return Err(CommandError::InvalidArgument(
+                    "The entries_added specified in XSETID is smaller than the target stream length"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some((top, _)) = self.last_entry() {
+            if last_id < top {
+                return Err(CommandError::InvalidArgument(
+                    "The ID specified in XSETID is smaller than the target stream top item"
+                        .to_string(),
+                ));
+            }
+        }
+
+        self.last_id = last_id;
+        if let Some(added) = entries_added {
+            self.entries_added = added;
+        }
+        if let Some(max_deleted) = max_deleted_entry_id {
+            self.max_deleted_entry_id = max_deleted;
+        }
+        Ok(())
     }
 
-    pub fn to_resp_range(&self, start: StreamId, end: StreamId) -> String {
-        if start > end {
-            return "*0\r\n".to_string();
+    /// Whether an `XDEL` tombstone may lie at or after `start`.
+    fn has_tombstones_from(&self, start: StreamId) -> bool {
+        if self.length == 0 || self.max_deleted_entry_id == StreamId::MIN {
+            return false;
+        }
+        if self.recorded_first_id() > self.max_deleted_entry_id {
+            return false;
+        }
+        start <= self.max_deleted_entry_id
+    }
+
+    /// Logical position of `id` counted from the first entry ever added, when
+    /// it can be known without scanning (mirrors Redis' estimation).
+    fn distance_from_first_ever_entry(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.length == 0 && id <= self.last_id {
+            return Some(self.entries_added);
+        }
+        if id == self.last_id {
+            return Some(self.entries_added);
+        }
+        if id > self.last_id {
+            return None;
+        }
+        let first = self.recorded_first_id();
+        if self.max_deleted_entry_id == StreamId::MIN || self.max_deleted_entry_id < first {
+            let len = self.length as u64;
+            if id < first {
+                return Some(self.entries_added - len);
+            } else if id == first {
+                return Some(self.entries_added - len + 1);
+            }
+        }
+        None
+    }
+
+    /// Number of entries still to be delivered to `group`, `None` when unknown.
+    pub fn group_lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if let Some(read) = group.entries_read {
+            if !self.has_tombstones_from(group.last_id) {
+                return Some(self.entries_added.saturating_sub(read));
+            }
+        }
+        self.distance_from_first_ever_entry(group.last_id)
+            .map(|read| self.entries_added.saturating_sub(read))
+    }
+
+    /// Adds a group as a whole, replacing any group of the same name. Used
+    /// when loading a stream along with its PEL and consumers.
+    pub fn insert_group(&mut self, name: String, group: ConsumerGroup) {
+        self.groups.insert(name, group);
+    }
+
+    pub fn groups(&self) -> &BTreeMap<String, ConsumerGroup> {
+        &self.groups
+    }
+
+    pub fn group(&self, name: &str) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    /// Creates a consumer group, returning `false` if it already exists.
+    pub fn create_group(
+        &mut self,
+        name: &str,
+        last_id: StreamId,
+        entries_read: Option<u64>,
+    ) -> bool {
+        if self.groups.contains_key(name) {
+            return false;
+        }
+        self.groups.insert(
+            name.to_string(),
+            ConsumerGroup {
+                last_id,
+                entries_read,
+                pel: BTreeMap::new(),
+                consumers: BTreeMap::new(),
+            },
+        );
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Moves the group's last delivered ID, returning `false` for a missing group.
+    pub fn set_group_id(
+        &mut self,
+        name: &str,
+        last_id: StreamId,
+        entries_read: Option<u64>,
+    ) -> bool {
+        match self.groups.get_mut(name) {
+            Some(group) => {
+                group.last_id = last_id;
+                group.entries_read = entries_read;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Creates a consumer, returning `None` for a missing group and whether
+    /// the consumer is new otherwise.
+    pub fn create_consumer(&mut self, group: &str, consumer: &str) -> Option<bool> {
+        let group = self.groups.get_mut(group)?;
+        if group.consumers.contains_key(consumer) {
+            return Some(false);
+        }
+        group
+            .consumers
+            .insert(consumer.to_string(), Consumer::new());
+        Some(true)
+    }
+
+    /// Deletes a consumer along with its pending entries, returning how many
+    /// entries it still had pending, or `None` for a missing group.
+    pub fn delete_consumer(&mut self, group: &str, consumer: &str) -> Option<usize> {
+        let group = self.groups.get_mut(group)?;
+        let Some(removed) = group.consumers.remove(consumer) else {
+            return Some(0);
+        };
+        for id in &removed.pending {
+            group.pel.remove(id);
+        }
+        Some(removed.pending.len())
+    }
+
+    /// Delivers entries after the group's last ID to `consumer`, advancing the
+    /// group and, unless `noack`, recording them as pending.
+    pub fn read_group_new(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        count: Option<usize>,
+        noack: bool,
+    ) -> Option<Vec<(StreamId, StreamFields)>> {
+        let start = self.groups.get(group)?.last_id.incr()?;
+        let delivered = self.range(start, StreamId::MAX, count, false);
+
+        let now = now_millis();
+        for (id, _) in &delivered {
+            let tombstones = self.has_tombstones_from(*id);
+            let estimated = self.distance_from_first_ever_entry(*id);
+            let entries_added = self.entries_added;
+            let group = self.groups.get_mut(group)?;
+            group.entries_read = match group.entries_read {
+                Some(read) if !tombstones => Some(read + 1),
+                read if entries_added == 0 => read,
+                _ => estimated,
+            };
+            group.last_id = *id;
+        }
+
+        let group = self.groups.get_mut(group)?;
+        let mut previous_owners = Vec::new();
+        if !noack {
+            for (id, _) in &delivered {
+                let previous = group.pel.insert(
+                    *id,
+                    PendingEntry {
+                        consumer: consumer.to_string(),
+                        delivery_time: now,
+                        delivery_count: 1,
+                    },
+                );
+                if let Some(previous) = previous {
+                    previous_owners.push((*id, previous.consumer));
+                }
+            }
+        }
+        // An entry re-delivered after `XGROUP SETID` changes owner.
+        for (id, owner) in previous_owners {
+            if let Some(owner) = group.consumers.get_mut(&owner) {
+                owner.pending.remove(&id);
+            }
+        }
+
+        let entry = group
+            .consumers
+            .entry(consumer.to_string())
+            .or_insert_with(Consumer::new);
+        entry.seen_time = now;
+        if !delivered.is_empty() {
+            entry.active_time = Some(now);
         }
+        if !noack {
+            entry.pending.extend(delivered.iter().map(|(id, _)| *id));
+        }
+        Some(delivered)
+    }
+
+    /// Returns the consumer's pending entries after `start`; entries deleted
+    /// from the stream in the meantime come back without fields.
+    pub fn read_group_pending(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        start: StreamId,
+        count: Option<usize>,
+    ) -> Option<Vec<(StreamId, Option<StreamFields>)>> {
+        let group = self.groups.get_mut(group)?;
+        let entry = group
+            .consumers
+            .entry(consumer.to_string())
+            .or_insert_with(Consumer::new);
+        entry.seen_time = now_millis();
+        let ids: Vec<StreamId> = entry
+            .pending
+            .range(start..)
+            .take(count.unwrap_or(usize::MAX))
+            .copied()
+            .collect();
+        Some(ids.into_iter().map(|id| (id, self.get(id))).collect())
+    }
+
+    /// Transfers ownership of pending entries to `consumer` and returns the
+    /// claimed ones, or `None` if the group doesn't exist. Entries deleted
+    /// from the stream in the meantime are dropped from the PEL instead.
+    pub fn claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        ids: &[StreamId],
+        opts: &ClaimOptions,
+    ) -> Option<Vec<(StreamId, StreamFields)>> {
+        let fields: Vec<Option<StreamFields>> = ids.iter().map(|id| self.get(*id)).collect();
+        let group = self.groups.get_mut(group)?;
+        let now = now_millis();
+        if let Some(last_id) = opts.last_id {
+            group.last_id = group.last_id.max(last_id);
+        }
+        group
+            .consumers
+            .entry(consumer.to_string())
+            .or_insert_with(Consumer::new)
+            .seen_time = now;
+
+        let mut claimed = Vec::new();
+        for (id, fields) in ids.iter().zip(fields) {
+            let Some(fields) = fields else {
+                if let Some(pending) = group.pel.remove(id) {
+                    if let Some(owner) = group.consumers.get_mut(&pending.consumer) {
+                        owner.pending.remove(id);
+                    }
+                }
+                continue;
+            };
+            let pending = match group.pel.get_mut(id) {
+                Some(pending) => {
+                    if now.saturating_sub(pending.delivery_time) < opts.min_idle {
+                        continue;
+                    }
+                    pending
+                }
+                None if opts.force => group.pel.entry(*id).or_insert(PendingEntry {
+                    consumer: consumer.to_string(),
+                    delivery_time: now,
+                    delivery_count: 1,
+                }),
+                None => continue,
+            };
+            let previous = std::mem::replace(&mut pending.consumer, consumer.to_string());
+            pending.delivery_time = opts.delivery_time.unwrap_or(now);
+            match opts.retry_count {
+                Some(count) => pending.delivery_count = count,
+                None if !opts.justid => pending.delivery_count += 1,
+                None => {}
+            }
+            if previous != consumer {
+                if let Some(owner) = group.consumers.get_mut(&previous) {
+                    owner.pending.remove(id);
+                }
+            }
+            claimed.push((*id, fields));
+        }
+
+        let entry = group
+            .consumers
+            .get_mut(consumer)
+            .expect("consumer was just created");
+        if !claimed.is_empty() {
+            entry.active_time = Some(now);
+        }
+        entry.pending.extend(claimed.iter().map(|(id, _)| *id));
+        Some(claimed)
+    }
 
-        let range = self.entries.range(start..=end);
-        let mut count = 0;
-        let mut body = String::new();
-        for (id, entries) in range {
-            let id_str = id.to_string();
-            let _ = write!(body, "*2\r\n${}\r\n{}\r\n", id_str.len(), id_str);
-            let _ = write!(body, "*{}\r\n", entries.len() * 2);
-            for entry in entries {
-                let _ = write!(body, "${}\r\n{}\r\n", entry.0.len(), entry.0);
-                let _ = write!(body, "${}\r\n{}\r\n", entry.1.len(), entry.1);
+    /// Acknowledges pending entries, returning how many were actually pending,
+    /// or `None` for a missing group.
+    pub fn ack(&mut self, group: &str, ids: &[StreamId]) -> Option<usize> {
+        let group = self.groups.get_mut(group)?;
+        let mut acked = 0;
+        for id in ids {
+            if let Some(pending) = group.pel.remove(id) {
+                if let Some(consumer) = group.consumers.get_mut(&pending.consumer) {
+                    consumer.pending.remove(id);
+                }
+                acked += 1;
             }
-            count += 1;
         }
-        format!("*{}\r\n{}", count, body)
+        Some(acked)
     }
 }
 
+pub fn entries_to_resp(entries: &[(StreamId, StreamFields)]) -> Reply {
+    Reply::Array(
+        entries
+            .iter()
+            .map(|(id, fields)| entry_to_resp(*id, fields))
+            .collect(),
+    )
+}
+
+/// A single entry, as `[id, [field1, value1, ...]]`.
+pub fn entry_to_resp(id: StreamId, fields: &[(String, String)]) -> Reply {
+    Reply::Array(vec![
+        Reply::bulk(id.to_string()),
+        Reply::bulks(
+            fields
+                .iter()
+                .flat_map(|(field, value)| [field.as_str(), value.as_str()]),
+        ),
+    ])
+}
+
 impl DBValue for StreamType {
     fn len(&self) -> usize {
-        self.entries.len()
+        self.length
     }
 
     fn type_name(&self) -> &'static str {
@@ -211,8 +1072,124 @@ impl DBValue for StreamType {
         self
     }
 
-    fn as_resp(&self) -> String {
+    fn memory_usage(&self) -> usize {
+        let nodes: usize = self.nodes.values().map(StreamNode::memory_usage).sum();
+        let groups: usize = self
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                name.capacity()
+                    + std::mem::size_of::<ConsumerGroup>()
+                    + group.pel.len()
+                        * (std::mem::size_of::<StreamId>() + std::mem::size_of::<PendingEntry>())
+                    + group
+                        .consumers
+                        .iter()
+                        .map(|(name, consumer)| {
+                            name.capacity()
+                                + std::mem::size_of::<Consumer>()
+                                + consumer.pending.len() * std::mem::size_of::<StreamId>()
+                        })
+                        .sum::<usize>()
+            })
+            .sum();
+        std::mem::size_of::<Self>() + nodes + groups
+    }
+
+    fn as_resp(&self) -> Reply {
         let id = StreamId { millis: 0, seq: 0 };
         self.to_resp_range(id, id)
     }
+
+    fn boxed_clone(&self) -> Box<dyn DBValue> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(millis: u64, seq: u64) -> StreamId {
+        StreamId { millis, seq }
+    }
+
+    fn ids(entries: &[(StreamId, StreamFields)]) -> Vec<StreamId> {
+        entries.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn rejects_exhausted_sequences() {
+        let mut stream = StreamType::default();
+        stream
+            .set_id(id(99_999_999_999_999, u64::MAX), None, None)
+            .unwrap();
+        let err = stream.parse_stream_id("99999999999999-*").unwrap_err();
+        assert_eq!(
+            err.message(),
+            "The ID specified in XADD is equal or smaller than the target stream top item"
+        );
+        assert_eq!(
+            stream.parse_stream_id("100000000000000-*").unwrap(),
+            id(100_000_000_000_000, 0)
+        );
+    }
+
+    #[test]
+    fn claim_moves_idle_entries_and_drops_deleted_ones() {
+        let mut stream = StreamType::default();
+        for seq in 1..=4 {
+            let fields = vec![("f".to_string(), seq.to_string())];
+            stream.add(id(1, seq), fields).unwrap();
+        }
+        stream.create_group("g", StreamId::default(), Some(0));
+        let read = stream.read_group_new("g", "alice", Some(3), false).unwrap();
+        assert_eq!(ids(&read), vec![id(1, 1), id(1, 2), id(1, 3)]);
+        stream
+            .groups
+            .get_mut("g")
+            .unwrap()
+            .pel
+            .get_mut(&id(1, 1))
+            .unwrap()
+            .delivery_time = now_millis() - 10_000;
+        stream.delete(&[id(1, 2)]);
+
+        let opts = ClaimOptions {
+            min_idle: 5_000,
+            ..ClaimOptions::default()
+        };
+        let all = [id(1, 1), id(1, 2), id(1, 3), id(1, 4)];
+        let claimed = stream.claim("g", "bob", &all, &opts).unwrap();
+        // 1-3 was delivered too recently, 1-4 never was.
+        assert_eq!(ids(&claimed), vec![id(1, 1)]);
+        let group = stream.group("g").unwrap();
+        assert_eq!(
+            group.pel.keys().copied().collect::<Vec<_>>(),
+            vec![id(1, 1), id(1, 3)]
+        );
+        assert_eq!(group.pel[&id(1, 1)].consumer, "bob");
+        assert_eq!(group.pel[&id(1, 1)].delivery_count, 2);
+        assert_eq!(group.consumers["alice"].pending, BTreeSet::from([id(1, 3)]));
+        assert_eq!(group.consumers["bob"].pending, BTreeSet::from([id(1, 1)]));
+
+        let opts = ClaimOptions {
+            force: true,
+            ..ClaimOptions::default()
+        };
+        let claimed = stream
+            .claim("g", "bob", &[id(1, 2), id(1, 4)], &opts)
+            .unwrap();
+        // Forcing doesn't bring back a deleted entry.
+        assert_eq!(ids(&claimed), vec![id(1, 4)]);
+        let group = stream.group("g").unwrap();
+        assert_eq!(group.pel[&id(1, 4)].consumer, "bob");
+        assert!(!group.pel.contains_key(&id(1, 2)));
+        assert_eq!(
+            group.consumers["bob"].pending,
+            BTreeSet::from([id(1, 1), id(1, 4)])
+        );
+
+        assert!(stream.claim("missing", "bob", &all, &opts).is_none());
+    }
 }
@@ -0,0 +1,3 @@
+#[macro_use]
+extern crate lazy_static;
+pub mod internal;
@@ -1,9 +1,5 @@
-#[macro_use]
-extern crate lazy_static;
-mod internal;
-
-use crate::internal::{cli, server};
 use clap::Parser;
+use codecrafters_redis::internal::{cli, server};
 use std::error::Error;
 
 #[tokio::main]
@@ -11,14 +7,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // You can use print statements as follows for debugging, they'll be visible when running tests.
 
     let args = cli::CliArgs::parse();
-    server::start_server(
-        "127.0.0.1",
-        args.port,
-        args.replicaof,
-        args.dir,
-        args.dbfilename,
-    )
-    .await?;
+    server::start_server("127.0.0.1", args).await?;
 
     Ok(())
 }